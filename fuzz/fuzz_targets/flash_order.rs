@@ -0,0 +1,98 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::pit::{Pit, PitEntry};
+
+// Names for the synthetic partitions, a few of them bootloader ones.
+const NAMES: [&str; 12] = [
+    "BOOTLOADER",
+    "BOOT",
+    "RECOVERY",
+    "SYSTEM",
+    "sboot",
+    "CACHE",
+    "TZSW",
+    "USERDATA",
+    "MODEM",
+    "PARAM",
+    "EFS",
+    "HIDDEN",
+];
+
+fn entry(identifier: u32, name: &str) -> PitEntry {
+    PitEntry {
+        binary_type: 0,
+        device_type: 2,
+        identifier,
+        attributes: 0,
+        update_attributes: 0,
+        block_size_or_offset: 0,
+        block_count: 0,
+        file_offset: 0,
+        file_size: 0,
+        partition_name: name.to_string(),
+        flash_filename: String::new(),
+        fota_filename: String::new(),
+    }
+}
+
+// The first byte picks the partitions of the PIT, the second which of them
+// are flashed, and the rest names partitions for the order, with values past
+// the names standing for ones that the PIT doesn't have.
+fuzz_target!(|data: &[u8]| {
+    let [entries, flashed, order @ ..] = data else {
+        return;
+    };
+
+    let pit = Pit {
+        entries: NAMES
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| entries & (1 << (index % 8)) != 0 || *index >= 8)
+            .map(|(index, name)| entry(index as u32 + 1, name))
+            .collect(),
+    };
+    let partitions = (0..pit.entries.len())
+        .filter(|index| flashed & (1 << (index % 8)) != 0)
+        .collect::<Vec<_>>();
+    let order = order
+        .iter()
+        .map(|&value| match NAMES.get(value as usize) {
+            Some(name) => name.to_string(),
+            None => format!("UNKNOWN{}", value),
+        })
+        .collect::<Vec<_>>();
+
+    let Ok(result) = pit.flash_order(&partitions, &order) else {
+        // Only names that aren't there or are there twice are refused.
+        let indices = order
+            .iter()
+            .map(|name| pit.find_index(name))
+            .collect::<Vec<_>>();
+        assert!(
+            indices.contains(&None)
+                || (0..indices.len()).any(|i| indices[i + 1..].contains(&indices[i]))
+        );
+        return;
+    };
+
+    // Every partition exactly once.
+    let mut sorted = result.clone();
+    sorted.sort();
+    assert_eq!(sorted, partitions);
+
+    // The named ones first, in their order.
+    let named = order
+        .iter()
+        .filter_map(|name| pit.find_index(name))
+        .filter(|index| partitions.contains(index))
+        .collect::<Vec<_>>();
+    assert_eq!(result[..named.len()], named);
+
+    // Then the others in the order of the PIT, with the bootloader last.
+    let rest = &result[named.len()..];
+    assert!(rest.windows(2).all(|pair| {
+        let bootloader = |index: usize| pit.entries[index].is_bootloader();
+        (bootloader(pair[0]), pair[0]) < (bootloader(pair[1]), pair[1])
+    }));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::inflate;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = inflate::inflate(&mut &data[..], &mut std::io::sink());
+});
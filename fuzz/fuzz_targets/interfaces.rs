@@ -0,0 +1,104 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::interfaces::{self, Direction, Endpoint, Setting, TransferType};
+
+const CLASSES: [u8; 5] = [0x02, 0x0a, 0xff, 0x08, 0x06];
+const TRANSFER_TYPES: [TransferType; 4] = [
+    TransferType::Control,
+    TransferType::Isochronous,
+    TransferType::Bulk,
+    TransferType::Interrupt,
+];
+
+fn endpoint(address: u8, transfer_type: TransferType, max_packet_size: u16) -> Endpoint {
+    Endpoint {
+        address,
+        direction: match address & 0x80 {
+            0 => Direction::Out,
+            _ => Direction::In,
+        },
+        transfer_type,
+        max_packet_size,
+    }
+}
+
+// The first byte asks for an interface if it's below 4. Then each setting is
+// its interface, a class, a subclass, which is Android's for odd values, and
+// the number of endpoints, followed by a byte for each endpoint with the
+// direction in the top bit and the transfer type in the lowest two.
+fn decode(data: &[u8]) -> Option<(Option<u8>, Vec<Setting>)> {
+    let (&forced, mut rest) = data.split_first()?;
+    let mut settings = Vec::new();
+
+    while let [interface, class, subclass, count, remaining @ ..] = rest {
+        let count = (*count % 4) as usize;
+        if remaining.len() < count {
+            break;
+        }
+
+        settings.push(Setting {
+            interface: interface % 4,
+            setting: settings.len() as u8,
+            class: CLASSES[*class as usize % CLASSES.len()],
+            subclass: if subclass & 1 != 0 { 0x42 } else { 0 },
+            protocol: 0,
+            endpoints: remaining[..count]
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| {
+                    endpoint(
+                        byte & 0x80 | index as u8 + 1,
+                        TRANSFER_TYPES[(byte & 3) as usize],
+                        512,
+                    )
+                })
+                .collect(),
+        });
+        rest = &remaining[count..];
+    }
+
+    Some(((forced < 4).then_some(forced), settings))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((forced, settings)) = decode(data) else {
+        return;
+    };
+    let choice = interfaces::choose(&settings, forced);
+
+    // Each setting is either picked or has a reason why not.
+    assert_eq!(choice.reasons.len(), settings.len());
+    for (index, reason) in choice.reasons.iter().enumerate() {
+        assert_eq!(reason.is_none(), choice.picked == Some(index));
+    }
+
+    let Some(picked) = choice.picked else {
+        assert_eq!(choice.control_interface, None);
+        return;
+    };
+    let setting = &settings[picked];
+    let (endpoint_in, endpoint_out) = setting.bulk_pair().unwrap();
+    assert_eq!(endpoint_in.direction, Direction::In);
+    assert_eq!(endpoint_out.direction, Direction::Out);
+
+    match forced {
+        Some(interface) => assert_eq!(setting.interface, interface),
+        None => {
+            assert!(setting.class == 0x0a || (setting.class == 0xff && setting.subclass != 0x42));
+
+            // A CDC data interface wins over vendor-specific ones.
+            if setting.class == 0xff {
+                assert!(!settings
+                    .iter()
+                    .any(|other| other.class == 0x0a && other.bulk_pair().is_ok()));
+            }
+        }
+    }
+
+    if let Some(control) = choice.control_interface {
+        assert!(settings
+            .iter()
+            .any(|other| other.interface == control && other.class == 0x02));
+    }
+});
@@ -0,0 +1,53 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::config::VendorAllowList;
+use sbootil::template::{self, Value};
+use sbootil::parse::DeviceSpec;
+use sbootil::{expr, format, parse, sha256};
+use std::time::Duration;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(string) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let _ = parse::parse_u64(string);
+    let _ = expr::evaluate(string, |name| (name == "base").then_some(0x4000_0000));
+    let _ = template::expand(
+        string,
+        &[
+            ("start", Value::Number(0x4000_0000)),
+            ("end", Value::Number(u64::MAX)),
+            ("name", Value::Text(string.to_string())),
+        ],
+    );
+
+    if let Some(digest) = sha256::parse_hex(string) {
+        assert_eq!(sha256::to_hex(&digest), string.to_ascii_lowercase());
+    }
+
+    // Vendor lists read back the way they are shown.
+    if let Ok(vendors) = VendorAllowList::parse(string) {
+        assert_eq!(VendorAllowList::parse(&vendors.to_string()), Ok(vendors));
+    }
+
+    // Devices are passed on the way --print-device prints them.
+    if let Ok(spec) = DeviceSpec::parse(string) {
+        assert_eq!(DeviceSpec::parse(&spec.to_string()), Ok(spec));
+    }
+
+    // Whatever a template prints, the number parser reads back.
+    if data.len() >= 8 {
+        let number = u64::from_le_bytes(data[..8].try_into().unwrap());
+
+        let _ = format::human_bytes(number);
+        let _ = format::human_duration(Duration::from_millis(number));
+        let _ = format::human_rate(number, Duration::from_nanos(number));
+
+        for format in ["{start}", "{start:#x}", "{start:#018X}"] {
+            let expanded = template::expand(format, &[("start", Value::Number(number))]).unwrap();
+            assert_eq!(parse::parse_u64(&expanded), Ok(number));
+        }
+    }
+});
@@ -0,0 +1,89 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::odin::Session;
+use sbootil::pit::{Pit, PitEntry};
+use sbootil::timeouts::Timeouts;
+use sbootil::transport::Transport;
+use sbootil::{CancelToken, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+// A device that answers with the fuzzer's input, a few bytes at a time, and
+// accepts whatever is sent to it.
+struct Device {
+    responses: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.responses.len() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        let count = buf.len().min(self.responses.len() - self.position).min(7);
+        buf[..count].copy_from_slice(&self.responses[self.position..self.position + count]);
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Device {
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let device = Device {
+        responses: data.to_vec(),
+        position: 0,
+    };
+
+    let Ok(mut session) = Session::begin(Box::new(device), Timeouts::new(Duration::ZERO)) else {
+        return;
+    };
+
+    if let Ok(pit) = session.receive_pit() {
+        let _ = Pit::parse(&pit);
+    }
+
+    let entry = PitEntry {
+        binary_type: 0,
+        device_type: 2,
+        identifier: 1,
+        attributes: 0,
+        update_attributes: 0,
+        block_size_or_offset: 0,
+        block_count: 0,
+        file_offset: 0,
+        file_size: 0,
+        partition_name: "BOOT".to_string(),
+        flash_filename: "boot.img".to_string(),
+        fota_filename: String::new(),
+    };
+    let file = [0x5au8; 300];
+
+    let _ = session.flash(
+        &entry,
+        &mut &file[..],
+        file.len() as u64,
+        &CancelToken::new(),
+    );
+});
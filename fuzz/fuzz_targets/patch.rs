@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::patch::{self, Format};
+
+fuzz_target!(|data: &[u8]| {
+    for format in [Format::Text, Format::Ips] {
+        if let Ok(runs) = patch::parse(data, format) {
+            // Whatever gets through can be written in any order.
+            let mut runs = runs.iter().collect::<Vec<_>>();
+            runs.sort_by_key(|run| run.offset);
+            assert!(runs.windows(2).all(|pair| pair[0].end() <= pair[1].offset));
+            assert!(runs.iter().all(|run| !run.data.is_empty()));
+        }
+    }
+});
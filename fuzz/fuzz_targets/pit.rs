@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::pit::Pit;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(pit) = Pit::parse(data) {
+        // Every entry has to have come out of the data.
+        assert!(28 + pit.entries.len() * 132 <= data.len());
+
+        for entry in &pit.entries {
+            assert!(pit.find(&entry.partition_name).is_some());
+        }
+    }
+});
@@ -0,0 +1,60 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::resume::{ResumeWriter, State};
+use std::io::{Cursor, Write};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(state) = std::str::from_utf8(data).map(State::parse) {
+        if let Ok(state) = state {
+            assert_eq!(State::parse(&state.to_text()), Ok(state));
+        }
+    }
+
+    // A chunk size, the size of the dump, and then for every run that dies,
+    // after how many chunks. Runs die with a chunk that arrived corrupted or
+    // in the middle of one, like a crash, until the last one goes through.
+    let [chunk_size, size, crashes @ ..] = data else {
+        return;
+    };
+    let chunk_size = u64::from(chunk_size % 16 + 1);
+    let memory = (0..*size).map(|byte| byte ^ 0x5a).collect::<Vec<_>>();
+
+    let mut state = State::new(0x1000, 0x1000 + u64::from(*size), chunk_size, "crc32");
+    // Whatever was in the file before.
+    let mut output = Cursor::new(vec![0xee; memory.len()]);
+
+    for crash in crashes.iter().map(Some).chain([None]) {
+        let mut writer = ResumeWriter::new(output, state, None);
+        let mut left = crash.map(|crash| crash / 2);
+
+        'runs: for run in writer.state().missing() {
+            writer.start_run(run.start).unwrap();
+
+            for index in run {
+                let chunk = writer.state().chunk(index);
+                let data = &memory[(chunk.start - 0x1000) as usize..(chunk.end - 0x1000) as usize];
+
+                if left == Some(0) {
+                    match crash.unwrap() % 2 {
+                        0 => writer.write_all(&vec![0xcc; data.len()]).unwrap(),
+                        _ => writer.write_all(&data[..data.len() / 2]).unwrap(),
+                    }
+                    break 'runs;
+                }
+
+                writer.write_all(data).unwrap();
+                left = left.map(|left| left - 1);
+            }
+
+            writer.commit().unwrap();
+        }
+
+        // Carries on from what would be in the state file.
+        state = State::parse(&writer.state().to_text()).unwrap();
+        output = writer.into_inner();
+    }
+
+    assert!(state.missing().is_empty());
+    assert_eq!(output.into_inner(), memory);
+});
@@ -0,0 +1,170 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::sink::{Compression, Destination, Pipeline, Plan, StreamSink};
+use sbootil::{crc32, inflate, sha256};
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+// Collects what a sink writes, for looking at it once the sink is done.
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    assert_eq!(data[..10], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+
+    let mut output = Vec::new();
+    inflate::inflate(&mut &data[10..data.len() - 8], &mut output).unwrap();
+
+    let trailer = &data[data.len() - 8..];
+    assert_eq!(trailer[..4], crc32::crc32(&output).to_le_bytes());
+    assert_eq!(trailer[4..], (output.len() as u32).to_le_bytes());
+
+    output
+}
+
+// Only the raw and RLE blocks that zstd.rs writes.
+fn unzstd(data: &[u8]) -> Vec<u8> {
+    assert_eq!(data[..6], [0x28, 0xb5, 0x2f, 0xfd, 0, 7 << 3]);
+
+    let mut output = Vec::new();
+    let mut rest = &data[6..];
+
+    loop {
+        let header = u32::from_le_bytes([rest[0], rest[1], rest[2], 0]);
+        let size = (header >> 3) as usize;
+        assert!(size <= 128 * 1024);
+        rest = &rest[3..];
+
+        match header >> 1 & 3 {
+            0 => {
+                output.extend_from_slice(&rest[..size]);
+                rest = &rest[size..];
+            }
+            1 => {
+                output.extend(std::iter::repeat_n(rest[0], size));
+                rest = &rest[1..];
+            }
+            block_type => panic!("unexpected block type {}", block_type),
+        }
+
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    assert!(rest.is_empty());
+    output
+}
+
+// Puts the data back together from the lines of the hexdump.
+fn unhexdump(text: &str, start: u64) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut previous: Vec<u8> = Vec::new();
+    let mut repeating = false;
+
+    for line in text.lines() {
+        if line == "*" {
+            repeating = true;
+            continue;
+        }
+
+        let address = u64::from_str_radix(&line[..8], 16).unwrap();
+        while repeating && start + (output.len() as u64) < address {
+            output.extend_from_slice(&previous);
+        }
+        repeating = false;
+        assert_eq!(address, start + output.len() as u64);
+
+        if line.len() == 8 {
+            break;
+        }
+
+        previous = line[10..59]
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect();
+        output.extend_from_slice(&previous);
+    }
+
+    output
+}
+
+// The first byte picks the sinks, the second how big the pieces are that
+// they are handed, the rest is the data. Runs of the same byte are common
+// in dumps, so the top bit of the first byte stretches every byte of the
+// data into a run.
+fuzz_target!(|data: &[u8]| {
+    let [options, piece, data @ ..] = data else {
+        return;
+    };
+
+    let data = if options & 0x80 != 0 {
+        data.iter()
+            .flat_map(|&byte| std::iter::repeat_n(byte, usize::from(byte)))
+            .collect::<Vec<_>>()
+    } else {
+        data.to_vec()
+    };
+
+    let plan = Plan {
+        destination: Destination::Stdout,
+        compression: match options % 3 {
+            0 => None,
+            1 => Some(Compression::Gzip),
+            _ => Some(Compression::Zstd),
+        },
+        hexdump: (options & 0x10 != 0).then_some(u64::from(*piece) << 12),
+        force: false,
+    };
+
+    let stages = plan.stages();
+    assert_eq!(stages.first(), Some(&"sha256"));
+    assert_eq!(stages.last(), Some(&"stdout"));
+    assert_eq!(stages.contains(&"hexdump"), plan.hexdump.is_some());
+
+    let output = Shared::default();
+    let terminal = Shared::default();
+    let mut pipeline = Pipeline::new(plan.compose(
+        Box::new(StreamSink::new(output.clone())),
+        Box::new(terminal.clone()),
+    ));
+
+    for chunk in data.chunks(usize::from(*piece).max(1)) {
+        pipeline.write_all(chunk).unwrap();
+    }
+    let summary = pipeline.finish().unwrap();
+
+    assert_eq!(summary.bytes, data.len() as u64);
+    assert_eq!(summary.sha256, Some(sha256::sha256(&data)));
+    assert!(summary.files.is_empty());
+
+    let output = output.0.borrow();
+    let decoded = match plan.compression {
+        None => output.clone(),
+        Some(Compression::Gzip) => gunzip(&output),
+        Some(Compression::Zstd) => unzstd(&output),
+    };
+    assert_eq!(decoded, data);
+
+    let terminal = terminal.0.borrow();
+    match plan.hexdump {
+        Some(start) => {
+            let text = std::str::from_utf8(&terminal).unwrap();
+            assert_eq!(unhexdump(text, start), data);
+        }
+        None => assert!(terminal.is_empty()),
+    }
+});
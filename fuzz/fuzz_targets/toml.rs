@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sbootil::profile::{self, Origin, Profile};
+use sbootil::toml;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = toml::parse(text);
+
+        // A user profile replaces the built-in one of the same name as a
+        // whole, and leaves the others alone.
+        if let Ok(user) = Profile::parse("exynos4412", text, Origin::File("fuzz.toml".into())) {
+            let builtin = profile::builtin();
+            let merged = profile::merge(builtin.clone(), vec![user.clone()]);
+
+            assert_eq!(merged.len(), builtin.len());
+            for profile in &merged {
+                match profile.name.as_str() {
+                    "exynos4412" => assert_eq!(*profile, user),
+                    name => assert!(builtin.iter().any(|builtin| builtin.name == name
+                        && builtin == profile)),
+                }
+            }
+        }
+    }
+});
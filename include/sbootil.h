@@ -0,0 +1,130 @@
+#ifndef SBOOTIL_H
+#define SBOOTIL_H
+
+/* Generated with cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Success.
+ */
+#define SBOOTIL_OK 0
+
+/**
+ * Any error that doesn't fall into one of the other classes.
+ */
+#define SBOOTIL_ERR_FAILURE -1
+
+/**
+ * An argument is missing or doesn't make sense.
+ */
+#define SBOOTIL_ERR_INVALID_ARGUMENT -2
+
+/**
+ * No matching device was found, or it went away.
+ */
+#define SBOOTIL_ERR_DEVICE_NOT_FOUND -3
+
+/**
+ * The device can't be opened for lack of permissions.
+ */
+#define SBOOTIL_ERR_PERMISSION_DENIED -4
+
+/**
+ * The device didn't answer as expected.
+ */
+#define SBOOTIL_ERR_PROTOCOL -5
+
+/**
+ * The data was transferred, but doesn't check out.
+ */
+#define SBOOTIL_ERR_VERIFICATION -6
+
+/**
+ * The transfer was interrupted.
+ */
+#define SBOOTIL_ERR_INTERRUPTED -7
+
+/**
+ * The device is in use by another call, in this process or another one.
+ */
+#define SBOOTIL_ERR_DEVICE_IN_USE -8
+
+/**
+ * Called with the number of bytes transferred so far and the total, on the
+ * thread that made the call. It must not call back into sbootil, other
+ * than to cancel the call.
+ */
+typedef void (*sbootil_progress_cb)(uint64_t done, uint64_t total, void *user);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Flashes an image to a partition of a device in download mode, which is
+ * looked up by its name or identifier in the PIT of the device. The device
+ * is rebooted afterwards.
+ *
+ * `usb_selector` picks the device with comma-separated options, e.g.
+ * `usb=04e8:685d,serial-number=R58M123456` or `bus-address=1:4`. With NULL
+ * or an empty string, the only Samsung device is used.
+ *
+ * Returns SBOOTIL_OK or one of the SBOOTIL_ERR_* codes, see
+ * sbootil_last_error_message() for the details. SBOOTIL_ERR_INTERRUPTED
+ * after sbootil_cancel(), once the current sequence of file parts is done.
+ *
+ * # Safety
+ *
+ * The strings must be NUL-terminated and stay valid for the duration of
+ * the call, only `usb_selector` may be NULL. `progress` may be NULL.
+ */
+int sbootil_flash_partition(const char *usb_selector,
+                            const char *partition,
+                            const char *image_path,
+                            sbootil_progress_cb progress,
+                            void *user);
+
+/**
+ * Dumps the memory from `start` up to `end` from bootstub on a serial port
+ * into a file.
+ *
+ * Returns SBOOTIL_OK or one of the SBOOTIL_ERR_* codes, see
+ * sbootil_last_error_message() for the details. SBOOTIL_ERR_INTERRUPTED
+ * after sbootil_cancel(), once the current chunk is done. The file is left
+ * with what was dumped until then.
+ *
+ * # Safety
+ *
+ * The strings must be NUL-terminated and stay valid for the duration of
+ * the call. `progress` may be NULL.
+ */
+int sbootil_dump(const char *serial_path,
+                 uint64_t start,
+                 uint64_t end,
+                 const char *output_path,
+                 sbootil_progress_cb progress,
+                 void *user);
+
+/**
+ * Cancels the calls that are running on any thread, which return
+ * SBOOTIL_ERR_INTERRUPTED once they got to a point where they can stop.
+ * Calls that start afterwards aren't affected. Unlike the other calls, this
+ * one may be made from a progress callback.
+ */
+void sbootil_cancel(void);
+
+/**
+ * The message for the last error on the calling thread, or NULL if the last
+ * call succeeded. It stays valid until the next call on the same thread.
+ */
+const char *sbootil_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SBOOTIL_H */
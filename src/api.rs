@@ -0,0 +1,412 @@
+use crate::bootstub::{self, Checksum, DumpOptions};
+use crate::cancel::CancelToken;
+#[cfg(feature = "usb")]
+use crate::device::{LineCoding, Selector, UsbCdcDevice};
+use crate::error::{Error, Result};
+use crate::events;
+#[cfg(any(feature = "serial", feature = "usb"))]
+use crate::lock::LockMode;
+use crate::odin::{self, Identity};
+use crate::pit::Pit;
+#[cfg(feature = "serial")]
+use crate::serial::SerialPort;
+use crate::timeouts::Timeouts;
+#[cfg(feature = "usb")]
+use crate::transport::UsbTransport;
+use crate::transport::{CountingTransport, Statistics, Transport};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+// One call for each of the common flows, for programs that would rather not
+// manage sessions themselves. Progress is reported to a callback, the events
+// and status output of the command line aren't involved.
+
+/// How far a transfer has come, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: u64,
+}
+
+/// What a dump came to.
+#[derive(Clone, Copy, Debug)]
+pub struct DumpSummary {
+    pub bytes: u64,
+    pub sha256: [u8; 32],
+    pub crc32: u32,
+    /// What the chunks were checked with on the way.
+    pub checksum: Checksum,
+    pub statistics: Statistics,
+    pub elapsed: Duration,
+}
+
+/// What flashing a partition came to.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// The device that was flashed.
+    pub device: Identity,
+    pub partition: String,
+    pub bytes: u64,
+    pub sha256: [u8; 32],
+    pub statistics: Statistics,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct FlashOptions {
+    /// Reboot the device afterwards, rather than leaving it in download mode.
+    pub reboot: bool,
+    pub timeouts: Timeouts,
+    /// Don't flash anything unless the device has this USB serial number.
+    pub expect_serial_number: Option<String>,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        Self {
+            reboot: true,
+            timeouts: Timeouts::new(odin::DEFAULT_TIMEOUT),
+            expect_serial_number: None,
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+const DEFAULT_BAUD: u32 = 115200;
+
+// Only hands on the progress events that belong to the operation.
+fn progress_hook(
+    event: &'static str,
+    mut progress: impl FnMut(Progress) + 'static,
+) -> impl FnMut(&str, u64, u64) + 'static {
+    move |name, done, total| {
+        if name == event {
+            progress(Progress { done, total });
+        }
+    }
+}
+
+/// Dumps a range of memory from bootstub on a serial port into `writer`.
+///
+/// ```no_run
+/// let mut file = std::fs::File::create("dump.bin")?;
+/// let summary = sbootil::dump_memory(
+///     "/dev/ttyUSB0",
+///     0x0..0x1000,
+///     &mut file,
+///     &sbootil::CancelToken::new(),
+///     |progress| eprintln!("{} of {} bytes", progress.done, progress.total),
+/// )?;
+/// println!("SHA-256: {}", sbootil::sha256::to_hex(&summary.sha256));
+/// # Ok::<(), sbootil::Error>(())
+/// ```
+#[cfg(feature = "serial")]
+pub fn dump_memory(
+    serial_path: &str,
+    range: Range<u64>,
+    writer: &mut dyn Write,
+    cancel: &CancelToken,
+    progress: impl FnMut(Progress) + 'static,
+) -> Result<DumpSummary> {
+    let port = SerialPort::open(serial_path, DEFAULT_BAUD, LockMode::Fail)?;
+
+    dump_memory_over(
+        Box::new(port),
+        range,
+        &DumpOptions::default(),
+        writer,
+        cancel,
+        progress,
+    )
+}
+
+/// Like [`dump_memory`], over any transport and with the options of the
+/// command line.
+///
+/// ```
+/// use sbootil::bootstub::DumpOptions;
+/// use sbootil::transport::MockTransport;
+/// use sbootil::CancelToken;
+///
+/// // A stub from before the capabilities query, which dumps four bytes.
+/// let mock = MockTransport::new();
+/// mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+/// mock.expect_write(b"GETCAPS").time_out();
+/// mock.expect_write(b"UPLDMEM").expect_write(b"0x0").expect_write(b"0x4");
+/// mock.respond(b"STRTUPLD").respond(&[1, 2, 3, 4, 4]).respond(b"ENDUPLD");
+///
+/// let mut data = Vec::new();
+/// let summary = sbootil::dump_memory_over(
+///     Box::new(mock.clone()),
+///     0x0..0x4,
+///     &DumpOptions::default(),
+///     &mut data,
+///     &CancelToken::new(),
+///     |_| {},
+/// )?;
+///
+/// assert_eq!(data, [1, 2, 3, 4]);
+/// assert_eq!(summary.bytes, 4);
+/// assert!(mock.is_finished());
+/// # Ok::<(), sbootil::Error>(())
+/// ```
+///
+/// A stub that sends more than the range is given up on, without writing
+/// more than the range:
+///
+/// ```
+/// use sbootil::bootstub::DumpOptions;
+/// use sbootil::transport::MockTransport;
+/// use sbootil::CancelToken;
+/// use sbootil::Error;
+///
+/// // Ten times the 64 bytes that were asked for.
+/// let mock = MockTransport::new();
+/// mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+/// mock.expect_write(b"GETCAPS").time_out();
+/// mock.expect_write(b"UPLDMEM").expect_write(b"0x0").expect_write(b"0x40");
+/// mock.respond(b"STRTUPLD").respond(&[0xaa; 640]);
+///
+/// let mut data = Vec::new();
+/// let result = sbootil::dump_memory_over(
+///     Box::new(mock.clone()),
+///     0x0..0x40,
+///     &DumpOptions::default(),
+///     &mut data,
+///     &CancelToken::new(),
+///     |_| {},
+/// );
+///
+/// assert!(matches!(result, Err(Error::Overrun { requested: 64, .. })));
+/// assert_eq!(data, [0xaa; 64]);
+/// assert!(mock.is_finished());
+/// ```
+///
+/// A stub that claims CRC-32 but doesn't take to selecting it fails the
+/// negotiation, which the error tells apart from failed transfers:
+///
+/// ```
+/// use sbootil::bootstub::DumpOptions;
+/// use sbootil::transport::MockTransport;
+/// use sbootil::CancelToken;
+/// use sbootil::Error;
+///
+/// // Version 2 with CRC-32, which echoes SETCSUM instead of confirming it.
+/// let mock = MockTransport::new();
+/// mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+/// mock.expect_write(b"GETCAPS").respond(b"CAPS\x02\0\0\0\x02\0\0\0");
+/// mock.expect_write(b"SETCSUM").expect_write(b"crc32").respond(b"SETCSUM");
+///
+/// let result = sbootil::dump_memory_over(
+///     Box::new(mock.clone()),
+///     0x0..0x4,
+///     &DumpOptions::default(),
+///     &mut Vec::new(),
+///     &CancelToken::new(),
+///     |_| {},
+/// );
+///
+/// assert!(matches!(result, Err(Error::Negotiation(_))));
+/// ```
+///
+/// Cancelling the token, e.g. from the progress callback or another thread,
+/// stops the dump before the next chunk:
+///
+/// ```
+/// use sbootil::bootstub::DumpOptions;
+/// use sbootil::transport::MockTransport;
+/// use sbootil::CancelToken;
+/// use sbootil::Error;
+///
+/// // Only the first of two chunks is asked for.
+/// let mock = MockTransport::new();
+/// mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+/// mock.expect_write(b"GETCAPS").time_out();
+/// mock.expect_write(b"UPLDMEM").expect_write(b"0x0").expect_write(b"0x10000");
+/// mock.respond(b"STRTUPLD").respond(&[0xaa; 0x10000]).respond(&[0]).respond(b"ENDUPLD");
+///
+/// let cancel = CancelToken::new();
+/// let cancel_midway = cancel.clone();
+/// let mut data = Vec::new();
+/// let result = sbootil::dump_memory_over(
+///     Box::new(mock.clone()),
+///     0x0..0x20000,
+///     &DumpOptions {
+///         chunk_size: 0x10000,
+///         ..DumpOptions::default()
+///     },
+///     &mut data,
+///     &cancel,
+///     move |progress| {
+///         if progress.done == 0x10000 {
+///             cancel_midway.cancel();
+///         }
+///     },
+/// );
+///
+/// assert!(matches!(result, Err(Error::Cancelled)));
+/// assert_eq!(data, [0xaa; 0x10000]);
+/// assert!(mock.is_finished());
+/// ```
+pub fn dump_memory_over(
+    transport: Box<dyn Transport>,
+    range: Range<u64>,
+    options: &DumpOptions,
+    writer: &mut dyn Write,
+    cancel: &CancelToken,
+    progress: impl FnMut(Progress) + 'static,
+) -> Result<DumpSummary> {
+    let started = Instant::now();
+    let mut session = bootstub::Session::connect(
+        Box::new(CountingTransport::new(transport)),
+        Timeouts::new(bootstub::DEFAULT_TIMEOUT),
+    )?;
+
+    let digest = events::with_progress_hook(progress_hook("dump_progress", progress), || {
+        session.dump(range.start, range.end, options, writer, cancel)
+    })?;
+
+    Ok(DumpSummary {
+        bytes: range.end.saturating_sub(range.start),
+        sha256: digest.sha256,
+        crc32: digest.crc32,
+        checksum: session.checksum(),
+        statistics: session.statistics(),
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Flashes a file to a partition of a device in download mode, which is
+/// looked up by its name or identifier in the PIT of the device.
+///
+/// ```no_run
+/// use sbootil::api::FlashOptions;
+/// use sbootil::device::Selector;
+///
+/// let selector = Selector {
+///     serial_number: Some("R58M123456".to_string()),
+///     ..Selector::default()
+/// };
+/// let report = sbootil::flash_partition(
+///     &selector,
+///     "BOOT",
+///     "boot.img".as_ref(),
+///     &FlashOptions::default(),
+///     &sbootil::CancelToken::new(),
+///     |progress| eprintln!("{} of {} bytes", progress.done, progress.total),
+/// )?;
+/// println!("Flashed {} bytes to {}", report.bytes, report.partition);
+/// # Ok::<(), sbootil::Error>(())
+/// ```
+#[cfg(feature = "usb")]
+pub fn flash_partition(
+    selector: &Selector,
+    partition: &str,
+    path: &std::path::Path,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    progress: impl FnMut(Progress) + 'static,
+) -> Result<Report> {
+    let file_error = |source| Error::File {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut file = std::fs::File::open(path).map_err(file_error)?;
+    let size = file.metadata().map_err(file_error)?.len();
+
+    let device =
+        UsbCdcDevice::open_selected(selector, Some(LineCoding::default()), LockMode::Fail)?;
+    let strings = device.strings().clone();
+    let identity = Identity {
+        product: strings.product,
+        serial_number: strings.serial_number,
+        ..Identity::default()
+    };
+
+    flash(
+        (Box::new(UsbTransport::new(device)), identity),
+        partition,
+        &mut file,
+        size,
+        options,
+        cancel,
+        progress,
+    )
+}
+
+/// Like [`flash_partition`], over any transport and with the data coming
+/// from anywhere. The transport doesn't tell the serial number of the device,
+/// so [`FlashOptions::expect_serial_number`] always fails here.
+pub fn flash_partition_over(
+    transport: Box<dyn Transport>,
+    partition: &str,
+    data: &mut dyn Read,
+    size: u64,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    progress: impl FnMut(Progress) + 'static,
+) -> Result<Report> {
+    flash(
+        (transport, Identity::default()),
+        partition,
+        data,
+        size,
+        options,
+        cancel,
+        progress,
+    )
+}
+
+// `identity` has what the USB string descriptors say, if anything.
+fn flash(
+    (transport, identity): (Box<dyn Transport>, Identity),
+    partition: &str,
+    data: &mut dyn Read,
+    size: u64,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    progress: impl FnMut(Progress) + 'static,
+) -> Result<Report> {
+    let started = Instant::now();
+    let mut session = odin::Session::begin(
+        Box::new(CountingTransport::new(transport)),
+        options.timeouts,
+    )?;
+
+    let identity = session.identity(identity.product, identity.serial_number);
+    if let Some(expected) = &options.expect_serial_number {
+        identity.check_serial_number(expected)?;
+    }
+
+    let pit = Pit::parse(&session.receive_pit()?)?;
+    let entry = pit
+        .find_index(partition)
+        .map(|index| &pit.entries[index])
+        .ok_or_else(|| {
+            Error::InvalidArgument(format!("There is no partition {} in the PIT", partition))
+        })?;
+
+    session.set_total_bytes(size)?;
+
+    let sha256 = events::with_progress_hook(progress_hook("flash_progress", progress), || {
+        session.flash(entry, data, size, cancel)
+    })?;
+
+    let report = Report {
+        device: identity,
+        partition: entry.partition_name.clone(),
+        bytes: size,
+        sha256,
+        statistics: session.statistics(),
+        elapsed: started.elapsed(),
+    };
+
+    if options.reboot {
+        session.reboot()?;
+    } else {
+        session.end()?;
+    }
+
+    Ok(report)
+}
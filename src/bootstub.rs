@@ -0,0 +1,2154 @@
+use crate::cancel::CancelToken;
+use crate::crc32::Crc32;
+use crate::error::{Error, Result};
+use crate::events;
+use crate::format::human_duration;
+use crate::json::Object;
+use crate::retry::RetryPolicy;
+use crate::sha256::{self, Sha256};
+use crate::timeouts::Timeouts;
+use crate::transport::{Statistics, Transport};
+use crate::{status, step, warning};
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+fn read_error(err: std::io::Error, step: &str) -> Error {
+    match err.kind() {
+        ErrorKind::TimedOut => Error::Timeout {
+            phase: step.to_string(),
+        },
+        _ => Error::Io(std::io::Error::new(
+            err.kind(),
+            format!("Failed to read {}: {}", step, err),
+        )),
+    }
+}
+
+fn read_step(device: &mut dyn Transport, buf: &mut [u8], step: &str) -> Result<()> {
+    device.read_exact(buf).map_err(|err| read_error(err, step))
+}
+
+fn send(device: &mut dyn Transport, data: &[u8]) -> Result<()> {
+    step!("sending {}", String::from_utf8_lossy(data));
+
+    Ok(device.write_all(data)?)
+}
+
+// Stubs built with debug output print all kinds of things, so the expected
+// response is searched for in up to this much of what arrives.
+const RESPONSE_SCAN_BUDGET: usize = 4096;
+
+// How much of the expected response the end of the buffer could be the start
+// of, so that reading on never goes past the response.
+fn partial_match(buf: &[u8], expected: &[u8]) -> usize {
+    (1..expected.len())
+        .rev()
+        .find(|&length| buf.ends_with(&expected[..length]))
+        .unwrap_or(0)
+}
+
+fn expect_response(device: &mut dyn Transport, expected: &[u8], step: &str) -> Result<()> {
+    expect_response_within(device, expected, step, RESPONSE_SCAN_BUDGET)
+}
+
+// After the data and the checksum of a transfer, there's only ENDUPLD left,
+// maybe after a line of debug output. A device that keeps sending
+// instead is drained for a bounded time and given up on, rather than read
+// from for as long as it babbles.
+const TRAILER_SCAN_BUDGET: usize = 256;
+
+fn expect_trailer(device: &mut dyn Transport, what: &str, requested: u64) -> Result<()> {
+    let result = expect_response_within(
+        device,
+        b"ENDUPLD",
+        &format!("after receiving {}", what),
+        TRAILER_SCAN_BUDGET,
+    );
+
+    if let Err(Error::Protocol { .. }) = result {
+        if device.drain(DRAIN_IDLE).is_ok_and(|count| count > 0) {
+            return Err(Error::Overrun {
+                phase: what.to_string(),
+                requested,
+            });
+        }
+    }
+
+    result
+}
+
+fn expect_response_within(
+    device: &mut dyn Transport,
+    expected: &[u8],
+    step: &str,
+    budget: usize,
+) -> Result<()> {
+    let timeout = device.timeout().unwrap_or(DEFAULT_TIMEOUT);
+    let deadline = Instant::now() + timeout;
+    let mut buf = Vec::new();
+
+    while !buf.ends_with(expected) && buf.len() < budget {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let start = buf.len();
+        buf.resize(start + expected.len() - partial_match(&buf, expected), 0);
+
+        match device.read_with_timeout(&mut buf[start..], remaining.max(Duration::from_millis(1))) {
+            Ok(0) => {
+                return Err(read_error(
+                    ErrorKind::UnexpectedEof.into(),
+                    &format!("{} {}", String::from_utf8_lossy(expected), step),
+                ))
+            }
+            Ok(count) => buf.truncate(start + count),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut && start > 0 => {
+                buf.truncate(start);
+                break;
+            }
+            Err(Error::Io(err)) => {
+                return Err(read_error(
+                    err,
+                    &format!("{} {}", String::from_utf8_lossy(expected), step),
+                ))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if !buf.ends_with(expected) {
+        if buf.is_empty() {
+            return Err(Error::Timeout {
+                phase: format!("{} {}", String::from_utf8_lossy(expected), step),
+            });
+        }
+
+        return Err(Error::Protocol {
+            phase: step.to_string(),
+            expected: expected.to_vec(),
+            got: buf[buf.len().saturating_sub(64)..].to_vec(),
+        });
+    }
+
+    let skipped = &buf[..buf.len() - expected.len()];
+    if !skipped.is_empty() {
+        step!(
+            "skipped {:?} before {}",
+            String::from_utf8_lossy(skipped),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    step!("got {}", String::from_utf8_lossy(expected));
+
+    Ok(())
+}
+
+// Sends the binary after the stub accepted it, counting down what remains.
+fn send_binary(
+    device: &mut dyn Transport,
+    binary: &mut dyn Read,
+    size: u64,
+    remaining: &mut u64,
+    sha256: &mut Sha256,
+    cancel: &CancelToken,
+) -> Result<()> {
+    loop {
+        // Between the echoes, where the stub has caught up.
+        if remaining.is_multiple_of(256) {
+            cancel.check()?;
+        }
+
+        let mut value = [0u8; 1];
+        binary.read_exact(&mut value)?;
+        device.write_all(&value)?;
+        sha256.update(&value);
+
+        if remaining.is_multiple_of(256) {
+            // Ensure that the same byte is sent back to confirm that it was received.
+            let mut returned_value = [0u8; 1];
+            read_step(
+                device,
+                &mut returned_value,
+                &format!("the echo with {} bytes remaining", *remaining),
+            )?;
+
+            if value != returned_value {
+                return Err(Error::Protocol {
+                    phase: format!("echo with {} bytes remaining", *remaining),
+                    expected: value.to_vec(),
+                    got: returned_value.to_vec(),
+                });
+            }
+        }
+
+        *remaining -= 1;
+
+        events::progress("boot_progress", size - *remaining, size, Object::new());
+
+        if *remaining == 0 {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Answers to the chunks of a windowed dump, each followed by the index of the
+// chunk (little-endian).
+const WINDOW_ACK: u8 = b'A';
+const WINDOW_NAK: u8 = b'N';
+// Stops the dump, no matter the index.
+const WINDOW_ABORT: u8 = b'X';
+// Has the stub service the watchdog, no matter the index.
+const WINDOW_KEEPALIVE: u8 = b'K';
+
+// Memory writes are sent in blocks of this size, which are confirmed with the
+// same answers as the chunks of windowed dumps.
+const BLOCK_SIZE: u64 = 4096;
+
+// How long the line has to be quiet after an aborted windowed dump, by which
+// the chunks that were on their way have arrived.
+const WINDOW_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+// What follows the data of every dump transfer. Stubs only switch to CRC-32
+// when asked to, older ones always send the XOR of all bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Xor,
+    Crc32,
+}
+
+impl Checksum {
+    pub fn name(self) -> &'static str {
+        match self {
+            Checksum::Xor => "xor",
+            Checksum::Crc32 => "crc32",
+        }
+    }
+
+    // Its size in bytes, CRC-32 is sent little-endian.
+    fn size(self) -> usize {
+        match self {
+            Checksum::Xor => 1,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Checksum::Xor => data.iter().fold(0, |checksum, byte| checksum ^ byte).into(),
+            Checksum::Crc32 => {
+                let mut crc = Crc32::new();
+                crc.update(data);
+                crc.finish()
+            }
+        }
+    }
+
+    // Returns the reason for rejecting the data, if any.
+    fn mismatch(self, data: &[u8], received: &[u8]) -> Option<String> {
+        let mut bytes = [0u8; 4];
+        bytes[..received.len()].copy_from_slice(received);
+        let received = u32::from_le_bytes(bytes);
+        let computed = self.compute(data);
+
+        (received != computed).then(|| {
+            format!(
+                "{} checksum does not match: received {:#0width$x}, computed {:#0width$x}",
+                self.name(),
+                received,
+                computed,
+                width = 2 + 2 * self.size()
+            )
+        })
+    }
+}
+
+// A chunk of a windowed dump, along with the reason for rejecting it.
+struct WindowFrame {
+    index: u64,
+    data: Vec<u8>,
+    mismatch: Option<String>,
+}
+
+fn receive_window_frame(
+    device: &mut dyn Transport,
+    start_address: u64,
+    end_address: u64,
+    chunk_size: u64,
+    timeouts: Timeouts,
+    checksum: Checksum,
+) -> Result<WindowFrame> {
+    expect_response(device, b"STRTUPLD", "for the next chunk")?;
+
+    let mut index = [0u8; 4];
+    read_step(device, &mut index, "the chunk index")?;
+    let index = u64::from(u32::from_le_bytes(index));
+
+    let chunks = (end_address - start_address).div_ceil(chunk_size).max(1);
+    if index >= chunks {
+        return Err(Error::Protocol {
+            phase: format!("chunk index, with only {} chunks", chunks),
+            expected: Vec::new(),
+            got: (index as u32).to_le_bytes().to_vec(),
+        });
+    }
+
+    let chunk_start = start_address + index * chunk_size;
+    let length = end_address.min(chunk_start.saturating_add(chunk_size)) - chunk_start;
+
+    // The data is followed by the checksum.
+    let mut data = vec![0u8; length as usize + checksum.size()];
+    device.set_timeout(Some(timeouts.transfer))?;
+    let result = read_step(device, &mut data, &format!("the data of chunk {}", index));
+    device.set_timeout(Some(timeouts.response))?;
+    result?;
+
+    let received = data.split_off(length as usize);
+    let mismatch = checksum.mismatch(&data, &received);
+
+    step!(
+        "received chunk {} with {:#x} bytes of data, {} checksum {}",
+        index,
+        length,
+        checksum.name(),
+        if mismatch.is_none() { "ok" } else { "mismatch" }
+    );
+
+    expect_trailer(device, &format!("chunk {}", index), length)?;
+
+    Ok(WindowFrame {
+        index,
+        data,
+        mismatch,
+    })
+}
+
+fn send_window_answer(device: &mut dyn Transport, answer: u8, index: u64) -> Result<()> {
+    let mut message = vec![answer];
+    message.extend_from_slice(&(index as u32).to_le_bytes());
+
+    step!("sending {} for chunk {}", answer as char, index);
+
+    Ok(device.write_all(&message)?)
+}
+
+// The answer of the stub to a block of a memory write, ACK or NAK.
+fn receive_block_answer(device: &mut dyn Transport, index: u64) -> Result<u8> {
+    let mut answer = [0u8; 5];
+    read_step(
+        device,
+        &mut answer,
+        &format!("the answer to block {}", index),
+    )?;
+
+    let received = u64::from(u32::from_le_bytes(answer[1..].try_into().unwrap()));
+    if ![WINDOW_ACK, WINDOW_NAK].contains(&answer[0]) || received != index {
+        return Err(Error::Protocol {
+            phase: format!("answer to block {}", index),
+            expected: (index as u32).to_le_bytes().to_vec(),
+            got: answer.to_vec(),
+        });
+    }
+
+    step!("got {} for block {}", answer[0] as char, index);
+
+    Ok(answer[0])
+}
+
+// Stops the stub and throws away whatever it sent before noticing, so that
+// the next command starts from a quiet line. Failing to do so doesn't matter
+// much when the dump failed anyway.
+fn abort_window(device: &mut dyn Transport) {
+    let _ = send_window_answer(device, WINDOW_ABORT, 0);
+    let _ = device.drain(WINDOW_DRAIN_TIMEOUT);
+}
+
+// Explains the fixed tokens of the protocol, for looking through session logs.
+pub fn describe_token(data: &[u8]) -> Option<&'static str> {
+    match data {
+        b"WHOISDIS" => Some("bootstub: handshake"),
+        b"BOOTSTUB" => Some("bootstub: handshake response"),
+        b"UPLDMEM" => Some("bootstub: dump memory"),
+        b"BOOTFILE" => Some("bootstub: boot a binary"),
+        b"SETBAUD" => Some("bootstub: change the baud rate"),
+        b"STRTUPLD" => Some("bootstub: start of transfer"),
+        b"ENDUPLD" => Some("bootstub: end of transfer"),
+        b"BAUDSET" => Some("bootstub: baud rate changed"),
+        b"GETCAPS" => Some("bootstub: capabilities query"),
+        b"UPLDWIN" => Some("bootstub: dump memory with a window"),
+        b"SETCSUM" => Some("bootstub: select the dump checksum"),
+        b"CSUMSET" => Some("bootstub: dump checksum selected"),
+        b"SETWIDTH" => Some("bootstub: select the access width"),
+        b"WIDTHSET" => Some("bootstub: access width selected"),
+        b"PETWDOG" => Some("bootstub: service the watchdog"),
+        b"WDOGPET" => Some("bootstub: watchdog serviced"),
+        b"WRITEMEM" => Some("bootstub: write memory"),
+        b"STRTDNLD" => Some("bootstub: start of write"),
+        b"ENDDNLD" => Some("bootstub: end of write"),
+        b"CRCMEM" => Some("bootstub: checksum memory"),
+        b"MEMCRC" => Some("bootstub: memory checksum"),
+        b"EXECMEM" => Some("bootstub: jump to an address"),
+        b"JUMPING" => Some("bootstub: jumping"),
+        b"RESETDEV" => Some("bootstub: reset the device"),
+        b"DEVRESET" => Some("bootstub: resetting"),
+        b"PWROFF" => Some("bootstub: power the device off"),
+        b"OFFPWR" => Some("bootstub: powering off"),
+        _ => None,
+    }
+}
+
+// Dumps are split into requests of this size, so that a checksum mismatch
+// only costs as much.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_RETRIES: u32 = 2;
+
+pub struct DumpOptions {
+    pub chunk_size: u64,
+    // For chunks whose checksum doesn't match.
+    pub retry: RetryPolicy,
+    // How many chunks may be on their way before the first is confirmed,
+    // with stubs that support windowed dumps.
+    pub window: u32,
+    // How many bytes the stub reads at once, 1, 2, 4 or 8.
+    pub access_width: u32,
+    // How often to have the stub service the watchdog, in between chunks.
+    pub keepalive: Option<Duration>,
+    // After how long the watchdog of the board resets it, to warn about dumps
+    // that take longer.
+    pub watchdog_period: Option<Duration>,
+}
+
+// How to tell whether the payload came up at all.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConsoleOptions {
+    // How long the payload may stay silent after the jump before that is
+    // pointed out.
+    pub first_output_timeout: Option<Duration>,
+    // Give up at that point instead of listening on.
+    pub exit_on_silence: bool,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            retry: RetryPolicy::new(DEFAULT_RETRIES),
+            window: 1,
+            access_width: 1,
+            keepalive: None,
+            watchdog_period: None,
+        }
+    }
+}
+
+// Stubs don't service the watchdog of the board while they are busy with a
+// transfer, so long dumps need them to do so in between chunks, or at least
+// a warning that the board is likely to reset in the middle.
+struct Watchdog {
+    keepalive: Option<Duration>,
+    period: Option<Duration>,
+    supported: bool,
+    started: Instant,
+    last_serviced: Instant,
+    warned: bool,
+}
+
+impl Watchdog {
+    fn new(options: &DumpOptions, supported: bool) -> Self {
+        Self {
+            keepalive: options.keepalive.filter(|_| supported),
+            period: options.watchdog_period,
+            supported,
+            started: Instant::now(),
+            last_serviced: Instant::now(),
+            warned: false,
+        }
+    }
+
+    // Whether it's time to have the stub service the watchdog, assuming
+    // that it will be.
+    fn due(&mut self) -> bool {
+        match self.keepalive {
+            Some(interval) if self.last_serviced.elapsed() >= interval => {
+                self.last_serviced = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Extrapolates from what has been dumped so far, once.
+    fn predict(&mut self, done: u64, total: u64) {
+        let (Some(period), None) = (self.period, self.keepalive) else {
+            return;
+        };
+
+        if self.warned || done == 0 {
+            return;
+        }
+
+        let predicted = self.started.elapsed().mul_f64(total as f64 / done as f64);
+
+        if predicted <= period {
+            return;
+        }
+
+        self.warned = true;
+        warning!(
+            "At this rate the dump takes about {:.0} s, which is longer than the watchdog period of {:.0} s{}",
+            predicted.as_secs_f64(),
+            period.as_secs_f64(),
+            if self.supported {
+                ", pass --keepalive to have the stub service the watchdog"
+            } else {
+                " and the stub can't be told to service the watchdog"
+            }
+        );
+    }
+}
+
+// What all of a dump came to, however it was checked on the way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DumpDigest {
+    pub sha256: [u8; 32],
+    pub crc32: u32,
+}
+
+// Every access has to be aligned to its width, and so does every chunk that
+// the range is split into.
+pub fn check_alignment(start_address: u64, end_address: u64, options: &DumpOptions) -> Result<()> {
+    let width = u64::from(options.access_width.max(1));
+
+    for (what, value) in [
+        ("start address", start_address),
+        ("end address", end_address),
+        ("chunk size", options.chunk_size),
+    ] {
+        if value % width != 0 {
+            return Err(Error::InvalidArgument(format!(
+                "The {} {:#x} isn't a multiple of the access width ({} bytes)",
+                what, value, width
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// The stub needs a moment to process every command before the next one.
+const COMMAND_DELAY: Duration = Duration::from_millis(100);
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Commands that not every stub has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    SetBaud,
+    Crc,
+    BlockMode,
+    Fill,
+    Window,
+    AccessWidth,
+    Keepalive,
+    Exec,
+    Reset,
+    PowerOff,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 10] = [
+        Feature::SetBaud,
+        Feature::Crc,
+        Feature::BlockMode,
+        Feature::Fill,
+        Feature::Window,
+        Feature::AccessWidth,
+        Feature::Keepalive,
+        Feature::Exec,
+        Feature::Reset,
+        Feature::PowerOff,
+    ];
+
+    fn bit(self) -> u32 {
+        match self {
+            Feature::SetBaud => 1 << 0,
+            Feature::Crc => 1 << 1,
+            Feature::BlockMode => 1 << 2,
+            Feature::Fill => 1 << 3,
+            Feature::Window => 1 << 4,
+            Feature::AccessWidth => 1 << 5,
+            Feature::Keepalive => 1 << 6,
+            Feature::Exec => 1 << 7,
+            Feature::Reset => 1 << 8,
+            Feature::PowerOff => 1 << 9,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::SetBaud => "set-baud",
+            Feature::Crc => "crc",
+            Feature::BlockMode => "block-mode",
+            Feature::Fill => "fill",
+            Feature::Window => "window",
+            Feature::AccessWidth => "access-width",
+            Feature::Keepalive => "keepalive",
+            Feature::Exec => "exec",
+            Feature::Reset => "reset",
+            Feature::PowerOff => "power-off",
+        }
+    }
+
+    // The first version of the stub that has it.
+    pub fn since(self) -> u32 {
+        match self {
+            Feature::SetBaud | Feature::Crc => 2,
+            Feature::BlockMode
+            | Feature::Fill
+            | Feature::Window
+            | Feature::AccessWidth
+            | Feature::Keepalive
+            | Feature::Exec => 3,
+            Feature::Reset | Feature::PowerOff => 4,
+        }
+    }
+}
+
+// What the stub announced in answer to GETCAPS, which is a version and a
+// bitmask of features (both little-endian) following CAPS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u32,
+    features: u32,
+}
+
+impl Capabilities {
+    // Stubs from before the capabilities query only dump and boot.
+    pub const LEGACY: Capabilities = Capabilities {
+        version: 1,
+        features: 0,
+    };
+
+    pub fn parse(response: &[u8]) -> Option<Self> {
+        let response = response.strip_prefix(b"CAPS")?;
+        let version = u32::from_le_bytes(response.get(0..4)?.try_into().ok()?);
+        let features = u32::from_le_bytes(response.get(4..8)?.try_into().ok()?);
+
+        Some(Self { version, features })
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & feature.bit() != 0
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL
+            .into_iter()
+            .filter(|feature| self.has(*feature))
+    }
+
+    pub fn require(&self, feature: Feature) -> Result<()> {
+        if self.has(feature) {
+            return Ok(());
+        }
+
+        Err(Error::Unsupported(format!(
+            "The stub (version {}) doesn't support {}, update it to version {} or later",
+            self.version,
+            feature.name(),
+            feature.since()
+        )))
+    }
+}
+
+// What the stub can do to the device at the end of a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutDown {
+    Reset,
+    PowerOff,
+}
+
+impl ShutDown {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutDown::Reset => "reset",
+            ShutDown::PowerOff => "power-off",
+        }
+    }
+
+    pub fn feature(self) -> Feature {
+        match self {
+            ShutDown::Reset => Feature::Reset,
+            ShutDown::PowerOff => Feature::PowerOff,
+        }
+    }
+}
+
+// Stubs that know the capabilities query answer right away, anything else
+// ignores it.
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long to wait for a device that went away to come back, e.g. because the
+// payload set up the USB controller again.
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Session {
+    transport: Box<dyn Transport>,
+    timeouts: Timeouts,
+    capabilities: Capabilities,
+    negotiated: bool,
+    checksum: Checksum,
+    access_width: u32,
+    // How long to wait for the device to come back after it went away, if at
+    // all.
+    reconnect_timeout: Option<Duration>,
+    // Whether the stub jumped to something else, which may well keep talking.
+    jumped: bool,
+}
+
+impl Session {
+    pub fn connect(transport: Box<dyn Transport>, timeouts: Timeouts) -> Result<Self> {
+        let mut session = Self::start(transport, timeouts)?;
+
+        // Only answers that make no sense point at the stub, not e.g. the
+        // device going away.
+        session.negotiate().map_err(|err| match err {
+            Error::Protocol { .. }
+            | Error::Timeout { .. }
+            | Error::ShortRead { .. }
+            | Error::Overrun { .. } => Error::Negotiation(Box::new(err)),
+            err => err,
+        })?;
+
+        Ok(session)
+    }
+
+    // Talks to the stub the way the first ones expected, with XOR checksums
+    // and none of the optional features, without asking what it supports.
+    // For old stubs that the capabilities query confuses, e.g. by echoing it.
+    pub fn connect_legacy(transport: Box<dyn Transport>, timeouts: Timeouts) -> Result<Self> {
+        let session = Self::start(transport, timeouts)?;
+        step!("not asking for capabilities, using the legacy protocol");
+
+        Ok(session)
+    }
+
+    fn start(mut transport: Box<dyn Transport>, timeouts: Timeouts) -> Result<Self> {
+        transport.set_timeout(Some(timeouts.response))?;
+
+        let mut session = Self {
+            transport,
+            timeouts,
+            capabilities: Capabilities::LEGACY,
+            negotiated: false,
+            checksum: Checksum::Xor,
+            access_width: 1,
+            reconnect_timeout: None,
+            jumped: false,
+        };
+
+        session.handshake()?;
+
+        Ok(session)
+    }
+
+    fn negotiate(&mut self) -> Result<()> {
+        self.capabilities = self.query_capabilities()?;
+        if self.capabilities.has(Feature::Crc) {
+            self.checksum = self.negotiate_crc()?;
+        }
+        self.negotiated = true;
+
+        Ok(())
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // Whether the stub was asked what it supports, rather than taken for a
+    // legacy one.
+    pub fn negotiated(&self) -> bool {
+        self.negotiated
+    }
+
+    // Whether the stub still runs, rather than something that it jumped to.
+    pub fn is_running(&self) -> bool {
+        !self.jumped
+    }
+
+    // What protects the data of dumps.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    // The traffic with the device so far.
+    pub fn statistics(&self) -> Statistics {
+        self.transport.statistics()
+    }
+
+    // Lets dumps and the console carry on after the device went away and came
+    // back within the timeout.
+    pub fn set_reconnect_timeout(&mut self, timeout: Option<Duration>) {
+        self.reconnect_timeout = timeout;
+    }
+
+    // Waits for the device to come back after `err` if it went away, returning
+    // `err` otherwise or if it doesn't.
+    fn reconnect(&mut self, err: Error) -> Result<()> {
+        let Some(timeout) = self.reconnect_timeout.filter(|_| err.is_disconnect()) else {
+            return Err(err);
+        };
+
+        let started = Instant::now();
+        if let Err(reconnect_err) = self.transport.reconnect(timeout) {
+            step!("reconnecting failed: {}", reconnect_err);
+            return Err(err);
+        }
+        let elapsed = started.elapsed();
+
+        let location = self.transport.location();
+        match &location {
+            Some(location) => status!(
+                "The device went away, reconnected as {} after {}",
+                location,
+                human_duration(elapsed)
+            ),
+            None => status!(
+                "The device went away, reconnected after {}",
+                human_duration(elapsed)
+            ),
+        }
+
+        events::emit(
+            "reconnected",
+            Object::new()
+                .field("location", location)
+                .field("elapsed_ms", elapsed.as_millis() as u64),
+        );
+
+        Ok(())
+    }
+
+    // Like reconnect, but for carrying on with the stub, which starts over
+    // with every handshake.
+    fn reconnect_stub(&mut self, err: Error) -> Result<()> {
+        self.reconnect(err)?;
+        self.handshake()?;
+
+        if self.checksum == Checksum::Crc32 {
+            self.checksum = self.negotiate_crc()?;
+        }
+
+        let access_width = std::mem::replace(&mut self.access_width, 1);
+        self.select_access_width(access_width)
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        let device = self.transport.as_mut();
+        device.set_phase("handshake");
+
+        send(device, b"WHOISDIS")?;
+        let mut buf = [0u8; 16 * 1024];
+        let deadline = Instant::now() + self.timeouts.handshake;
+        let mut handshake_end_offset = 0;
+
+        // The answer doesn't necessarily arrive in one piece.
+        while handshake_end_offset < buf.len()
+            && !buf[..handshake_end_offset].ends_with(b"BOOTSTUB")
+        {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            match device.read_with_timeout(
+                &mut buf[handshake_end_offset..],
+                remaining.max(Duration::from_millis(1)),
+            ) {
+                Ok(count) => handshake_end_offset += count,
+                Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if handshake_end_offset == 0 {
+            return Err(Error::Timeout {
+                phase: "BOOTSTUB after sending WHOISDIS".to_string(),
+            });
+        }
+
+        // The stub may print other things before answering, only the end matters.
+        let response = &buf[handshake_end_offset.saturating_sub(8)..handshake_end_offset];
+        if response != b"BOOTSTUB" {
+            return Err(Error::Protocol {
+                phase: "after sending WHOISDIS".to_string(),
+                expected: b"BOOTSTUB".to_vec(),
+                got: response.to_vec(),
+            });
+        }
+
+        step!("got BOOTSTUB");
+        events::emit("connected", Object::new().field("protocol", "bootstub"));
+
+        Ok(())
+    }
+
+    fn query_capabilities(&mut self) -> Result<Capabilities> {
+        let device = self.transport.as_mut();
+        device.set_phase("capabilities");
+
+        send(device, b"GETCAPS")?;
+
+        let mut buf = [0u8; 12];
+        let timeout = CAPABILITIES_TIMEOUT.min(self.timeouts.response);
+        let capabilities = match device.read_full(&mut buf, timeout) {
+            Ok(count) => Capabilities::parse(&buf[..count]),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut => None,
+            Err(err) => return Err(err),
+        };
+
+        let Some(capabilities) = capabilities else {
+            step!("no capabilities, assuming a legacy stub");
+            return Ok(Capabilities::LEGACY);
+        };
+
+        step!(
+            "got capabilities, version {}, features {:#x}",
+            capabilities.version,
+            capabilities.features
+        );
+
+        Ok(capabilities)
+    }
+
+    // Has the stub protect dumps with CRC-32 rather than XOR, which misses
+    // swapped and repeated bytes.
+    fn negotiate_crc(&mut self) -> Result<Checksum> {
+        let device = self.transport.as_mut();
+        device.set_phase("checksum");
+
+        send(device, b"SETCSUM")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, Checksum::Crc32.name().as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        expect_response(device, b"CSUMSET", "after selecting the checksum")?;
+
+        Ok(Checksum::Crc32)
+    }
+
+    // The stub keeps the access width until told otherwise.
+    fn select_access_width(&mut self, width: u32) -> Result<()> {
+        if width == self.access_width {
+            return Ok(());
+        }
+
+        self.capabilities.require(Feature::AccessWidth)?;
+
+        let device = self.transport.as_mut();
+        device.set_phase("access width");
+
+        send(device, b"SETWIDTH")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, width.to_string().as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        expect_response(device, b"WIDTHSET", "after selecting the access width")?;
+
+        self.access_width = width;
+
+        Ok(())
+    }
+
+    // Has the stub service the watchdog, which it doesn't do while it's busy
+    // with a transfer.
+    fn keep_alive(&mut self) -> Result<()> {
+        let device = self.transport.as_mut();
+
+        send(device, b"PETWDOG")?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        expect_response(device, b"WDOGPET", "after the keep-alive")
+    }
+
+    pub fn dump(
+        &mut self,
+        start_address: u64,
+        end_address: u64,
+        options: &DumpOptions,
+        output: &mut dyn Write,
+        cancel: &CancelToken,
+    ) -> Result<DumpDigest> {
+        if end_address < start_address {
+            return Err(Error::InvalidArgument(format!(
+                "End address {:#x} is before the start address {:#x}",
+                end_address, start_address
+            )));
+        }
+
+        check_alignment(start_address, end_address, options)?;
+        self.select_access_width(options.access_width)?;
+
+        let keepalive_supported = self.capabilities.has(Feature::Keepalive);
+        if options.keepalive.is_some() && !keepalive_supported {
+            warning!(
+                "The stub (version {}) can't be told to service the watchdog, dumping without keep-alives",
+                self.capabilities.version
+            );
+        }
+        let mut watchdog = Watchdog::new(options, keepalive_supported);
+
+        self.transport.set_phase("dump");
+
+        let size = end_address - start_address;
+        let mut crc = Crc32::new();
+        let mut sha256 = Sha256::new();
+        // How far the dump has got, where it carries on after reconnecting.
+        let written = std::cell::Cell::new(0);
+
+        // The data is written out regardless of its checksum, it may still
+        // be useful.
+        let mut sink = |data: &[u8]| -> Result<()> {
+            // Never more than the range, whatever the stub claims to send.
+            if written.get() + data.len() as u64 > size {
+                return Err(Error::Overrun {
+                    phase: "the dump".to_string(),
+                    requested: size,
+                });
+            }
+
+            output.write_all(data)?;
+            crc.update(data);
+            sha256.update(data);
+            written.set(written.get() + data.len() as u64);
+            Ok(())
+        };
+
+        let windowed = options.window > 1 && self.capabilities.has(Feature::Window);
+        if options.window > 1 && !windowed {
+            warning!(
+                "The stub (version {}) doesn't support windowed dumps, requesting one chunk at a time",
+                self.capabilities.version
+            );
+        }
+
+        let result = loop {
+            let resume_address = start_address + written.get();
+
+            let result = if windowed {
+                self.dump_windowed(
+                    resume_address,
+                    end_address,
+                    options,
+                    &mut watchdog,
+                    &mut sink,
+                    cancel,
+                )
+            } else {
+                self.dump_chunked(
+                    resume_address,
+                    end_address,
+                    options,
+                    &mut watchdog,
+                    &mut sink,
+                    cancel,
+                )
+            };
+
+            match result {
+                Err(err) if err.is_disconnect() && self.reconnect_timeout.is_some() => {
+                    self.reconnect_stub(err)?;
+                    self.transport.set_phase("dump");
+                    step!("carrying on at {:#x}", start_address + written.get());
+                }
+                result => break result,
+            }
+        };
+
+        let checksum_ok = match result {
+            Ok(()) => true,
+            Err(Error::Verification(_)) => false,
+            Err(err) => return Err(err),
+        };
+
+        let digest = DumpDigest {
+            sha256: sha256.finish(),
+            crc32: crc.finish(),
+        };
+
+        events::emit(
+            "dump_complete",
+            Object::new()
+                .field("bytes", size)
+                .field("crc", format!("{:08x}", digest.crc32))
+                .field("sha256", sha256::to_hex(&digest.sha256))
+                .field("checksum", self.checksum.name())
+                .field("checksum_ok", checksum_ok),
+        );
+
+        result.map(|()| digest)
+    }
+
+    // Requests one chunk after the other, each after the previous one has
+    // been confirmed.
+    fn dump_chunked(
+        &mut self,
+        start_address: u64,
+        end_address: u64,
+        options: &DumpOptions,
+        watchdog: &mut Watchdog,
+        sink: &mut dyn FnMut(&[u8]) -> Result<()>,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        let size = end_address - start_address;
+        let mut address = start_address;
+
+        // An empty range still makes for one (empty) request.
+        loop {
+            cancel.check()?;
+
+            if watchdog.due() {
+                self.keep_alive()?;
+            }
+
+            let chunk_end = end_address.min(address.saturating_add(options.chunk_size.max(1)));
+            let (data, result) = self.dump_chunk_with_retries(
+                address,
+                chunk_end,
+                &options.retry,
+                start_address,
+                size,
+                cancel,
+            );
+
+            let result = result.map_err(|err| {
+                err.at_byte("dump", address - start_address + data.len() as u64, size)
+            });
+
+            // The chunk is requested again after reconnecting.
+            if result.as_ref().is_err_and(Error::is_disconnect) && self.reconnect_timeout.is_some()
+            {
+                return result;
+            }
+
+            sink(&data)?;
+
+            if result.is_err() || chunk_end == end_address {
+                return result;
+            }
+
+            watchdog.predict(chunk_end - start_address, size);
+            address = chunk_end;
+        }
+    }
+
+    // Has the stub send all chunks on its own, with up to `window` of them on
+    // their way before the first has to be confirmed. Every chunk comes with
+    // its index, and is answered with an ACK or NAK and the index. After a
+    // NAK, the stub goes back to that chunk and sends everything from there
+    // again, so whatever arrives in the meantime is dropped.
+    fn dump_windowed(
+        &mut self,
+        start_address: u64,
+        end_address: u64,
+        options: &DumpOptions,
+        watchdog: &mut Watchdog,
+        sink: &mut dyn FnMut(&[u8]) -> Result<()>,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        let size = end_address - start_address;
+        let chunk_size = options.chunk_size.max(1);
+        // An empty range still makes for one (empty) chunk.
+        let chunks = size.div_ceil(chunk_size).max(1);
+
+        if chunks > u64::from(u32::MAX) {
+            return Err(Error::InvalidArgument(format!(
+                "{} chunks are too many for a windowed dump, use a larger chunk size",
+                chunks
+            )));
+        }
+
+        let timeouts = self.timeouts;
+        let checksum = self.checksum;
+        let device = self.transport.as_mut();
+
+        send(device, b"UPLDWIN")?;
+        std::thread::sleep(COMMAND_DELAY);
+        for value in [
+            start_address,
+            end_address,
+            chunk_size,
+            u64::from(options.window),
+        ] {
+            send(device, format!("{:#x}", value).as_bytes())?;
+            std::thread::sleep(COMMAND_DELAY);
+        }
+
+        // Every chunk before this one has been confirmed and written out.
+        let mut next = 0;
+        let mut retries = options.retry.start();
+        // Only the chunks that were on their way when asking for one again
+        // are dropped, a stub that sends others is making things up.
+        let mut dropped = 0;
+
+        while next < chunks {
+            // The stub stops sending once told to.
+            if cancel.is_cancelled() {
+                abort_window(device);
+                return Err(Error::Cancelled);
+            }
+
+            let done = (next * chunk_size).min(size);
+            let frame = receive_window_frame(
+                device,
+                start_address,
+                end_address,
+                chunk_size,
+                timeouts,
+                checksum,
+            );
+
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(err) => {
+                    abort_window(device);
+                    return Err(err.at_byte("dump", done, size));
+                }
+            };
+
+            if frame.index != next {
+                step!(
+                    "dropped chunk {} while waiting for chunk {}",
+                    frame.index,
+                    next
+                );
+
+                dropped += 1;
+                if dropped > options.window {
+                    abort_window(device);
+                    return Err(Error::Overrun {
+                        phase: format!("the dump while waiting for chunk {}", next),
+                        requested: size,
+                    });
+                }
+                continue;
+            }
+            dropped = 0;
+
+            let chunk_start = start_address + frame.index * chunk_size;
+
+            let Some(message) = frame.mismatch else {
+                sink(&frame.data)?;
+                send_window_answer(device, WINDOW_ACK, frame.index)?;
+                events::progress(
+                    "dump_progress",
+                    done + frame.data.len() as u64,
+                    size,
+                    Object::new(),
+                );
+
+                watchdog.predict(done + frame.data.len() as u64, size);
+                if watchdog.due() {
+                    send_window_answer(device, WINDOW_KEEPALIVE, 0)?;
+                }
+
+                next += 1;
+                retries.reset();
+                continue;
+            };
+
+            let chunk_end = chunk_start + frame.data.len() as u64;
+
+            if retries.remaining() {
+                device.count_retry();
+                device.count_checksum_failure();
+                warning!(
+                    "{} for {:#x} to {:#x}, requesting it again ({} of {})",
+                    message,
+                    chunk_start,
+                    chunk_end,
+                    retries.attempt() + 1,
+                    retries.attempts()
+                );
+                retries.next(&format!("chunk {}", frame.index));
+                send_window_answer(device, WINDOW_NAK, frame.index)?;
+                continue;
+            }
+
+            abort_window(device);
+            sink(&frame.data)?;
+            return Err(Error::Verification(message));
+        }
+
+        Ok(())
+    }
+
+    // Requests a chunk again while its checksum doesn't match, returning
+    // whatever arrived last along with the outcome.
+    fn dump_chunk_with_retries(
+        &mut self,
+        start_address: u64,
+        end_address: u64,
+        retry: &RetryPolicy,
+        dump_start: u64,
+        dump_size: u64,
+        cancel: &CancelToken,
+    ) -> (Vec<u8>, Result<()>) {
+        let mut data = Vec::new();
+        let mut retries = 0;
+
+        let result = retry.run(
+            &format!("{:#x} to {:#x}", start_address, end_address),
+            || {
+                data.clear();
+                self.dump_chunk(
+                    start_address,
+                    end_address,
+                    &mut data,
+                    |done| {
+                        events::progress(
+                            "dump_progress",
+                            start_address - dump_start + done,
+                            dump_size,
+                            Object::new(),
+                        )
+                    },
+                    cancel,
+                )
+            },
+            |err, attempt| {
+                let Error::Verification(message) = err else {
+                    return false;
+                };
+
+                retries += 1;
+                warning!(
+                    "{} for {:#x} to {:#x}, requesting it again ({} of {})",
+                    message,
+                    start_address,
+                    end_address,
+                    attempt,
+                    retry.attempts
+                );
+                true
+            },
+        );
+
+        for _ in 0..retries {
+            self.transport.count_retry();
+            self.transport.count_checksum_failure();
+        }
+
+        (data, result)
+    }
+
+    // A single upload request, which is covered by a checksum as a whole.
+    fn dump_chunk(
+        &mut self,
+        start_address: u64,
+        end_address: u64,
+        data: &mut Vec<u8>,
+        progress: impl Fn(u64),
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        let device = self.transport.as_mut();
+
+        send(device, b"UPLDMEM")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", start_address).as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", end_address).as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        // Ensure that the device accepted the upload.
+        expect_response(device, b"STRTUPLD", "after sending the end address")?;
+
+        let size = end_address - start_address;
+        let mut remaining = size;
+
+        device.set_timeout(Some(self.timeouts.transfer))?;
+
+        while remaining > 0 {
+            // Chunks take a while over slow links. The rest of one that is
+            // given up on is drained along with the session.
+            //
+            // This is only checked between bytes, so a cancel takes effect
+            // once the next byte arrives, or once the transfer timeout runs
+            // out for a stub that went quiet. Shorter reads would notice it
+            // sooner, but a USB transfer that times out drops whatever it got
+            // so far.
+            cancel.check()?;
+
+            let mut value = [0u8; 1];
+            if let Err(err) = device.read_exact(&mut value) {
+                // It's the cancel that the dump ends with, not the timeout.
+                cancel.check()?;
+
+                return Err(read_error(
+                    err,
+                    &format!("dump data with {} bytes remaining", remaining),
+                ));
+            }
+            data.push(value[0]);
+
+            remaining -= 1;
+
+            progress(size - remaining);
+        }
+
+        let mut received = vec![0u8; self.checksum.size()];
+        read_step(device, &mut received, "the checksum of the dump data")?;
+
+        device.set_timeout(Some(self.timeouts.response))?;
+
+        let mismatch = self.checksum.mismatch(data, &received);
+
+        step!(
+            "received {:#x} bytes of data, {} checksum {}",
+            size,
+            self.checksum.name(),
+            if mismatch.is_none() { "ok" } else { "mismatch" }
+        );
+
+        // Check end of transfer.
+        expect_trailer(device, "the dump data", size)?;
+
+        match mismatch {
+            Some(message) => Err(Error::Verification(message)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn boot(
+        &mut self,
+        binary: &mut dyn Read,
+        size: u64,
+        cancel: &CancelToken,
+    ) -> Result<[u8; 32]> {
+        if size == 0 {
+            return Err(Error::InvalidArgument("The binary is empty".to_string()));
+        }
+
+        let device = self.transport.as_mut();
+        device.set_phase("boot");
+
+        send(device, b"BOOTFILE")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", size).as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        // Ensure that the device accepted the upload.
+        expect_response(device, b"STRTUPLD", "after sending the binary size")?;
+
+        device.set_timeout(Some(self.timeouts.transfer))?;
+
+        let mut sha256 = Sha256::new();
+        let mut remaining = size;
+
+        send_binary(device, binary, size, &mut remaining, &mut sha256, cancel)
+            .map_err(|err| err.at_byte("boot upload", size - remaining, size))?;
+
+        device.set_timeout(Some(self.timeouts.response))?;
+
+        step!("sent {:#x} bytes of data", size);
+        self.jumped = true;
+
+        // Check end of transfer.
+        expect_response(device, b"ENDUPLD", "after sending the binary")?;
+
+        let digest = sha256.finish();
+
+        events::emit(
+            "boot_complete",
+            Object::new()
+                .field("bytes", size)
+                .field("sha256", sha256::to_hex(&digest)),
+        );
+
+        Ok(digest)
+    }
+
+    // Writes to memory with the block protocol: after WRITEMEM, the address,
+    // the size and the block size, the stub answers STRTDNLD and confirms
+    // every block (its index, the data and the checksum) with an ACK or NAK
+    // and the index. A rejected block is sent again, ENDDNLD follows the last.
+    pub fn write_memory(
+        &mut self,
+        address: u64,
+        data: &mut dyn Read,
+        size: u64,
+        retry: &RetryPolicy,
+        cancel: &CancelToken,
+    ) -> Result<DumpDigest> {
+        self.capabilities.require(Feature::BlockMode)?;
+
+        if size == 0 {
+            return Err(Error::InvalidArgument("The binary is empty".to_string()));
+        }
+
+        let checksum = self.checksum;
+        let timeouts = self.timeouts;
+        let device = self.transport.as_mut();
+        device.set_phase("write");
+
+        send(device, b"WRITEMEM")?;
+        std::thread::sleep(COMMAND_DELAY);
+        for value in [address, size, BLOCK_SIZE] {
+            send(device, format!("{:#x}", value).as_bytes())?;
+            std::thread::sleep(COMMAND_DELAY);
+        }
+
+        expect_response(device, b"STRTDNLD", "after sending the block size")?;
+
+        let mut sha256 = Sha256::new();
+        let mut crc32 = Crc32::new();
+        let mut written = 0;
+        let mut index = 0;
+
+        while written < size {
+            cancel.check()?;
+
+            let mut block = vec![0u8; (size - written).min(BLOCK_SIZE) as usize];
+            data.read_exact(&mut block)?;
+            sha256.update(&block);
+            crc32.update(&block);
+
+            let mut frame = (index as u32).to_le_bytes().to_vec();
+            frame.extend_from_slice(&block);
+            frame.extend_from_slice(&checksum.compute(&block).to_le_bytes()[..checksum.size()]);
+
+            let block_start = address + written;
+            let mut retries = retry.start();
+
+            loop {
+                device.set_timeout(Some(timeouts.transfer))?;
+                let result = device
+                    .write_all(&frame)
+                    .map_err(Error::from)
+                    .and_then(|()| receive_block_answer(device, index));
+                device.set_timeout(Some(timeouts.response))?;
+
+                match result.map_err(|err| err.at_byte("write", written, size))? {
+                    WINDOW_ACK => break,
+                    _ if retries.remaining() => {
+                        device.count_retry();
+                        device.count_checksum_failure();
+                        warning!(
+                            "The stub rejected the block at {:#x}, sending it again ({} of {})",
+                            block_start,
+                            retries.attempt() + 1,
+                            retries.attempts()
+                        );
+                        retries.next(&format!("block {}", index));
+                    }
+                    _ => {
+                        return Err(Error::Verification(format!(
+                            "The stub still rejected the block at {:#x} after {} retries",
+                            block_start,
+                            retries.attempt()
+                        )))
+                    }
+                }
+            }
+
+            written += block.len() as u64;
+            index += 1;
+            events::progress("write_progress", written, size, Object::new());
+        }
+
+        step!("wrote {:#x} bytes to {:#x}", size, address);
+
+        expect_response(device, b"ENDDNLD", "after the last block")?;
+
+        Ok(DumpDigest {
+            sha256: sha256.finish(),
+            crc32: crc32.finish(),
+        })
+    }
+
+    // Checks that memory holds what was written there, with a CRC-32 computed
+    // by the stub if it can, or by reading it back otherwise.
+    pub fn verify_memory(
+        &mut self,
+        address: u64,
+        size: u64,
+        expected: &DumpDigest,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        if !self.capabilities.has(Feature::Crc) {
+            step!("reading {:#x} bytes at {:#x} back", size, address);
+
+            let digest = self.dump(
+                address,
+                address + size,
+                &DumpOptions::default(),
+                &mut std::io::sink(),
+                cancel,
+            )?;
+
+            if digest.sha256 != expected.sha256 {
+                return Err(Error::Verification(format!(
+                    "The memory at {:#x} doesn't match what was written: SHA-256 {} instead of {}",
+                    address,
+                    sha256::to_hex(&digest.sha256),
+                    sha256::to_hex(&expected.sha256)
+                )));
+            }
+
+            return Ok(());
+        }
+
+        self.transport.set_phase("verify");
+        let crc32 = self.memory_crc(address, size)?;
+
+        if crc32 != expected.crc32 {
+            return Err(Error::Verification(format!(
+                "The memory at {:#x} doesn't match what was written: crc32 {:#010x} instead of {:#010x}",
+                address, crc32, expected.crc32
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Has the stub compute the CRC-32 of a range of memory (CRCMEM, the
+    // address and the size, answered with MEMCRC and the CRC little-endian).
+    pub fn memory_crc(&mut self, address: u64, size: u64) -> Result<u32> {
+        self.capabilities.require(Feature::Crc)?;
+
+        let timeouts = self.timeouts;
+        let device = self.transport.as_mut();
+
+        send(device, b"CRCMEM")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", address).as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", size).as_bytes())?;
+
+        // Going over large ranges takes the stub a while.
+        device.set_timeout(Some(timeouts.transfer))?;
+        let result = expect_response(device, b"MEMCRC", "after sending the size");
+        device.set_timeout(Some(timeouts.response))?;
+        result?;
+
+        let mut crc32 = [0u8; 4];
+        read_step(device, &mut crc32, "the memory checksum")?;
+        let crc32 = u32::from_le_bytes(crc32);
+
+        step!("the stub computed crc32 {:#010x}", crc32);
+
+        Ok(crc32)
+    }
+
+    // Has the stub jump to an address (EXECMEM and the address), which it
+    // confirms with JUMPING right before.
+    pub fn exec(&mut self, address: u64) -> Result<()> {
+        self.capabilities.require(Feature::Exec)?;
+
+        let device = self.transport.as_mut();
+        device.set_phase("exec");
+
+        send(device, b"EXECMEM")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, format!("{:#x}", address).as_bytes())?;
+        self.jumped = true;
+
+        expect_response(device, b"JUMPING", "after sending the address")?;
+
+        events::emit(
+            "exec",
+            Object::new().field("address", format!("{:#x}", address)),
+        );
+
+        Ok(())
+    }
+
+    // Has the stub reset the device (RESETDEV), which usually brings it back
+    // to the bootrom, or power it off (PWROFF). The stub confirms with
+    // DEVRESET or OFFPWR right before, and is gone afterwards.
+    pub fn shut_down(&mut self, action: ShutDown) -> Result<()> {
+        let (command, confirmation) = match action {
+            ShutDown::Reset => (b"RESETDEV".as_slice(), b"DEVRESET".as_slice()),
+            ShutDown::PowerOff => (b"PWROFF".as_slice(), b"OFFPWR".as_slice()),
+        };
+        self.capabilities.require(action.feature())?;
+
+        let device = self.transport.as_mut();
+        device.set_phase(action.name());
+
+        send(device, command)?;
+        // Whatever the device does next, the stub won't be there to answer.
+        self.jumped = true;
+
+        expect_response(device, confirmation, "after asking for it")?;
+
+        events::emit("shut_down", Object::new().field("action", action.name()));
+
+        Ok(())
+    }
+
+    pub fn console(
+        &mut self,
+        options: &ConsoleOptions,
+        output: &mut dyn Write,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        self.transport.set_phase("console");
+
+        // Reads give up every so often to look at the token.
+        self.transport.set_timeout(Some(CONSOLE_POLL_INTERVAL))?;
+
+        // The payload may stay silent for as long as it wants, it's only
+        // worth pointing out if it never said anything at all.
+        let started = Instant::now();
+        let mut waiting_for_first = options.first_output_timeout;
+
+        step!("listening for console output");
+
+        loop {
+            cancel.check()?;
+
+            let mut value = [0u8; 1];
+            match self.transport.read_exact(&mut value) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    if let Some(timeout) = waiting_for_first {
+                        if started.elapsed() >= timeout {
+                            waiting_for_first = None;
+                            self.silent(timeout, options.exit_on_silence)?;
+                        }
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    // The payload may have set up the USB controller again,
+                    // it's still running afterwards.
+                    self.reconnect(err.into())?;
+                    self.transport.set_timeout(Some(CONSOLE_POLL_INTERVAL))?;
+                    continue;
+                }
+            }
+
+            waiting_for_first = None;
+
+            let mut encoded = [0u8; 4];
+            output.write_all((value[0] as char).encode_utf8(&mut encoded).as_bytes())?;
+        }
+    }
+
+    // Nothing came from the payload since it was jumped to.
+    fn silent(&mut self, timeout: Duration, exit: bool) -> Result<()> {
+        let location = self
+            .transport
+            .location()
+            .map(|location| format!(" than {}", location))
+            .unwrap_or_default();
+
+        warning!(
+            "No output received within {}, the payload may have crashed or its UART may be on a different port{}",
+            human_duration(timeout),
+            location
+        );
+        events::emit(
+            "console_silent",
+            Object::new().field("timeout_ms", timeout.as_millis() as u64),
+        );
+
+        if exit {
+            return Err(Error::Timeout {
+                phase: "the first output of the payload".to_string(),
+            });
+        }
+
+        status!("Still listening for console output");
+
+        Ok(())
+    }
+
+    pub fn set_baud(&mut self, rate: u32) -> Result<()> {
+        self.capabilities.require(Feature::SetBaud)?;
+
+        let device = self.transport.as_mut();
+        device.set_phase("set-baud");
+
+        send(device, b"SETBAUD")?;
+        std::thread::sleep(COMMAND_DELAY);
+        send(device, rate.to_string().as_bytes())?;
+        std::thread::sleep(COMMAND_DELAY);
+
+        // The stub acknowledges at the old rate before switching over.
+        expect_response(device, b"BAUDSET", "after sending the new baud rate")?;
+
+        device.set_baud(rate)?;
+
+        // Make sure that the stub is still reachable at the new rate.
+        self.handshake()
+    }
+}
+
+// How long the console waits for output before looking whether it was
+// cancelled.
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Whatever the stub still sends, e.g. the rest of a dump that was given up
+// on, would otherwise be taken as the answer to the first command of the next
+// session. Nothing is sent by it once nothing came for this long.
+const DRAIN_IDLE: Duration = Duration::from_millis(200);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // What something else sends is none of our business.
+        if self.jumped {
+            return;
+        }
+
+        self.transport.set_phase("drain");
+        match self.transport.drain(DRAIN_IDLE) {
+            Ok(0) => {}
+            Ok(count) => step!("dropped {} bytes that the stub still sent", count),
+            Err(err) => step!("failed to drain the connection: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    fn timeouts() -> Timeouts {
+        Timeouts::new(DEFAULT_TIMEOUT)
+    }
+
+    // A stub from before the capabilities query, with XOR checksums.
+    fn legacy_session(mock: &MockTransport) -> Session {
+        mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+
+        Session::connect_legacy(Box::new(mock.clone()), timeouts()).unwrap()
+    }
+
+    // A stub that announces `features`, which switches to CRC-32 if it has it.
+    fn session_with(mock: &MockTransport, features: &[Feature]) -> Session {
+        let bits = features
+            .iter()
+            .fold(0, |bits, feature| bits | feature.bit());
+        let mut capabilities = b"CAPS".to_vec();
+        capabilities.extend_from_slice(&4u32.to_le_bytes());
+        capabilities.extend_from_slice(&bits.to_le_bytes());
+
+        mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+        mock.expect_write(b"GETCAPS").respond(&capabilities);
+        if features.contains(&Feature::Crc) {
+            mock.expect_write(b"SETCSUM")
+                .expect_write(b"crc32")
+                .respond(b"CSUMSET");
+        }
+
+        Session::connect(Box::new(mock.clone()), timeouts()).unwrap()
+    }
+
+    fn expect_upload(mock: &MockTransport, start: &[u8], end: &[u8]) {
+        mock.expect_write(b"UPLDMEM")
+            .expect_write(start)
+            .expect_write(end)
+            .respond(b"STRTUPLD");
+    }
+
+    fn dump(session: &mut Session, end: u64, options: &DumpOptions) -> (Vec<u8>, Result<()>) {
+        let mut data = Vec::new();
+        let result = session
+            .dump(0, end, options, &mut data, &CancelToken::new())
+            .map(|_| ());
+
+        (data, result)
+    }
+
+    #[test]
+    fn handshake_skips_debug_output() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"WHOISDIS")
+            .respond_fragmented(b"U-Boot SPL\r\nBOOTSTUB", 3);
+        mock.expect_write(b"GETCAPS").time_out();
+
+        let session = Session::connect(Box::new(mock.clone()), timeouts()).unwrap();
+
+        assert_eq!(session.capabilities(), Capabilities::LEGACY);
+        assert_eq!(session.checksum(), Checksum::Xor);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn handshake_fails_on_silence_and_wrong_answers() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"WHOISDIS");
+        let result = Session::connect_legacy(Box::new(mock.clone()), timeouts());
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+
+        let mock = MockTransport::new();
+        mock.expect_write(b"WHOISDIS")
+            .respond(b"BOOTSTUX")
+            .time_out();
+        let result = Session::connect_legacy(Box::new(mock.clone()), timeouts());
+        assert!(matches!(result, Err(Error::Protocol { got, .. }) if got == b"BOOTSTUX"));
+    }
+
+    #[test]
+    fn dump_with_crc32() {
+        let mock = MockTransport::new();
+        let mut session = session_with(&mock, &[Feature::Crc]);
+        assert_eq!(session.checksum(), Checksum::Crc32);
+
+        expect_upload(&mock, b"0x0", b"0x4");
+        mock.respond(&[1, 2, 3, 4])
+            .respond(&crate::crc32::crc32(&[1, 2, 3, 4]).to_le_bytes())
+            .respond(b"ENDUPLD");
+
+        let (data, result) = dump(&mut session, 4, &DumpOptions::default());
+
+        assert!(result.is_ok());
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn dump_requests_a_chunk_with_a_bad_checksum_again() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+
+        expect_upload(&mock, b"0x0", b"0x4");
+        mock.respond(&[1, 2, 0xff, 4, 4]).respond(b"ENDUPLD");
+        expect_upload(&mock, b"0x0", b"0x4");
+        mock.respond(&[1, 2, 3, 4, 4]).respond(b"ENDUPLD");
+
+        let (data, result) = dump(&mut session, 4, &DumpOptions::default());
+
+        assert!(result.is_ok());
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn dump_gives_up_on_a_bad_checksum_but_keeps_the_data() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+
+        for _ in 0..2 {
+            expect_upload(&mock, b"0x0", b"0x4");
+            mock.respond(&[1, 2, 0xff, 4, 4]).respond(b"ENDUPLD");
+        }
+
+        let options = DumpOptions {
+            retry: RetryPolicy::new(1),
+            ..DumpOptions::default()
+        };
+        let (data, result) = dump(&mut session, 4, &options);
+
+        assert!(matches!(result, Err(Error::Verification(_))));
+        assert_eq!(data, [1, 2, 0xff, 4]);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn dump_short_read_times_out_with_what_arrived() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+
+        expect_upload(&mock, b"0x0", b"0x4");
+        mock.respond(&[1, 2]).time_out();
+
+        let (data, result) = dump(&mut session, 4, &DumpOptions::default());
+
+        assert!(
+            matches!(result, Err(Error::Timeout { phase }) if phase == "dump data with 2 bytes remaining")
+        );
+        assert_eq!(data, [1, 2]);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn dump_is_cancelled_within_a_chunk() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+        let cancel = CancelToken::new();
+
+        // The second half of the chunk is slow to arrive, and the dump is
+        // cancelled while it's waiting for it, as sending the request takes
+        // three times COMMAND_DELAY. The cancel is only noticed once the next
+        // byte arrives.
+        let started = Instant::now();
+        expect_upload(&mock, b"0x0", b"0x8");
+        mock.respond(&[1, 2, 3, 4])
+            .respond_after(COMMAND_DELAY * 8, &[5, 6, 7, 8]);
+        let cancelling = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(COMMAND_DELAY * 4);
+                cancel.cancel();
+            })
+        };
+
+        let mut data = Vec::new();
+        let result = session.dump(0, 8, &DumpOptions::default(), &mut data, &cancel);
+        cancelling.join().unwrap();
+
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(data, [1, 2, 3, 4, 5]);
+        assert_eq!(mock.remaining_steps(), 1);
+        assert!(started.elapsed() >= COMMAND_DELAY * 8);
+    }
+
+    #[test]
+    fn dump_cancelled_while_the_stub_is_quiet_stops_at_the_transfer_timeout() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"WHOISDIS").respond(b"BOOTSTUB");
+        let mut session =
+            Session::connect_legacy(Box::new(mock.clone()), Timeouts::new(COMMAND_DELAY * 5))
+                .unwrap();
+        let cancel = CancelToken::new();
+
+        // Nothing more arrives before the timeout, but the dump was
+        // cancelled in the meantime.
+        expect_upload(&mock, b"0x0", b"0x8");
+        mock.respond(&[1, 2, 3, 4])
+            .respond_after(COMMAND_DELAY * 20, &[5, 6, 7, 8]);
+        let cancelling = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(COMMAND_DELAY * 4);
+                cancel.cancel();
+            })
+        };
+
+        let mut data = Vec::new();
+        let result = session.dump(0, 8, &DumpOptions::default(), &mut data, &cancel);
+        cancelling.join().unwrap();
+
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+
+    fn window_frame(index: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = b"STRTUPLD".to_vec();
+        frame.extend_from_slice(&index.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame.push(Checksum::Xor.compute(data) as u8);
+        frame.extend_from_slice(b"ENDUPLD");
+
+        frame
+    }
+
+    fn window_answer(answer: u8, index: u32) -> Vec<u8> {
+        let mut message = vec![answer];
+        message.extend_from_slice(&index.to_le_bytes());
+
+        message
+    }
+
+    #[test]
+    fn windowed_dump_naks_a_bad_chunk_and_drops_the_ones_in_flight() {
+        let mock = MockTransport::new();
+        let mut session = session_with(&mock, &[Feature::Window]);
+
+        mock.expect_write(b"UPLDWIN");
+        for value in ["0x0", "0x8", "0x4", "0x2"] {
+            mock.expect_write(value.as_bytes());
+        }
+
+        let mut damaged = window_frame(0, &[1, 2, 3, 4]);
+        damaged[8 + 4 + 4] ^= 0xff;
+        mock.respond(&damaged)
+            .expect_write(&window_answer(WINDOW_NAK, 0));
+        // Already on its way when the NAK arrived.
+        mock.respond(&window_frame(1, &[5, 6, 7, 8]));
+        mock.respond(&window_frame(0, &[1, 2, 3, 4]))
+            .expect_write(&window_answer(WINDOW_ACK, 0));
+        mock.respond(&window_frame(1, &[5, 6, 7, 8]))
+            .expect_write(&window_answer(WINDOW_ACK, 1));
+
+        let options = DumpOptions {
+            chunk_size: 4,
+            window: 2,
+            ..DumpOptions::default()
+        };
+        let (data, result) = dump(&mut session, 8, &options);
+
+        assert!(result.is_ok());
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn boot_sends_the_binary() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+
+        mock.expect_write(b"BOOTFILE")
+            .expect_write(b"0x2")
+            .respond(b"STRTUPLD")
+            .expect_write(&[0xaa, 0xbb])
+            .respond(b"ENDUPLD");
+
+        let digest = session
+            .boot(&mut [0xaa, 0xbb].as_slice(), 2, &CancelToken::new())
+            .unwrap();
+
+        assert_eq!(digest, sha256::sha256(&[0xaa, 0xbb]));
+        assert!(!session.is_running());
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn boot_fails_on_a_wrong_echo() {
+        let mock = MockTransport::new();
+        let mut session = legacy_session(&mock);
+
+        mock.expect_write(b"BOOTFILE")
+            .expect_write(b"0x100")
+            .respond(b"STRTUPLD")
+            .expect_write(&[0xaa])
+            .respond(&[0x55]);
+
+        let result = session.boot(&mut [0xaa; 0x100].as_slice(), 0x100, &CancelToken::new());
+
+        assert!(matches!(result, Err(Error::Protocol { got, .. }) if got == [0x55]));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn write_memory_sends_a_nakd_block_again() {
+        let mock = MockTransport::new();
+        let mut session = session_with(&mock, &[Feature::BlockMode]);
+
+        let mut block = vec![0, 0, 0, 0, 1, 2, 3, 4];
+        block.push(Checksum::Xor.compute(&[1, 2, 3, 4]) as u8);
+
+        mock.expect_write(b"WRITEMEM");
+        for value in ["0x1000", "0x4", "0x1000"] {
+            mock.expect_write(value.as_bytes());
+        }
+        mock.respond(b"STRTDNLD")
+            .expect_write(&block)
+            .respond(&window_answer(WINDOW_NAK, 0))
+            .expect_write(&block)
+            .respond(&window_answer(WINDOW_ACK, 0))
+            .respond(b"ENDDNLD");
+
+        let digest = session
+            .write_memory(
+                0x1000,
+                &mut [1, 2, 3, 4].as_slice(),
+                4,
+                &RetryPolicy::new(1),
+                &CancelToken::new(),
+            )
+            .unwrap();
+
+        assert_eq!(digest.crc32, crate::crc32::crc32(&[1, 2, 3, 4]));
+        assert!(mock.is_finished());
+    }
+}
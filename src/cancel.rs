@@ -0,0 +1,142 @@
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+// Stops an operation that is under way from somewhere else, e.g. Ctrl-C or
+// another thread. Transfers look at the token between chunks and end with
+// Error::Cancelled, so they take the same way out as for any other error:
+// the stub is told to stop where the protocol allows it, the session drains
+// what is still coming and partial files are dealt with as usual.
+
+// A signal handler can't wake up a condition variable, so waits look at the
+// flag at least this often.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    lock: Mutex<()>,
+    woken: Condvar,
+}
+
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<Inner>);
+
+impl std::fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CancelToken")
+            .field(&self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+
+        // Under the lock, so that a wait that just found the token not
+        // cancelled is already waiting to be woken up.
+        let _lock = self.0.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.0.woken.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    // For the points at which an operation can stop.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    // Sleeps for `duration`, unless the token is cancelled in the meantime.
+    pub fn sleep(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        let mut lock = self.0.lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        loop {
+            self.check()?;
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+
+            lock = self
+                .0
+                .woken
+                .wait_timeout(lock, remaining.min(SIGNAL_POLL_INTERVAL))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+// The token that Ctrl-C cancels while it's armed.
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if ARMED.load(Ordering::SeqCst) {
+        if let Some(token) = INTERRUPT.get() {
+            // A second Ctrl-C doesn't wait for the operation to wind down,
+            // e.g. if it's stuck somewhere that doesn't look at the token.
+            if !token.0.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    unsafe { libc::_exit(crate::error::EXIT_INTERRUPTED) };
+}
+
+// Ctrl-C would otherwise end the process with whatever the shell makes of the
+// signal, which scripts can't tell apart from our own exit codes. It ends the
+// process right away unless an operation that can be cancelled is under way.
+pub fn catch_interrupt() {
+    INTERRUPT.get_or_init(CancelToken::new);
+
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// Has Ctrl-C cancel the token rather than end the process, for as long as
+// this is around. Not while waiting for input, where Ctrl-C should still end
+// the process.
+pub struct Interrupt(CancelToken);
+
+impl Interrupt {
+    pub fn token(&self) -> &CancelToken {
+        &self.0
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        ARMED.store(false, Ordering::SeqCst);
+    }
+}
+
+pub fn cancel_on_interrupt() -> Interrupt {
+    let token = INTERRUPT.get_or_init(CancelToken::new);
+    // A new operation, Ctrl-C during an earlier one is done with.
+    token.0.cancelled.store(false, Ordering::SeqCst);
+    ARMED.store(true, Ordering::SeqCst);
+
+    Interrupt(token.clone())
+}
@@ -0,0 +1,69 @@
+use crate::bootstub;
+use crate::odin;
+use crate::transport::{LoggedTransfer, Transfer};
+
+// Explains the transfers of a session log in terms of the protocol, as far as
+// they can be recognized. Some things depend on what came before, e.g. file
+// parts are numbered from the last Odin packet on.
+#[derive(Default)]
+pub struct Annotator {
+    file_part: usize,
+}
+
+fn is_number(data: &[u8]) -> bool {
+    match data.strip_prefix(b"0x") {
+        Some(digits) => !digits.is_empty() && digits.iter().all(u8::is_ascii_hexdigit),
+        None => !data.is_empty() && data.iter().all(u8::is_ascii_digit),
+    }
+}
+
+impl Annotator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn annotate(&mut self, logged: &LoggedTransfer) -> Option<String> {
+        match &logged.transfer {
+            Transfer::Write(data) | Transfer::Read(data) if data == b"ODIN" || data == b"LOKE" => {
+                Some("odin: handshake".to_string())
+            }
+            Transfer::Write(data) | Transfer::Read(data) => {
+                if let Some(token) = bootstub::describe_token(data) {
+                    return Some(token.to_string());
+                }
+
+                if let Transfer::Read(data) = &logged.transfer {
+                    if let Some(capabilities) = bootstub::Capabilities::parse(data) {
+                        return Some(format!(
+                            "bootstub: capabilities, version {}",
+                            capabilities.version
+                        ));
+                    }
+
+                    return odin::describe_response(data);
+                }
+
+                if let Some(packet) = odin::describe_packet(data) {
+                    self.file_part = 0;
+                    return Some(packet);
+                }
+
+                // Bootstub takes addresses and sizes as text.
+                if is_number(data) {
+                    return Some(format!("bootstub: {}", String::from_utf8_lossy(data)));
+                }
+
+                if logged.phase == "flash" {
+                    self.file_part += 1;
+                    return Some(format!(
+                        "odin: file part {} of the sequence",
+                        self.file_part - 1
+                    ));
+                }
+
+                None
+            }
+            Transfer::Timeout => Some("timed out".to_string()),
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use clap::{Arg, ArgAction, Command, ValueHint};
+use std::fmt::Write;
+
+// Shell completion scripts, generated from the same command definitions as
+// the argument parser so that they can't get out of sync.
+
+enum Values {
+    None,
+    Files,
+    Directories,
+    List(Vec<String>),
+}
+
+fn values(arg: &Arg) -> Values {
+    if let Some(possible_values) = arg.get_value_parser().possible_values() {
+        return Values::List(
+            possible_values
+                .filter(|value| !value.is_hide_set())
+                .map(|value| value.get_name().to_string())
+                .collect(),
+        );
+    }
+
+    match arg.get_value_hint() {
+        ValueHint::FilePath | ValueHint::AnyPath | ValueHint::ExecutablePath => Values::Files,
+        ValueHint::DirPath => Values::Directories,
+        _ => Values::None,
+    }
+}
+
+fn options<'a, 'help>(command: &'a Command<'help>) -> impl Iterator<Item = &'a Arg<'help>> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+        // Help is added to every command by the shells themselves, and there
+        // is no version to print.
+        .filter(|arg| !matches!(arg.get_id(), "help" | "version"))
+}
+
+fn positionals<'a, 'help>(command: &'a Command<'help>) -> impl Iterator<Item = &'a Arg<'help>> {
+    command
+        .get_arguments()
+        .filter(|arg| arg.is_positional() && !arg.is_hide_set())
+}
+
+fn subcommands<'a, 'help>(command: &'a Command<'help>) -> impl Iterator<Item = &'a Command<'help>> {
+    command.get_subcommands().filter(|sub| !sub.is_hide_set())
+}
+
+// All the ways an option can be spelled on the command line.
+fn spellings(arg: &Arg) -> Vec<String> {
+    let mut spellings = Vec::new();
+
+    if let Some(short) = arg.get_short() {
+        spellings.push(format!("-{}", short));
+    }
+    if let Some(long) = arg.get_long() {
+        spellings.push(format!("--{}", long));
+    }
+
+    spellings
+}
+
+fn visit<'a, 'help>(
+    command: &'a Command<'help>,
+    path: &mut Vec<&'a str>,
+    f: &mut dyn FnMut(&[&'a str], &'a Command<'help>),
+) {
+    f(path, command);
+
+    for subcommand in subcommands(command) {
+        path.push(subcommand.get_name());
+        visit(subcommand, path, f);
+        path.pop();
+    }
+}
+
+fn quote(string: &str) -> String {
+    format!("'{}'", string.replace('\'', "'\\''"))
+}
+
+fn help(arg_or_command: Option<&str>) -> String {
+    arg_or_command
+        .unwrap_or_default()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn bash(command: &Command) -> String {
+    let name = command.get_name();
+    let mut script = String::new();
+
+    // Options that take a value, so that their values aren't mistaken for
+    // subcommands while figuring out where on the command line we are.
+    let mut valued = Vec::new();
+    visit(command, &mut Vec::new(), &mut |_, command| {
+        for arg in options(command).filter(|arg| arg.is_takes_value_set()) {
+            valued.extend(spellings(arg));
+        }
+    });
+    valued.sort();
+    valued.dedup();
+
+    writeln!(script, "_{}() {{", name).unwrap();
+    writeln!(script, "    local cur prev path i word").unwrap();
+    writeln!(script, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(script, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+    writeln!(script, "    path=\"{}\"", name).unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    for ((i = 1; i < COMP_CWORD; i++)); do").unwrap();
+    writeln!(script, "        word=\"${{COMP_WORDS[i]}}\"").unwrap();
+    writeln!(script, "        case \"$word\" in").unwrap();
+    if !valued.is_empty() {
+        writeln!(script, "            {})", valued.join("|")).unwrap();
+        writeln!(script, "                ((i++))").unwrap();
+        writeln!(script, "                ;;").unwrap();
+    }
+    writeln!(script, "            -*)").unwrap();
+    writeln!(script, "                ;;").unwrap();
+    writeln!(script, "            *)").unwrap();
+    writeln!(script, "                case \"$path:$word\" in").unwrap();
+    visit(command, &mut Vec::new(), &mut |path, command| {
+        let parent = std::iter::once(name)
+            .chain(path.iter().copied())
+            .collect::<Vec<_>>()
+            .join("__");
+
+        for subcommand in subcommands(command) {
+            writeln!(
+                script,
+                "                    {}:{}) path=\"{}__{}\" ;;",
+                parent,
+                subcommand.get_name(),
+                parent,
+                subcommand.get_name()
+            )
+            .unwrap();
+        }
+    });
+    writeln!(script, "                esac").unwrap();
+    writeln!(script, "                ;;").unwrap();
+    writeln!(script, "        esac").unwrap();
+    writeln!(script, "    done").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    case \"$path\" in").unwrap();
+
+    visit(command, &mut Vec::new(), &mut |path, command| {
+        let key = std::iter::once(name)
+            .chain(path.iter().copied())
+            .collect::<Vec<_>>()
+            .join("__");
+
+        let mut words = vec!["-h".to_string(), "--help".to_string()];
+        for arg in options(command) {
+            words.extend(spellings(arg));
+        }
+        for subcommand in subcommands(command) {
+            words.push(subcommand.get_name().to_string());
+        }
+        for arg in positionals(command) {
+            if let Values::List(list) = values(arg) {
+                words.extend(list);
+            }
+        }
+
+        writeln!(script, "        {})", key).unwrap();
+        writeln!(script, "            case \"$prev\" in").unwrap();
+        for arg in options(command).filter(|arg| arg.is_takes_value_set()) {
+            let completion = match values(arg) {
+                Values::None => "COMPREPLY=()".to_string(),
+                Values::Files => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+                Values::Directories => "COMPREPLY=($(compgen -d -- \"$cur\"))".to_string(),
+                Values::List(list) => {
+                    format!(
+                        "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                        list.join(" ")
+                    )
+                }
+            };
+
+            writeln!(
+                script,
+                "                {})\n                    {}\n                    return 0\n                    ;;",
+                spellings(arg).join("|"),
+                completion
+            )
+            .unwrap();
+        }
+        writeln!(script, "            esac").unwrap();
+
+        // Positional arguments are completed as files if any of them are files.
+        let files = positionals(command).any(|arg| matches!(values(arg), Values::Files));
+        if files {
+            writeln!(script, "            if [[ \"$cur\" != -* ]]; then").unwrap();
+            writeln!(
+                script,
+                "                COMPREPLY=($(compgen -f -- \"$cur\"))"
+            )
+            .unwrap();
+            writeln!(script, "                return 0").unwrap();
+            writeln!(script, "            fi").unwrap();
+        }
+
+        writeln!(
+            script,
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            words.join(" ")
+        )
+        .unwrap();
+        writeln!(script, "            ;;").unwrap();
+    });
+
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "complete -o filenames -F _{} {}", name, name).unwrap();
+
+    script
+}
+
+pub fn zsh(command: &Command) -> String {
+    let name = command.get_name();
+    let mut script = String::new();
+
+    writeln!(script, "#compdef {}", name).unwrap();
+    writeln!(script).unwrap();
+
+    visit(command, &mut Vec::new(), &mut |path, command| {
+        let function = std::iter::once(name)
+            .chain(path.iter().copied())
+            .collect::<Vec<_>>()
+            .join("__");
+
+        writeln!(script, "_{}() {{", function).unwrap();
+        writeln!(script, "    local line state").unwrap();
+        writeln!(script, "    _arguments -C \\").unwrap();
+        writeln!(
+            script,
+            "        '(- *)'{{-h,--help}}'[Print help information]' \\"
+        )
+        .unwrap();
+
+        for arg in options(command) {
+            let description = help(arg.get_help()).replace('\'', "'\\''");
+            let value = if arg.is_takes_value_set() {
+                let value_name = arg
+                    .get_value_names()
+                    .and_then(|names| names.first().copied())
+                    .unwrap_or("VALUE");
+
+                match values(arg) {
+                    Values::None => format!(":{}: ", value_name),
+                    Values::Files => format!(":{}:_files", value_name),
+                    Values::Directories => format!(":{}:_files -/", value_name),
+                    Values::List(list) => format!(":{}:({})", value_name, list.join(" ")),
+                }
+            } else {
+                String::new()
+            };
+
+            for spelling in spellings(arg) {
+                let spelling = match arg.get_long() {
+                    Some(long) if arg.is_takes_value_set() && spelling == format!("--{}", long) => {
+                        format!("{}=", spelling)
+                    }
+                    _ => spelling,
+                };
+
+                // Counted flags may be given several times.
+                let repeat = if matches!(arg.get_action(), ArgAction::Count) {
+                    "*"
+                } else {
+                    ""
+                };
+
+                writeln!(
+                    script,
+                    "        '{}{}[{}]{}' \\",
+                    repeat,
+                    spelling,
+                    description,
+                    value.replace('\'', "'\\''")
+                )
+                .unwrap();
+            }
+        }
+
+        for arg in positionals(command) {
+            let completion = match values(arg) {
+                Values::None => " ".to_string(),
+                Values::Files => "_files".to_string(),
+                Values::Directories => "_files -/".to_string(),
+                Values::List(list) => format!("({})", list.join(" ")),
+            };
+            let optional = if arg.is_required_set() { "" } else { ":" };
+
+            writeln!(
+                script,
+                "        '{}:{}:{}' \\",
+                optional,
+                help(arg.get_help()).replace('\'', "'\\''"),
+                completion
+            )
+            .unwrap();
+        }
+
+        if subcommands(command).next().is_some() {
+            writeln!(script, "        ':command:->command' \\").unwrap();
+            writeln!(script, "        '*::arguments:->arguments'").unwrap();
+            writeln!(script).unwrap();
+            writeln!(script, "    case $state in").unwrap();
+            writeln!(script, "        command)").unwrap();
+            writeln!(script, "            local commands=(").unwrap();
+            for subcommand in subcommands(command) {
+                writeln!(
+                    script,
+                    "                {}",
+                    quote(&format!(
+                        "{}:{}",
+                        subcommand.get_name(),
+                        help(subcommand.get_about())
+                    ))
+                )
+                .unwrap();
+            }
+            writeln!(script, "            )").unwrap();
+            writeln!(script, "            _describe 'command' commands").unwrap();
+            writeln!(script, "            ;;").unwrap();
+            writeln!(script, "        arguments)").unwrap();
+            writeln!(script, "            case $line[1] in").unwrap();
+            for subcommand in subcommands(command) {
+                writeln!(
+                    script,
+                    "                {}) _{}__{} ;;",
+                    subcommand.get_name(),
+                    function,
+                    subcommand.get_name()
+                )
+                .unwrap();
+            }
+            writeln!(script, "            esac").unwrap();
+            writeln!(script, "            ;;").unwrap();
+            writeln!(script, "    esac").unwrap();
+        } else {
+            writeln!(script, "        && return 0").unwrap();
+        }
+
+        writeln!(script, "}}").unwrap();
+        writeln!(script).unwrap();
+    });
+
+    writeln!(script, "_{} \"$@\"", name).unwrap();
+
+    script
+}
+
+pub fn fish(command: &Command) -> String {
+    let name = command.get_name();
+    let mut script = String::new();
+
+    visit(command, &mut Vec::new(), &mut |path, command| {
+        // Fish doesn't know about the structure of the command line, so every
+        // completion is conditioned on the subcommands seen so far.
+        let mut conditions = path
+            .iter()
+            .map(|sub| format!("__fish_seen_subcommand_from {}", sub))
+            .collect::<Vec<_>>();
+
+        let children = subcommands(command)
+            .map(|sub| sub.get_name())
+            .collect::<Vec<_>>();
+        if path.is_empty() {
+            conditions.push("__fish_use_subcommand".to_string());
+        } else if !children.is_empty() {
+            conditions.push(format!(
+                "not __fish_seen_subcommand_from {}",
+                children.join(" ")
+            ));
+        }
+
+        let condition = conditions.join("; and ");
+
+        writeln!(
+            script,
+            "complete -c {} -n {} -s h -l help -d 'Print help information'",
+            name,
+            quote(&condition)
+        )
+        .unwrap();
+
+        for arg in options(command) {
+            let mut line = format!("complete -c {} -n {}", name, quote(&condition));
+
+            if let Some(short) = arg.get_short() {
+                write!(line, " -s {}", short).unwrap();
+            }
+            if let Some(long) = arg.get_long() {
+                write!(line, " -l {}", long).unwrap();
+            }
+
+            if arg.is_takes_value_set() {
+                match values(arg) {
+                    Values::None => line.push_str(" -x"),
+                    Values::Files | Values::Directories => line.push_str(" -r -F"),
+                    Values::List(list) => {
+                        write!(line, " -x -a {}", quote(&list.join(" "))).unwrap()
+                    }
+                }
+            }
+
+            write!(line, " -d {}", quote(&help(arg.get_help()))).unwrap();
+            writeln!(script, "{}", line).unwrap();
+        }
+
+        for subcommand in subcommands(command) {
+            writeln!(
+                script,
+                "complete -c {} -n {} -f -a {} -d {}",
+                name,
+                quote(&condition),
+                subcommand.get_name(),
+                quote(&help(subcommand.get_about()))
+            )
+            .unwrap();
+        }
+
+        for arg in positionals(command) {
+            if let Values::List(list) = values(arg) {
+                writeln!(
+                    script,
+                    "complete -c {} -n {} -f -a {} -d {}",
+                    name,
+                    quote(&condition),
+                    quote(&list.join(" ")),
+                    quote(&help(arg.get_help()))
+                )
+                .unwrap();
+            }
+        }
+
+        // Don't offer files where a command doesn't take any.
+        if children.is_empty()
+            && !positionals(command).any(|arg| matches!(values(arg), Values::Files))
+        {
+            writeln!(script, "complete -c {} -n {} -f", name, quote(&condition)).unwrap();
+        }
+    });
+
+    script
+}
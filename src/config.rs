@@ -0,0 +1,441 @@
+use crate::error::{Error, Result};
+use crate::parse::parse_usb_id;
+use crate::serial::parse_baud;
+use crate::timeouts::parse_timeout;
+use crate::toml::{self, Value};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Defaults for the command line, read from the config file and overridden by
+// environment variables. Command line options override both.
+
+pub const SAMSUNG_VENDOR_ID: u16 = 0x04e8;
+
+#[derive(Clone, Debug)]
+pub enum Source {
+    File(PathBuf),
+    Environment(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Environment(variable) => write!(f, "${}", variable),
+        }
+    }
+}
+
+impl Source {
+    // Environment variables take precedence over the config file.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Source::File(_) => 0,
+            Source::Environment(_) => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+// A vendor by its hexadecimal ID, or by a part of its name in the USB ID
+// database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Vendor {
+    Id(u16),
+    Name(String),
+}
+
+impl Vendor {
+    pub fn parse(string: &str) -> std::result::Result<Self, String> {
+        let string = string.trim();
+
+        if string.is_empty() {
+            return Err("expected a hexadecimal vendor ID or a name".to_string());
+        }
+
+        let digits = string.strip_prefix("0x").unwrap_or(string);
+        if digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            if let Ok(id) = u16::from_str_radix(digits, 16) {
+                return Ok(Vendor::Id(id));
+            }
+        }
+
+        Ok(Vendor::Name(string.to_lowercase()))
+    }
+
+    pub fn matches(&self, id: u16, name: Option<&str>) -> bool {
+        match self {
+            Vendor::Id(wanted) => *wanted == id,
+            Vendor::Name(wanted) => name.is_some_and(|name| name.to_lowercase().contains(wanted)),
+        }
+    }
+}
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Vendor::Id(id) => write!(f, "{:04x}", id),
+            Vendor::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// The vendors whose devices are looked at when no USB ID is given, only
+// Samsung by default. Some devices enumerate with the ID of their chipset
+// vendor in diagnostic modes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VendorAllowList(Vec<Vendor>);
+
+impl VendorAllowList {
+    pub fn new(vendors: Vec<Vendor>) -> Self {
+        Self(vendors)
+    }
+
+    // Comma-separated vendors, e.g. `04e8, qualcomm`.
+    pub fn parse(string: &str) -> std::result::Result<Self, String> {
+        string
+            .split(',')
+            .map(Vendor::parse)
+            .collect::<std::result::Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn vendors(&self) -> &[Vendor] {
+        &self.0
+    }
+
+    pub fn matches(&self, id: u16, name: Option<&str>) -> bool {
+        self.0.iter().any(|vendor| vendor.matches(id, name))
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Default for VendorAllowList {
+    fn default() -> Self {
+        Self(vec![Vendor::Id(SAMSUNG_VENDOR_ID)])
+    }
+}
+
+impl fmt::Display for VendorAllowList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vendors = self.0.iter().map(Vendor::to_string).collect::<Vec<_>>();
+        write!(f, "{}", vendors.join(", "))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    // The config file that was read, if there was one.
+    pub path: Option<PathBuf>,
+    pub serial: Option<Setting<String>>,
+    pub usb: Option<Setting<(u16, u16)>>,
+    pub baud: Option<Setting<u32>>,
+    pub timeout: Option<Setting<Duration>>,
+    pub handshake_timeout: Option<Setting<Duration>>,
+    pub transfer_timeout: Option<Setting<Duration>>,
+    // After how long the watchdog of the board resets it, if it has one.
+    pub watchdog_period: Option<Setting<Duration>>,
+    pub vendors: Option<Setting<VendorAllowList>>,
+}
+
+// The keys that are understood, along with the environment variables that
+// can override them.
+const KEYS: [(&str, &str); 8] = [
+    ("serial", "SBOOTIL_SERIAL"),
+    ("usb", "SBOOTIL_USB"),
+    ("baud", "SBOOTIL_BAUD"),
+    ("timeout", "SBOOTIL_TIMEOUT"),
+    ("handshake_timeout", "SBOOTIL_HANDSHAKE_TIMEOUT"),
+    ("transfer_timeout", "SBOOTIL_TRANSFER_TIMEOUT"),
+    ("watchdog_period", "SBOOTIL_WATCHDOG_PERIOD"),
+    ("vendors", "SBOOTIL_VENDORS"),
+];
+
+pub fn config_dir() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let base = std::env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(not(windows))]
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    base.map(|base| base.join("sbootil"))
+}
+
+// SBOOTIL_CONFIG points at a different file, which then has to exist.
+pub fn config_path() -> Option<(PathBuf, bool)> {
+    match std::env::var_os("SBOOTIL_CONFIG") {
+        Some(path) => Some((PathBuf::from(path), true)),
+        None => config_dir().map(|dir| (dir.join("config.toml"), false)),
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut config = Config::default();
+
+        if let Some((path, required)) = config_path() {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => config.load_file(path, &text)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => {}
+                Err(source) => {
+                    return Err(Error::File {
+                        path: path.display().to_string(),
+                        source,
+                    })
+                }
+            }
+        }
+
+        for (key, variable) in KEYS {
+            if let Some(value) = std::env::var_os(variable) {
+                let invalid =
+                    |message: String| Error::InvalidConfig(format!("${}: {}", variable, message));
+
+                let value = value
+                    .into_string()
+                    .map_err(|_| invalid("not valid UTF-8".to_string()))?;
+
+                // Setting a variable to nothing is the usual way of unsetting it.
+                if value.is_empty() {
+                    continue;
+                }
+
+                config
+                    .set(key, &value, Source::Environment(variable))
+                    .map_err(invalid)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn load_file(&mut self, path: PathBuf, text: &str) -> Result<()> {
+        let entries = toml::parse(text).map_err(|err| {
+            Error::InvalidConfig(format!("{}:{}: {}", path.display(), err.line, err.message))
+        })?;
+
+        for entry in entries {
+            let invalid = |message: String| {
+                Error::InvalidConfig(format!(
+                    "{}:{}: '{}': {}",
+                    path.display(),
+                    entry.line,
+                    entry.name(),
+                    message
+                ))
+            };
+
+            if !entry.table.is_empty() || !KEYS.iter().any(|(key, _)| *key == entry.key) {
+                return Err(invalid("unknown key".to_string()));
+            }
+
+            // Strings and numbers are accepted alike, the value parsers take care
+            // of the details.
+            let value = match &entry.value {
+                Value::String(string) => string.clone(),
+                Value::Integer(integer) => integer.to_string(),
+                Value::Float(float) => float.to_string(),
+                // Lists are only taken where a comma-separated string would
+                // be, which is the same to the value parser.
+                Value::Array(values) if entry.key == "vendors" => values
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(string) => Ok(string.as_str()),
+                        value => Err(invalid(format!(
+                            "expected a list of strings, got {} in it",
+                            value.type_name()
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .join(","),
+                value => {
+                    return Err(invalid(format!(
+                        "expected a string or a number, got {}",
+                        value.type_name()
+                    )))
+                }
+            };
+
+            self.set(&entry.key, &value, Source::File(path.clone()))
+                .map_err(invalid)?;
+        }
+
+        self.path = Some(path);
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: Source) -> std::result::Result<(), String> {
+        match key {
+            "serial" => {
+                self.serial = Some(Setting {
+                    value: value.to_string(),
+                    source,
+                })
+            }
+            "usb" => {
+                let usb_id = parse_usb_id(value).ok_or_else(|| {
+                    format!("'{}' is not a vendor:product ID (e.g. 04e8:685d)", value)
+                })?;
+
+                self.usb = Some(Setting {
+                    value: usb_id,
+                    source,
+                });
+            }
+            "baud" => {
+                let baud = parse_baud(value)?;
+
+                self.baud = Some(Setting {
+                    value: baud,
+                    source,
+                });
+            }
+            "timeout" | "handshake_timeout" | "transfer_timeout" | "watchdog_period" => {
+                let timeout = parse_timeout(value)?;
+                let setting = Some(Setting {
+                    value: timeout,
+                    source,
+                });
+
+                match key {
+                    "timeout" => self.timeout = setting,
+                    "handshake_timeout" => self.handshake_timeout = setting,
+                    "transfer_timeout" => self.transfer_timeout = setting,
+                    _ => self.watchdog_period = setting,
+                }
+            }
+            "vendors" => {
+                self.vendors = Some(Setting {
+                    value: VendorAllowList::parse(value)?,
+                    source,
+                });
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // The settings in the same format as the config file, with their origin.
+    pub fn show(&self) -> Vec<String> {
+        fn line<T>(
+            key: &str,
+            setting: &Option<Setting<T>>,
+            format: impl Fn(&T) -> String,
+        ) -> String {
+            match setting {
+                Some(setting) => format!(
+                    "{} = {}  # from {}",
+                    key,
+                    format(&setting.value),
+                    setting.source
+                ),
+                None => format!("# {} is not set", key),
+            }
+        }
+
+        let timeout = |timeout: &Duration| timeout.as_secs_f64().to_string();
+
+        vec![
+            line("serial", &self.serial, |serial| format!("{:?}", serial)),
+            line("usb", &self.usb, |(vendor_id, product_id)| {
+                format!("\"{:04x}:{:04x}\"", vendor_id, product_id)
+            }),
+            line("baud", &self.baud, u32::to_string),
+            line("timeout", &self.timeout, timeout),
+            line("handshake_timeout", &self.handshake_timeout, timeout),
+            line("transfer_timeout", &self.transfer_timeout, timeout),
+            line("watchdog_period", &self.watchdog_period, timeout),
+            line("vendors", &self.vendors, |vendors| {
+                let vendors = vendors
+                    .vendors()
+                    .iter()
+                    .map(|vendor| format!("{:?}", vendor.to_string()))
+                    .collect::<Vec<_>>();
+                format!("[{}]", vendors.join(", "))
+            }),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendors_are_ids_or_names() {
+        assert_eq!(Vendor::parse("04e8"), Ok(Vendor::Id(0x04e8)));
+        assert_eq!(Vendor::parse("0x05C6"), Ok(Vendor::Id(0x05c6)));
+        assert_eq!(Vendor::parse(" 18d1 "), Ok(Vendor::Id(0x18d1)));
+
+        // Anything but four hexadecimal digits is a name.
+        assert_eq!(
+            Vendor::parse("Qualcomm"),
+            Ok(Vendor::Name("qualcomm".to_string()))
+        );
+        assert_eq!(Vendor::parse("4e8"), Ok(Vendor::Name("4e8".to_string())));
+        assert_eq!(
+            Vendor::parse("0x04e80"),
+            Ok(Vendor::Name("0x04e80".to_string()))
+        );
+        assert_eq!(
+            Vendor::parse("MediaTek Inc."),
+            Ok(Vendor::Name("mediatek inc.".to_string()))
+        );
+
+        assert!(Vendor::parse("").is_err());
+        assert!(Vendor::parse("  ").is_err());
+    }
+
+    #[test]
+    fn names_match_a_part_of_the_vendor_name() {
+        let vendor = Vendor::parse("qualcomm").unwrap();
+
+        assert!(vendor.matches(0x05c6, Some("Qualcomm, Inc.")));
+        assert!(!vendor.matches(0x05c6, None));
+        assert!(Vendor::Id(0x05c6).matches(0x05c6, None));
+    }
+
+    #[test]
+    fn allow_lists_are_separated_by_commas() {
+        assert_eq!(
+            VendorAllowList::parse("04e8, qualcomm,0x0e8d"),
+            Ok(VendorAllowList::new(vec![
+                Vendor::Id(0x04e8),
+                Vendor::Name("qualcomm".to_string()),
+                Vendor::Id(0x0e8d),
+            ]))
+        );
+        assert!(VendorAllowList::parse("04e8").unwrap().is_default());
+
+        for string in ["", "04e8,", ",qualcomm", "04e8,,qualcomm"] {
+            assert!(VendorAllowList::parse(string).is_err(), "{:?}", string);
+        }
+    }
+
+    #[test]
+    fn allow_lists_parse_back_from_how_they_are_shown() {
+        for string in ["04e8", "04e8, qualcomm", "0x05C6,MediaTek,18d1"] {
+            let vendors = VendorAllowList::parse(string).unwrap();
+
+            assert_eq!(VendorAllowList::parse(&vendors.to_string()), Ok(vendors));
+        }
+
+        let default = VendorAllowList::default();
+        assert_eq!(default.to_string(), "04e8");
+        assert_eq!(VendorAllowList::parse(&default.to_string()), Ok(default));
+    }
+}
@@ -0,0 +1,59 @@
+// The common CRC-32 (as used by zlib and friends), computed incrementally.
+const POLYNOMIAL: u32 = 0xedb88320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            value = if value & 1 != 0 {
+                (value >> 1) ^ POLYNOMIAL
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = value;
+        i += 1;
+    }
+
+    table
+};
+
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { value: 0xffffffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value = TABLE[((self.value ^ byte as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
@@ -0,0 +1,292 @@
+use crate::crc32::Crc32;
+use crate::inflate::{DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+// An encoder for DEFLATE (RFC 1951) and the gzip format around it (RFC
+// 1952), for compressing dumps as they arrive. Matches are found with hash
+// chains and encoded with the fixed Huffman codes, which saves building a
+// code for every block and still takes runs of zero or erased memory down to
+// almost nothing.
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const HASH_BITS: u32 = 15;
+// How many earlier positions with the same hash are tried for a match.
+const MAX_CHAIN: usize = 32;
+
+// Data is compressed into a block once this much of it has come in.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+const END_OF_BLOCK: u16 = 256;
+const NONE: u64 = u64::MAX;
+
+struct BitWriter {
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32, output: &mut Vec<u8>) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+
+        while self.count >= 8 {
+            output.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored starting with their most significant bit.
+    fn code(&mut self, code: u32, length: u32, output: &mut Vec<u8>) {
+        self.bits(code.reverse_bits() >> (32 - length), length, output);
+    }
+
+    fn align(&mut self, output: &mut Vec<u8>) {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count, output);
+        }
+    }
+}
+
+pub struct Deflater {
+    // The last window of the data that has been compressed, followed by the
+    // data that hasn't.
+    data: Vec<u8>,
+    // Where the data that hasn't been compressed starts in `data`.
+    pending: usize,
+    // The position of the first byte of `data` in everything so far.
+    base: u64,
+    // The last position of every hash, and the one before that of every
+    // position in the window.
+    head: Vec<u64>,
+    prev: Vec<u64>,
+    writer: BitWriter,
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deflater {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            pending: 0,
+            base: 0,
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_SIZE],
+            writer: BitWriter { bits: 0, count: 0 },
+        }
+    }
+
+    // Appends what `data` compresses to to `output`, which may be nothing
+    // until enough has come in.
+    pub fn compress(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        self.data.extend_from_slice(data);
+
+        if self.data.len() - self.pending >= BLOCK_SIZE {
+            self.block(output);
+        }
+    }
+
+    // Compresses whatever is left and ends the stream.
+    pub fn finish(&mut self, output: &mut Vec<u8>) {
+        if self.pending < self.data.len() {
+            self.block(output);
+        }
+
+        // An empty last block, as the blocks so far didn't know they were.
+        self.writer.bits(1, 1, output);
+        self.writer.bits(1, 2, output);
+        literal(&mut self.writer, END_OF_BLOCK, output);
+        self.writer.align(output);
+    }
+
+    fn hash(&self, index: usize) -> usize {
+        let value = u32::from(self.data[index]) << 16
+            | u32::from(self.data[index + 1]) << 8
+            | u32::from(self.data[index + 2]);
+
+        (value.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    // Remembers the position for later matches, returning the previous one
+    // with the same hash.
+    fn insert(&mut self, index: usize) -> u64 {
+        let hash = self.hash(index);
+        let position = self.base + index as u64;
+        let previous = self.head[hash];
+
+        self.prev[position as usize % WINDOW_SIZE] = previous;
+        self.head[hash] = position;
+
+        previous
+    }
+
+    // The longest earlier match for the data at `index`, as its length and
+    // distance.
+    fn longest_match(&self, index: usize, mut candidate: u64) -> (usize, usize) {
+        let position = self.base + index as u64;
+        let limit = MAX_MATCH.min(self.data.len() - index);
+        let mut best = (0, 0);
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE || candidate + WINDOW_SIZE as u64 <= position {
+                break;
+            }
+
+            let start = (candidate - self.base) as usize;
+            let length = self.data[start..start + limit]
+                .iter()
+                .zip(&self.data[index..index + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if length > best.0 {
+                best = (length, index - start);
+                if length == limit {
+                    break;
+                }
+            }
+
+            let next = self.prev[candidate as usize % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        best
+    }
+
+    fn block(&mut self, output: &mut Vec<u8>) {
+        // Not the last block, with the fixed codes.
+        self.writer.bits(0, 1, output);
+        self.writer.bits(1, 2, output);
+
+        let end = self.data.len();
+        let mut index = self.pending;
+
+        while index < end {
+            let (length, distance) = if end - index >= MIN_MATCH {
+                let candidate = self.insert(index);
+                self.longest_match(index, candidate)
+            } else {
+                (0, 0)
+            };
+
+            if length < MIN_MATCH {
+                literal(&mut self.writer, u16::from(self.data[index]), output);
+                index += 1;
+                continue;
+            }
+
+            encode_match(&mut self.writer, length, distance, output);
+            for skipped in index + 1..index + length {
+                if end - skipped >= MIN_MATCH {
+                    self.insert(skipped);
+                }
+            }
+            index += length;
+        }
+
+        literal(&mut self.writer, END_OF_BLOCK, output);
+        self.pending = end;
+
+        // Only the last window is needed for matches from here on.
+        if self.pending > 2 * WINDOW_SIZE {
+            let drop = self.pending - WINDOW_SIZE;
+            self.data.drain(..drop);
+            self.pending -= drop;
+            self.base += drop as u64;
+        }
+    }
+}
+
+// A literal byte or the end of a block, with the fixed code.
+fn literal(writer: &mut BitWriter, symbol: u16, output: &mut Vec<u8>) {
+    let symbol = u32::from(symbol);
+
+    match symbol {
+        0..=143 => writer.code(0x30 + symbol, 8, output),
+        144..=255 => writer.code(0x190 + symbol - 144, 9, output),
+        256..=279 => writer.code(symbol - 256, 7, output),
+        _ => writer.code(0xc0 + symbol - 280, 8, output),
+    }
+}
+
+fn encode_match(writer: &mut BitWriter, length: usize, distance: usize, output: &mut Vec<u8>) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .unwrap();
+    literal(writer, 257 + index as u16, output);
+    writer.bits(
+        (length - usize::from(LENGTH_BASE[index])) as u32,
+        u32::from(LENGTH_EXTRA[index]),
+        output,
+    );
+
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .unwrap();
+    writer.code(index as u32, 5, output);
+    writer.bits(
+        (distance - usize::from(DISTANCE_BASE[index])) as u32,
+        u32::from(DISTANCE_EXTRA[index]),
+        output,
+    );
+}
+
+// A gzip member without a file name or time.
+pub struct GzipEncoder {
+    deflater: Deflater,
+    crc: Crc32,
+    size: u64,
+    started: bool,
+}
+
+impl Default for GzipEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GzipEncoder {
+    pub fn new() -> Self {
+        Self {
+            deflater: Deflater::new(),
+            crc: Crc32::new(),
+            size: 0,
+            started: false,
+        }
+    }
+
+    fn header(&mut self, output: &mut Vec<u8>) {
+        if !self.started {
+            // Deflate, no flags, no time, no extra flags, an unknown system.
+            output.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+            self.started = true;
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        self.header(output);
+        self.crc.update(data);
+        self.size += data.len() as u64;
+        self.deflater.compress(data, output);
+    }
+
+    pub fn finish(&mut self, output: &mut Vec<u8>) {
+        self.header(output);
+        self.deflater.finish(output);
+        output.extend_from_slice(&self.crc.finish().to_le_bytes());
+        // The size is only kept modulo 2^32.
+        output.extend_from_slice(&(self.size as u32).to_le_bytes());
+    }
+}
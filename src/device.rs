@@ -1,94 +1,1160 @@
-use rusb::{DeviceHandle, Direction, GlobalContext};
-use std::error::Error;
-use std::time::Duration;
+use crate::config::VendorAllowList;
+use crate::error::{Error, Result};
+use crate::interfaces::{self, Choice, Endpoint, Setting};
+use crate::json::Object;
+use crate::lock::{self, DeviceLock, LockMode};
+use crate::log::{self, Level};
+use crate::parse::DeviceSpec;
+use crate::permissions;
+use crate::picker;
+use crate::retry::RetryPolicy;
+use crate::{status, step};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, TransferType, UsbContext};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use usb_ids::FromId;
 
-pub(crate) struct UsbCdcDevice {
-    handle: DeviceHandle<GlobalContext>,
+pub use crate::config::SAMSUNG_VENDOR_ID;
+
+// Gives whoever is at the seat access to Samsung devices, and the plugdev
+// group to everyone else (e.g. over ssh).
+pub fn udev_rule() -> String {
+    format!(
+        "SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"",
+        SAMSUNG_VENDOR_ID
+    )
+}
+
+// The last hint when a USB device can't be opened for lack of permissions.
+pub fn udev_hint() -> String {
+    format!(
+        "Or put this into /etc/udev/rules.d/51-sbootil.rules (see `sbootil udev-rule`) and replug the device:\n    {}",
+        udev_rule()
+    )
+}
+
+// The name of a vendor in the USB ID database.
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    usb_ids::Vendor::from_id(vendor_id).map(|vendor| vendor.name())
+}
+
+// Large writes are split into transfers of this size.
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024;
+
+// How often a stalled transfer is tried again by default.
+pub const STALL_RETRIES: u32 = 1;
+
+// How long to wait for string descriptors, which some devices never answer.
+const STRING_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How often to look for a device that is expected to come back.
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Makes libusb log to stderr, from 0 for nothing to 4 for everything.
+pub fn set_libusb_log_level(level: u8) {
+    rusb::set_log_level(match level {
+        0 => rusb::LogLevel::None,
+        1 => rusb::LogLevel::Error,
+        2 => rusb::LogLevel::Warning,
+        3 => rusb::LogLevel::Info,
+        _ => rusb::LogLevel::Debug,
+    });
+}
+
+// The string descriptors of a device, which some devices leave out.
+#[derive(Clone, Debug, Default)]
+pub struct Strings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+// Reads the string descriptors, which requires opening the device. Returns
+// None if that isn't permitted.
+pub fn read_strings<T: UsbContext>(device: &Device<T>) -> Option<Strings> {
+    Some(read_handle_strings(&device.open().ok()?))
+}
+
+fn read_handle_strings<T: UsbContext>(handle: &DeviceHandle<T>) -> Strings {
+    let Ok(device_desc) = handle.device().device_descriptor() else {
+        return Strings::default();
+    };
+
+    let language = match handle.read_languages(STRING_TIMEOUT) {
+        Ok(languages) => match languages.first() {
+            Some(language) => *language,
+            None => return Strings::default(),
+        },
+        Err(_) => return Strings::default(),
+    };
+
+    Strings {
+        manufacturer: handle
+            .read_manufacturer_string(language, &device_desc, STRING_TIMEOUT)
+            .ok(),
+        product: handle
+            .read_product_string(language, &device_desc, STRING_TIMEOUT)
+            .ok(),
+        serial_number: handle
+            .read_serial_number_string(language, &device_desc, STRING_TIMEOUT)
+            .ok(),
+    }
+}
+
+// Where the device is plugged in, like 1-2.3 for port 3 of the hub on port 2
+// of bus 1. This stays the same when the device re-enumerates.
+pub fn port_path<T: UsbContext>(device: &Device<T>) -> Option<String> {
+    let ports = device.port_numbers().ok()?;
+
+    if ports.is_empty() {
+        return None;
+    }
+
+    let ports = ports.iter().map(u8::to_string).collect::<Vec<_>>();
+
+    Some(format!("{}-{}", device.bus_number(), ports.join(".")))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Download,
+}
+
+impl Mode {
+    // The name used in machine-readable output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Download => "download",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Download => write!(f, "download mode"),
+        }
+    }
+}
+
+// Guesses what the device is currently running. Download mode presents
+// itself as a Samsung CDC device, usually named after the gadget driver.
+pub fn guess_mode<T: UsbContext>(device: &Device<T>, strings: Option<&Strings>) -> Option<Mode> {
+    let device_desc = device.device_descriptor().ok()?;
+
+    if device_desc.vendor_id() != SAMSUNG_VENDOR_ID {
+        return None;
+    }
+
+    let product = strings.and_then(|strings| strings.product.as_deref());
+    if let Some(product) = product {
+        let product = product.to_lowercase();
+
+        if product.contains("gadget serial") || product.contains("download") {
+            return Some(Mode::Download);
+        }
+    }
+
+    find_cdc_interface(device, None)
+        .ok()
+        .map(|_| Mode::Download)
+}
+
+// Everything list-devices knows about a device, so that all output formats
+// show the same information.
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub vendor_name: Option<&'static str>,
+    pub product_name: Option<&'static str>,
+    pub bus: u8,
+    pub address: u8,
+    pub port: Option<String>,
+    // None if the device couldn't be opened.
+    pub strings: Option<Strings>,
+    pub mode: Option<Mode>,
+}
+
+impl DeviceInfo {
+    pub fn new<T: UsbContext>(device: &Device<T>) -> Result<Self> {
+        let device_desc = device.device_descriptor()?;
+        let strings = read_strings(device);
+
+        Ok(Self {
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            vendor_name: vendor_name(device_desc.vendor_id()),
+            product_name: usb_ids::Device::from_vid_pid(
+                device_desc.vendor_id(),
+                device_desc.product_id(),
+            )
+            .map(|product| product.name()),
+            bus: device.bus_number(),
+            address: device.address(),
+            port: port_path(device),
+            mode: guess_mode(device, strings.as_ref()),
+            strings,
+        })
+    }
+
+    pub fn spec(&self) -> DeviceSpec {
+        device_spec(
+            (self.vendor_id, self.product_id),
+            self.strings
+                .as_ref()
+                .and_then(|strings| strings.serial_number.clone()),
+            (self.bus, self.address),
+        )
+    }
+
+    pub fn to_object(&self) -> Object {
+        let strings = self.strings.clone().unwrap_or_default();
+
+        Object::new()
+            .field("vendor_id", format!("{:04x}", self.vendor_id))
+            .field("product_id", format!("{:04x}", self.product_id))
+            .field("vendor_name", self.vendor_name)
+            .field("product_name", self.product_name)
+            .field("bus", self.bus)
+            .field("address", self.address)
+            .field("port", &self.port)
+            .field("strings_readable", self.strings.is_some())
+            .field("manufacturer", strings.manufacturer)
+            .field("product", strings.product)
+            .field("serial", strings.serial_number)
+            .field("mode", self.mode.map(|mode| mode.name()))
+            .field("device_spec", self.spec().to_string())
+    }
+}
+
+struct CdcInterface {
     interface: u8,
     setting: u8,
     endpoint_in: u8,
     endpoint_out: u8,
+    max_packet_size: usize,
+    control_interface: Option<u8>,
+}
+
+// The alternate settings of all interfaces in the configuration that the
+// devices are talked to in.
+pub fn settings<T: UsbContext>(device: &Device<T>) -> Result<Vec<Setting>> {
+    let config_descriptor = device.config_descriptor(0)?;
+
+    Ok(config_descriptor
+        .interfaces()
+        .flat_map(|interface| interface.descriptors())
+        .map(|interface_descriptor| Setting {
+            interface: interface_descriptor.interface_number(),
+            setting: interface_descriptor.setting_number(),
+            class: interface_descriptor.class_code(),
+            subclass: interface_descriptor.sub_class_code(),
+            protocol: interface_descriptor.protocol_code(),
+            endpoints: interface_descriptor
+                .endpoint_descriptors()
+                .map(|ed| Endpoint {
+                    address: ed.address(),
+                    direction: match ed.direction() {
+                        Direction::In => interfaces::Direction::In,
+                        Direction::Out => interfaces::Direction::Out,
+                    },
+                    transfer_type: match ed.transfer_type() {
+                        TransferType::Control => interfaces::TransferType::Control,
+                        TransferType::Isochronous => interfaces::TransferType::Isochronous,
+                        TransferType::Bulk => interfaces::TransferType::Bulk,
+                        TransferType::Interrupt => interfaces::TransferType::Interrupt,
+                    },
+                    max_packet_size: ed.max_packet_size(),
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+// Looks for the data interface with its pair of bulk endpoints, or takes the
+// one given by number.
+fn find_cdc_interface<T: UsbContext>(
+    device: &Device<T>,
+    forced: Option<u8>,
+) -> Result<CdcInterface> {
+    let settings = settings(device)?;
+    let choice = interfaces::choose(&settings, forced);
+
+    cdc_interface(&settings, &choice, forced)
+}
+
+fn cdc_interface(
+    settings: &[Setting],
+    choice: &Choice,
+    forced: Option<u8>,
+) -> Result<CdcInterface> {
+    let Some(picked) = choice.picked else {
+        return Err(Error::DeviceNotFound(match forced {
+            Some(interface) => format!(
+                "Interface {} has no pair of bulk endpoints, `sbootil usb info` shows what it has",
+                interface
+            ),
+            None => "No matching interface found, `sbootil usb info` shows why".to_string(),
+        }));
+    };
+
+    let setting = &settings[picked];
+    let (endpoint_in, endpoint_out) = setting.bulk_pair().unwrap();
+
+    Ok(CdcInterface {
+        interface: setting.interface,
+        setting: setting.setting,
+        endpoint_in: endpoint_in.address,
+        endpoint_out: endpoint_out.address,
+        max_packet_size: endpoint_out.max_packet_size.into(),
+        control_interface: choice.control_interface,
+    })
+}
+
+// A device that could be talked to, along with what is needed to tell it
+// apart from others.
+pub struct Candidate<T: UsbContext = GlobalContext> {
+    pub device: Device<T>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus: u8,
+    pub address: u8,
+    // Only available if the device could be opened.
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+}
+
+impl<T: UsbContext> Candidate<T> {
+    // The option that picks out this device, preferring the serial number as
+    // it survives replugging.
+    pub fn selector_arg(&self) -> String {
+        match &self.serial_number {
+            Some(serial_number) => format!("--serial-number {}", serial_number),
+            None => format!("--bus-address {}:{}", self.bus, self.address),
+        }
+    }
+
+    // The same as a single string, for --device-spec.
+    pub fn spec(&self) -> DeviceSpec {
+        device_spec(
+            (self.vendor_id, self.product_id),
+            self.serial_number.clone(),
+            (self.bus, self.address),
+        )
+    }
+}
+
+impl<T: UsbContext> fmt::Display for Candidate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:04x}:{:04x}] bus-address {}:{}, serial {}, {}",
+            self.vendor_id,
+            self.product_id,
+            self.bus,
+            self.address,
+            self.serial_number.as_deref().unwrap_or("unknown"),
+            self.product.as_deref().unwrap_or("unknown product")
+        )
+    }
+}
+
+// The bus address only changes once the device is replugged, but it is all
+// there is to go by without a serial number.
+pub fn device_spec(
+    usb_id: (u16, u16),
+    serial_number: Option<String>,
+    bus_address: (u8, u8),
+) -> DeviceSpec {
+    DeviceSpec {
+        usb_id: Some(usb_id),
+        bus_address: Some(bus_address).filter(|_| serial_number.is_none()),
+        serial_number,
+    }
+}
+
+// Restricts which devices are considered.
+#[derive(Clone, Debug, Default)]
+pub struct Selector {
+    pub usb_id: Option<(u16, u16)>,
+    pub serial_number: Option<String>,
+    pub bus_address: Option<(u8, u8)>,
+    // The vendors to consider without an explicit ID.
+    pub vendors: VendorAllowList,
+    // The interface to talk to, for when the matcher picks the wrong one.
+    pub interface: Option<u8>,
+}
+
+impl Selector {
+    fn matches_ids(&self, vendor_id: u16, product_id: u16) -> bool {
+        match self.usb_id {
+            Some(usb_id) => usb_id == (vendor_id, product_id),
+            // Without an explicit ID, only devices of the allowed vendors are
+            // of interest, unless the device has been picked by its position.
+            None => {
+                self.bus_address.is_some()
+                    || self.vendors.matches(vendor_id, vendor_name(vendor_id))
+            }
+        }
+    }
+}
+
+impl From<DeviceSpec> for Selector {
+    fn from(spec: DeviceSpec) -> Self {
+        Self {
+            usb_id: spec.usb_id,
+            serial_number: spec.serial_number,
+            bus_address: spec.bus_address,
+            ..Self::default()
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.usb_id, self.bus_address) {
+            (Some((vendor_id, product_id)), _) => {
+                write!(f, "{:04x}:{:04x}", vendor_id, product_id)?
+            }
+            (None, Some(_)) => write!(f, "device")?,
+            (None, None) if self.vendors.is_default() => write!(f, "Samsung device")?,
+            (None, None) => write!(f, "device from {}", self.vendors)?,
+        }
+
+        if let Some((bus, address)) = self.bus_address {
+            write!(f, " at bus-address {}:{}", bus, address)?;
+        }
+
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, " with serial number {}", serial_number)?;
+        }
+
+        if let Some(interface) = self.interface {
+            write!(f, " (interface {})", interface)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Lists the devices with a CDC data interface that match the selector.
+pub fn find_candidates(selector: &Selector) -> Result<Vec<Candidate>> {
+    find_candidates_in(&GlobalContext::default(), selector)
+}
+
+pub fn find_candidates_in<T: UsbContext>(
+    context: &T,
+    selector: &Selector,
+) -> Result<Vec<Candidate<T>>> {
+    find_matching_in(context, selector, true)
+}
+
+// Lists the devices that match the selector, whether they can be talked to or
+// not, for looking into why they can't.
+pub fn find_any(selector: &Selector) -> Result<Vec<Candidate>> {
+    find_matching_in(&GlobalContext::default(), selector, false)
+}
+
+fn find_matching_in<T: UsbContext>(
+    context: &T,
+    selector: &Selector,
+    cdc_only: bool,
+) -> Result<Vec<Candidate<T>>> {
+    let mut candidates = Vec::new();
+
+    for device in context.devices()?.iter() {
+        // Devices that can't even be described are of no use, but shouldn't
+        // stop others from being found.
+        let device_desc = match device.device_descriptor() {
+            Ok(device_desc) => device_desc,
+            Err(_) => continue,
+        };
+
+        if !selector.matches_ids(device_desc.vendor_id(), device_desc.product_id()) {
+            continue;
+        }
+
+        if let Some(bus_address) = selector.bus_address {
+            if bus_address != (device.bus_number(), device.address()) {
+                continue;
+            }
+        }
+
+        if cdc_only && find_cdc_interface(&device, selector.interface).is_err() {
+            continue;
+        }
+
+        let strings = read_strings(&device).unwrap_or_default();
+
+        if let Some(wanted) = &selector.serial_number {
+            if strings.serial_number.as_deref() != Some(wanted.as_str()) {
+                continue;
+            }
+        }
+
+        candidates.push(Candidate {
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            bus: device.bus_number(),
+            address: device.address(),
+            device,
+            serial_number: strings.serial_number,
+            product: strings.product,
+        });
+    }
+
+    Ok(candidates)
+}
+
+// Line settings for SET_LINE_CODING. These mean nothing for a device that
+// isn't an actual serial port, but some bootloaders wait for them anyway.
+#[derive(Clone, Copy, Debug)]
+pub struct LineCoding {
+    pub baud: u32,
+    // 0 for 1 stop bit, 1 for 1.5, 2 for 2.
+    pub stop_bits: u8,
+    // 0 for none, 1 for odd, 2 for even, 3 for mark, 4 for space.
+    pub parity: u8,
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            baud: 115200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+const SET_LINE_CODING: u8 = 0x20;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Defaults to the global libusb context, but can also live in a context that
+// the application manages.
+pub struct UsbCdcDevice<T: UsbContext = GlobalContext> {
+    handle: DeviceHandle<T>,
+    interface: u8,
+    setting: u8,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    max_packet_size: usize,
+    control_interface: Option<u8>,
+    // What to send to the control interface on setup, if anything.
+    line_coding: Option<LineCoding>,
+    // The interfaces that have been claimed, and whether a kernel driver had
+    // to be detached from them by hand and so has to be attached again.
+    claimed: Vec<(u8, bool)>,
+    // What the device said about itself when it was opened, the serial
+    // number also finds it again after it re-enumerated.
+    strings: Strings,
+    // For transfers that stalled.
+    retry: RetryPolicy,
+    // How often a halt was cleared to go on, across reconnects.
+    stalls: u64,
+    // The interface that was asked for, which is looked for again after
+    // reconnecting.
+    forced_interface: Option<u8>,
+    // Released after the interfaces, when dropping.
+    _device_lock: Option<DeviceLock>,
 }
 
 impl UsbCdcDevice {
-    pub(crate) fn from_handle(handle: DeviceHandle<GlobalContext>) -> Result<Self, Box<dyn Error>> {
-        let config_descriptor = handle.device().config_descriptor(0)?;
+    pub fn open(vendor_id: u16, product_id: u16) -> Result<Self> {
+        Self::open_in(&GlobalContext::default(), vendor_id, product_id)
+    }
 
-        for interface in config_descriptor.interfaces() {
-            for interface_descriptor in interface.descriptors() {
-                if interface_descriptor.num_endpoints() != 2 {
-                    continue;
-                }
+    // Opens the single device matching the selector, refusing to guess if
+    // there are several.
+    //
+    // Without a line coding, the CDC class requests are skipped.
+    pub fn open_selected(
+        selector: &Selector,
+        line_coding: Option<LineCoding>,
+        lock: LockMode,
+    ) -> Result<Self> {
+        Self::open_selected_in(&GlobalContext::default(), selector, line_coding, lock)
+    }
+}
+
+impl<T: UsbContext> UsbCdcDevice<T> {
+    pub fn open_in(context: &T, vendor_id: u16, product_id: u16) -> Result<Self> {
+        let handle = context
+            .open_device_with_vid_pid(vendor_id, product_id)
+            .ok_or_else(|| {
+                Error::DeviceNotFound(format!(
+                    "Device {:04x}:{:04x} not found or not openable",
+                    vendor_id, product_id
+                ))
+            })?;
+
+        let mut device = Self::from_handle(handle)?;
+
+        device.setup_interface()?;
+
+        Ok(device)
+    }
+
+    pub fn open_selected_in(
+        context: &T,
+        selector: &Selector,
+        line_coding: Option<LineCoding>,
+        lock: LockMode,
+    ) -> Result<Self> {
+        let candidates = find_candidates_in(context, selector)?;
+
+        if candidates.is_empty() {
+            return Err(Error::DeviceNotFound(format!(
+                "No {} with a CDC interface found",
+                selector
+            )));
+        }
+
+        let several = candidates.len() > 1;
+        let candidate = picker::choose(
+            "devices",
+            candidates,
+            "select one with --usb, --serial-number or --bus-address",
+        )?;
+
+        if several {
+            status!("Using {}", candidate);
+            status!(
+                "Pass {} to select it directly next time",
+                candidate.selector_arg()
+            );
+        }
+
+        // Nothing may be sent to the device before the lock is taken.
+        let device_lock =
+            DeviceLock::acquire(&lock::usb_key(candidate.bus, candidate.address), lock)?;
+
+        let handle = candidate.device.open().map_err(|err| match err {
+            // Where libusb opens devices on Linux.
+            rusb::Error::Access => permissions::denied(
+                format!("Not allowed to open {}", candidate),
+                Path::new(&format!(
+                    "/dev/bus/usb/{:03}/{:03}",
+                    candidate.bus, candidate.address
+                )),
+                &udev_hint(),
+            ),
+            err => Error::DeviceNotFound(format!("Failed to open {}: {}", candidate, err)),
+        })?;
+
+        let mut device = Self::from_handle_using(handle, selector.interface)?;
+        device.line_coding = line_coding;
+        device._device_lock = device_lock;
+
+        device.setup_interface()?;
+
+        Ok(device)
+    }
 
-                if interface_descriptor.class_code() != 0x0a {
-                    continue;
+    pub fn from_handle(handle: DeviceHandle<T>) -> Result<Self> {
+        Self::from_handle_using(handle, None)
+    }
+
+    // Talks to the given interface rather than the one that the matcher
+    // picks, if any.
+    pub fn from_handle_using(handle: DeviceHandle<T>, interface: Option<u8>) -> Result<Self> {
+        let settings = settings(&handle.device())?;
+        let choice = interfaces::choose(&settings, interface);
+
+        if log::enabled(Level::Steps) {
+            for (setting, reason) in settings.iter().zip(&choice.reasons) {
+                if let Some(reason) = reason {
+                    step!(
+                        "passing over interface {} setting {}: {}",
+                        setting.interface,
+                        setting.setting,
+                        reason
+                    );
                 }
+            }
+        }
+        let cdc_interface = cdc_interface(&settings, &choice, interface)?;
+        step!(
+            "using interface {} setting {} (class {:#04x}{}), endpoints {:#04x} and {:#04x}{}",
+            cdc_interface.interface,
+            cdc_interface.setting,
+            settings[choice.picked.unwrap()].class,
+            if interface.is_some() {
+                ", as asked for"
+            } else {
+                ""
+            },
+            cdc_interface.endpoint_in,
+            cdc_interface.endpoint_out,
+            cdc_interface
+                .control_interface
+                .map(|control| format!(", class requests to interface {}", control))
+                .unwrap_or_default()
+        );
+
+        let strings = read_handle_strings(&handle);
+
+        Ok(Self {
+            handle,
+            interface: cdc_interface.interface,
+            setting: cdc_interface.setting,
+            endpoint_in: cdc_interface.endpoint_in,
+            endpoint_out: cdc_interface.endpoint_out,
+            max_packet_size: cdc_interface.max_packet_size,
+            control_interface: cdc_interface.control_interface,
+            line_coding: Some(LineCoding::default()),
+            claimed: Vec::new(),
+            strings,
+            retry: RetryPolicy::new(STALL_RETRIES),
+            stalls: 0,
+            forced_interface: interface,
+            _device_lock: None,
+        })
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    // Resets the device, for when it has stopped responding. The device may
+    // re-enumerate because of this, which then needs a reconnect.
+    pub fn reset(&mut self) -> Result<()> {
+        step!("resetting the device");
+
+        self.handle.reset()?;
+
+        Ok(())
+    }
+
+    pub fn strings(&self) -> &Strings {
+        &self.strings
+    }
 
-                let endpoint_in = interface_descriptor
-                    .endpoint_descriptors()
-                    .filter(|ed| ed.direction() == Direction::In)
-                    .next()
-                    .unwrap()
-                    .address();
-
-                let endpoint_out = interface_descriptor
-                    .endpoint_descriptors()
-                    .filter(|ed| ed.direction() == Direction::Out)
-                    .next()
-                    .unwrap()
-                    .address();
-
-                return Ok(Self {
-                    handle,
-                    interface: interface.number(),
-                    setting: interface_descriptor.setting_number(),
-                    endpoint_in,
-                    endpoint_out,
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    // Of the bulk OUT endpoint.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub fn spec(&self) -> Result<DeviceSpec> {
+        let device = self.handle.device();
+        let device_desc = device.device_descriptor()?;
+
+        Ok(device_spec(
+            (device_desc.vendor_id(), device_desc.product_id()),
+            self.strings.serial_number.clone(),
+            (device.bus_number(), device.address()),
+        ))
+    }
+
+    // Where the device currently is, e.g. 1:7.
+    pub fn location(&self) -> String {
+        let device = self.handle.device();
+        format!("{}:{}", device.bus_number(), device.address())
+    }
+
+    // Waits for the device to come back after it dropped off the bus, and
+    // sets it up again. A device with the same serial number is preferred,
+    // but as not every device keeps it across modes, any device with the same
+    // ID will do if that doesn't show up.
+    pub fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        let device = self.handle.device();
+        let device_desc = device.device_descriptor()?;
+        let usb_id = (device_desc.vendor_id(), device_desc.product_id());
+        let previous = (device.bus_number(), device.address());
+        let context = self.handle.context().clone();
+
+        let _ = self.teardown_interface();
+
+        let selectors = [
+            Selector {
+                usb_id: Some(usb_id),
+                serial_number: self.strings.serial_number.clone(),
+                interface: self.forced_interface,
+                ..Selector::default()
+            },
+            Selector {
+                usb_id: Some(usb_id),
+                interface: self.forced_interface,
+                ..Selector::default()
+            },
+        ];
+
+        step!("waiting for {:04x}:{:04x} to reconnect", usb_id.0, usb_id.1);
+
+        let start = Instant::now();
+
+        loop {
+            for selector in &selectors {
+                // The old device may linger for a moment, so only take a new one.
+                let candidates = find_candidates_in(&context, selector)?
+                    .into_iter()
+                    .filter(|candidate| (candidate.bus, candidate.address) != previous)
+                    .collect::<Vec<_>>();
+
+                let candidate = match candidates.len() {
+                    0 => continue,
+                    1 => &candidates[0],
+                    _ => {
+                        return Err(Error::DeviceNotFound(format!(
+                            "Several devices appeared while reconnecting to {}",
+                            selector
+                        )))
+                    }
+                };
+
+                // Permissions may not have been applied to the new device yet.
+                let handle = match candidate.device.open() {
+                    Ok(handle) => handle,
+                    Err(err) => {
+                        step!("failed to open {}: {}", candidate, err);
+                        break;
+                    }
+                };
+
+                let mut device = Self::from_handle_using(handle, self.forced_interface)?;
+                device.line_coding = self.line_coding;
+                device.retry = self.retry;
+                device.stalls = self.stalls;
+                device.setup_interface()?;
+
+                *self = device;
+
+                return Ok(());
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(Error::Timeout {
+                    phase: format!("{:04x}:{:04x} to reconnect", usb_id.0, usb_id.1),
                 });
             }
+
+            std::thread::sleep(RECONNECT_POLL_INTERVAL);
+        }
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> Result<()> {
+        let mut reattach = false;
+
+        // On Linux, cdc_acm usually has the interface already. Let libusb take
+        // care of detaching it where it can, and do it by hand otherwise.
+        if self.handle.set_auto_detach_kernel_driver(true).is_err()
+            && self.handle.kernel_driver_active(interface) == Ok(true)
+        {
+            step!("detaching the kernel driver from interface {}", interface);
+
+            self.handle.detach_kernel_driver(interface)?;
+            reattach = true;
         }
 
-        Err("No matching interface found")?
+        match self.handle.claim_interface(interface) {
+            Ok(()) => {
+                self.claimed.push((interface, reattach));
+
+                Ok(())
+            }
+            Err(err) => {
+                if reattach {
+                    let _ = self.handle.attach_kernel_driver(interface);
+                }
+
+                Err(match err {
+                    rusb::Error::Busy => Error::DeviceNotFound(format!(
+                        "Interface {} is in use by another driver or program",
+                        interface
+                    )),
+                    err => err.into(),
+                })
+            }
+        }
     }
 
-    pub(crate) fn setup_interface(&mut self) -> Result<(), Box<dyn Error>> {
-        self.handle.claim_interface(self.interface)?;
+    // Does nothing if the interface has already been set up.
+    pub fn setup_interface(&mut self) -> Result<()> {
+        if !self.claimed.is_empty() {
+            return Ok(());
+        }
+
+        self.claim_interface(self.interface)?;
 
         self.handle
             .set_alternate_setting(self.interface, self.setting)?;
 
         self.handle.reset()?;
 
+        if let Some(line_coding) = self.line_coding {
+            self.setup_control_interface(line_coding);
+        }
+
+        Ok(())
+    }
+
+    // Unlike cdc_acm, libusb doesn't do this by itself. Devices that don't
+    // need it may well reject it, so failing here isn't fatal.
+    fn setup_control_interface(&mut self, line_coding: LineCoding) {
+        let control_interface = match self.control_interface {
+            Some(control_interface) => control_interface,
+            None => {
+                step!("no CDC control interface, skipping the line setup");
+                return;
+            }
+        };
+
+        if let Err(err) = self.claim_interface(control_interface) {
+            step!("failed to claim the CDC control interface: {}", err);
+            return;
+        }
+
+        if let Err(err) = self.set_line_coding(line_coding) {
+            step!("failed to set the line coding: {}", err);
+        }
+
+        if let Err(err) = self.set_control_lines(true, true) {
+            step!("failed to set the control lines: {}", err);
+        }
+    }
+
+    fn class_request(&self, request: u8, value: u16, data: &[u8]) -> Result<()> {
+        let control_interface = self.control_interface.ok_or_else(|| {
+            Error::Unsupported("The device has no CDC control interface".to_string())
+        })?;
+
+        let request_type = rusb::request_type(
+            Direction::Out,
+            rusb::RequestType::Class,
+            rusb::Recipient::Interface,
+        );
+
+        self.handle.write_control(
+            request_type,
+            request,
+            value,
+            control_interface.into(),
+            data,
+            CONTROL_TIMEOUT,
+        )?;
+
         Ok(())
     }
 
-    pub(crate) fn teardown_interface(&mut self) -> Result<(), Box<dyn Error>> {
-        self.handle.release_interface(self.interface)?;
+    pub fn set_line_coding(&self, line_coding: LineCoding) -> Result<()> {
+        step!(
+            "setting the line coding to {} baud, {} data bits",
+            line_coding.baud,
+            line_coding.data_bits
+        );
+
+        let mut data = line_coding.baud.to_le_bytes().to_vec();
+        data.extend_from_slice(&[
+            line_coding.stop_bits,
+            line_coding.parity,
+            line_coding.data_bits,
+        ]);
+
+        self.class_request(SET_LINE_CODING, 0, &data)
+    }
+
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> Result<()> {
+        step!("setting DTR {} and RTS {}", dtr, rts);
+
+        self.class_request(SET_CONTROL_LINE_STATE, dtr as u16 | (rts as u16) << 1, &[])
+    }
+
+    // Also happens when the device is dropped, but errors get lost there.
+    pub fn teardown_interface(&mut self) -> Result<()> {
+        let mut result = Ok(());
+
+        while let Some((interface, reattach)) = self.claimed.pop() {
+            if let Err(err) = self.handle.release_interface(interface) {
+                result = result.and(Err(err.into()));
+            }
+
+            if reattach {
+                if let Err(err) = self.handle.attach_kernel_driver(interface) {
+                    result = result.and(Err(err.into()));
+                }
+            }
+        }
+
+        result
+    }
+
+    // The bootloader stalls the endpoint when it rejects something, which
+    // sticks until the halt is cleared. Retry once after that by default,
+    // since stalling again points at a protocol problem instead.
+    fn transfer(
+        &mut self,
+        endpoint: u8,
+        direction: &'static str,
+        mut transfer: impl FnMut(&DeviceHandle<T>) -> rusb::Result<usize>,
+    ) -> Result<usize> {
+        let mut transfer = |handle: &DeviceHandle<T>| {
+            let start = Instant::now();
+            let result = transfer(handle);
+
+            if log::enabled(Level::Transfers) {
+                let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+
+                match &result {
+                    Ok(count) => log::write(format_args!(
+                        "usb {} {:#04x}: {} bytes in {:.3} ms",
+                        direction, endpoint, count, elapsed
+                    )),
+                    Err(err) => log::write(format_args!(
+                        "usb {} {:#04x}: {} after {:.3} ms",
+                        direction, endpoint, err, elapsed
+                    )),
+                }
+            }
+
+            result
+        };
+
+        let mut retries = self.retry.start();
+
+        loop {
+            match transfer(&self.handle) {
+                Err(rusb::Error::Pipe) if retries.remaining() => {
+                    step!(
+                        "bulk {} endpoint {:#04x} stalled, clearing the halt",
+                        direction,
+                        endpoint
+                    );
+
+                    self.handle.clear_halt(endpoint)?;
+                    self.stalls += 1;
+                    retries.next(&format!("the bulk {} transfer", direction));
+                }
+                Err(rusb::Error::Pipe) => {
+                    // Leave the endpoint usable for whatever comes next.
+                    let _ = self.handle.clear_halt(endpoint);
+
+                    return Err(Error::Stall {
+                        endpoint,
+                        direction,
+                    });
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<usize> {
+        let endpoint = self.endpoint_out;
+
+        self.transfer(endpoint, "OUT", |handle| {
+            handle.write_bulk(endpoint, buf, timeout)
+        })
+    }
+
+    // Keeps writing until everything has been sent, in chunks that every
+    // platform can handle. The timeout applies to the whole buffer, zero
+    // meaning no timeout like for single transfers.
+    //
+    // A transfer that ends on a packet boundary is terminated with a
+    // zero-length packet, as the device would otherwise wait for more data.
+    pub fn write_all(&mut self, buf: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Deadline::new(timeout);
+        let mut count = 0;
+
+        while count < buf.len() {
+            let end = buf.len().min(count + MAX_TRANSFER_SIZE);
+            let result = match deadline.remaining() {
+                Some(remaining) => self.write(&buf[count..end], remaining),
+                None => Err(Error::Usb(rusb::Error::Timeout)),
+            };
+
+            match result {
+                Ok(written) => count += written,
+                Err(Error::Usb(rusb::Error::Timeout)) if count > 0 => {
+                    return Err(Error::ShortWrite {
+                        phase: format!("the bulk OUT endpoint {:#04x}", self.endpoint_out),
+                        expected: buf.len(),
+                        got: count,
+                    })
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        if !buf.is_empty() && buf.len().is_multiple_of(self.max_packet_size) {
+            let remaining = deadline
+                .remaining()
+                .ok_or(Error::Usb(rusb::Error::Timeout))?;
+            self.write(&[], remaining)?;
+        }
 
         Ok(())
     }
 
-    pub(crate) fn write(&self, buf: &[u8], timeout: Duration) -> Result<usize, Box<dyn Error>> {
-        let transferred = self.handle.write_bulk(self.endpoint_out, buf, timeout)?;
+    pub fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let endpoint = self.endpoint_in;
+
+        self.transfer(endpoint, "IN", |handle| {
+            handle.read_bulk(endpoint, buf, timeout)
+        })
+    }
+
+    // Keeps reading until the buffer is full. The timeout applies to the
+    // whole buffer, zero meaning no timeout like for single transfers. The
+    // buffer still has to be able to hold every packet that the device sends.
+    pub fn read_exact(&mut self, buf: &mut [u8], timeout: Duration) -> Result<()> {
+        let deadline = Deadline::new(timeout);
+        let mut count = 0;
+
+        while count < buf.len() {
+            let result = match deadline.remaining() {
+                Some(remaining) => self.read(&mut buf[count..], remaining),
+                None => Err(Error::Usb(rusb::Error::Timeout)),
+            };
+
+            match result {
+                Ok(read) => count += read,
+                Err(Error::Usb(rusb::Error::Timeout)) if count > 0 => {
+                    return Err(Error::ShortRead {
+                        phase: format!("the bulk IN endpoint {:#04x}", self.endpoint_in),
+                        expected: buf.len(),
+                        got: count,
+                    })
+                }
+                Err(err) => return Err(err),
+            }
+        }
 
-        Ok(transferred)
+        Ok(())
     }
+}
 
-    pub(crate) fn write_packet(
-        &self,
-        buf: &[u8],
-        size: usize,
-        timeout: Duration,
-    ) -> Result<usize, Box<dyn Error>> {
-        let mut packet = vec![0u8; size];
-        packet[0..buf.len()].clone_from_slice(buf);
+struct Deadline(Option<Instant>);
 
-        self.write(&packet, timeout)
+impl Deadline {
+    fn new(timeout: Duration) -> Self {
+        Self((!timeout.is_zero()).then(|| Instant::now() + timeout))
     }
 
-    pub(crate) fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Box<dyn Error>> {
-        let transferred = self.handle.read_bulk(self.endpoint_in, buf, timeout)?;
+    // The timeout for the next transfer, or None if the time is up.
+    fn remaining(&self) -> Option<Duration> {
+        match self.0 {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                // libusb rounds down to milliseconds, and zero means forever.
+                (remaining >= Duration::from_millis(1)).then_some(remaining)
+            }
+            None => Some(Duration::ZERO),
+        }
+    }
+}
 
-        Ok(transferred)
+impl<T: UsbContext> Drop for UsbCdcDevice<T> {
+    fn drop(&mut self) {
+        let _ = self.teardown_interface();
     }
 }
@@ -0,0 +1,447 @@
+use crate::config::{Vendor, VendorAllowList};
+use crate::json::Object;
+use crate::permissions::{current_groups, user_name};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+// Looks at the things that keep new users from reaching their devices: the
+// permissions on the device nodes, the udev rule that sets them, ModemManager
+// probing the serial ports and kernel drivers holding on to the interfaces.
+
+// The group that the rule from `sbootil udev-rule` gives access to.
+const UDEV_GROUP: &str = "plugdev";
+
+const UDEV_RULE_DIRS: [&str; 4] = [
+    "/etc/udev/rules.d",
+    "/run/udev/rules.d",
+    "/lib/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+];
+
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    // Something that may get in the way, but doesn't have to.
+    Warn,
+    // Something that keeps devices from being used.
+    Fail,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    // What to do about it, unless it passed.
+    pub remedy: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: String) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            message,
+            remedy: None,
+        }
+    }
+
+    fn problem(name: &'static str, status: Status, message: String, remedy: String) -> Self {
+        Self {
+            name,
+            status,
+            message,
+            remedy: Some(remedy),
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        Object::new()
+            .field("check", self.name)
+            .field("status", self.status.name())
+            .field("message", self.message.as_str())
+            .field("remedy", self.remedy.as_deref())
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+pub fn run(vendors: &VendorAllowList) -> Vec<Check> {
+    let rules = udev_rules(vendors);
+
+    let mut checks = vec![
+        check_groups(),
+        check_udev_rule(&rules),
+        check_modem_manager(&rules),
+    ];
+    checks.extend(check_devices(vendors));
+
+    checks
+}
+
+// The groups that the user is in according to the group database, which
+// includes the ones that only take effect after logging in again.
+fn configured_groups() -> Vec<libc::gid_t> {
+    let uid = unsafe { libc::getuid() };
+    let entry = unsafe { libc::getpwuid(uid) };
+    if entry.is_null() {
+        return Vec::new();
+    }
+    let (name, gid) = unsafe { ((*entry).pw_name, (*entry).pw_gid) };
+
+    let mut groups = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let result = unsafe { libc::getgrouplist(name, gid, groups.as_mut_ptr(), &mut count) };
+
+        if result >= 0 {
+            groups.truncate(count.max(0) as usize);
+            return groups;
+        }
+
+        // Told how many there are, or else guessing at more.
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+fn group_id(name: &str) -> Option<libc::gid_t> {
+    let name = CString::new(name).ok()?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+
+    (!entry.is_null()).then(|| unsafe { (*entry).gr_gid })
+}
+
+fn check_groups() -> Check {
+    const NAME: &str = "groups";
+
+    let user = user_name(unsafe { libc::getuid() });
+
+    if is_root() {
+        return Check::pass(
+            NAME,
+            "running as root, which may access everything".to_string(),
+        );
+    }
+
+    let Some(gid) = group_id(UDEV_GROUP) else {
+        return Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "there is no {} group, so the udev rule only gives access to whoever is at the seat",
+                UDEV_GROUP
+            ),
+            format!(
+                "Create it with `sudo groupadd {0}` and add yourself with `sudo usermod -aG {0} {1}` for access over ssh",
+                UDEV_GROUP, user
+            ),
+        );
+    };
+
+    if current_groups().contains(&gid) {
+        Check::pass(NAME, format!("{} is in the {} group", user, UDEV_GROUP))
+    } else if configured_groups().contains(&gid) {
+        Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} was added to the {} group, but this session doesn't have it yet",
+                user, UDEV_GROUP
+            ),
+            "Log out and in again, or run `newgrp plugdev` for this shell".to_string(),
+        )
+    } else {
+        Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} isn't in the {} group, so only a session at the seat gets access",
+                user, UDEV_GROUP
+            ),
+            format!(
+                "Add yourself with `sudo usermod -aG {} {}` and log in again",
+                UDEV_GROUP, user
+            ),
+        )
+    }
+}
+
+// A rule for one of the vendors, and the file it's in.
+struct UdevRule {
+    path: PathBuf,
+    // Whether it tells ModemManager to leave the device alone.
+    ignored_by_modem_manager: bool,
+}
+
+// The rules that match devices of one of the vendors by their vendor ID and
+// do something about the permissions. Rules in /etc replace those of the same
+// name in the other directories, like udev does it.
+fn udev_rules(vendors: &VendorAllowList) -> Vec<UdevRule> {
+    // Rules can only match vendors by their ID.
+    let ids = vendors
+        .vendors()
+        .iter()
+        .filter_map(|vendor| match vendor {
+            Vendor::Id(id) => Some(format!("{:04x}", id)),
+            Vendor::Name(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let mut seen = Vec::new();
+    let mut rules = Vec::new();
+
+    for dir in UDEV_RULE_DIRS {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "rules")
+                || seen.contains(&name)
+            {
+                continue;
+            }
+            seen.push(name);
+
+            let Ok(text) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+
+            let matching = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.starts_with('#'))
+                .filter(|line| {
+                    let line = line.to_ascii_lowercase();
+                    line.contains("idvendor")
+                        && ids.iter().any(|id| line.contains(&format!("\"{}\"", id)))
+                })
+                .collect::<Vec<_>>();
+
+            let grants_access = matching.iter().any(|line| {
+                line.contains("MODE") || line.contains("GROUP") || line.contains("uaccess")
+            });
+            if grants_access {
+                rules.push(UdevRule {
+                    path,
+                    ignored_by_modem_manager: matching
+                        .iter()
+                        .any(|line| line.contains("ID_MM_DEVICE_IGNORE")),
+                });
+            }
+        }
+    }
+
+    rules
+}
+
+fn check_udev_rule(rules: &[UdevRule]) -> Check {
+    const NAME: &str = "udev rule";
+
+    match rules.first() {
+        Some(rule) => Check::pass(
+            NAME,
+            format!("{} gives access to the devices", rule.path.display()),
+        ),
+        None if is_root() => Check::pass(
+            NAME,
+            "there is no udev rule for the devices, which root doesn't need".to_string(),
+        ),
+        None => Check::problem(
+            NAME,
+            Status::Fail,
+            "there is no udev rule for the devices, so only root may access them".to_string(),
+            "Install one with `sbootil udev-rule | sudo tee /etc/udev/rules.d/51-sbootil.rules` and `sudo udevadm control --reload`, then plug the device in again".to_string(),
+        ),
+    }
+}
+
+// Whether a process of this name is running, from the names in /proc.
+fn is_running(name: &str) -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+
+    entries.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path().join("comm")).is_ok_and(|comm| comm.trim_end() == name)
+    })
+}
+
+fn check_modem_manager(rules: &[UdevRule]) -> Check {
+    const NAME: &str = "ModemManager";
+
+    if !is_running("ModemManager") {
+        return Check::pass(NAME, "ModemManager isn't running".to_string());
+    }
+
+    if let Some(rule) = rules.iter().find(|rule| rule.ignored_by_modem_manager) {
+        return Check::pass(
+            NAME,
+            format!(
+                "ModemManager is running, but {} tells it to leave the devices alone",
+                rule.path.display()
+            ),
+        );
+    }
+
+    Check::problem(
+        NAME,
+        Status::Warn,
+        "ModemManager is running and may send AT commands to the devices when they show up".to_string(),
+        "Add ENV{ID_MM_DEVICE_IGNORE}=\"1\" to the udev rule, or stop it with `sudo systemctl stop ModemManager`".to_string(),
+    )
+}
+
+fn read_attribute(device: &Path, name: &str) -> Option<String> {
+    std::fs::read_to_string(device.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+// The devices of the vendors that are plugged in: whether their device node
+// may be opened, and which kernel drivers have their interfaces.
+fn check_devices(vendors: &VendorAllowList) -> Vec<Check> {
+    const NAME: &str = "device";
+
+    let Ok(entries) = std::fs::read_dir(USB_DEVICES) else {
+        return vec![Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} can't be read, so plugged in devices can't be checked",
+                USB_DEVICES
+            ),
+            "Check that sysfs is mounted".to_string(),
+        )];
+    };
+
+    let mut devices = entries
+        .flatten()
+        .map(|entry| entry.path())
+        // Interfaces have a colon in their names.
+        .filter(|path| !path.file_name().unwrap().to_string_lossy().contains(':'))
+        .filter_map(|path| {
+            let id = u16::from_str_radix(&read_attribute(&path, "idVendor")?, 16).ok()?;
+            let manufacturer = read_attribute(&path, "manufacturer");
+            vendors.matches(id, manufacturer.as_deref()).then_some(path)
+        })
+        .collect::<Vec<_>>();
+    devices.sort();
+
+    if devices.is_empty() {
+        return vec![Check::pass(
+            NAME,
+            "no device is plugged in, so there is nothing more to check".to_string(),
+        )];
+    }
+
+    let mut checks = Vec::new();
+
+    for device in devices {
+        let port = device.file_name().unwrap().to_string_lossy().into_owned();
+        let description = format!(
+            "{}:{} at {}",
+            read_attribute(&device, "idVendor").unwrap_or_default(),
+            read_attribute(&device, "idProduct").unwrap_or_default(),
+            port
+        );
+
+        // The node that libusb opens, e.g. /dev/bus/usb/001/004.
+        let node = read_attribute(&device, "busnum")
+            .zip(read_attribute(&device, "devnum"))
+            .and_then(|(bus, address)| {
+                Some(format!(
+                    "/dev/bus/usb/{:03}/{:03}",
+                    bus.parse::<u32>().ok()?,
+                    address.parse::<u32>().ok()?
+                ))
+            });
+
+        checks.push(match node {
+            Some(node) => {
+                let path = CString::new(node.as_str()).unwrap();
+                if unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) } == 0 {
+                    Check::pass(NAME, format!("{} may be opened ({})", description, node))
+                } else {
+                    Check::problem(
+                        NAME,
+                        Status::Fail,
+                        format!(
+                            "{} can't be opened, {}: {}",
+                            description,
+                            node,
+                            std::io::Error::last_os_error()
+                        ),
+                        "Install the udev rule and plug the device in again, the rule only applies to devices that show up afterwards".to_string(),
+                    )
+                }
+            }
+            None => Check::problem(
+                NAME,
+                Status::Warn,
+                format!("{} has no bus number or address in sysfs", description),
+                "Plug the device in again".to_string(),
+            ),
+        });
+
+        for (interface, driver) in bound_drivers(&device, &port) {
+            checks.push(Check::problem(
+                "kernel driver",
+                Status::Warn,
+                format!(
+                    "interface {} of {} is held by the {} driver",
+                    interface, description, driver
+                ),
+                format!(
+                    "sbootil detaches it when opening the device, if that fails, run `echo {} | sudo tee /sys/bus/usb/drivers/{}/unbind`",
+                    interface, driver
+                ),
+            ));
+        }
+    }
+
+    checks
+}
+
+// The interfaces of a device that a kernel driver is bound to, by their name
+// in sysfs (e.g. 1-2:1.0), along with the name of the driver.
+fn bound_drivers(device: &Path, port: &str) -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir(device) else {
+        return Vec::new();
+    };
+
+    let prefix = format!("{}:", port);
+    let mut drivers = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .filter_map(|entry| {
+            let driver = std::fs::read_link(entry.path().join("driver")).ok()?;
+
+            Some((
+                entry.file_name().to_string_lossy().into_owned(),
+                driver.file_name()?.to_string_lossy().into_owned(),
+            ))
+        })
+        // usbfs is what libusb shows up as once it has claimed an interface.
+        .filter(|(_, driver)| driver != "usbfs")
+        .collect::<Vec<_>>();
+    drivers.sort();
+
+    drivers
+}
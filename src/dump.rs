@@ -0,0 +1,287 @@
+use crate::bootstub::{self, DumpDigest, DumpOptions, Feature};
+use crate::cancel::CancelToken;
+use crate::error::{Error, Result};
+use crate::events;
+use crate::format::human_bytes;
+use crate::json::Object;
+use crate::output::{self, Part};
+use crate::resume::{self, ResumeWriter};
+use crate::sha256;
+use crate::sink::{Destination, Pipeline, Plan};
+use crate::{status, step, warning};
+use std::fs::File;
+use std::path::PathBuf;
+
+// Dumping a range of memory into files the way the dump command does it:
+// through the sinks of a plan, or chunk by chunk into a file that a later run
+// can carry on with, cleaning up after a failed dump and checking the result
+// of one that went through.
+
+pub struct Dump {
+    pub start: u64,
+    pub end: u64,
+    pub output: PathBuf,
+    pub force: bool,
+    pub expected_sha256: Option<[u8; 32]>,
+    pub verify: bool,
+    pub options: DumpOptions,
+    pub keep_partial: bool,
+    // Where the data goes, unless resuming.
+    pub plan: Plan,
+    pub metadata: bool,
+    pub resume: bool,
+    // What an earlier run with --resume got done, if there was one.
+    pub resume_from: Option<resume::State>,
+}
+
+// Fails right away if the sinks can't be set up, otherwise returns how the
+// dump went along with the files it wrote.
+pub fn run(
+    session: &mut bootstub::Session,
+    dump: &Dump,
+    cancel: &CancelToken,
+) -> Result<(Result<DumpDigest>, Vec<Part>)> {
+    if dump.resume {
+        return Ok((resumable(session, dump, cancel), Vec::new()));
+    }
+
+    let pipeline = dump.plan.build()?;
+
+    Ok(through(session, dump, pipeline, cancel))
+}
+
+// Sends the dump through the sinks of its plan, which are finished either
+// way, so that the files of a failed dump can be kept or deleted.
+pub fn through(
+    session: &mut bootstub::Session,
+    dump: &Dump,
+    mut pipeline: Pipeline,
+    cancel: &CancelToken,
+) -> (Result<DumpDigest>, Vec<Part>) {
+    step!("the dump goes through {}", dump.plan.stages().join(", "));
+
+    let result = session.dump(dump.start, dump.end, &dump.options, &mut pipeline, cancel);
+    let finished = pipeline.finish();
+    let files = pipeline.files();
+
+    let result = result.and_then(|digest| {
+        if finished?.sha256 != Some(digest.sha256) {
+            return Err(Error::Verification(
+                "What went into the output isn't what was received".to_string(),
+            ));
+        }
+
+        Ok(digest)
+    });
+
+    (result, files)
+}
+
+// Dumps the chunks that the state doesn't have yet, each where it belongs in
+// the output, and gets rid of the state once all of them are there.
+pub fn resumable(
+    session: &mut bootstub::Session,
+    dump: &Dump,
+    cancel: &CancelToken,
+) -> Result<DumpDigest> {
+    let path = resume::State::path(&dump.output);
+    let checksum = session.checksum().name();
+    let size = dump.end - dump.start;
+
+    let (file, state) = match &dump.resume_from {
+        Some(state) => {
+            if state.checksum != checksum {
+                warning!(
+                    "The chunks so far were checked with {}, the stub now checks them with {}",
+                    state.checksum,
+                    checksum
+                );
+            }
+
+            let file = File::options()
+                .write(true)
+                .open(&dump.output)
+                .map_err(|source| Error::File {
+                    path: dump.output.display().to_string(),
+                    source,
+                })?;
+            status!(
+                "Resuming the dump, {} of {} chunks are there already",
+                state.verified_count(),
+                state.chunks()
+            );
+
+            (file, state.clone())
+        }
+        None => (
+            output::create(&dump.output, dump.force)?,
+            resume::State::new(dump.start, dump.end, dump.options.chunk_size, checksum),
+        ),
+    };
+
+    // The chunks may come in any order, so the output has its full size from
+    // the start.
+    file.set_len(size)?;
+    state.save(&path)?;
+
+    let mut writer = ResumeWriter::new(file, state, Some(path.clone()));
+
+    for run in writer.state().missing() {
+        let state = writer.state();
+        let (start, end) = (state.chunk(run.start).start, state.chunk(run.end - 1).end);
+
+        step!("requesting chunks {} to {}", run.start, run.end - 1);
+        writer.start_run(run.start)?;
+        session.dump(start, end, &dump.options, &mut writer, cancel)?;
+        writer.commit()?;
+    }
+
+    if let Err(err) = std::fs::remove_file(&path) {
+        warning!("Failed to delete {}: {}", path.display(), err);
+    }
+
+    // Only this run's part of the data went through the session.
+    resume::digest(&dump.output)
+}
+
+// Deals with what a failed dump left behind: the state of a resumable one
+// stays for the next run, the files of any other are deleted unless they're
+// to be kept.
+pub fn clean_up(dump: &Dump, result: &Result<DumpDigest>, files: &[Part]) {
+    if result.is_err() && dump.resume {
+        if let Ok(Some(state)) = resume::State::load(&resume::State::path(&dump.output)) {
+            status!(
+                "{} of {} chunks are there, run the same command again to carry on",
+                state.verified_count(),
+                state.chunks()
+            );
+        }
+    } else if result.is_err() {
+        // What arrived before a disconnect is worth keeping, rather than
+        // dumping all of it again.
+        let keep = dump.keep_partial || matches!(result, Err(Error::Disconnected { .. }));
+
+        for file in files {
+            output::discard(&file.path, keep);
+        }
+    }
+
+    if let Err(Error::Disconnected { done, .. }) = result {
+        status!(
+            "The rest of the dump is {:#x} to {:#x}",
+            dump.start + done,
+            dump.end
+        );
+    }
+}
+
+// Shows the digests of the dump, and of the files if they differ from it.
+pub fn report(dump: &Dump, digest: &DumpDigest, files: &[Part]) {
+    let split = matches!(dump.plan.destination, Destination::Split { .. });
+
+    match &dump.plan.destination {
+        Destination::File(path) if dump.plan.compression.is_none() => status!(
+            "SHA-256 of {}: {}",
+            path.display(),
+            sha256::to_hex(&digest.sha256)
+        ),
+        _ => status!("SHA-256 of the dump: {}", sha256::to_hex(&digest.sha256)),
+    }
+
+    // Compressed files don't have the digest of the dump.
+    if split || dump.plan.compression.is_some() {
+        for file in files {
+            if split {
+                events::emit(
+                    "dump_part",
+                    Object::new()
+                        .field("file", file.path.display().to_string())
+                        .field("bytes", file.size)
+                        .field("sha256", sha256::to_hex(&file.sha256)),
+                );
+            }
+            status!(
+                "{}: {}, SHA-256 {}",
+                file.path.display(),
+                human_bytes(file.size),
+                sha256::to_hex(&file.sha256)
+            );
+        }
+    }
+}
+
+// Checks the dump against the device if the stub can compute checksums of
+// memory, the files against what was received, and the data against the
+// SHA-256 it was expected to have.
+pub fn verify(
+    session: &mut bootstub::Session,
+    dump: &Dump,
+    digest: &DumpDigest,
+    files: &[Part],
+) -> Result<()> {
+    if dump.verify && session.capabilities().has(Feature::Crc) {
+        // The digest of a resumed dump comes from the file, so this checks
+        // the file against the device as well.
+        let remote = session.memory_crc(dump.start, dump.end - dump.start)?;
+        if remote != digest.crc32 {
+            return Err(Error::Verification(format!(
+                "The memory doesn't match the dump: crc32 {:#010x} instead of {:#010x}",
+                remote, digest.crc32
+            )));
+        }
+        status!(
+            "Verified the dump against the device (crc32 {:#010x})",
+            digest.crc32
+        );
+    } else if dump.verify {
+        step!(
+            "the stub can't compute checksums of memory, not checking the dump against the device"
+        );
+    }
+
+    if dump.verify && !dump.resume {
+        for file in files {
+            output::verify(&file.path, &file.sha256)?;
+            status!("Verified {}", file.path.display());
+        }
+    }
+
+    if let Some(expected) = dump.expected_sha256 {
+        if digest.sha256 != expected {
+            return Err(Error::Verification(format!(
+                "The SHA-256 of the dump doesn't match, expected {}",
+                sha256::to_hex(&expected)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Where the data of a dump to `output` goes: into parts of `split_size`, to
+// stdout for -, or into the file.
+pub fn destination(
+    output: &std::path::Path,
+    split_size: Option<u64>,
+    replaying: bool,
+    resume: bool,
+) -> Result<Destination> {
+    let stdout = output == std::path::Path::new("-");
+
+    match split_size {
+        // Don't overwrite the dump from the recorded session.
+        _ if replaying => Ok(Destination::Discard),
+        Some(_) if stdout => Err(Error::InvalidArgument(
+            "A dump to stdout can't be split into parts".to_string(),
+        )),
+        Some(part_size) => Ok(Destination::Split {
+            path: output.to_path_buf(),
+            part_size,
+        }),
+        None if stdout && resume => Err(Error::InvalidArgument(
+            "--resume needs an output file to keep the chunks in".to_string(),
+        )),
+        None if stdout => Ok(Destination::Stdout),
+        None => Ok(Destination::File(output.to_path_buf())),
+    }
+}
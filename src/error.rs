@@ -0,0 +1,354 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "usb")]
+    Usb(rusb::Error),
+    Serial(String),
+    Io(std::io::Error),
+    File {
+        path: String,
+        source: std::io::Error,
+    },
+    Protocol {
+        phase: String,
+        expected: Vec<u8>,
+        got: Vec<u8>,
+    },
+    Timeout {
+        phase: String,
+    },
+    ShortRead {
+        phase: String,
+        expected: usize,
+        got: usize,
+    },
+    ShortWrite {
+        phase: String,
+        expected: usize,
+        got: usize,
+    },
+    // The device kept sending past the end of what was requested.
+    Overrun {
+        phase: String,
+        requested: u64,
+    },
+    InvalidPit(String),
+    // A zip archive that can't be read.
+    InvalidArchive(String),
+    InvalidArgument(String),
+    DeviceNotFound(String),
+    // Another invocation holds the lock for the device.
+    DeviceInUse(String),
+    PermissionDenied(String),
+    Unsupported(String),
+    ReplayMismatch(String),
+    InvalidConfig(String),
+    Stall {
+        endpoint: u8,
+        direction: &'static str,
+    },
+    // The data arrived, but doesn't check out.
+    Verification(String),
+    // The operation was stopped with a cancel token, e.g. by Ctrl-C.
+    Cancelled,
+    // Asking the stub what it supports went wrong, which very old stubs can
+    // make happen.
+    Negotiation(Box<Error>),
+    // The device went away in the middle of a transfer.
+    Disconnected {
+        phase: String,
+        done: u64,
+        total: u64,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // For features that were left out at compile time.
+    pub fn not_in_build(what: &str) -> Self {
+        Error::Unsupported(format!("{} support is not included in this build", what))
+    }
+
+    // Whether the error means that the device is gone, as opposed to just
+    // not answering (anymore).
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            #[cfg(feature = "usb")]
+            Error::Usb(rusb::Error::NoDevice) => true,
+            Error::Io(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionReset
+            ),
+            Error::Disconnected { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Turns a disconnect during a transfer into an error that tells how far
+    // it got, leaving other errors alone.
+    pub fn at_byte(self, phase: &str, done: u64, total: u64) -> Self {
+        match self {
+            Error::Disconnected { .. } => self,
+            err if err.is_disconnect() => Error::Disconnected {
+                phase: phase.to_string(),
+                done,
+                total,
+            },
+            err => err,
+        }
+    }
+
+    // A stable name for the kind of error, for machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "usb")]
+            Error::Usb(_) => "usb",
+            Error::Serial(_) => "serial",
+            Error::Io(_) => "io",
+            Error::File { .. } => "file",
+            Error::Protocol { .. } => "protocol",
+            Error::Timeout { .. } => "timeout",
+            Error::ShortRead { .. } => "short_read",
+            Error::ShortWrite { .. } => "short_write",
+            Error::Overrun { .. } => "overrun",
+            Error::InvalidPit(_) => "invalid_pit",
+            Error::InvalidArchive(_) => "invalid_archive",
+            Error::InvalidArgument(_) => "invalid_argument",
+            Error::DeviceNotFound(_) => "device_not_found",
+            Error::DeviceInUse(_) => "device_in_use",
+            Error::PermissionDenied(_) => "permission_denied",
+            Error::Unsupported(_) => "unsupported",
+            Error::ReplayMismatch(_) => "replay_mismatch",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::Stall { .. } => "stall",
+            Error::Verification(_) => "verification",
+            Error::Disconnected { .. } => "disconnected",
+            Error::Negotiation(_) => "negotiation",
+            Error::Cancelled => "cancelled",
+        }
+    }
+
+    // The exit status for the error, so that scripts can tell the classes of
+    // failures apart.
+    pub fn exit_code(&self) -> i32 {
+        if let Error::Negotiation(err) = self {
+            return err.exit_code();
+        }
+
+        let io_kind = match self {
+            Error::Io(err) | Error::File { source: err, .. } => Some(err.kind()),
+            _ => None,
+        };
+
+        match (self, io_kind) {
+            (Error::InvalidArgument(_) | Error::InvalidConfig(_), _) => EXIT_USAGE,
+            (Error::DeviceNotFound(_) | Error::Disconnected { .. }, _) => EXIT_DEVICE_NOT_FOUND,
+            (_, Some(std::io::ErrorKind::NotConnected)) => EXIT_DEVICE_NOT_FOUND,
+            #[cfg(feature = "usb")]
+            (Error::Usb(rusb::Error::NoDevice | rusb::Error::NotFound), _) => EXIT_DEVICE_NOT_FOUND,
+            (Error::PermissionDenied(_), _) | (_, Some(std::io::ErrorKind::PermissionDenied)) => {
+                EXIT_PERMISSION_DENIED
+            }
+            #[cfg(feature = "usb")]
+            (Error::Usb(rusb::Error::Access), _) => EXIT_PERMISSION_DENIED,
+            (Error::Cancelled, _) | (_, Some(std::io::ErrorKind::Interrupted)) => EXIT_INTERRUPTED,
+            #[cfg(feature = "usb")]
+            (Error::Usb(rusb::Error::Interrupted), _) => EXIT_INTERRUPTED,
+            (
+                Error::Protocol { .. }
+                | Error::Timeout { .. }
+                | Error::ShortRead { .. }
+                | Error::ShortWrite { .. }
+                | Error::Overrun { .. }
+                | Error::Stall { .. }
+                | Error::InvalidPit(_),
+                _,
+            ) => EXIT_PROTOCOL,
+            (Error::Verification(_) | Error::ReplayMismatch(_), _) => EXIT_VERIFICATION,
+            (Error::DeviceInUse(_), _) => EXIT_DEVICE_IN_USE,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+pub const EXIT_FAILURE: i32 = 1;
+// Also what clap exits with for invalid command lines.
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_DEVICE_NOT_FOUND: i32 = 3;
+pub const EXIT_PERMISSION_DENIED: i32 = 4;
+pub const EXIT_PROTOCOL: i32 = 5;
+pub const EXIT_VERIFICATION: i32 = 6;
+pub const EXIT_INTERRUPTED: i32 = 7;
+pub const EXIT_DEVICE_IN_USE: i32 = 8;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "usb")]
+            Error::Usb(err) => write!(f, "USB error: {}", err),
+            Error::Serial(message) => write!(f, "{}", message),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::File { path, source } => write!(f, "Failed to access {}: {}", path, source),
+            Error::Protocol {
+                phase,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Unexpected response {}: expected [{}] ({:?}), got [{}] ({:?})",
+                phase,
+                hex(expected),
+                String::from_utf8_lossy(expected),
+                hex(got),
+                String::from_utf8_lossy(got),
+            ),
+            Error::Timeout { phase } => write!(f, "Timed out waiting for {}", phase),
+            Error::ShortRead {
+                phase,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Short read for {}: expected {} bytes, got {}",
+                phase, expected, got
+            ),
+            Error::ShortWrite {
+                phase,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Short write for {}: expected to send {} bytes, sent {}",
+                phase, expected, got
+            ),
+            Error::Overrun { phase, requested } => write!(
+                f,
+                "The device sent more data than requested for {} ({} bytes)",
+                phase, requested
+            ),
+            Error::InvalidPit(message) => write!(f, "Invalid PIT: {}", message),
+            Error::InvalidArchive(message) => write!(f, "Invalid archive: {}", message),
+            Error::InvalidArgument(message) => write!(f, "{}", message),
+            Error::DeviceNotFound(message) => write!(f, "{}", message),
+            Error::DeviceInUse(message) => write!(f, "{}", message),
+            Error::PermissionDenied(message) => write!(f, "{}", message),
+            Error::Unsupported(message) => write!(f, "{}", message),
+            Error::ReplayMismatch(message) => {
+                write!(f, "Replay doesn't match the recording: {}", message)
+            }
+            Error::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            Error::Stall {
+                endpoint,
+                direction,
+            } => write!(
+                f,
+                "The bulk {} endpoint {:#04x} stalled again after clearing the halt",
+                direction, endpoint
+            ),
+            Error::Verification(message) => write!(f, "{}", message),
+            Error::Disconnected { phase, done, total } => write!(
+                f,
+                "The device disconnected during the {}, at byte {} of {}",
+                phase, done, total
+            ),
+            Error::Cancelled => write!(f, "Cancelled before it was done"),
+            Error::Negotiation(err) => write!(
+                f,
+                "{}, pass --legacy-protocol if the stub is too old to be asked what it supports",
+                err
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "usb")]
+            Error::Usb(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::File { source, .. } => Some(source),
+            Error::Negotiation(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "usb")]
+impl From<rusb::Error> for Error {
+    fn from(err: rusb::Error) -> Self {
+        Error::Usb(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn errors_map_to_exit_codes() {
+        let cases = [
+            (Error::InvalidArgument("bad".to_string()), EXIT_USAGE),
+            (
+                Error::DeviceNotFound("gone".to_string()),
+                EXIT_DEVICE_NOT_FOUND,
+            ),
+            (
+                Error::Io(ErrorKind::NotConnected.into()),
+                EXIT_DEVICE_NOT_FOUND,
+            ),
+            (
+                Error::Io(ErrorKind::PermissionDenied.into()),
+                EXIT_PERMISSION_DENIED,
+            ),
+            (Error::Cancelled, EXIT_INTERRUPTED),
+            (Error::Io(ErrorKind::Interrupted.into()), EXIT_INTERRUPTED),
+            (
+                Error::Timeout {
+                    phase: "BOOTSTUB".to_string(),
+                },
+                EXIT_PROTOCOL,
+            ),
+            (
+                Error::Verification("mismatch".to_string()),
+                EXIT_VERIFICATION,
+            ),
+            (Error::Io(ErrorKind::Other.into()), EXIT_FAILURE),
+        ];
+
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{}", err);
+        }
+    }
+
+    #[test]
+    fn negotiation_failures_keep_the_exit_code_of_the_cause() {
+        let err = Error::Negotiation(Box::new(Error::Protocol {
+            phase: "after sending GETCAPS".to_string(),
+            expected: b"CAPS".to_vec(),
+            got: b"GETC".to_vec(),
+        }));
+
+        assert_eq!(err.exit_code(), EXIT_PROTOCOL);
+        assert_eq!(err.kind(), "negotiation");
+    }
+}
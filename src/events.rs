@@ -0,0 +1,92 @@
+use crate::json::Object;
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Bumped whenever existing events change in an incompatible way.
+pub const VERSION: u32 = 1;
+
+// How often to report progress of long transfers.
+pub const PROGRESS_INTERVAL: u64 = 64 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+type ProgressHook = Box<dyn FnMut(&str, u64, u64)>;
+
+thread_local! {
+    // Follows the progress of transfers on this thread, for users of the
+    // library that want to show it their own way.
+    static PROGRESS_HOOK: RefCell<Option<ProgressHook>> = RefCell::new(None);
+}
+
+// Emits newline-delimited JSON events to the given writer from now on.
+pub fn enable(writer: Box<dyn Write + Send>) {
+    *SINK.lock().unwrap() = Some(writer);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: &str, fields: Object) {
+    if !enabled() {
+        return;
+    }
+
+    let line = Object::new()
+        .field("v", VERSION)
+        .field("event", event)
+        .merge(fields);
+
+    if let Some(sink) = SINK.lock().unwrap().as_mut() {
+        let _ = writeln!(sink, "{}", line);
+        let _ = sink.flush();
+    }
+}
+
+// Progress for transfers is only reported every once in a while, and always
+// at the end.
+pub fn progress(event: &str, done: u64, total: u64, fields: Object) {
+    if !done.is_multiple_of(PROGRESS_INTERVAL) && done != total {
+        return;
+    }
+
+    PROGRESS_HOOK.with(|hook| {
+        if let Some(hook) = hook.borrow_mut().as_mut() {
+            hook(event, done, total);
+        }
+    });
+
+    if enabled() {
+        emit(event, fields.field("done", done).field("total", total));
+    }
+}
+
+// Has the hook called with the event, the progress and the total of every
+// progress report on this thread while running `f`.
+pub fn with_progress_hook<T>(
+    hook: impl FnMut(&str, u64, u64) + 'static,
+    f: impl FnOnce() -> T,
+) -> T {
+    let previous = PROGRESS_HOOK.with(|slot| slot.borrow_mut().replace(Box::new(hook)));
+    let result = f();
+    PROGRESS_HOOK.with(|slot| *slot.borrow_mut() = previous);
+
+    result
+}
+
+// Output meant for humans, which has to make way for the events when those go
+// to stdout.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::events::enabled() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,184 @@
+use crate::parse::parse_u64;
+
+// Points out the offending part of the expression below it.
+fn error_at(expression: &str, start: usize, end: usize, message: String) -> String {
+    let offset = expression[..start].chars().count();
+    let length = expression[start..end].chars().count().max(1);
+
+    format!(
+        "{}\n    {}\n    {}{}",
+        message,
+        expression,
+        " ".repeat(offset),
+        "^".repeat(length)
+    )
+}
+
+// Evaluates a sum of numbers and names from left to right, e.g.
+// `0x40000000+0x200000` or `base+4K-1`. Names are resolved through `lookup`.
+pub fn evaluate(expression: &str, lookup: impl Fn(&str) -> Option<u64>) -> Result<u64, String> {
+    let is_term_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let skip_whitespace = |position: usize| {
+        expression[position..]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(expression.len(), |offset| position + offset)
+    };
+
+    let mut result: u64 = 0;
+    let mut subtract = false;
+    let mut position = skip_whitespace(0);
+
+    loop {
+        let start = position;
+        let end = expression[start..]
+            .find(|c: char| !is_term_char(c))
+            .map_or(expression.len(), |offset| start + offset);
+
+        let term = &expression[start..end];
+        let next = expression[start..]
+            .chars()
+            .next()
+            .map_or(start, |c| start + c.len_utf8());
+
+        if term.is_empty() {
+            return Err(error_at(
+                expression,
+                start,
+                next,
+                "expected a number or a name".to_string(),
+            ));
+        }
+
+        let value = if term.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_u64(term).map_err(|err| error_at(expression, start, end, err))?
+        } else {
+            lookup(term).ok_or_else(|| {
+                error_at(expression, start, end, format!("unknown name '{}'", term))
+            })?
+        };
+
+        result = if subtract {
+            result.checked_sub(value).ok_or_else(|| {
+                error_at(expression, start, end, "the result is negative".to_string())
+            })?
+        } else {
+            result.checked_add(value).ok_or_else(|| {
+                error_at(
+                    expression,
+                    start,
+                    end,
+                    "the result is too large".to_string(),
+                )
+            })?
+        };
+
+        position = skip_whitespace(end);
+
+        match expression[position..].chars().next() {
+            None => return Ok(result),
+            Some('+') => subtract = false,
+            Some('-') => subtract = true,
+            Some(c) => {
+                return Err(error_at(
+                    expression,
+                    position,
+                    position + c.len_utf8(),
+                    "expected '+' or '-'".to_string(),
+                ))
+            }
+        }
+
+        position = skip_whitespace(position + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<u64> {
+        match name {
+            "sram_base" => Some(0x4000_0000),
+            "big" => Some(u64::MAX),
+            _ => None,
+        }
+    }
+
+    fn eval(expression: &str) -> Result<u64, String> {
+        evaluate(expression, lookup)
+    }
+
+    #[test]
+    fn numbers_and_names() {
+        assert_eq!(eval("0x1000"), Ok(0x1000));
+        assert_eq!(eval("0x40000000+0x200000"), Ok(0x4020_0000));
+        assert_eq!(eval("sram_base+4K"), Ok(0x4000_1000));
+        assert_eq!(eval(" sram_base + 4K - 1 "), Ok(0x4000_0fff));
+        assert_eq!(eval("big-big+1"), Ok(1));
+    }
+
+    #[test]
+    fn left_to_right() {
+        assert_eq!(eval("10-4-3"), Ok(3));
+        assert_eq!(eval("10-4+3"), Ok(9));
+        // Going below zero on the way isn't made up for later.
+        assert!(eval("1-2+2").is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(
+            eval("big+1"),
+            Err("the result is too large\n    big+1\n        ^".to_string())
+        );
+        assert_eq!(
+            eval("sram_base-0x40000001"),
+            Err(
+                "the result is negative\n    sram_base-0x40000001\n              ^^^^^^^^^^"
+                    .to_string()
+            )
+        );
+        assert!(eval("0x1_0000_0000_0000_0000").is_err());
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert_eq!(
+            eval("sram_bas+4K"),
+            Err("unknown name 'sram_bas'\n    sram_bas+4K\n    ^^^^^^^^".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(
+            eval(""),
+            Err("expected a number or a name\n    \n    ^".to_string())
+        );
+        assert_eq!(
+            eval("0x10+"),
+            Err("expected a number or a name\n    0x10+\n         ^".to_string())
+        );
+        assert_eq!(
+            eval("0x10*2"),
+            Err("expected '+' or '-'\n    0x10*2\n        ^".to_string())
+        );
+        assert_eq!(
+            eval("1 2"),
+            Err("expected '+' or '-'\n    1 2\n      ^".to_string())
+        );
+        assert_eq!(
+            eval("0x1z+1"),
+            Err(
+                "'0x1z' contains 'z', which isn't a base 16 digit\n    0x1z+1\n    ^^^^"
+                    .to_string()
+            )
+        );
+        // The caret lines up with characters rather than bytes.
+        assert_eq!(
+            eval("ä+1"),
+            Err("expected a number or a name\n    ä+1\n    ^".to_string())
+        );
+        assert!(eval("1++1").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::api::{self, FlashOptions, Progress};
+use crate::cancel::CancelToken;
+use crate::device::Selector;
+use crate::error::{self, Error, Result};
+use crate::parse::DeviceSpec;
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, PoisonError};
+
+// The C interface of the library, include/sbootil.h is generated from this
+// file with cbindgen. The shared library is built with
+// `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//
+// Every call opens the device it's given, works on it on the calling thread
+// and closes it again before returning, so nothing is shared between calls
+// except for libusb. A device can only be used by one call at a time, which
+// the device lock takes care of, also between threads of the same process.
+// Errors are kept per thread. Calls can be cancelled from any thread.
+
+/// Success.
+pub const SBOOTIL_OK: c_int = 0;
+/// Any error that doesn't fall into one of the other classes.
+pub const SBOOTIL_ERR_FAILURE: c_int = -1;
+/// An argument is missing or doesn't make sense.
+pub const SBOOTIL_ERR_INVALID_ARGUMENT: c_int = -2;
+/// No matching device was found, or it went away.
+pub const SBOOTIL_ERR_DEVICE_NOT_FOUND: c_int = -3;
+/// The device can't be opened for lack of permissions.
+pub const SBOOTIL_ERR_PERMISSION_DENIED: c_int = -4;
+/// The device didn't answer as expected.
+pub const SBOOTIL_ERR_PROTOCOL: c_int = -5;
+/// The data was transferred, but doesn't check out.
+pub const SBOOTIL_ERR_VERIFICATION: c_int = -6;
+/// The transfer was interrupted.
+pub const SBOOTIL_ERR_INTERRUPTED: c_int = -7;
+/// The device is in use by another call, in this process or another one.
+pub const SBOOTIL_ERR_DEVICE_IN_USE: c_int = -8;
+
+/// Called with the number of bytes transferred so far and the total, on the
+/// thread that made the call. It must not call back into sbootil, other
+/// than to cancel the call.
+pub type ProgressCallback = Option<unsafe extern "C" fn(done: u64, total: u64, user: *mut c_void)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+    // Whether a call is running on this thread, calls from the progress
+    // callback are refused.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+// What the calls that are running look at, sbootil_cancel() cancels it and
+// leaves a new one for the calls that come after.
+static CANCEL: Mutex<Option<CancelToken>> = Mutex::new(None);
+
+fn cancel_token() -> CancelToken {
+    CANCEL
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(CancelToken::new)
+        .clone()
+}
+
+// Maps the exit status for an error to the return code, they are the same
+// classes with the sign flipped.
+fn return_code(err: &Error) -> c_int {
+    match err.exit_code() {
+        error::EXIT_USAGE => SBOOTIL_ERR_INVALID_ARGUMENT,
+        error::EXIT_DEVICE_NOT_FOUND => SBOOTIL_ERR_DEVICE_NOT_FOUND,
+        error::EXIT_PERMISSION_DENIED => SBOOTIL_ERR_PERMISSION_DENIED,
+        error::EXIT_PROTOCOL => SBOOTIL_ERR_PROTOCOL,
+        error::EXIT_VERIFICATION => SBOOTIL_ERR_VERIFICATION,
+        error::EXIT_INTERRUPTED => SBOOTIL_ERR_INTERRUPTED,
+        error::EXIT_DEVICE_IN_USE => SBOOTIL_ERR_DEVICE_IN_USE,
+        _ => SBOOTIL_ERR_FAILURE,
+    }
+}
+
+fn set_last_error(message: String) {
+    // Messages don't have NUL bytes in them, but that isn't worth a panic.
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs a call, turning errors and panics into return codes, neither of which
+// may cross into C.
+fn run(call: impl FnOnce() -> Result<()>) -> c_int {
+    if BUSY.with(|busy| busy.replace(true)) {
+        set_last_error("sbootil can't be called from a progress callback".to_string());
+        return SBOOTIL_ERR_INVALID_ARGUMENT;
+    }
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    let result = panic::catch_unwind(AssertUnwindSafe(call));
+    BUSY.with(|busy| busy.set(false));
+
+    match result {
+        Ok(Ok(())) => SBOOTIL_OK,
+        Ok(Err(err)) => {
+            set_last_error(err.to_string());
+            return_code(&err)
+        }
+        Err(_) => {
+            set_last_error("Internal error in sbootil".to_string());
+            SBOOTIL_ERR_FAILURE
+        }
+    }
+}
+
+// None for NULL.
+//
+// The pointer must be NULL or point to a NUL-terminated string that outlives
+// the call.
+unsafe fn string_arg<'a>(name: &str, pointer: *const c_char) -> Result<Option<&'a str>> {
+    if pointer.is_null() {
+        return Ok(None);
+    }
+
+    CStr::from_ptr(pointer)
+        .to_str()
+        .map(Some)
+        .map_err(|_| Error::InvalidArgument(format!("The {} is not valid UTF-8", name)))
+}
+
+unsafe fn required_arg<'a>(name: &str, pointer: *const c_char) -> Result<&'a str> {
+    string_arg(name, pointer)?.ok_or_else(|| Error::InvalidArgument(format!("No {} given", name)))
+}
+
+// Comma-separated options in the spelling of the command line, e.g.
+// `usb=04e8:685d,serial-number=R58M123456` or `bus-address=1:4`. Without
+// any, the only Samsung device is used.
+fn parse_selector(string: &str) -> Result<Selector> {
+    DeviceSpec::parse(string)
+        .map(Selector::from)
+        .map_err(|err| Error::InvalidArgument(format!("Invalid USB selector: {}", err)))
+}
+
+fn progress_callback(callback: ProgressCallback, user: *mut c_void) -> impl FnMut(Progress) {
+    move |progress| {
+        if let Some(callback) = callback {
+            unsafe { callback(progress.done, progress.total, user) };
+        }
+    }
+}
+
+/// Flashes an image to a partition of a device in download mode, which is
+/// looked up by its name or identifier in the PIT of the device. The device
+/// is rebooted afterwards.
+///
+/// `usb_selector` picks the device with comma-separated options, e.g.
+/// `usb=04e8:685d,serial-number=R58M123456` or `bus-address=1:4`. With NULL
+/// or an empty string, the only Samsung device is used.
+///
+/// Returns SBOOTIL_OK or one of the SBOOTIL_ERR_* codes, see
+/// sbootil_last_error_message() for the details. SBOOTIL_ERR_INTERRUPTED
+/// after sbootil_cancel(), once the current sequence of file parts is done.
+///
+/// # Safety
+///
+/// The strings must be NUL-terminated and stay valid for the duration of
+/// the call, only `usb_selector` may be NULL. `progress` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn sbootil_flash_partition(
+    usb_selector: *const c_char,
+    partition: *const c_char,
+    image_path: *const c_char,
+    progress: ProgressCallback,
+    user: *mut c_void,
+) -> c_int {
+    run(|| {
+        let selector = parse_selector(string_arg("USB selector", usb_selector)?.unwrap_or(""))?;
+        let partition = required_arg("partition", partition)?;
+        let image_path = required_arg("image path", image_path)?;
+
+        api::flash_partition(
+            &selector,
+            partition,
+            image_path.as_ref(),
+            &FlashOptions::default(),
+            &cancel_token(),
+            progress_callback(progress, user),
+        )?;
+
+        Ok(())
+    })
+}
+
+/// Dumps the memory from `start` up to `end` from bootstub on a serial port
+/// into a file.
+///
+/// Returns SBOOTIL_OK or one of the SBOOTIL_ERR_* codes, see
+/// sbootil_last_error_message() for the details. SBOOTIL_ERR_INTERRUPTED
+/// after sbootil_cancel(), once the current chunk is done. The file is left
+/// with what was dumped until then.
+///
+/// # Safety
+///
+/// The strings must be NUL-terminated and stay valid for the duration of
+/// the call. `progress` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn sbootil_dump(
+    serial_path: *const c_char,
+    start: u64,
+    end: u64,
+    output_path: *const c_char,
+    progress: ProgressCallback,
+    user: *mut c_void,
+) -> c_int {
+    run(|| {
+        let serial_path = required_arg("serial port", serial_path)?;
+        let output_path = required_arg("output path", output_path)?;
+
+        if end <= start {
+            return Err(Error::InvalidArgument(format!(
+                "The end {:#x} is not after the start {:#x}",
+                end, start
+            )));
+        }
+
+        let file_error = |source| Error::File {
+            path: output_path.to_string(),
+            source,
+        };
+        let mut output = BufWriter::new(std::fs::File::create(output_path).map_err(file_error)?);
+
+        api::dump_memory(
+            serial_path,
+            start..end,
+            &mut output,
+            &cancel_token(),
+            progress_callback(progress, user),
+        )?;
+
+        output.flush().map_err(file_error)
+    })
+}
+
+/// Cancels the calls that are running on any thread, which return
+/// SBOOTIL_ERR_INTERRUPTED once they got to a point where they can stop.
+/// Calls that start afterwards aren't affected. Unlike the other calls, this
+/// one may be made from a progress callback.
+#[no_mangle]
+pub extern "C" fn sbootil_cancel() {
+    let token = CANCEL.lock().unwrap_or_else(PoisonError::into_inner).take();
+
+    if let Some(token) = token {
+        token.cancel();
+    }
+}
+
+/// The message for the last error on the calling thread, or NULL if the last
+/// call succeeded. It stays valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn sbootil_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,486 @@
+use crate::cancel::CancelToken;
+use crate::error::{Error, Result};
+use crate::events;
+use crate::format::human_bytes;
+use crate::json::Object;
+use crate::odin::{self, Identity};
+use crate::pit::Pit;
+use crate::sha256;
+use crate::zip::{self, Archive};
+use crate::{say, status, step, warning};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+// Flashing several images in one session: matching them to the partitions of
+// the PIT, putting them in the order to flash them in, and checking what
+// download mode lets be checked afterwards.
+
+pub struct FlashFile {
+    pub partition: String,
+    pub path: String,
+    pub file: Box<dyn Read>,
+    pub size: u64,
+}
+
+// The files in a directory, to be matched to partitions by the file names
+// in the PIT.
+pub struct FlashDirectory {
+    pub path: PathBuf,
+    // Set if the images are in a zip archive rather than a directory.
+    pub archive: Option<Archive>,
+    pub file_names: Vec<String>,
+    // Partition names, an empty list for --only means all of them.
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+    // Partition names to flash first, in this order.
+    pub order: Vec<String>,
+}
+
+impl FlashDirectory {
+    // Only lists the directory, the files are opened once it's known which
+    // of them are going to be flashed.
+    pub fn list(path: PathBuf) -> Result<Self> {
+        let directory_error = |source| Error::File {
+            path: path.display().to_string(),
+            source,
+        };
+
+        let mut archive = None;
+        let mut file_names = Vec::new();
+
+        if path.is_file() {
+            let opened = Archive::open(&path)?;
+            file_names.extend(
+                opened
+                    .members()
+                    .iter()
+                    .map(|member| member.file_name().to_string()),
+            );
+            archive = Some(opened);
+        } else {
+            for entry in std::fs::read_dir(&path).map_err(directory_error)? {
+                let entry = entry.map_err(directory_error)?;
+
+                if entry.path().is_file() {
+                    file_names.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        file_names.sort();
+
+        Ok(Self {
+            path,
+            archive,
+            file_names,
+            only: Vec::new(),
+            skip: Vec::new(),
+            order: Vec::new(),
+        })
+    }
+}
+
+pub enum FlashSource {
+    Files(Vec<FlashFile>),
+    Directory(FlashDirectory),
+}
+
+// Opens an image to flash, which may also be a member of a zip archive, like
+// firmware.zip:boot.img.
+pub fn open_image(path: &str) -> Result<(Box<dyn Read>, u64)> {
+    if let Some((archive, member)) = zip::split_path(path) {
+        let archive = Archive::open(archive.as_ref())?;
+        let member = archive.find(member)?;
+
+        return Ok((archive.open_member(member)?, member.size));
+    }
+
+    let file = File::open(path).map_err(|source| Error::File {
+        path: path.to_string(),
+        source,
+    })?;
+    let size = file.metadata()?.len();
+
+    Ok((Box::new(file), size))
+}
+
+// What was left over after matching a directory to the PIT.
+pub struct Unmatched {
+    pub files: Vec<String>,
+    pub partitions: Vec<String>,
+}
+
+impl Unmatched {
+    pub fn report(&self) {
+        events::emit(
+            "flash_unmatched",
+            Object::new()
+                .field("files", &self.files)
+                .field("partitions", &self.partitions),
+        );
+
+        if !self.files.is_empty() {
+            say!("Files without a partition: {}", self.files.join(", "));
+        }
+        if !self.partitions.is_empty() {
+            say!("Partitions without a file: {}", self.partitions.join(", "));
+        }
+    }
+}
+
+// Checks the files given for partitions against the PIT and puts them in the
+// order to flash them in.
+pub fn plan_files(pit: &Pit, files: Vec<FlashFile>) -> Result<Vec<(usize, FlashFile)>> {
+    let mut planned: Vec<(usize, FlashFile)> = Vec::with_capacity(files.len());
+    for file in files {
+        let index = pit.find_index(&file.partition).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "There is no partition {} in the PIT",
+                file.partition
+            ))
+        })?;
+
+        // The same partition may have been given by name and by identifier.
+        if let Some((_, other)) = planned.iter().find(|(other, _)| *other == index) {
+            return Err(Error::InvalidArgument(format!(
+                "Partition {} was given twice, with {} and {}",
+                pit.entries[index].partition_name, other.path, file.path
+            )));
+        }
+
+        planned.push((index, file));
+    }
+
+    // Like Heimdall in the order of the PIT, but with the bootloader last.
+    let order = pit.flash_order(
+        &planned.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+        &[],
+    )?;
+    planned.sort_by_key(|(index, _)| order.iter().position(|other| other == index));
+
+    Ok(planned)
+}
+
+// Matches the files in a directory to partitions by their file names in the
+// PIT and shows what is going to be flashed.
+pub fn plan_directory(
+    pit: &Pit,
+    directory: FlashDirectory,
+) -> Result<(Vec<(usize, FlashFile)>, Unmatched)> {
+    // A misspelled --skip must not let anything through.
+    for name in directory
+        .only
+        .iter()
+        .chain(&directory.skip)
+        .chain(&directory.order)
+    {
+        if pit.find(name).is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "There is no partition {} in the PIT",
+                name
+            )));
+        }
+    }
+
+    let listed = |names: &[String], partition: &str| {
+        names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(partition))
+    };
+    let wanted = |partition: &str| {
+        (directory.only.is_empty() || listed(&directory.only, partition))
+            && !listed(&directory.skip, partition)
+    };
+
+    let mut matched: Vec<(usize, String)> = Vec::new();
+    let mut skipped = Vec::new();
+    let mut unmatched_files = Vec::new();
+
+    for file_name in directory.file_names {
+        let Some(index) = pit.find_by_file_name(&file_name) else {
+            unmatched_files.push(file_name);
+            continue;
+        };
+        let partition = &pit.entries[index].partition_name;
+
+        if !wanted(partition) {
+            skipped.push(format!("{} ({})", partition, file_name));
+            continue;
+        }
+
+        if let Some((_, other)) = matched.iter().find(|(other, _)| *other == index) {
+            return Err(Error::InvalidArgument(format!(
+                "Both {} and {} are for partition {}",
+                other, file_name, partition
+            )));
+        }
+
+        matched.push((index, file_name));
+    }
+
+    if let Some((_, file_name)) = matched
+        .iter()
+        .find(|(_, file_name)| file_name.to_ascii_lowercase().ends_with(".lz4"))
+    {
+        return Err(Error::InvalidArgument(format!(
+            "{} is compressed with LZ4, which can't be flashed yet, decompress it with `lz4 -d` first",
+            file_name
+        )));
+    }
+
+    if matched.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "None of the files in {} are to be flashed",
+            directory.path.display()
+        )));
+    }
+
+    let unmatched_partitions = pit
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(index, entry)| {
+            !entry.flash_filename.is_empty()
+                && wanted(&entry.partition_name)
+                && !matched.iter().any(|(other, _)| other == index)
+        })
+        .map(|(_, entry)| entry.partition_name.clone())
+        .collect();
+
+    let order = pit.flash_order(
+        &matched.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+        &directory.order,
+    )?;
+    matched.sort_by_key(|(index, _)| order.iter().position(|other| other == index));
+
+    let mut planned = Vec::with_capacity(matched.len());
+    for (index, file_name) in matched {
+        let (path, file, size) = match &directory.archive {
+            Some(archive) => {
+                let member = archive.find(&file_name)?;
+                let path = format!("{}:{}", archive.path().display(), member.name);
+
+                (path, archive.open_member(member)?, member.size)
+            }
+            None => {
+                let path = directory.path.join(&file_name).display().to_string();
+                let (file, size) = open_image(&path)?;
+
+                (path, file, size)
+            }
+        };
+
+        planned.push((
+            index,
+            FlashFile {
+                partition: pit.entries[index].partition_name.clone(),
+                path,
+                file,
+                size,
+            },
+        ));
+    }
+
+    say!(
+        "Going to flash from {}, in this order:",
+        directory.path.display()
+    );
+    for (_, file) in &planned {
+        say!(
+            "  {:<16} {} ({})",
+            file.partition,
+            file.path,
+            human_bytes(file.size)
+        );
+    }
+    if !skipped.is_empty() {
+        say!("Leaving out: {}", skipped.join(", "));
+    }
+
+    Ok((
+        planned,
+        Unmatched {
+            files: unmatched_files,
+            partitions: unmatched_partitions,
+        },
+    ))
+}
+
+// What was flashed, for --verify-after.
+pub struct Flashed {
+    pub pit: Pit,
+    // Set if the PIT came from the device rather than from --pit.
+    pub device_pit: Option<Vec<u8>>,
+    // Indices into the PIT.
+    pub partitions: Vec<usize>,
+}
+
+// Flashes everything from `source` in one session, with the PIT from the
+// device unless `pit` is given.
+pub fn flash_files(
+    session: &mut odin::Session,
+    pit: Option<Vec<u8>>,
+    source: FlashSource,
+    cancel: &CancelToken,
+) -> Result<Flashed> {
+    let device_pit = match pit {
+        Some(_) => None,
+        None => Some(session.receive_pit()?),
+    };
+    let pit = Pit::parse(pit.as_ref().or(device_pit.as_ref()).unwrap())?;
+
+    let (files, unmatched) = match source {
+        FlashSource::Files(files) => (plan_files(&pit, files)?, None),
+        FlashSource::Directory(directory) => {
+            let (files, unmatched) = plan_directory(&pit, directory)?;
+            (files, Some(unmatched))
+        }
+    };
+
+    step!(
+        "flashing in the order {}",
+        files
+            .iter()
+            .map(|(_, file)| file.partition.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    session.set_total_bytes(files.iter().map(|(_, file)| file.size).sum())?;
+    let partitions = files.iter().map(|(index, _)| *index).collect();
+
+    for (index, mut file) in files {
+        let entry = &pit.entries[index];
+
+        status!(
+            "Flashing {} ({}) to {}",
+            file.path,
+            human_bytes(file.size),
+            entry.partition_name
+        );
+
+        let digest = session.flash(entry, &mut file.file, file.size, cancel)?;
+
+        events::emit(
+            "flash_complete",
+            Object::new()
+                .field("partition", entry.partition_name.as_str())
+                .field("file", file.path.as_str())
+                .field("bytes", file.size)
+                .field("sha256", sha256::to_hex(&digest)),
+        );
+        status!("SHA-256 of {}: {}", file.path, sha256::to_hex(&digest));
+    }
+
+    if let Some(unmatched) = unmatched {
+        unmatched.report();
+    }
+
+    Ok(Flashed {
+        pit,
+        device_pit,
+        partitions,
+    })
+}
+
+// Begins a new session after flashing and checks what download mode lets
+// be checked: that the device answers, and that the partitions that were
+// flashed are still where the PIT had them. The flashed data itself can't be
+// read back, and the bootloader doesn't tell whether it took it well.
+pub fn verify_flashed(
+    session: odin::Session,
+    identity: &Identity,
+    flashed: &Flashed,
+    reboot_first: bool,
+) -> Result<odin::Session> {
+    status!(
+        "Verifying: {}",
+        if reboot_first {
+            "rebooting into download mode and beginning a new session"
+        } else {
+            "beginning a new session"
+        }
+    );
+
+    let mut session = session.restart(reboot_first)?;
+    status!("The device answers in download mode again");
+
+    let lock_state = session.identity(None, None).lock_state;
+    if lock_state != identity.lock_state {
+        warning!(
+            "The lock state changed from {} to {}",
+            identity
+                .lock_state
+                .map_or("not reported".to_string(), |state| state.to_string()),
+            lock_state.map_or("not reported".to_string(), |state| state.to_string())
+        );
+    }
+
+    let data = session.receive_pit()?;
+    let pit = Pit::parse(&data)?;
+    let mut problems = Vec::new();
+
+    for &index in &flashed.partitions {
+        let before = &flashed.pit.entries[index];
+
+        match pit.find(&before.partition_name) {
+            Some(after)
+                if (after.identifier, after.block_size_or_offset, after.block_count)
+                    == (
+                        before.identifier,
+                        before.block_size_or_offset,
+                        before.block_count,
+                    ) => {}
+            Some(after) => problems.push(format!(
+                "{} is now partition {} with {} blocks at {}, rather than partition {} with {} blocks at {}",
+                before.partition_name,
+                after.identifier,
+                after.block_count,
+                after.block_size_or_offset,
+                before.identifier,
+                before.block_count,
+                before.block_size_or_offset
+            )),
+            None => problems.push(format!(
+                "{} is no longer in the PIT",
+                before.partition_name
+            )),
+        }
+    }
+
+    // Nothing that was flashed should have touched the rest of it either.
+    let pit_unchanged = flashed.device_pit.as_ref().map(|before| *before == data);
+    if pit_unchanged == Some(false) && problems.is_empty() {
+        problems.push("the PIT changed since before flashing".to_string());
+    }
+
+    events::emit(
+        "flash_verified",
+        Object::new()
+            .field("rebooted", reboot_first)
+            .field("device_answers", true)
+            .field("partitions_intact", problems.is_empty())
+            .field("pit_unchanged", pit_unchanged)
+            .field("contents_verified", false),
+    );
+
+    if !problems.is_empty() {
+        return Err(Error::Verification(format!(
+            "After flashing, {}",
+            problems.join(", ")
+        )));
+    }
+
+    match pit_unchanged {
+        Some(_) => status!("The PIT is the same as before flashing"),
+        None => status!(
+            "The {} flashed partitions are where the local PIT has them",
+            flashed.partitions.len()
+        ),
+    }
+    status!(
+        "Not verified: the flashed data, which download mode can't read back, and whether the bootloader accepted it, which it doesn't tell"
+    );
+
+    Ok(session)
+}
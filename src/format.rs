@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Sizes, durations and rates for people to read, the same way everywhere.
+// --raw-sizes has them as exact integers instead, for scripts.
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+fn raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+// Binary units with one decimal, plain bytes below a KiB.
+pub fn human_bytes(bytes: u64) -> String {
+    format_bytes(bytes, raw())
+}
+
+fn format_bytes(bytes: u64, raw: bool) -> String {
+    if bytes == 1 {
+        return "1 byte".to_string();
+    }
+
+    if raw || bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    // Going by what the value rounds to, so that it never shows as 1024.0
+    // of a unit.
+    while value >= 1023.95 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// Seconds with one decimal below a minute, whole minutes and seconds or hours
+// and minutes above. Milliseconds when raw.
+pub fn human_duration(duration: Duration) -> String {
+    format_duration(duration, raw())
+}
+
+fn format_duration(duration: Duration, raw: bool) -> String {
+    if raw {
+        return format!("{} ms", duration.as_millis());
+    }
+
+    if duration.as_secs_f64() < 59.95 {
+        return format!("{:.1} s", duration.as_secs_f64());
+    }
+
+    let seconds = duration.as_secs() + u64::from(duration.subsec_millis() >= 500);
+    if seconds < 3600 {
+        format!("{} min {} s", seconds / 60, seconds % 60)
+    } else {
+        format!("{} h {} min", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+// How many bytes went by per second, nothing for no time at all.
+pub fn human_rate(bytes: u64, duration: Duration) -> String {
+    format_rate(bytes, duration, raw())
+}
+
+fn format_rate(bytes: u64, duration: Duration, raw: bool) -> String {
+    let seconds = duration.as_secs_f64();
+    let rate = if seconds > 0.0 {
+        (bytes as f64 / seconds) as u64
+    } else {
+        0
+    };
+
+    format!("{}/s", format_bytes(rate, raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        for (bytes, human) in [
+            (0, "0 bytes"),
+            (1, "1 byte"),
+            (1023, "1023 bytes"),
+            (1024, "1.0 KiB"),
+            (1536, "1.5 KiB"),
+            (1024 * 1024 - 1, "1.0 MiB"),
+            (1024 * 1024 - 52, "1023.9 KiB"),
+            (5 << 30, "5.0 GiB"),
+            (u64::MAX, "16.0 EiB"),
+        ] {
+            assert_eq!(format_bytes(bytes, false), human);
+        }
+
+        for (bytes, raw) in [(0, "0 bytes"), (1, "1 byte"), (1024, "1024 bytes")] {
+            assert_eq!(format_bytes(bytes, true), raw);
+        }
+        assert_eq!(format_bytes(u64::MAX, true), format!("{} bytes", u64::MAX));
+    }
+
+    #[test]
+    fn durations() {
+        for (millis, human) in [
+            (0, "0.0 s"),
+            (1234, "1.2 s"),
+            (59_949, "59.9 s"),
+            (59_950, "1 min 0 s"),
+            (61_500, "1 min 2 s"),
+            (3_599_499, "59 min 59 s"),
+            (3_599_500, "1 h 0 min"),
+            (90_061_000, "25 h 1 min"),
+        ] {
+            assert_eq!(format_duration(Duration::from_millis(millis), false), human);
+        }
+
+        assert_eq!(
+            format_duration(Duration::from_millis(61_500), true),
+            "61500 ms"
+        );
+        assert_eq!(format_duration(Duration::from_micros(1999), true), "1 ms");
+    }
+
+    #[test]
+    fn rates() {
+        let second = Duration::from_secs(1);
+
+        assert_eq!(format_rate(0, second, false), "0 bytes/s");
+        assert_eq!(
+            format_rate(3 << 20, Duration::from_secs(2), false),
+            "1.5 MiB/s"
+        );
+        assert_eq!(format_rate(1 << 20, Duration::ZERO, false), "0 bytes/s");
+        assert_eq!(format_rate(u64::MAX, second, false), "16.0 EiB/s");
+        assert_eq!(
+            format_rate(3 << 20, Duration::from_secs(2), true),
+            "1572864 bytes/s"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+const BYTES_PER_LINE: usize = 16;
+
+// Formats data in the same layout as `hexdump -C`, with addresses starting at
+// the given offset.
+pub fn hexdump(data: &[u8], offset: u64) -> Vec<String> {
+    data.chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(index, line)| {
+            let mut hex = String::with_capacity(3 * BYTES_PER_LINE + 1);
+
+            for position in 0..BYTES_PER_LINE {
+                if position == BYTES_PER_LINE / 2 {
+                    hex.push(' ');
+                }
+
+                match line.get(position) {
+                    Some(byte) => hex.push_str(&format!("{:02x} ", byte)),
+                    None => hex.push_str("   "),
+                }
+            }
+
+            let ascii = line
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+
+            format!(
+                "{:08x}  {} |{}|",
+                offset + (index * BYTES_PER_LINE) as u64,
+                hex,
+                ascii
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,119 @@
+use crate::error::Result;
+use crate::step;
+use rusb::{Device, GlobalContext, Hotplug, HotplugBuilder, UsbContext};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+// How often to check for devices without hotplug support, and how long to
+// wait for hotplug events before checking whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub enum Event {
+    Arrived(Device<GlobalContext>),
+    Left(Device<GlobalContext>),
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Lets Ctrl-C end the watch instead of killing the process, so that the
+// hotplug callback gets unregistered.
+fn catch_interrupt() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// The callback can't do any I/O on the devices, so pass them on to the
+// thread that is watching.
+struct Forwarder(Sender<Event>);
+
+impl Hotplug<GlobalContext> for Forwarder {
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        let _ = self.0.send(Event::Arrived(device));
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        let _ = self.0.send(Event::Left(device));
+    }
+}
+
+// Reports devices that are plugged in or removed after the start, until
+// interrupted.
+pub fn watch(mut handle: impl FnMut(Event) -> Result<()>) -> Result<()> {
+    catch_interrupt();
+
+    if rusb::has_hotplug() {
+        watch_hotplug(&mut handle)
+    } else {
+        step!("no hotplug support, polling for devices");
+        watch_polling(&mut handle)
+    }
+}
+
+fn watch_hotplug(handle: &mut impl FnMut(Event) -> Result<()>) -> Result<()> {
+    let context = GlobalContext::default();
+    let (sender, receiver) = mpsc::channel();
+
+    let mut builder = HotplugBuilder::new();
+    builder.enumerate(false);
+
+    // Unregisters the callback when dropped.
+    let _registration = builder.register(context, Box::new(Forwarder(sender)))?;
+
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match context.handle_events(Some(POLL_INTERVAL)) {
+            Ok(()) | Err(rusb::Error::Interrupted) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        for event in receiver.try_iter() {
+            handle(event)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn snapshot() -> Result<HashMap<(u8, u8), Device<GlobalContext>>> {
+    Ok(rusb::devices()?
+        .iter()
+        .map(|device| ((device.bus_number(), device.address()), device))
+        .collect())
+}
+
+fn watch_polling(handle: &mut impl FnMut(Event) -> Result<()>) -> Result<()> {
+    let mut devices = snapshot()?;
+
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        std::thread::sleep(POLL_INTERVAL);
+
+        let current = snapshot()?;
+
+        for (key, device) in &devices {
+            if !current.contains_key(key) {
+                handle(Event::Left(device.clone()))?;
+            }
+        }
+
+        for (key, device) in &current {
+            if !devices.contains_key(key) {
+                handle(Event::Arrived(device.clone()))?;
+            }
+        }
+
+        devices = current;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,332 @@
+use crate::error::{Error, Result};
+use std::io::{Read, Write};
+
+// A decoder for DEFLATE (RFC 1951), which zip archives compress their members
+// with. The output is written out as it's decoded, only the last 32 KiB are
+// kept around for back references. Codes are decoded bit by bit with the
+// counts of codes per length, like zlib's puff does, which is slow but
+// simple.
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MAX_BITS: usize = 15;
+
+// The lengths and distances, which deflate.rs encodes with the same tables.
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order in which the lengths of the code length code are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn corrupt(message: &str) -> Error {
+    Error::InvalidArchive(format!("corrupt compressed data, {}", message))
+}
+
+struct BitReader<'a> {
+    input: &'a mut dyn Read,
+    buffer: Box<[u8]>,
+    position: usize,
+    filled: usize,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a mut dyn Read) -> Self {
+        Self {
+            input,
+            buffer: vec![0; 64 * 1024].into_boxed_slice(),
+            position: 0,
+            filled: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        if self.position == self.filled {
+            self.filled = self.input.read(&mut self.buffer)?;
+            self.position = 0;
+
+            if self.filled == 0 {
+                return Err(corrupt("it ends early"));
+            }
+        }
+
+        self.position += 1;
+
+        Ok(self.buffer[self.position - 1])
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        while self.count < count {
+            self.bits |= u64::from(self.byte()?) << self.count;
+            self.count += 8;
+        }
+
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.count -= count;
+
+        Ok(value)
+    }
+
+    // Stored blocks start at a byte boundary.
+    fn align(&mut self) {
+        let skip = self.count % 8;
+        self.bits >>= skip;
+        self.count -= skip;
+    }
+}
+
+// A canonical Huffman code, given by the number of codes of every length and
+// the symbols ordered by their codes.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+
+        // Codes that are incomplete are fine, ones that don't fit aren't.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(corrupt("a code has too many symbols"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // The first code of the current length, and the index of its symbol.
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(corrupt("there is an unknown code"))
+    }
+}
+
+struct Output<'a> {
+    writer: &'a mut dyn Write,
+    // The data that hasn't been written yet, which is at least the window
+    // once there has been that much.
+    window: Vec<u8>,
+    written: u64,
+}
+
+impl Output<'_> {
+    fn push(&mut self, byte: u8) -> Result<()> {
+        self.window.push(byte);
+        self.flush_some()
+    }
+
+    fn copy(&mut self, distance: usize, length: usize) -> Result<()> {
+        if distance > self.window.len() {
+            return Err(corrupt("a reference goes back too far"));
+        }
+
+        // The copy may overlap with what it produces, so byte by byte.
+        let start = self.window.len() - distance;
+        for index in start..start + length {
+            let byte = self.window[index];
+            self.window.push(byte);
+        }
+
+        self.flush_some()
+    }
+
+    fn flush_some(&mut self) -> Result<()> {
+        if self.window.len() >= 4 * WINDOW_SIZE {
+            let count = self.window.len() - WINDOW_SIZE;
+            self.writer.write_all(&self.window[..count])?;
+            self.window.drain(..count);
+            self.written += count as u64;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<u64> {
+        self.writer.write_all(&self.window)?;
+
+        Ok(self.written + self.window.len() as u64)
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    if literal_count > 286 || distance_count > 30 {
+        return Err(corrupt("a block has too many codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + reader.bits(2)?),
+                None => return Err(corrupt("a length is repeated before the first")),
+            },
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+
+        if lengths.len() + repeat as usize > literal_count + distance_count {
+            return Err(corrupt("the code lengths run over"));
+        }
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+
+    if lengths[256] == 0 {
+        return Err(corrupt("a block has no end"));
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+fn inflate_codes(
+    reader: &mut BitReader,
+    output: &mut Output,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+
+        match symbol {
+            0..=255 => output.push(symbol as u8)?,
+            256 => return Ok(()),
+            257..=285 => {
+                let index = symbol - 257;
+                let length = LENGTH_BASE[index] as usize
+                    + reader.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+
+                let index = distances.decode(reader)? as usize;
+                if index >= DISTANCE_BASE.len() {
+                    return Err(corrupt("there is an unknown distance"));
+                }
+                let distance = DISTANCE_BASE[index] as usize
+                    + reader.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+
+                output.copy(distance, length)?;
+            }
+            _ => return Err(corrupt("there is an unknown length")),
+        }
+    }
+}
+
+// Decompresses everything from `input` into `output`, returning how much
+// that came to.
+pub fn inflate(input: &mut dyn Read, output: &mut dyn Write) -> Result<u64> {
+    let mut reader = BitReader::new(input);
+    let mut output = Output {
+        writer: output,
+        window: Vec::with_capacity(4 * WINDOW_SIZE),
+        written: 0,
+    };
+
+    loop {
+        let last = reader.bits(1)? == 1;
+
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let length = reader.bits(16)?;
+                if reader.bits(16)? != !length & 0xffff {
+                    return Err(corrupt("a stored block has a broken length"));
+                }
+
+                for _ in 0..length {
+                    let byte = reader.bits(8)? as u8;
+                    output.push(byte)?;
+                }
+            }
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                inflate_codes(&mut reader, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_codes(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err(corrupt("there is a block of an unknown type")),
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    output.finish()
+}
@@ -0,0 +1,350 @@
+use crate::json::Object;
+
+// Which interface of a USB device is talked to, worked out from nothing but
+// its descriptors, so that this can be tried on the descriptors of devices
+// that aren't plugged in.
+//
+// A CDC data interface (class 0x0a) is preferred. Some download modes have
+// a vendor-specific one (class 0xff) instead, which is taken if there is no
+// CDC data interface. Either needs one bulk IN and one bulk OUT endpoint,
+// interrupt endpoints next to them don't matter.
+
+pub const CDC_COMMUNICATION: u8 = 0x02;
+pub const CDC_DATA: u8 = 0x0a;
+pub const VENDOR_SPECIFIC: u8 = 0xff;
+
+// The subclass of Android's vendor-specific interfaces (ADB and fastboot),
+// which have a pair of bulk endpoints too.
+const ANDROID_SUBCLASS: u8 = 0x42;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: u8,
+    pub direction: Direction,
+    pub transfer_type: TransferType,
+    pub max_packet_size: u16,
+}
+
+impl Endpoint {
+    pub fn direction_name(&self) -> &'static str {
+        match self.direction {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+        }
+    }
+
+    pub fn transfer_type_name(&self) -> &'static str {
+        match self.transfer_type {
+            TransferType::Control => "control",
+            TransferType::Isochronous => "isochronous",
+            TransferType::Bulk => "bulk",
+            TransferType::Interrupt => "interrupt",
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        Object::new()
+            .field("address", format!("{:#04x}", self.address))
+            .field("direction", self.direction_name())
+            .field("type", self.transfer_type_name())
+            .field("max_packet_size", self.max_packet_size)
+    }
+}
+
+// An alternate setting of an interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub interface: u8,
+    pub setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl Setting {
+    // Its pair of bulk endpoints (IN, OUT), or why it doesn't have one.
+    pub fn bulk_pair(&self) -> Result<(&Endpoint, &Endpoint), String> {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.transfer_type != TransferType::Interrupt)
+            .collect::<Vec<_>>();
+
+        let find = |direction| {
+            endpoints.iter().copied().find(|endpoint| {
+                endpoint.direction == direction && endpoint.transfer_type == TransferType::Bulk
+            })
+        };
+
+        match (endpoints.len(), find(Direction::In), find(Direction::Out)) {
+            (2, Some(endpoint_in), Some(endpoint_out)) => Ok((endpoint_in, endpoint_out)),
+            (count, _, _) if count != 2 => Err(format!(
+                "{} endpoints besides interrupt ones rather than a pair",
+                count
+            )),
+            _ => Err("no pair of bulk IN and OUT endpoints".to_string()),
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        Object::new()
+            .field("interface", self.interface)
+            .field("setting", self.setting)
+            .field("class", format!("{:#04x}", self.class))
+            .field("subclass", format!("{:#04x}", self.subclass))
+            .field("protocol", format!("{:#04x}", self.protocol))
+            .field(
+                "endpoints",
+                self.endpoints
+                    .iter()
+                    .map(Endpoint::to_object)
+                    .collect::<Vec<_>>(),
+            )
+    }
+
+    // Why it can't be talked to without being asked for, if it can't.
+    fn rejection(&self) -> Option<String> {
+        match self.class {
+            CDC_DATA => self.bulk_pair().err(),
+            VENDOR_SPECIFIC if self.subclass == ANDROID_SUBCLASS => {
+                Some("it's Android's, for ADB or fastboot".to_string())
+            }
+            VENDOR_SPECIFIC => self.bulk_pair().err(),
+            class => Some(format!(
+                "class {:#04x} is neither CDC data (0x0a) nor vendor specific (0xff)",
+                class
+            )),
+        }
+    }
+}
+
+// What the common interface classes are, for showing descriptors.
+pub fn class_name(class: u8) -> Option<&'static str> {
+    match class {
+        CDC_COMMUNICATION => Some("CDC communication"),
+        0x03 => Some("HID"),
+        0x06 => Some("image"),
+        0x08 => Some("mass storage"),
+        CDC_DATA => Some("CDC data"),
+        0xe0 => Some("wireless controller"),
+        0xef => Some("miscellaneous"),
+        VENDOR_SPECIFIC => Some("vendor specific"),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Choice {
+    // The setting that is talked to, as an index into the settings.
+    pub picked: Option<usize>,
+    // Why each of the settings was passed over, nothing for the picked one.
+    pub reasons: Vec<Option<String>>,
+    // The communication interface that takes the class requests.
+    pub control_interface: Option<u8>,
+}
+
+// Picks the setting to talk to, or the first one of the given interface that
+// has a pair of bulk endpoints, whatever its class.
+pub fn choose(settings: &[Setting], forced: Option<u8>) -> Choice {
+    let mut reasons = settings
+        .iter()
+        .map(|setting| match forced {
+            Some(interface) if setting.interface != interface => {
+                Some(format!("interface {} was asked for instead", interface))
+            }
+            Some(_) => setting.bulk_pair().err(),
+            None => setting.rejection(),
+        })
+        .collect::<Vec<_>>();
+
+    let candidates = (0..settings.len())
+        .filter(|&index| reasons[index].is_none())
+        .collect::<Vec<_>>();
+    let picked = candidates
+        .iter()
+        .copied()
+        .find(|&index| forced.is_some() || settings[index].class == CDC_DATA)
+        .or(candidates.first().copied());
+
+    if let Some(picked) = picked {
+        for index in candidates.into_iter().filter(|&index| index != picked) {
+            reasons[index] = Some(format!(
+                "interface {} setting {} is preferred",
+                settings[picked].interface, settings[picked].setting
+            ));
+        }
+    }
+
+    Choice {
+        picked,
+        control_interface: picked
+            .and_then(|picked| find_control_interface(settings, settings[picked].interface)),
+        reasons,
+    }
+}
+
+// The communication interface that takes the class requests for the data
+// interface. It usually comes right before it.
+fn find_control_interface(settings: &[Setting], data_interface: u8) -> Option<u8> {
+    let mut control_interfaces = settings
+        .iter()
+        .filter(|setting| setting.class == CDC_COMMUNICATION)
+        .map(|setting| setting.interface)
+        .collect::<Vec<_>>();
+    control_interfaces.dedup();
+
+    match control_interfaces
+        .iter()
+        .position(|interface| Some(*interface) == data_interface.checked_sub(1))
+    {
+        Some(position) => Some(control_interfaces.remove(position)),
+        None => control_interfaces.first().copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(address: u8, transfer_type: TransferType, max_packet_size: u16) -> Endpoint {
+        Endpoint {
+            address,
+            direction: match address & 0x80 {
+                0 => Direction::Out,
+                _ => Direction::In,
+            },
+            transfer_type,
+            max_packet_size,
+        }
+    }
+
+    fn setting(interface: u8, class: (u8, u8, u8), endpoints: Vec<Endpoint>) -> Setting {
+        Setting {
+            interface,
+            setting: 0,
+            class: class.0,
+            subclass: class.1,
+            protocol: class.2,
+            endpoints,
+        }
+    }
+
+    fn bulk_pair(endpoint_in: u8, endpoint_out: u8) -> Vec<Endpoint> {
+        vec![
+            endpoint(endpoint_in, TransferType::Bulk, 512),
+            endpoint(endpoint_out, TransferType::Bulk, 512),
+        ]
+    }
+
+    // The descriptors of download mode (04e8:685d), as lsusb -v shows them:
+    // a communication interface with its interrupt endpoint, and the data
+    // interface.
+    fn download_mode() -> [Setting; 2] {
+        [
+            setting(
+                0,
+                (0x02, 0x02, 0x01),
+                vec![endpoint(0x83, TransferType::Interrupt, 16)],
+            ),
+            setting(1, (0x0a, 0x00, 0x00), bulk_pair(0x81, 0x02)),
+        ]
+    }
+
+    fn adb() -> Setting {
+        setting(0, (0xff, 0x42, 0x01), bulk_pair(0x81, 0x01))
+    }
+
+    // A phone that is booted with MTP only.
+    fn mtp() -> Setting {
+        let mut endpoints = bulk_pair(0x81, 0x01);
+        endpoints.push(endpoint(0x82, TransferType::Interrupt, 28));
+
+        setting(0, (0x06, 0x01, 0x01), endpoints)
+    }
+
+    #[test]
+    fn download_mode_talks_to_the_data_interface() {
+        let choice = choose(&download_mode(), None);
+
+        assert_eq!(choice.picked, Some(1));
+        assert_eq!(choice.control_interface, Some(0));
+        assert!(choice.reasons[0].is_some());
+    }
+
+    #[test]
+    fn vendor_specific_interfaces_may_have_interrupt_endpoints() {
+        let mut endpoints = bulk_pair(0x81, 0x01);
+        endpoints.push(endpoint(0x82, TransferType::Interrupt, 8));
+
+        let choice = choose(&[setting(0, (0xff, 0x00, 0x00), endpoints)], None);
+
+        assert_eq!(choice.picked, Some(0));
+        assert_eq!(choice.control_interface, None);
+    }
+
+    #[test]
+    fn cdc_data_wins_over_adb_in_either_order() {
+        let data = setting(1, (0x0a, 0x00, 0x00), bulk_pair(0x82, 0x02));
+
+        let choice = choose(&[adb(), data.clone()], None);
+        assert_eq!(choice.picked, Some(1));
+
+        let choice = choose(&[data, adb()], None);
+        assert_eq!(choice.picked, Some(0));
+    }
+
+    #[test]
+    fn other_classes_are_passed_over() {
+        assert_eq!(choose(&[adb()], None).picked, None);
+
+        let choice = choose(&[mtp()], None);
+        assert_eq!(choice.picked, None);
+        assert_eq!(
+            choice.reasons[0].as_deref(),
+            Some("class 0x06 is neither CDC data (0x0a) nor vendor specific (0xff)")
+        );
+    }
+
+    #[test]
+    fn an_interface_asked_for_overrides_the_heuristic() {
+        // Whatever its class, as long as it has a pair of bulk endpoints.
+        assert_eq!(choose(&[adb()], Some(0)).picked, Some(0));
+        assert_eq!(choose(&[mtp()], Some(0)).picked, Some(0));
+
+        // Even with a CDC data interface next to it.
+        let data = setting(1, (0x0a, 0x00, 0x00), bulk_pair(0x82, 0x02));
+        let choice = choose(&[adb(), data], Some(0));
+        assert_eq!(choice.picked, Some(0));
+        assert_eq!(
+            choice.reasons[1].as_deref(),
+            Some("interface 0 was asked for instead")
+        );
+
+        // An interface without bulk endpoints can't be talked to at all.
+        let choice = choose(&download_mode(), Some(0));
+        assert_eq!(choice.picked, None);
+        assert_eq!(
+            choice.reasons,
+            [
+                Some("0 endpoints besides interrupt ones rather than a pair".to_string()),
+                Some("interface 0 was asked for instead".to_string()),
+            ]
+        );
+        assert_eq!(choice.control_interface, None);
+    }
+}
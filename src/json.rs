@@ -0,0 +1,460 @@
+use std::fmt;
+
+// Just enough JSON to produce machine-readable output without pulling in a
+// serialization framework.
+pub trait ToJson {
+    fn to_json(&self) -> String;
+}
+
+pub fn escape(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len() + 2);
+
+    escaped.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+
+    escaped
+}
+
+impl ToJson for str {
+    fn to_json(&self) -> String {
+        escape(self)
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> String {
+        escape(self)
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+macro_rules! impl_to_json_for_number {
+    ($($type:ty),*) => {
+        $(
+            impl ToJson for $type {
+                fn to_json(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+impl_to_json_for_number!(u8, u16, u32, u64, usize, i32, i64);
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> String {
+        match self {
+            Some(value) => value.to_json(),
+            None => "null".to_string(),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> String {
+        let values = self.iter().map(ToJson::to_json).collect::<Vec<_>>();
+
+        format!("[{}]", values.join(","))
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> String {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn to_json(&self) -> String {
+        (**self).to_json()
+    }
+}
+
+// An object whose fields are kept in the order they were added.
+#[derive(Clone, Default)]
+pub struct Object {
+    fields: Vec<(String, String)>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(mut self, key: &str, value: impl ToJson) -> Self {
+        self.fields.push((key.to_string(), value.to_json()));
+
+        self
+    }
+
+    pub fn merge(mut self, other: Object) -> Self {
+        self.fields.extend(other.fields);
+
+        self
+    }
+}
+
+impl ToJson for Object {
+    fn to_json(&self) -> String {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{}:{}", escape(key), value))
+            .collect::<Vec<_>>();
+
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+// A parsed JSON document, for reading back what was written with the above.
+// Numbers are kept as they were written, so that large ones don't lose
+// precision.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    // Fields in the order they appear in.
+    Object(Vec<(String, Value)>),
+}
+
+impl fmt::Display for Value {
+    // Scalars as they'd be shown to a user, containers as JSON.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "-"),
+            Value::Bool(boolean) => write!(f, "{}", boolean),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::String(string) => write!(f, "{}", string),
+            Value::Array(values) => {
+                let values = values.iter().map(Value::to_string).collect::<Vec<_>>();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Value::Object(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect::<Vec<_>>();
+                write!(f, "{{{}}}", fields.join(", "))
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    text: &'a str,
+}
+
+impl Parser<'_> {
+    fn error(&mut self, message: &str) -> String {
+        match self.chars.peek() {
+            Some(&(offset, _)) => format!("{} at offset {}", message, offset),
+            None => format!("{} at the end", message),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some((_, ' ' | '\t' | '\r' | '\n'))) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => self.literal(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some((_, '}'))) {
+            self.chars.next();
+            return Ok(Value::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if !matches!(self.chars.peek(), Some((_, '"'))) {
+                return Err(self.error("expected a key"));
+            }
+
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => return Ok(Value::Object(fields)),
+                _ => return Err(self.error("expected ',' or '}' in object")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some((_, ']'))) {
+            self.chars.next();
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, ']')) => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+
+        loop {
+            match self.chars.next().map(|(_, c)| c) {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.chars.next().map(|(_, c)| c) {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('u') => {
+                        let digits = (0..4)
+                            .filter_map(|_| self.chars.next().map(|(_, c)| c))
+                            .collect::<String>();
+                        let c = u32::from_str_radix(&digits, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .unwrap_or(char::REPLACEMENT_CHARACTER);
+                        string.push(c);
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self
+            .chars
+            .peek()
+            .map_or(self.text.len(), |&(offset, _)| offset);
+        let mut end = start;
+
+        while let Some(&(offset, c)) = self.chars.peek() {
+            if !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                break;
+            }
+
+            end = offset + c.len_utf8();
+            self.chars.next();
+        }
+
+        let number = &self.text[start..end];
+        if number.parse::<f64>().is_err() {
+            return Err(format!("invalid number '{}'", number));
+        }
+
+        Ok(Value::Number(number.to_string()))
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        let mut word = String::new();
+
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !c.is_ascii_alphabetic() {
+                break;
+            }
+
+            word.push(c);
+            self.chars.next();
+        }
+
+        match word.as_str() {
+            "null" => Ok(Value::Null),
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.char_indices().peekable(),
+        text,
+    };
+
+    let value = parser.value()?;
+    parser.skip_whitespace();
+
+    if parser.chars.peek().is_some() {
+        return Err(parser.error("unexpected trailing data"));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pit::tests::Random;
+
+    #[test]
+    fn written_documents_parse_back() {
+        let text = Object::new()
+            .field("quoted", "say \"hi\"\\now")
+            .field("control", "tab\tline\nbell\u{7}")
+            .field("unicode", "Größe ✓")
+            .field("large", u64::MAX)
+            .field("negative", -5i64)
+            .field("flag", false)
+            .field("missing", None::<u32>)
+            .field("list", vec!["a", "b"])
+            .field("nested", Object::new().field("empty", Vec::<u8>::new()))
+            .to_json();
+
+        assert_eq!(
+            parse(&text).unwrap(),
+            Value::Object(vec![
+                (
+                    "quoted".to_string(),
+                    Value::String("say \"hi\"\\now".to_string())
+                ),
+                (
+                    "control".to_string(),
+                    Value::String("tab\tline\nbell\u{7}".to_string())
+                ),
+                ("unicode".to_string(), Value::String("Größe ✓".to_string())),
+                ("large".to_string(), Value::Number(u64::MAX.to_string())),
+                ("negative".to_string(), Value::Number("-5".to_string())),
+                ("flag".to_string(), Value::Bool(false)),
+                ("missing".to_string(), Value::Null),
+                (
+                    "list".to_string(),
+                    Value::Array(vec![
+                        Value::String("a".to_string()),
+                        Value::String("b".to_string())
+                    ])
+                ),
+                (
+                    "nested".to_string(),
+                    Value::Object(vec![("empty".to_string(), Value::Array(Vec::new()))])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn escapes_that_are_only_read() {
+        assert_eq!(
+            parse(r#"" \/ \b \f \u00e9 \ud800 ""#).unwrap(),
+            Value::String(" / \u{8} \u{c} é \u{fffd} ".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        for text in [
+            "",
+            "   ",
+            "{",
+            "[1,",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{1: 2}",
+            "{\"a\": 1,}",
+            "\"unterminated",
+            "\"bad \\x escape\"",
+            "tru",
+            "nul",
+            "-",
+            "1e",
+            "1.2.3",
+            "{} {}",
+            "1 2",
+        ] {
+            assert!(parse(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn arbitrary_text_never_panics() {
+        // Pieces of JSON, so that the parser gets past the first character
+        // more often than with random bytes.
+        const PIECES: &[&str] = &[
+            "{", "}", "[", "]", ":", ",", "\"", "\\", "\\u", "\\u12", "0", "-", "1.5e3", "e",
+            "true", "nul", " ", "\n", "é", "✓", "\u{0}",
+        ];
+
+        let mut random = Random::new(0x6a736f6e);
+        for _ in 0..5000 {
+            let count = random.below(16);
+            let text = (0..count)
+                .map(|_| PIECES[random.below(PIECES.len())])
+                .collect::<String>();
+
+            if let Ok(value) = parse(&text) {
+                let _ = value.to_string();
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+pub mod api;
+pub mod bootstub;
+pub mod cancel;
+pub mod capture;
+pub mod config;
+pub mod crc32;
+pub mod deflate;
+#[cfg(feature = "usb")]
+pub mod device;
+#[cfg(target_os = "linux")]
+pub mod doctor;
+pub mod dump;
+pub mod error;
+pub mod events;
+pub mod expr;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flash;
+pub mod format;
+pub mod hexdump;
+#[cfg(feature = "usb")]
+pub mod hotplug;
+pub mod inflate;
+pub mod interfaces;
+pub mod json;
+pub mod lineedit;
+pub mod lock;
+pub mod log;
+pub mod metadata;
+pub mod odin;
+pub mod output;
+pub mod pagemap;
+pub mod parse;
+pub mod patch;
+pub mod permissions;
+pub mod picker;
+pub mod pit;
+pub mod profile;
+pub mod resume;
+pub mod retry;
+pub mod script;
+pub mod search;
+pub mod serial;
+pub mod sha256;
+#[cfg(all(unix, feature = "serial"))]
+pub mod simulator;
+pub mod sink;
+pub mod soak;
+pub mod summary;
+pub mod template;
+pub mod timeouts;
+pub mod toml;
+pub mod transport;
+pub mod ui;
+pub mod wait;
+pub mod zip;
+pub mod zstd;
+
+#[cfg(feature = "serial")]
+pub use api::dump_memory;
+#[cfg(feature = "usb")]
+pub use api::flash_partition;
+pub use api::{dump_memory_over, flash_partition_over};
+pub use cancel::CancelToken;
+pub use error::{Error, Result};
@@ -0,0 +1,243 @@
+use crate::error::Result;
+use std::io::{BufRead, Write};
+#[cfg(unix)]
+use std::io::{IsTerminal, Read};
+
+// Reads lines from the terminal with a little bit of editing: moving around
+// with the arrow keys, Home/End (or Ctrl-A/Ctrl-E), Backspace, Ctrl-U to
+// clear the line and Up/Down for the history. When not on a terminal (and on
+// Windows), lines are read as they come.
+#[derive(Default)]
+pub struct LineEditor {
+    history: Vec<String>,
+}
+
+// Reverts the terminal to how it was found, also when bailing out early.
+#[cfg(unix)]
+struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Option<Self> {
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return None;
+        }
+
+        // Keep ISIG, so that Ctrl-C still interrupts.
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+
+        Some(Self { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+#[cfg(unix)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Clear,
+    Eof,
+    Other,
+}
+
+#[cfg(unix)]
+fn read_byte(input: &mut impl Read) -> Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+#[cfg(unix)]
+fn read_key(input: &mut impl Read) -> Result<Key> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(Key::Eof);
+    };
+
+    Ok(match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x04 => Key::Eof,
+        0x05 => Key::End,
+        0x15 => Key::Clear,
+        0x1b => match (read_byte(input)?, read_byte(input)?) {
+            (Some(b'[' | b'O'), Some(b'A')) => Key::Up,
+            (Some(b'[' | b'O'), Some(b'B')) => Key::Down,
+            (Some(b'[' | b'O'), Some(b'C')) => Key::Right,
+            (Some(b'[' | b'O'), Some(b'D')) => Key::Left,
+            (Some(b'[' | b'O'), Some(b'H')) => Key::Home,
+            (Some(b'[' | b'O'), Some(b'F')) => Key::End,
+            _ => Key::Other,
+        },
+        byte if byte < 0x20 => Key::Other,
+        byte if byte < 0x80 => Key::Char(byte as char),
+        // The rest of a UTF-8 sequence.
+        first => {
+            let length = first.leading_ones() as usize;
+            let mut bytes = vec![first];
+
+            for _ in 1..length.clamp(1, 4) {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    })
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns None at the end of the input (Ctrl-D on an empty line).
+    pub fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        let line = self.read_raw(prompt)?;
+
+        if let Some(line) = &line {
+            let line = line.trim();
+            if !line.is_empty() && self.history.last().map(String::as_str) != Some(line) {
+                self.history.push(line.to_string());
+            }
+        }
+
+        Ok(line)
+    }
+
+    #[cfg(unix)]
+    fn read_raw(&mut self, prompt: &str) -> Result<Option<String>> {
+        if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+            return read_plain(prompt);
+        }
+
+        let Some(_raw_mode) = RawMode::enable() else {
+            return read_plain(prompt);
+        };
+
+        self.edit(prompt)
+    }
+
+    #[cfg(not(unix))]
+    fn read_raw(&mut self, prompt: &str) -> Result<Option<String>> {
+        read_plain(prompt)
+    }
+
+    #[cfg(unix)]
+    fn edit(&mut self, prompt: &str) -> Result<Option<String>> {
+        let mut input = std::io::stdin().lock();
+        let mut output = std::io::stderr().lock();
+
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Where in the history the line came from, with the line that was
+        // being typed before going there kept aside.
+        let mut history_index = self.history.len();
+        let mut typed = Vec::new();
+
+        loop {
+            let behind = line.len() - cursor;
+            write!(
+                output,
+                "\r{}{}\x1b[K",
+                prompt,
+                line.iter().collect::<String>()
+            )?;
+            if behind > 0 {
+                write!(output, "\x1b[{}D", behind)?;
+            }
+            output.flush()?;
+
+            match read_key(&mut input)? {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    writeln!(output, "\r")?;
+                    return Ok(Some(line.into_iter().collect()));
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::Clear => {
+                    line.clear();
+                    cursor = 0;
+                }
+                Key::Up if history_index > 0 => {
+                    if history_index == self.history.len() {
+                        typed = line.clone();
+                    }
+                    history_index -= 1;
+                    line = self.history[history_index].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if history_index < self.history.len() => {
+                    history_index += 1;
+                    line = match self.history.get(history_index) {
+                        Some(entry) => entry.chars().collect(),
+                        None => typed.clone(),
+                    };
+                    cursor = line.len();
+                }
+                Key::Eof if line.is_empty() => {
+                    writeln!(output, "\r")?;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn read_plain(prompt: &str) -> Result<Option<String>> {
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
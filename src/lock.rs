@@ -0,0 +1,269 @@
+use crate::error::{Error, Result};
+use crate::status;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// What to do about other invocations that use the same device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Off,
+    // Fail right away if the device is in use.
+    Fail,
+    // Wait until the device is free.
+    Wait,
+}
+
+// How often to check whether a device has become free.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Keeps other invocations off a device for as long as it's held, by way of
+// an flock() on a file in the runtime directory that has the PID and command
+// line of the holder. The kernel lets go of the flock when the holder exits,
+// so a file left behind by a process that is gone is simply locked again.
+pub struct DeviceLock {
+    path: PathBuf,
+    // Holds the flock.
+    _file: File,
+}
+
+// Who is holding a lock.
+struct Holder {
+    pid: u32,
+    command: String,
+}
+
+fn lock_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("sbootil"),
+        #[cfg(unix)]
+        _ => std::env::temp_dir().join(format!("sbootil-{}", unsafe { libc::getuid() })),
+        #[cfg(not(unix))]
+        _ => std::env::temp_dir().join("sbootil"),
+    }
+}
+
+// The file for a device, e.g. `serial-_dev_ttyUSB0.lock` or `usb-1-4.lock`.
+fn lock_path(key: &str) -> PathBuf {
+    let name = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    lock_dir().join(format!("{}.lock", name))
+}
+
+// Serial ports are locked under their real path, so that symlinks like the
+// ones in /dev/serial/by-id/ lead to the same lock.
+pub fn serial_key(path: &str) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+
+    format!("serial-{}", path.display())
+}
+
+pub fn usb_key(bus: u8, address: u8) -> String {
+    format!("usb-{}-{}", bus, address)
+}
+
+// Returns None if the file is gone or doesn't make sense.
+fn read_holder(path: &Path) -> Option<Holder> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let (pid, command) = contents.split_once('\n')?;
+
+    Some(Holder {
+        pid: pid.parse().ok()?,
+        command: command.trim_end().to_string(),
+    })
+}
+
+fn file_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    |source| Error::File {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+// Locks the file at the path, or returns None if someone else has it. The
+// file might have been removed by the previous holder between opening and
+// locking it, in which case the lock is on a file nobody else will look at
+// and it's tried again.
+#[cfg(unix)]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The holder's PID and command line have to stay until it's ours.
+            .truncate(false)
+            .open(path)
+            .map_err(file_error(path))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(file_error(path)(err)),
+            };
+        }
+
+        let locked = file.metadata().map_err(file_error(path))?;
+        match std::fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(Some(file))
+            }
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(file_error(path)(err)),
+        }
+    }
+}
+
+// Without flock(), the file existing is what counts, and one that is left
+// behind has to be removed by hand.
+#[cfg(not(unix))]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    match File::options().write(true).create_new(true).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(None),
+        Err(err) => Err(file_error(path)(err)),
+    }
+}
+
+// Puts our PID and command line into a file we have the lock on.
+fn write_holder(path: &Path, mut file: &File) -> Result<()> {
+    let command = std::env::args().collect::<Vec<_>>().join(" ");
+
+    file.set_len(0).map_err(file_error(path))?;
+    file.write_all(format!("{}\n{}\n", std::process::id(), command).as_bytes())
+        .map_err(file_error(path))
+}
+
+impl DeviceLock {
+    // Takes the lock for a device, unless locking is off.
+    pub fn acquire(key: &str, mode: LockMode) -> Result<Option<Self>> {
+        if mode == LockMode::Off {
+            return Ok(None);
+        }
+
+        Self::acquire_path(lock_path(key), mode).map(Some)
+    }
+
+    // Takes the lock that is the file at the path.
+    pub fn acquire_path(path: PathBuf, mode: LockMode) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(file_error(parent))?;
+        }
+
+        let mut waiting = false;
+
+        loop {
+            if let Some(file) = try_lock(&path)? {
+                write_holder(&path, &file)?;
+                return Ok(Self { path, _file: file });
+            }
+
+            // The holder might not have gotten around to writing the file.
+            let holder = match read_holder(&path) {
+                Some(holder) => format!("PID {} ({})", holder.pid, holder.command),
+                None => "another process".to_string(),
+            };
+
+            if mode == LockMode::Fail {
+                return Err(Error::DeviceInUse(format!(
+                    "The device is in use by {}, pass --wait-lock to wait for it",
+                    holder
+                )));
+            }
+
+            if !waiting {
+                status!("Waiting for {} to be done with the device", holder);
+                waiting = true;
+            }
+
+            std::thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static LOCKS: AtomicUsize = AtomicUsize::new(0);
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "sbootil-test-{}-{}.lock",
+            std::process::id(),
+            LOCKS.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    #[test]
+    fn acquire_and_release() {
+        let path = path();
+
+        let lock = DeviceLock::acquire_path(path.clone(), LockMode::Fail).unwrap();
+        let holder = read_holder(&path).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+
+        drop(lock);
+        assert!(!path.exists());
+        assert!(DeviceLock::acquire("anything", LockMode::Off)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn contention() {
+        let path = path();
+
+        let lock = DeviceLock::acquire_path(path.clone(), LockMode::Fail).unwrap();
+        assert!(matches!(
+            DeviceLock::acquire_path(path.clone(), LockMode::Fail),
+            Err(Error::DeviceInUse(_))
+        ));
+
+        let waiting = {
+            let path = path.clone();
+            std::thread::spawn(move || DeviceLock::acquire_path(path, LockMode::Wait))
+        };
+        std::thread::sleep(WAIT_POLL_INTERVAL * 2);
+        assert!(!waiting.is_finished());
+
+        drop(lock);
+        let lock = waiting.join().unwrap().unwrap();
+        assert_eq!(read_holder(&path).unwrap().pid, std::process::id());
+        drop(lock);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let path = path();
+
+        // Nobody has the flock on a file with a PID that can't be running.
+        std::fs::write(&path, format!("{}\nsbootil dump\n", u32::MAX)).unwrap();
+
+        let lock = DeviceLock::acquire_path(path.clone(), LockMode::Fail).unwrap();
+        let holder = read_holder(&path).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert!(!holder.command.contains("sbootil dump"));
+        drop(lock);
+    }
+}
@@ -0,0 +1,56 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// How much to log, selected by the number of -v flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet = 0,
+    // Protocol steps, like the tokens being sent and received.
+    Steps = 1,
+    // Every transfer with its contents.
+    Transfers = 2,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Quiet as u8);
+static START: OnceLock<Instant> = OnceLock::new();
+
+pub fn set_level(level: Level) {
+    START.get_or_init(Instant::now);
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level_from_count(count: u8) -> Level {
+    match count {
+        0 => Level::Quiet,
+        1 => Level::Steps,
+        _ => Level::Transfers,
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+// Everything goes to stderr, stdout may be carrying a dump.
+pub fn write(message: std::fmt::Arguments) {
+    let elapsed = START.get_or_init(Instant::now).elapsed();
+
+    let _ = writeln!(
+        std::io::stderr().lock(),
+        "[{:4}.{:06}] {}",
+        elapsed.as_secs(),
+        elapsed.subsec_micros(),
+        message
+    );
+}
+
+#[macro_export]
+macro_rules! step {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Steps) {
+            $crate::log::write(format_args!($($arg)*));
+        }
+    };
+}
@@ -1,29 +1,131 @@
-mod device;
+mod completions;
 
-use clap::{arg, Command};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{arg, Arg, ArgMatches, Command, PossibleValue, ValueHint, ValueSource};
+use sbootil::cancel::{self, CancelToken};
+use sbootil::capture;
+#[cfg(feature = "usb")]
+use sbootil::config::VendorAllowList;
+use sbootil::config::{Config, Vendor};
+#[cfg(feature = "usb")]
+use sbootil::device::{self, DeviceInfo, LineCoding, Selector, UsbCdcDevice};
+#[cfg(target_os = "linux")]
+use sbootil::doctor;
+use sbootil::dump;
+#[cfg(feature = "usb")]
+use sbootil::flash::{self, FlashDirectory, FlashFile, FlashSource};
+use sbootil::format::{self, human_bytes, human_duration};
+use sbootil::hexdump::hexdump;
+#[cfg(feature = "usb")]
+use sbootil::interfaces;
+use sbootil::json::{Object, ToJson};
+use sbootil::lineedit::LineEditor;
+use sbootil::lock::LockMode;
+use sbootil::log::{self, Level};
+use sbootil::metadata::{self, DumpMetadata};
+use sbootil::pagemap::{CrcClassifier, PageMap};
+use sbootil::parse::{parse_bus_address, parse_u64, parse_usb_id, DeviceSpec};
+use sbootil::patch::{self, Run};
+use sbootil::picker;
+use sbootil::profile::{self, Profile};
+use sbootil::resume;
+use sbootil::retry::{parse_backoff, RetryPolicy};
+use sbootil::script;
+use sbootil::search::{self, Pattern, Search};
+use sbootil::serial;
+#[cfg(feature = "serial")]
+use sbootil::serial::SerialPort;
+use sbootil::sha256;
+#[cfg(all(unix, feature = "serial"))]
+use sbootil::simulator;
+use sbootil::sink;
+use sbootil::soak::{self, Soak};
+use sbootil::template::{self, Value};
+use sbootil::timeouts::{parse_timeout, Timeouts};
+#[cfg(feature = "usb")]
+use sbootil::transport::UsbTransport;
+use sbootil::transport::{
+    record_result, CountingTransport, MockTransport, Pacing, PacingTransport, Recording,
+    RecordingTransport, TcpTransport, TracingTransport, Transfer, Transport,
+};
+use sbootil::{
+    bootstub, error, events, expr, say, status, step, summary, ui, wait, warning, Error, Result,
+};
+#[cfg(feature = "usb")]
+use sbootil::{hotplug, odin};
+#[cfg(feature = "usb")]
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::num::ParseIntError;
-use std::os::unix::io::AsRawFd;
-use std::time::Duration;
-use termios::os::target::B115200;
-use termios::{
-    cfsetspeed, tcflush, tcsetattr, Termios, BRKINT, CS8, CSIZE, ECHO, ECHONL, ICANON, ICRNL,
-    IEXTEN, IGNBRK, IGNCR, INLCR, ISIG, ISTRIP, IXON, OPOST, PARENB, PARMRK, TCIOFLUSH, TCSANOW,
-};
-use usb_ids::FromId;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
 
 fn cli() -> Command<'static> {
     Command::new("sbootil")
+        .after_help(
+            "EXIT STATUS:\n    \
+             0  success\n    \
+             1  any other failure\n    \
+             2  invalid arguments or configuration\n    \
+             3  no device found\n    \
+             4  not allowed to access the device\n    \
+             5  the device didn't respond as expected\n    \
+             6  verification failed, e.g. a checksum mismatch\n    \
+             7  interrupted",
+        )
         .subcommand_required(true)
         .arg_required_else_help(true)
         .subcommand(
             Command::new("list-devices")
                 .about("Lists all connected USB devices")
                 .arg(
-                    arg!(<id> "The vendor ID to filter for")
+                    arg!(<id> "Same as --vendor, for compatibility")
+                        .required(false)
+                        .value_parser(Vendor::parse),
+                )
+                .arg(
+                    arg!(--vendor <VENDOR> "The vendors to list devices of, by hexadecimal ID or part of the name, instead of the configured ones [default: 04e8]")
+                        .required(false)
+                        .multiple_occurrences(true)
+                        .use_value_delimiter(true)
+                        .value_parser(Vendor::parse)
+                        .conflicts_with("id"),
+                )
+                .arg(
+                    arg!(--product <PRODUCT> "The product to filter for, by hexadecimal ID or part of the name")
+                        .required(false)
+                        .value_parser(parse_id_filter),
+                )
+                .arg(
+                    arg!(--all "List the devices of all vendors")
+                        .conflicts_with_all(&["id", "vendor"]),
+                )
+                .arg(arg!(--watch "Keep running and report devices as they are plugged in or removed"))
+                .arg(print_device_arg().conflicts_with_all(&["watch", "format"]))
+                .arg(
+                    arg!(--format <FORMAT> "The output format")
                         .required(false)
-                        .default_value(&"04e8"),
+                        .value_parser(PossibleValuesParser::new(["human", "json"]))
+                        .default_value("human"),
+                ),
+        )
+        .subcommand(
+            Command::new("usb")
+                .about("Look at USB devices at a low level")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("info")
+                        .about("Show the interfaces and endpoints of a device, and which interface would be talked to")
+                        .arg(usb_arg())
+                        .args(
+                            usb_selector_args()
+                                .into_iter()
+                                .filter(|arg| arg.get_id() != "no-cdc-setup"),
+                        )
+                        .arg(vendor_arg()),
                 ),
         )
         .subcommand(
@@ -31,340 +133,3429 @@ fn cli() -> Command<'static> {
                 .about("Talking to Download Mode")
                 .subcommand_required(true)
                 .arg_required_else_help(true)
-                .subcommand(Command::new("reboot").about("Reboot the device")),
+                .arg(usb_arg())
+                .args(usb_selector_args())
+                .arg(vendor_arg())
+                .args(lock_args())
+                .arg(arg!(--"pad-to-max-packet" "Pad packets only to a multiple of the max packet size of the USB endpoint, for bootloaders that don't take the usual padding"))
+                .subcommand(
+                    Command::new("reboot").about("Reboot the device").arg(
+                        arg!(--to <TARGET> "Where to reboot to, if the bootloader supports it")
+                            .required(false)
+                            .value_parser(PossibleValuesParser::new(["normal", "download", "recovery"]))
+                            .default_value("normal"),
+                    ),
+                )
+                .subcommand(
+                    Command::new("identify")
+                        .about("Show the model and serial number of the device"),
+                )
+                .subcommand(
+                    Command::new("flash")
+                        .about("Flash files to partitions, like Heimdall's --BOOT boot.img")
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)
+                        .arg(
+                            arg!(--pit <FILE> "Use a local PIT instead of the one on the device")
+                                .required(false)
+                                .value_hint(ValueHint::FilePath),
+                        )
+                        .arg(arg!(--"no-reboot" "Stay in download mode after flashing"))
+                        .arg(verify_after_arg())
+                        .arg(
+                            arg!(--"expect-serial" <SERIAL> "Don't flash anything unless the device has this USB serial number")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(<partitions> ... "The files to flash, as --PARTITION <FILE> with the name or identifier of the partition, FILE may be a member of a zip archive like firmware.zip:boot.img")
+                                .value_name("--PARTITION FILE")
+                                .allow_hyphen_values(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("flash-dir")
+                        .about("Flash the images in a directory to the partitions that the PIT has their file names for")
+                        .arg(arg!(<directory> "The directory with the images, or a zip archive with them").value_hint(ValueHint::AnyPath))
+                        .arg(
+                            arg!(--only <PARTITIONS> "Only flash these partitions, separated by commas")
+                                .required(false)
+                                .multiple_occurrences(true)
+                                .use_value_delimiter(true),
+                        )
+                        .arg(
+                            arg!(--skip <PARTITIONS> "Never flash these partitions, separated by commas")
+                                .required(false)
+                                .multiple_occurrences(true)
+                                .use_value_delimiter(true),
+                        )
+                        .arg(
+                            arg!(--order <PARTITIONS> "Flash these partitions first and in this order, separated by commas, the others follow in the order of the PIT with the bootloader last")
+                                .required(false)
+                                .multiple_occurrences(true)
+                                .use_value_delimiter(true),
+                        )
+                        .arg(
+                            arg!(--pit <FILE> "Use a local PIT instead of the one on the device")
+                                .required(false)
+                                .value_hint(ValueHint::FilePath),
+                        )
+                        .arg(arg!(--"no-reboot" "Stay in download mode after flashing"))
+                        .arg(verify_after_arg())
+                        .arg(
+                            arg!(--"expect-serial" <SERIAL> "Don't flash anything unless the device has this USB serial number")
+                                .required(false),
+                        ),
+                ),
         )
         .subcommand(
             Command::new("bootstub")
                 .about("Talking to bootstub")
                 .subcommand_required(true)
                 .arg_required_else_help(true)
+                .args(connection_args())
+                .arg(
+                    arg!(--"read-timeout" <SECONDS> "Deprecated, use --timeout instead")
+                        .required(false)
+                        .hide(true)
+                        .value_parser(parse_timeout),
+                )
+                .arg(
+                    arg!(--"tx-delay" <MICROSECONDS> "Pause this long between writes, for links that drop bytes")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"max-rate" <BYTES_PER_SECOND> "Don't send faster than this")
+                        .required(false)
+                        .value_parser(parse_size),
+                )
+                .arg(arg!(--"no-reconnect" "Give up when the device goes away during a dump or on the console, instead of waiting for it to come back"))
+                .arg(arg!(--"legacy-protocol" "Don't ask the stub what it supports, and dump with XOR checksums like the first stubs did, for old stubs that get confused by the question"))
+                .arg(
+                    arg!(--profile <NAME> "The SoC of the device, whose regions can then be used in addresses, e.g. iram and iram_end, see `sbootil profiles list`")
+                        .required(false),
+                )
+                .arg(arg!(--yes "Don't ask before accessing regions that the profile marks as dangerous"))
+                .arg(arg!(--"reboot-after" "Have the stub reset the device once the command went through, if it can"))
+                .arg(
+                    arg!(--"poweroff-after" "Have the stub power the device off once the command went through, if it can")
+                        .conflicts_with("reboot-after"),
+                )
+                .subcommand(
+                    Command::new("ping")
+                        .about("Check that the stub answers and show what it supports"),
+                )
                 .subcommand(
                     Command::new("dump")
                         .about("Dump memory from the device")
+                        .arg(arg!(<start> "The start address").required_unless_present("describe"))
+                        .arg(arg!(<end> "The end address").required_unless_present("describe"))
+                        .arg(
+                            arg!(<output> "The output file, {start}, {end} and {size} are replaced with the range, - for stdout")
+                                .required_unless_present("describe")
+                                .value_hint(ValueHint::FilePath),
+                        )
+                        .arg(
+                            arg!(--describe <FILE> "Show where an earlier dump came from, from its metadata file, instead of dumping")
+                                .required(false)
+                                .exclusive(true)
+                                .value_hint(ValueHint::FilePath),
+                        )
+                        .arg(arg!(--"no-metadata" "Don't write <output>.meta.json with where the dump came from"))
+                        .arg(arg!(-f --force "Overwrite the output file if it exists"))
+                        .arg(
+                            arg!(--"expected-sha256" <HEX> "Fail unless the dumped data has this SHA-256")
+                                .required(false)
+                                .value_parser(parse_sha256_arg),
+                        )
+                        .arg(
+                            arg!(--verify "Check the dump against the device if the stub can, and read the output file back in [default]")
+                                .overrides_with("no-verify"),
+                        )
+                        .arg(
+                            arg!(--"no-verify" "Don't check the dump after it's done")
+                                .overrides_with("verify"),
+                        )
+                        .arg(
+                            arg!(--"chunk-size" <SIZE> "Request the range in pieces of this size, each with its own checksum [default: 1M]")
+                                .required(false)
+                                .value_parser(parse_size),
+                        )
+                        .arg(
+                            arg!(--"split-size" <SIZE> "Write the dump into parts of this size, named <output>.000, <output>.001 and so on")
+                                .required(false)
+                                .value_parser(parse_size),
+                        )
+                        .arg(
+                            arg!(--retries <COUNT> "How often to request a piece again when its checksum doesn't match [default: --retries, or 2]")
+                                .required(false)
+                                .value_parser(clap::value_parser!(u32)),
+                        )
+                        .arg(
+                            arg!(--window <COUNT> "How many pieces may be on their way before the first is confirmed, if the stub supports it")
+                                .required(false)
+                                .default_value("1")
+                                .value_parser(clap::value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            arg!(--"access-width" <BYTES> "Have the stub read memory with aligned accesses of this width (1, 2, 4 or 8), e.g. for registers")
+                                .required(false)
+                                .default_value("1")
+                                .value_parser(parse_access_width),
+                        )
+                        .arg(
+                            arg!(--keepalive <SECONDS> "Have the stub service the watchdog of the board this often, in between pieces")
+                                .required(false)
+                                .value_parser(parse_timeout),
+                        )
+                        .arg(arg!(--"keep-partial" "Keep the output of a failed dump as <output>.partial instead of deleting it"))
+                        .arg(
+                            arg!(--compress <FORMAT> "Compress the output, zstd only compresses runs of a single byte")
+                                .required(false)
+                                .value_parser(sink::Compression::parse),
+                        )
+                        .arg(arg!(--hexdump "Show the data as a hexdump while it arrives, on stdout unless the dump goes there"))
+                        .arg(
+                            arg!(--resume "Keep track of the chunks that arrived intact in <output>.resume, and if that exists, only request the ones that are missing")
+                                .conflicts_with_all(&["split-size", "keep-partial", "compress", "hexdump"]),
+                        ),
+                )
+                .subcommand(
+                    Command::new("search")
+                        .about("Look for bytes in a range of memory, without dumping it to a file")
+                        .arg(arg!(<start> "The start address"))
+                        .arg(arg!(<end> "The end address"))
+                        .arg(
+                            arg!([pattern] "Hexadecimal bytes to look for, ?? matches any byte (e.g. 'de ad ?? ef')")
+                                .required_unless_present("ascii")
+                                .conflicts_with("ascii"),
+                        )
+                        .arg(arg!(--ascii <STRING> "Look for this text instead").required(false))
+                        .arg(
+                            arg!(--limit <COUNT> "Stop after this many matches")
+                                .required(false)
+                                .value_parser(clap::value_parser!(usize)),
+                        )
+                        .arg(
+                            arg!(--context <BYTES> "Show this many bytes before and after every match")
+                                .required(false)
+                                .value_parser(clap::value_parser!(usize)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("map")
+                        .about("Show which pages of a range of memory are zero, erased or hold data")
+                        .arg(arg!(<start> "The start address"))
+                        .arg(arg!(<end> "The end address"))
+                        .arg(
+                            arg!(--"page-size" <SIZE> "The size of the pages to tell apart")
+                                .required(false)
+                                .value_parser(parse_size)
+                                .default_value("4K"),
+                        )
+                        .arg(arg!(--"remote-crc" "Have the stub compute a CRC-32 of every page instead of reading the pages, which can't tell data from high-entropy pages but saves the transfer for large pages"))
+                        .arg(
+                            arg!(--format <FORMAT> "The output format")
+                                .required(false)
+                                .value_parser(PossibleValuesParser::new(["human", "json"]))
+                                .default_value("human"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("soak")
+                        .about("Read a range of memory over and over and check that every pass gives the same data, for testing a link or a region of memory")
                         .arg(arg!(<start> "The start address"))
                         .arg(arg!(<end> "The end address"))
-                        .arg(arg!(<output> "The output file")),
+                        .arg(
+                            arg!(--iterations <COUNT> "How many passes to make, Ctrl-C stops early")
+                                .required(false)
+                                .default_value("10")
+                                .value_parser(clap::value_parser!(u32).range(2..)),
+                        )
+                        .arg(arg!(--"remote-crc" "Have the stub compute a CRC-32 of every block instead of reading the range, which only tells which blocks differ but saves the transfer"))
+                        .arg(
+                            arg!(--"block-size" <SIZE> "The size of the blocks for --remote-crc")
+                                .required(false)
+                                .requires("remote-crc")
+                                .value_parser(parse_size)
+                                .default_value("64K"),
+                        )
+                        .arg(
+                            arg!(--format <FORMAT> "The output format")
+                                .required(false)
+                                .value_parser(PossibleValuesParser::new(["human", "json"]))
+                                .default_value("human"),
+                        ),
                 )
                 .subcommand(
                     Command::new("boot")
                         .about("Boot a raw binary on the device")
-                        .arg(arg!(<binary> "The binary file")),
+                        .arg(arg!(<binary> "The binary file").value_hint(ValueHint::FilePath))
+                        .args(console_args()),
+                )
+                .subcommand(
+                    Command::new("run-at")
+                        .about("Write a binary to memory and jump to it, if the stub supports that")
+                        .arg(arg!(<address> "Where to write the binary and jump to"))
+                        .arg(arg!(<binary> "The binary file").value_hint(ValueHint::FilePath))
+                        .arg(arg!(--verify "Check the memory before jumping, with a CRC-32 computed by the stub or by reading it back"))
+                        .arg(arg!(--console "Print the output of the binary after jumping to it"))
+                        .args(console_args().map(|arg| arg.requires("console"))),
+                )
+                .subcommand(
+                    Command::new("patch")
+                        .about("Change a few places in memory, e.g. of something loaded earlier, and check them afterwards")
+                        .arg(arg!(<address> "Where the offsets in the patch start"))
+                        .arg(arg!(<patch> "The patch file").value_hint(ValueHint::FilePath))
+                        .arg(
+                            arg!(--format <FORMAT> "The format of the patch: text with `offset: bytes...` per line, or IPS")
+                                .required(false)
+                                .value_parser(patch::Format::parse)
+                                .default_value("text"),
+                        )
+                        .arg(arg!(--"no-verify" "Don't check the memory after writing each change")),
+                )
+                .subcommand(
+                    Command::new("reset")
+                        .about("Have the stub reset the device, which usually brings it back to the bootrom")
+                        .arg(arg!(--poweroff "Power the device off instead")),
+                )
+                .subcommand(
+                    Command::new("set-baud")
+                        .about("Switch the stub and the serial connection to a different baud rate")
+                        .arg(arg!(<rate> "The new baud rate").value_parser(BaudParser)),
+                )
+                .subcommand(
+                    Command::new("shell")
+                        .about("Run commands interactively over a single connection")
+                        .after_help(script::HELP),
+                )
+                .subcommand(
+                    Command::new("run")
+                        .about("Run the commands in a script over a single connection, stopping at the first error")
+                        .after_help(script::HELP)
+                        .arg(arg!(<script> "The script, with one command per line").value_hint(ValueHint::FilePath)),
+                ),
+        )
+        .subcommand(
+            Command::new("detect")
+                .about("Find out whether the device is in download mode or running bootstub")
+                .args(connection_args())
+                .arg(vendor_arg().conflicts_with("serial"))
+                .arg(print_device_arg().conflicts_with("serial")),
+        )
+        .subcommand(
+            Command::new("wait-for-device")
+                .about("Wait until a device appears")
+                .arg(
+                    arg!(--serial <PATH> "The serial port to wait for")
+                        .required(false)
+                        .value_hint(ValueHint::FilePath)
+                        .conflicts_with("usb"),
+                )
+                .arg(usb_arg())
+                .arg(
+                    arg!([SECONDS] "How long to wait before giving up [default: forever]")
+                        .value_parser(parse_timeout),
+                ),
+        )
+        .subcommand(
+            Command::new("profiles")
+                .about("Inspect the SoC profiles for --profile")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List the built-in profiles and the ones in the profiles directory"),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("show")
+                        .about("Print the configuration from the config file and the environment"),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    arg!(<shell> "The shell to generate the script for")
+                        .value_parser(PossibleValuesParser::new(["bash", "zsh", "fish"])),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Re-run a session recorded with --record against the recorded responses")
+                .arg(arg!(<log> "The session log").value_hint(ValueHint::FilePath)),
+        )
+        .subcommand(
+            Command::new("capture")
+                .about("Inspect session logs recorded with --capture")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("dump")
+                        .about("Print every transfer in a session log, with what it means in the protocol")
+                        .arg(arg!(<log> "The session log").value_hint(ValueHint::FilePath))
+                        .arg(arg!(--hexdump "Print the full data of every transfer")),
+                ),
+        )
+        .subcommand(
+            Command::new("udev-rule")
+                .about("Print a udev rule that allows access to Samsung devices without root"),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check the groups, udev rules, ModemManager and kernel drivers that decide whether devices can be used"),
+        )
+        .subcommand(
+            Command::new("simulate")
+                .about("Run a simulated bootstub on a pseudo-terminal, for testing without a device")
+                .arg(
+                    arg!(--data <FILE> "Serve the contents of this file (repeated) for dumps")
+                        .required(false)
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(arg!(--"corrupt-checksum" "Send wrong checksums along with the dumps"))
+                .arg(arg!(--legacy "Don't answer the capabilities query, like old stubs"))
+                .arg(arg!(--"debug-output" "Print a line of text before every response marker"))
+                .arg(
+                    arg!(--delay <MILLISECONDS> "Wait this long before every write")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--fragment <BYTES> "Split writes into pieces of this size")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64).range(1..)),
                 ),
         )
-        .arg(arg!(--device <ID> "The vendor and device ID to communicate with").required(false))
+        .arg(
+            arg!(--device <ID> "Deprecated, use --serial or --usb on the subcommand instead")
+                .required(false)
+                .value_hint(ValueHint::FilePath)
+                .value_parser(parse_device),
+        )
+        .arg(
+            arg!(--timeout <SECONDS> "How long to wait for responses from the device")
+                .required(false)
+                .value_parser(parse_timeout),
+        )
+        .arg(
+            arg!(--"handshake-timeout" <SECONDS> "How long to wait for the device to answer the handshake [default: --timeout]")
+                .required(false)
+                .value_parser(parse_timeout),
+        )
+        .arg(
+            arg!(--"transfer-timeout" <SECONDS> "How long to wait for data during large transfers [default: --timeout]")
+                .required(false)
+                .value_parser(parse_timeout),
+        )
+        .arg(
+            arg!(--retries <COUNT> "How often to try again after errors that may go away, e.g. corrupted pieces or stalled transfers [default: depends on the operation]")
+                .required(false)
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            arg!(--"retry-backoff" <SECONDS> "How long to wait before trying again, doubled for every further attempt")
+                .required(false)
+                .default_value("0")
+                .value_parser(parse_backoff),
+        )
+        .arg(
+            arg!(--wait [SECONDS] "Wait for the device to appear, optionally giving up after some time")
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .default_missing_value("0")
+                .value_parser(parse_wait),
+        )
+        .arg(
+            arg!(--"usb-debug" <LEVEL> "Make libusb log to stderr, from 0 (nothing) to 4 (everything)")
+                .required(false)
+                .value_parser(clap::value_parser!(u8).range(0..=4)),
+        )
+        .arg(
+            arg!(-v --verbose "Log protocol steps to stderr, repeat to also dump all transfers")
+                .action(clap::ArgAction::Count),
+        )
+        .arg(arg!(-q --quiet "Only print errors and the output that was asked for"))
+        .arg(arg!(--"raw-sizes" "Print sizes, durations and rates as exact integers, for scripts"))
+        .arg(
+            arg!(--"json-events" [FD] "Emit newline-delimited JSON events to stdout (or the given file descriptor)")
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .default_missing_value("1")
+                .value_parser(clap::value_parser!(i32).range(1..)),
+        )
+        .arg(
+            arg!(--record <FILE> "Record all traffic with the device into a session log")
+                .required(false)
+                .visible_alias("capture")
+                .value_hint(ValueHint::FilePath),
+        )
 }
 
-fn parse_id(string: &str) -> Result<u16, ParseIntError> {
-    u16::from_str_radix(string, 16)
+// For telling a payload that never says anything from one that is just
+// quiet.
+fn console_args() -> [Arg<'static>; 2] {
+    [
+        arg!(--"first-output-timeout" <SECONDS> "Warn if the binary hasn't printed anything this long after jumping to it")
+            .required(false)
+            .value_parser(parse_timeout),
+        arg!(--"exit-on-silence" "Fail instead of listening on once the first output timeout passed")
+            .requires("first-output-timeout"),
+    ]
+}
+
+// How to reach a device that may be running bootstub.
+fn connection_args() -> Vec<Arg<'static>> {
+    let mut args = vec![
+        arg!(--serial <PATH> "The serial port (or tcp:host:port) to communicate with")
+            .required(false)
+            .value_hint(ValueHint::FilePath)
+            .conflicts_with("usb"),
+        usb_arg(),
+    ];
+
+    args.extend(usb_selector_args().map(|arg| arg.conflicts_with("serial")));
+    args.push(
+        arg!(--baud <RATE> "The baud rate of the serial connection [default: 115200]")
+            .required(false)
+            .value_parser(BaudParser),
+    );
+    args.extend(lock_args());
+
+    args
+}
+
+fn lock_args() -> [Arg<'static>; 2] {
+    [
+        arg!(--"no-lock" "Don't take exclusive access of the device"),
+        arg!(--"wait-lock" "Wait for other invocations to be done with the device instead of failing")
+            .conflicts_with("no-lock"),
+    ]
 }
 
-fn parse_u64(string: &str) -> Result<u64, ParseIntError> {
-    if string.starts_with("0x") || string.starts_with("0X") {
-        Ok(u64::from_str_radix(&string[2..], 16))?
+fn lock_mode(sub_matches: &ArgMatches) -> LockMode {
+    if sub_matches.is_present("no-lock") {
+        LockMode::Off
+    } else if sub_matches.is_present("wait-lock") {
+        LockMode::Wait
     } else {
-        Ok(u64::from_str_radix(string, 10))?
+        LockMode::Fail
     }
 }
 
-fn list_devices(vendor_id: u16) {
-    for device in rusb::devices().unwrap().iter() {
-        let device_desc = device.device_descriptor().unwrap();
+fn usb_arg() -> Arg<'static> {
+    arg!(--usb <ID> "The USB vendor and product ID to communicate with (vendor:product)")
+        .required(false)
+        .value_parser(parse_usb_arg)
+}
 
-        if device_desc.vendor_id() != vendor_id {
-            continue;
-        }
+// Tell identical devices apart, as shown by list-devices, and configure
+// them once they have been found.
+fn usb_selector_args() -> [Arg<'static>; 5] {
+    [
+        arg!(--"no-cdc-setup" "Don't send the CDC line coding and control line requests to USB devices"),
+        arg!(--"serial-number" <SERIAL> "The USB serial number of the device to use")
+            .required(false),
+        arg!(--"bus-address" <BUS_ADDRESS> "The USB bus and address of the device to use (bus:address)")
+            .required(false)
+            .value_parser(parse_bus_address_arg),
+        arg!(--"device-spec" <SPEC> "The device to use, as printed by --print-device (e.g. usb=04e8:685d,serial-number=R58M123456)")
+            .required(false)
+            .value_parser(DeviceSpec::parse)
+            .conflicts_with_all(&["usb", "serial-number", "bus-address"]),
+        arg!(--"usb-interface" <N> "The number of the USB interface to talk to, for devices where the wrong one is picked")
+            .required(false)
+            .value_parser(clap::value_parser!(u8)),
+    ]
+}
 
-        let vendor_name = match usb_ids::Vendor::from_id(device_desc.vendor_id()) {
-            Some(vendor) => vendor.name(),
-            None => "Unknown vendor",
-        };
+// For scripts to find a device once and pass it to later invocations with
+// --device-spec.
+fn print_device_arg() -> Arg<'static> {
+    arg!(--"print-device" "Only print the device, as a single line for --device-spec")
+}
 
-        let product_name = match usb_ids::Device::from_vid_pid(
-            device_desc.vendor_id(),
-            device_desc.product_id(),
-        ) {
-            Some(product) => product.name(),
-            None => "Unknown product",
-        };
+// Download mode can't read partitions back, so the most that can be checked
+// after flashing is that the device still answers and its PIT is intact.
+fn verify_after_arg() -> Arg<'static> {
+    arg!(--"verify-after" [HOW] "After flashing, begin a new session and check that the PIT is intact, with reboot going through a reboot into download mode first")
+        .min_values(0)
+        .max_values(1)
+        .require_equals(true)
+        .default_missing_value("session")
+        .value_parser(PossibleValuesParser::new(["session", "reboot"]))
+}
 
-        println!(
-            "[{:04x}:{:04x}] {}, {}",
-            device_desc.vendor_id(),
-            device_desc.product_id(),
-            vendor_name,
-            product_name,
-        );
+// The device options, from --device-spec or the separate ones.
+fn device_spec(sub_matches: &ArgMatches, usb_id: Option<(u16, u16)>) -> DeviceSpec {
+    match sub_matches.get_one::<DeviceSpec>("device-spec") {
+        Some(spec) => DeviceSpec {
+            usb_id: spec.usb_id.or(usb_id),
+            ..spec.clone()
+        },
+        None => DeviceSpec {
+            usb_id,
+            serial_number: sub_matches.get_one::<String>("serial-number").cloned(),
+            bus_address: sub_matches.get_one::<(u8, u8)>("bus-address").copied(),
+        },
     }
 }
 
-fn main() {
-    let matches = cli().get_matches();
+// Which devices are looked for without --usb.
+fn vendor_arg() -> Arg<'static> {
+    arg!(--vendor <VENDOR> "Look for devices of these vendors without --usb, by hexadecimal ID or part of the name, instead of the configured ones [default: 04e8]")
+        .required(false)
+        .multiple_occurrences(true)
+        .use_value_delimiter(true)
+        .value_parser(Vendor::parse)
+        .conflicts_with("usb")
+}
 
-    match matches.subcommand() {
-        Some(("list-devices", sub_matches)) => {
-            let vendor_id = match parse_id(sub_matches.get_one::<String>("id").unwrap()) {
-                Ok(vendor_id) => vendor_id,
-                Err(_) => {
-                    panic!("Invalid vendor ID")
-                }
-            };
+// The vendors from --vendor, or otherwise from the configuration.
+#[cfg(feature = "usb")]
+fn vendors(sub_matches: &ArgMatches, config: &Config) -> VendorAllowList {
+    match sub_matches.try_get_many::<Vendor>("vendor").ok().flatten() {
+        Some(vendors) => VendorAllowList::new(vendors.cloned().collect()),
+        None => config
+            .vendors
+            .as_ref()
+            .map(|setting| setting.value.clone())
+            .unwrap_or_default(),
+    }
+}
 
-            list_devices(vendor_id);
-            return;
-        }
-        Some(("bootstub", sub_matches)) => {
-            let device_path = matches.value_of("device").unwrap();
-            let mut device = File::options()
-                .read(true)
-                .write(true)
-                .open(device_path)
-                .unwrap();
-
-            let fd = device.as_raw_fd();
-            let mut termios = Termios::from_fd(fd).unwrap();
-
-            cfsetspeed(&mut termios, B115200).unwrap();
-
-            // Set options for "raw" mode (similar to cfmakeraw).
-            termios.c_iflag &= !(IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON);
-            termios.c_oflag &= !(OPOST);
-            termios.c_lflag &= !(ECHO | ECHONL | ICANON | ISIG | IEXTEN);
-            termios.c_cflag &= !(CSIZE | PARENB);
-            termios.c_cflag |= CS8;
-
-            tcsetattr(fd, TCSANOW, &termios).unwrap();
-            tcflush(fd, TCIOFLUSH).unwrap();
-
-            // Try the handshake.
-            device
-                .write(&[b'W', b'H', b'O', b'I', b'S', b'D', b'I', b'S'])
-                .unwrap();
-            let mut buf = [0u8; 16 * 1024];
-            let handshake_end_offset = device.read(&mut buf).unwrap();
-            let mut handshake_response = [0u8; 8];
-            handshake_response
-                .clone_from_slice(&buf[handshake_end_offset - 8..handshake_end_offset]);
-            assert_eq!(
-                handshake_response,
-                [b'B', b'O', b'O', b'T', b'S', b'T', b'U', b'B'],
-                "Protocol hello response not as expected: {:?}",
-                handshake_response
-            );
+// The CDC class requests are sent unless --no-cdc-setup was given.
+#[cfg(feature = "usb")]
+fn line_coding(sub_matches: &ArgMatches) -> Option<LineCoding> {
+    if sub_matches.is_present("no-cdc-setup") {
+        None
+    } else {
+        Some(LineCoding::default())
+    }
+}
 
-            match sub_matches.subcommand() {
-                Some(("dump", sub_matches)) => {
-                    let start_address_str = sub_matches.value_of("start").unwrap();
-                    let end_address_str = sub_matches.value_of("end").unwrap();
-                    let output_path = sub_matches.value_of("output").unwrap();
-
-                    let start_address = parse_u64(start_address_str).unwrap();
-                    let end_address = parse_u64(end_address_str).unwrap();
-
-                    let mut output = File::options()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(output_path)
-                        .unwrap();
-
-                    device
-                        .write(&[b'U', b'P', b'L', b'D', b'M', b'E', b'M'])
-                        .unwrap();
-                    std::thread::sleep(Duration::from_millis(100));
-                    device.write(start_address_str.as_bytes()).unwrap();
-                    std::thread::sleep(Duration::from_millis(100));
-                    device.write(end_address_str.as_bytes()).unwrap();
-                    std::thread::sleep(Duration::from_millis(100));
-
-                    // Ensure that the device accepted the upload.
-                    let mut buf = [0u8; 8];
-                    device.read(&mut buf).unwrap();
-                    assert_eq!(
-                        buf[0..8],
-                        [b'S', b'T', b'R', b'T', b'U', b'P', b'L', b'D'],
-                        "Upload start response not as expected: {:?}",
-                        buf
-                    );
-
-                    let mut remaining = end_address - start_address;
-                    let mut checksum = 0u8;
-
-                    loop {
-                        let mut value = [0u8; 1];
-                        device.read(&mut value).unwrap();
-                        checksum ^= value[0];
-
-                        if remaining > 0 {
-                            output.write(&value).unwrap();
-                        } else {
-                            break;
-                        }
+#[cfg(feature = "usb")]
+fn usb_selector(sub_matches: &ArgMatches, config: &Config, usb_id: Option<(u16, u16)>) -> Selector {
+    Selector {
+        vendors: vendors(sub_matches, config),
+        interface: sub_matches
+            .try_get_one::<u8>("usb-interface")
+            .ok()
+            .flatten()
+            .copied(),
+        ..Selector::from(device_spec(sub_matches, usb_id))
+    }
+}
 
-                        remaining -= 1;
-                    }
+// Parses baud rates like serial::parse_baud, but also lets the completions
+// know about the valid rates.
+#[derive(Clone)]
+struct BaudParser;
 
-                    if checksum != 0 {
-                        println!("Checksum does not match: {:#02x}", checksum);
-                    }
+impl TypedValueParser for BaudParser {
+    type Value = u32;
 
-                    // Check end of transfer.
-                    let mut buf = [0u8; 7];
-                    device.read(&mut buf).unwrap();
-                    assert_eq!(
-                        buf[0..7],
-                        [b'E', b'N', b'D', b'U', b'P', b'L', b'D'],
-                        "Upload end response not as expected: {:?}",
-                        buf
-                    );
-                }
-                Some(("boot", sub_matches)) => {
-                    let binary_path = sub_matches.value_of("binary").unwrap();
-
-                    let mut binary = File::options()
-                        .read(true)
-                        .write(false)
-                        .create(false)
-                        .truncate(false)
-                        .open(binary_path)
-                        .unwrap();
-                    let mut binary_size = binary.metadata().unwrap().len();
-
-                    device
-                        .write(&[b'B', b'O', b'O', b'T', b'F', b'I', b'L', b'E'])
-                        .unwrap();
-                    std::thread::sleep(Duration::from_millis(100));
-                    device.write(format!("{:#x}", binary_size).as_bytes()).unwrap();
-                    std::thread::sleep(Duration::from_millis(100));
-
-                    // Ensure that the device accepted the upload.
-                    let mut buf = [0u8; 8];
-                    device.read(&mut buf).unwrap();
-                    assert_eq!(
-                        buf[0..8],
-                        [b'S', b'T', b'R', b'T', b'U', b'P', b'L', b'D'],
-                        "Upload start response not as expected: {:?}",
-                        buf
-                    );
-
-                    loop {
-                        let mut value = [0u8; 1];
-                        binary.read(&mut value).unwrap();
-                        device.write(&value).unwrap();
-
-                        if binary_size % 256 == 0 {
-                            // Ensure that the same byte is sent back to confirm that it was received.
-                            let mut returned_value = [0u8; 1];
-                            device.read(&mut returned_value).unwrap();
-
-                            assert_eq!(value[0], returned_value[0], "Device did not echo back the correct byte");
-                        }
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        arg: Option<&Arg>,
+        value: &std::ffi::OsStr,
+    ) -> std::result::Result<u32, clap::Error> {
+        serial::parse_baud.parse_ref(cmd, arg, value)
+    }
 
-                        binary_size -= 1;
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue<'static>> + '_>> {
+        static NAMES: OnceLock<Vec<String>> = OnceLock::new();
 
-                        if binary_size == 0 {
-                            break;
-                        }
-                    }
+        let names = NAMES.get_or_init(|| serial::BAUD_RATES.iter().map(u32::to_string).collect());
 
-                    // Check end of transfer.
-                    let mut buf = [0u8; 7];
-                    device.read(&mut buf).unwrap();
-                    assert_eq!(
-                        buf[0..7],
-                        [b'E', b'N', b'D', b'U', b'P', b'L', b'D'],
-                        "Upload end response not as expected: {:?}",
-                        buf
-                    );
-
-                    loop {
-                        let mut value = [0u8; 1];
-                        device.read(&mut value).unwrap();
-                        print!("{}", value[0] as char);
-                    }
-                }
-                _ => unreachable!(),
-            }
+        Some(Box::new(
+            names.iter().map(|name| PossibleValue::new(name.as_str())),
+        ))
+    }
+}
 
-            return;
-        }
-        _ => {}
+#[derive(Clone)]
+enum DeviceArg {
+    Serial(String),
+    Usb(u16, u16),
+}
+
+fn parse_id(string: &str) -> std::result::Result<u16, ParseIntError> {
+    u16::from_str_radix(string, 16)
+}
+
+// Matches vendors or products by their ID or by a part of their name.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "usb"), allow(dead_code))]
+struct IdFilter {
+    pattern: String,
+    id: Option<u16>,
+}
+
+#[cfg(feature = "usb")]
+impl IdFilter {
+    fn matches(&self, id: u16, name: Option<&str>) -> bool {
+        self.id == Some(id) || name.is_some_and(|name| name.to_lowercase().contains(&self.pattern))
+    }
+}
+
+fn parse_id_filter(string: &str) -> std::result::Result<IdFilter, String> {
+    if string.is_empty() {
+        return Err("expected a hexadecimal ID or a name".to_string());
     }
 
-    let mut id_split = matches.value_of("device").unwrap().split(':');
+    Ok(IdFilter {
+        pattern: string.to_lowercase(),
+        id: parse_id(string).ok(),
+    })
+}
 
-    let vendor_id = match parse_id(id_split.next().unwrap()) {
-        Ok(id) => id,
-        Err(_) => {
-            panic!("Invalid vendor ID")
-        }
+fn parse_usb_arg(string: &str) -> std::result::Result<(u16, u16), String> {
+    parse_usb_id(string).ok_or_else(|| {
+        format!(
+            "'{}' is not a USB ID, expected the hexadecimal vendor and product ID (e.g. 04e8:685d)",
+            string
+        )
+    })
+}
+
+fn parse_sha256_arg(string: &str) -> std::result::Result<[u8; 32], String> {
+    sha256::parse_hex(string).ok_or_else(|| {
+        format!(
+            "'{}' is not a SHA-256, expected 64 hexadecimal digits",
+            string
+        )
+    })
+}
+
+fn parse_bus_address_arg(string: &str) -> std::result::Result<(u8, u8), String> {
+    parse_bus_address(string).ok_or_else(|| {
+        format!(
+            "'{}' is not a bus address, expected the decimal bus and address (e.g. 1:4)",
+            string
+        )
+    })
+}
+
+// The deprecated --device option accepts both kinds of devices, so guess
+// based on the format.
+fn parse_device(string: &str) -> std::result::Result<DeviceArg, String> {
+    match parse_usb_id(string) {
+        Some((vendor_id, product_id)) => Ok(DeviceArg::Usb(vendor_id, product_id)),
+        None => Ok(DeviceArg::Serial(string.to_string())),
+    }
+}
+
+// Sizes of pieces, which have to be of some size.
+fn parse_size(string: &str) -> std::result::Result<u64, String> {
+    match parse_u64(string)? {
+        0 => Err("the size can't be zero".to_string()),
+        size => Ok(size),
+    }
+}
+
+fn parse_access_width(string: &str) -> std::result::Result<u32, String> {
+    match string {
+        "1" | "2" | "4" | "8" => Ok(string.parse().unwrap()),
+        _ => Err("the access width has to be 1, 2, 4 or 8 bytes".to_string()),
+    }
+}
+
+// Names are looked up in the profile, if there is one.
+fn parse_address(string: &str, what: &str, profile: Option<&Profile>) -> Result<u64> {
+    expr::evaluate(string, |name| {
+        profile.and_then(|profile| profile.lookup(name))
+    })
+    .map_err(|err| Error::InvalidArgument(format!("Invalid {}: {}", what, err)))
+}
+
+// Accessing some regions may hang or reset the device, which is worth a
+// question first.
+fn check_dangerous(profile: Option<&Profile>, start: u64, end: u64, confirmed: bool) -> Result<()> {
+    let Some(profile) = profile else {
+        return Ok(());
     };
 
-    let device_id = match u16::from_str_radix(id_split.next().unwrap(), 16) {
-        Ok(id) => id,
-        Err(_) => {
-            panic!("Invalid device ID")
+    for region in profile.dangerous_regions(start, end) {
+        let reason = region
+            .description
+            .as_ref()
+            .map(|description| format!(" ({})", description))
+            .unwrap_or_default();
+
+        if confirmed {
+            warning!("Accessing {} of {}{}", region.name, profile.name, reason);
+            continue;
         }
-    };
 
-    let device_handle = rusb::open_device_with_vid_pid(vendor_id, device_id)
-        .expect("Device not found or not openable");
+        picker::confirm(
+            &format!(
+                "{:#x}-{:#x} covers {} of {}, which is marked as dangerous{}",
+                start, end, region.name, profile.name, reason
+            ),
+            "pass --yes to go ahead anyway",
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "usb")]
+fn device_info(device: &rusb::Device<rusb::GlobalContext>) -> Option<DeviceInfo> {
+    match DeviceInfo::new(device) {
+        Ok(info) => Some(info),
+        Err(err) => {
+            step!(
+                "skipping the device at bus-address {}:{}: {}",
+                device.bus_number(),
+                device.address(),
+                err
+            );
+            None
+        }
+    }
+}
 
-    let mut device = device::UsbCdcDevice::from_handle(device_handle).unwrap();
+// The first line of a device in the listing.
+#[cfg(feature = "usb")]
+fn device_summary(info: &DeviceInfo) -> String {
+    let summary = format!(
+        "[{:04x}:{:04x}] {}, {}",
+        info.vendor_id,
+        info.product_id,
+        info.vendor_name.unwrap_or("Unknown vendor"),
+        info.product_name.unwrap_or("Unknown product")
+    );
 
-    device.setup_interface().unwrap();
+    match info.mode {
+        Some(mode) => format!("{} ({})", summary, mode),
+        None => summary,
+    }
+}
 
-    match matches.subcommand() {
-        Some(("download", sub_matches)) => {
-            device
-                .write(&[0x4f, 0x44, 0x49, 0x4e], Duration::from_secs(1))
-                .unwrap();
+#[cfg(feature = "usb")]
+fn print_device(info: &DeviceInfo) {
+    say!("{}", device_summary(info));
 
-            let mut hello_response = [0u8; 4];
+    match &info.port {
+        Some(port) => say!(
+            "    bus-address {}:{}, port {}",
+            info.bus,
+            info.address,
+            port
+        ),
+        None => say!("    bus-address {}:{}", info.bus, info.address),
+    }
 
-            device
-                .read(&mut hello_response, Duration::from_secs(1))
-                .unwrap();
+    match &info.strings {
+        Some(strings) => {
+            let string =
+                |string: &Option<String>| string.clone().unwrap_or_else(|| "none".to_string());
 
-            assert_eq!(
-                hello_response[0..4],
-                [0x4C, 0x4F, 0x4B, 0x45],
-                "Protocol hello response not as expected: {:?}",
-                hello_response
+            say!(
+                "    manufacturer {:?}, product {:?}, serial {:?}",
+                string(&strings.manufacturer),
+                string(&strings.product),
+                string(&strings.serial_number)
             );
+        }
+        None => say!("    strings unreadable, check the permissions on the device"),
+    }
+}
 
-            device
-                .write_packet(
-                    &[0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-                    1024,
-                    Duration::from_secs(1),
-                )
-                .unwrap();
+#[cfg(feature = "usb")]
+fn matching_devices(
+    vendors: Option<&VendorAllowList>,
+    product: Option<&IdFilter>,
+) -> Result<Vec<DeviceInfo>> {
+    Ok(rusb::devices()?
+        .iter()
+        .filter_map(|device| device_info(&device))
+        .filter(|info| device_matches(info, vendors, product))
+        .collect())
+}
 
-            device
-                .read(&mut [0u8; 1024], Duration::from_secs(1))
-                .unwrap();
+#[cfg(feature = "usb")]
+fn device_matches(
+    info: &DeviceInfo,
+    vendors: Option<&VendorAllowList>,
+    product: Option<&IdFilter>,
+) -> bool {
+    vendors.is_none_or(|vendors| vendors.matches(info.vendor_id, info.vendor_name))
+        && product.is_none_or(|product| product.matches(info.product_id, info.product_name))
+}
 
-            match sub_matches.subcommand() {
-                Some(("reboot", _)) => {
-                    // Does nothing, we will reboot at the end of the session anyways.
-                }
-                _ => unreachable!(),
-            }
+// Shows the descriptors of a device, for finding out why it can't be talked
+// to, e.g. because its data interface isn't of the CDC data class.
+#[cfg(feature = "usb")]
+fn usb_info(sub_matches: &ArgMatches, config: &Config) -> Result<()> {
+    let selector = usb_selector(
+        sub_matches,
+        config,
+        sub_matches.get_one::<(u16, u16)>("usb").copied(),
+    );
 
-            device
-                .write_packet(
-                    &[0x67, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
-                    1024,
-                    Duration::from_secs(1),
-                )
-                .unwrap();
+    let candidates = device::find_any(&selector)?;
+    if candidates.is_empty() {
+        return Err(Error::DeviceNotFound(format!("No {} found", selector)));
+    }
+    let candidate = picker::choose(
+        "devices",
+        candidates,
+        "select one with --usb, --serial-number or --bus-address",
+    )?;
+
+    let descriptor = candidate.device.device_descriptor()?;
+    let settings = device::settings(&candidate.device)?;
+    let choice = interfaces::choose(&settings, selector.interface);
+
+    events::emit(
+        "usb_info",
+        Object::new()
+            .field("vendor_id", format!("{:04x}", candidate.vendor_id))
+            .field("product_id", format!("{:04x}", candidate.product_id))
+            .field("bus", candidate.bus)
+            .field("address", candidate.address)
+            .field("class", format!("{:#04x}", descriptor.class_code()))
+            .field("configurations", descriptor.num_configurations())
+            .field(
+                "interfaces",
+                settings
+                    .iter()
+                    .enumerate()
+                    .map(|(index, setting)| {
+                        setting
+                            .to_object()
+                            .field("picked", choice.picked == Some(index))
+                            .field("rejected", choice.reasons[index].clone())
+                            .field(
+                                "control",
+                                choice.control_interface == Some(setting.interface),
+                            )
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+    );
+
+    let class = |class: u8| match interfaces::class_name(class) {
+        Some(name) => format!("{:#04x} ({})", class, name),
+        None => format!("{:#04x}", class),
+    };
+    let version = descriptor.usb_version();
+
+    say!("{}", candidate);
+    say!(
+        "USB {}.{}, device class {}, maximum packet size {} for endpoint 0",
+        version.major(),
+        version.minor(),
+        class(descriptor.class_code()),
+        descriptor.max_packet_size()
+    );
+    if descriptor.num_configurations() > 1 {
+        say!(
+            "Only the first of the {} configurations is looked at",
+            descriptor.num_configurations()
+        );
+    }
 
-            device
-                .read(&mut [0u8; 1024], Duration::from_secs(1))
-                .unwrap();
+    for (index, setting) in settings.iter().enumerate() {
+        say!(
+            "Interface {}, setting {}: class {}, subclass {:#04x}, protocol {:#04x}",
+            setting.interface,
+            setting.setting,
+            class(setting.class),
+            setting.subclass,
+            setting.protocol
+        );
+        for endpoint in &setting.endpoints {
+            say!(
+                "    endpoint {:#04x}: {} {}, {} bytes per packet",
+                endpoint.address,
+                endpoint.direction_name(),
+                endpoint.transfer_type_name(),
+                endpoint.max_packet_size
+            );
         }
-        _ => unreachable!(),
+
+        match &choice.reasons[index] {
+            None => say!("    -> talked to"),
+            Some(reason) => say!("    -> passed over: {}", reason),
+        }
+        if choice.control_interface == Some(setting.interface) {
+            say!("    -> takes the class requests");
+        }
+    }
+
+    match selector.interface {
+        _ if choice.picked.is_some() => {}
+        Some(interface) => say!(
+            "Interface {} can't be talked to, it needs a setting with a pair of bulk endpoints",
+            interface
+        ),
+        None => say!("None of the interfaces can be talked to, sbootil needs a CDC data interface (class 0x0a) or a vendor specific one (class 0xff) with a pair of bulk endpoints, or one given with --usb-interface"),
+    }
+
+    Ok(())
+}
+
+// Prints nothing but the spec of the one matching device, so that scripts
+// can capture it.
+#[cfg(feature = "usb")]
+fn print_only_device(vendors: Option<&VendorAllowList>, product: Option<&IdFilter>) -> Result<()> {
+    let mut infos = matching_devices(vendors, product)?;
+
+    if infos.is_empty() {
+        return Err(Error::DeviceNotFound(
+            "No matching device found".to_string(),
+        ));
     }
 
-    device.teardown_interface().unwrap();
+    let labels = infos
+        .iter()
+        .map(|info| {
+            format!(
+                "{}, bus-address {}:{}",
+                device_summary(info),
+                info.bus,
+                info.address
+            )
+        })
+        .collect::<Vec<_>>();
+    let chosen = picker::choose(
+        "devices",
+        labels.clone(),
+        "narrow them down with --vendor or --product",
+    )?;
+    let info = infos.swap_remove(labels.iter().position(|label| *label == chosen).unwrap());
+
+    println!("{}", info.spec());
+
+    Ok(())
+}
+
+#[cfg(feature = "usb")]
+fn list_devices(
+    vendors: Option<&VendorAllowList>,
+    product: Option<&IdFilter>,
+    format: &str,
+    watch: bool,
+) -> Result<()> {
+    let matches = |info: &DeviceInfo| device_matches(info, vendors, product);
+
+    let infos = matching_devices(vendors, product)?;
+
+    if format == "json" {
+        let objects = infos.iter().map(DeviceInfo::to_object).collect::<Vec<_>>();
+        println!("{}", objects.to_json());
+    } else {
+        for info in &infos {
+            print_device(info);
+        }
+    }
+
+    if !watch {
+        return Ok(());
+    }
+
+    // Devices that have left can't be asked about themselves anymore, so
+    // remember what they were.
+    let mut seen = infos
+        .into_iter()
+        .map(|info| ((info.bus, info.address), info))
+        .collect::<HashMap<_, _>>();
+
+    let report = |event: &str, sign: char, info: &DeviceInfo| {
+        if format == "json" {
+            println!(
+                "{}",
+                Object::new().field("event", event).merge(info.to_object())
+            );
+        } else {
+            say!(
+                "{} {}, bus-address {}:{}",
+                sign,
+                device_summary(info),
+                info.bus,
+                info.address
+            );
+        }
+    };
+
+    hotplug::watch(|event| {
+        match event {
+            hotplug::Event::Arrived(device) => {
+                if let Some(info) = device_info(&device).filter(|info| matches(info)) {
+                    report("attached", '+', &info);
+                    seen.insert((info.bus, info.address), info);
+                }
+            }
+            hotplug::Event::Left(device) => {
+                if let Some(info) = seen.remove(&(device.bus_number(), device.address())) {
+                    report("detached", '-', &info);
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(feature = "usb")]
+fn list_devices_command(sub_matches: &ArgMatches, config: &Config) -> Result<()> {
+    let vendors = match sub_matches.get_one::<Vendor>("id") {
+        Some(vendor) => VendorAllowList::new(vec![vendor.clone()]),
+        None => vendors(sub_matches, config),
+    };
+
+    let vendors = Some(&vendors).filter(|_| !sub_matches.is_present("all"));
+    let product = sub_matches.get_one::<IdFilter>("product");
+
+    if sub_matches.is_present("print-device") {
+        return print_only_device(vendors, product);
+    }
+
+    list_devices(
+        vendors,
+        product,
+        sub_matches.get_one::<String>("format").unwrap(),
+        sub_matches.is_present("watch"),
+    )
+}
+
+// The arguments to record into a session log, which are the ones that this
+// invocation has been started with, minus the recording itself.
+fn recorded_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut skip_value = false;
+
+    for arg in std::env::args().skip(1) {
+        if skip_value {
+            skip_value = false;
+        } else if arg == "--record" || arg == "--capture" {
+            skip_value = true;
+        } else if !arg.starts_with("--record=") && !arg.starts_with("--capture=") {
+            args.push(arg);
+        }
+    }
+
+    args
+}
+
+const DEFAULT_BAUD: u32 = 115200;
+
+// The protocols have different defaults, which are replaced by --timeout and
+// then refined by the options for specific phases.
+fn timeouts(
+    matches: &ArgMatches,
+    config: &Config,
+    base: Option<Duration>,
+    default: Duration,
+) -> Timeouts {
+    let base = matches
+        .get_one::<Duration>("timeout")
+        .copied()
+        .or(base)
+        .or(config.timeout.as_ref().map(|setting| setting.value))
+        .unwrap_or(default);
+    let mut timeouts = Timeouts::new(base);
+
+    if let Some(timeout) = matches
+        .get_one::<Duration>("handshake-timeout")
+        .copied()
+        .or(config
+            .handshake_timeout
+            .as_ref()
+            .map(|setting| setting.value))
+    {
+        timeouts.handshake = timeout;
+    }
+
+    if let Some(timeout) = matches
+        .get_one::<Duration>("transfer-timeout")
+        .copied()
+        .or(config
+            .transfer_timeout
+            .as_ref()
+            .map(|setting| setting.value))
+    {
+        timeouts.transfer = timeout;
+    }
+
+    timeouts
+}
+
+// Like the timeouts, each place that retries has its own default.
+fn retry_policy(matches: &ArgMatches, default_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        attempts: matches
+            .get_one::<u32>("retries")
+            .copied()
+            .unwrap_or(default_attempts),
+        backoff: *matches.get_one::<Duration>("retry-backoff").unwrap(),
+        max_elapsed: None,
+    }
+}
+
+fn open_transport(
+    matches: &ArgMatches,
+    replay: Option<MockTransport>,
+    open: impl FnOnce() -> Result<Box<dyn Transport>>,
+) -> Result<Box<dyn Transport>> {
+    let device: Box<dyn Transport> = match replay {
+        Some(transport) => Box::new(transport),
+        None => open()?,
+    };
+    let device: Box<dyn Transport> = Box::new(CountingTransport::new(device));
+
+    let device: Box<dyn Transport> = match matches.get_one::<String>("record") {
+        Some(path) => Box::new(RecordingTransport::create(device, path, &recorded_args())?),
+        None => device,
+    };
+
+    if log::enabled(Level::Transfers) {
+        return Ok(Box::new(TracingTransport::new(device)));
+    }
+
+    Ok(device)
+}
+
+fn bootstub_device_arg(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+) -> Result<DeviceArg> {
+    given_device_arg(matches, sub_matches, config).ok_or_else(|| {
+        Error::InvalidArgument("No device given, use --serial <PATH> or --usb <ID>".to_string())
+    })
+}
+
+// The device from the command line, or otherwise from the configuration.
+fn given_device_arg(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+) -> Option<DeviceArg> {
+    let serial_path = sub_matches.get_one::<String>("serial").cloned();
+    let usb_id = sub_matches
+        .get_one::<(u16, u16)>("usb")
+        .copied()
+        .or_else(|| device_spec(sub_matches, None).usb_id);
+
+    let device_arg = match (serial_path, usb_id) {
+        (Some(path), _) => DeviceArg::Serial(path),
+        (_, Some((vendor_id, product_id))) => DeviceArg::Usb(vendor_id, product_id),
+        _ => match (
+            matches.get_one::<DeviceArg>("device"),
+            &config.serial,
+            &config.usb,
+        ) {
+            (Some(device_arg), _, _) => device_arg.clone(),
+            // If both are configured, the one from the more specific source wins.
+            (None, Some(serial), Some(usb))
+                if usb.source.precedence() > serial.source.precedence() =>
+            {
+                DeviceArg::Usb(usb.value.0, usb.value.1)
+            }
+            (None, Some(serial), _) => DeviceArg::Serial(serial.value.clone()),
+            (None, None, Some(usb)) => DeviceArg::Usb(usb.value.0, usb.value.1),
+            (None, None, None) => return None,
+        },
+    };
+
+    Some(device_arg)
+}
+
+// Parses the value of --wait, where zero stands for waiting forever.
+fn parse_wait(string: &str) -> std::result::Result<Duration, String> {
+    if string == "0" {
+        return Ok(Duration::ZERO);
+    }
+
+    parse_timeout(string)
+}
+
+fn wait_for_device(device_arg: &DeviceArg, timeout: Option<Duration>) -> Result<()> {
+    let target = match device_arg {
+        DeviceArg::Usb(vendor_id, product_id) => wait::Target::Usb(*vendor_id, *product_id),
+        DeviceArg::Serial(path) if path.starts_with("tcp:") => {
+            return Err(Error::Unsupported(
+                "Waiting for devices is not supported on TCP connections".to_string(),
+            ))
+        }
+        DeviceArg::Serial(path) => wait::Target::Serial(path.into()),
+    };
+
+    let interrupt = cancel::cancel_on_interrupt();
+    wait::wait_for(&target, timeout, interrupt.token())
+}
+
+// The timeout from --wait if it was given, which is None for waiting
+// forever.
+fn wait_timeout(matches: &ArgMatches) -> Option<Option<Duration>> {
+    matches
+        .get_one::<Duration>("wait")
+        .map(|timeout| Some(*timeout).filter(|timeout| !timeout.is_zero()))
+}
+
+// Waits for the device if --wait was given.
+fn maybe_wait_for_device(matches: &ArgMatches, device_arg: &DeviceArg) -> Result<()> {
+    match wait_timeout(matches) {
+        Some(timeout) => wait_for_device(device_arg, timeout),
+        None => Ok(()),
+    }
+}
+
+// Waits for a device that the selector may pick if --wait was given, which
+// is one of the allowed vendors without an ID.
+#[cfg(feature = "usb")]
+fn maybe_wait_for_selected(matches: &ArgMatches, selector: &Selector) -> Result<()> {
+    let target = match selector.usb_id {
+        Some((vendor_id, product_id)) => wait::Target::Usb(vendor_id, product_id),
+        None => wait::Target::Vendors(selector.vendors.clone()),
+    };
+
+    match wait_timeout(matches) {
+        Some(timeout) => {
+            let interrupt = cancel::cancel_on_interrupt();
+            wait::wait_for(&target, timeout, interrupt.token())
+        }
+        None => Ok(()),
+    }
+}
+
+fn baud(sub_matches: &ArgMatches, config: &Config) -> u32 {
+    sub_matches
+        .get_one::<u32>("baud")
+        .copied()
+        .or(config.baud.as_ref().map(|setting| setting.value))
+        .unwrap_or(DEFAULT_BAUD)
+}
+
+// How the device of a dump was reached, for its metadata.
+fn device_identity(device_arg: &DeviceArg, sub_matches: &ArgMatches, config: &Config) -> Object {
+    match device_arg {
+        DeviceArg::Serial(path) => match path.strip_prefix("tcp:") {
+            Some(address) => Object::new()
+                .field("transport", "tcp")
+                .field("address", address),
+            None => Object::new()
+                .field("transport", "serial")
+                .field("path", path)
+                .field(
+                    "resolved_path",
+                    std::fs::canonicalize(path)
+                        .ok()
+                        .map(|path| path.display().to_string()),
+                )
+                .field("baud", baud(sub_matches, config)),
+        },
+        DeviceArg::Usb(vendor_id, product_id) => {
+            let spec = device_spec(sub_matches, Some((*vendor_id, *product_id)));
+
+            Object::new()
+                .field("transport", "usb")
+                .field("usb_id", format!("{:04x}:{:04x}", vendor_id, product_id))
+                .field("serial_number", spec.serial_number)
+                .field(
+                    "bus_address",
+                    spec.bus_address
+                        .map(|(bus, address)| format!("{}:{}", bus, address)),
+                )
+        }
+    }
+}
+
+fn open_bootstub_device(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+) -> Result<Box<dyn Transport>> {
+    let device_arg = bootstub_device_arg(matches, sub_matches, config)?;
+
+    open_connection(matches, sub_matches, config, device_arg)
+}
+
+fn open_connection(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+    device_arg: DeviceArg,
+) -> Result<Box<dyn Transport>> {
+    maybe_wait_for_device(matches, &device_arg)?;
+
+    let baud = baud(sub_matches, config);
+    let lock = lock_mode(sub_matches);
+
+    let device: Box<dyn Transport> = match device_arg {
+        DeviceArg::Serial(_)
+            if ["serial-number", "bus-address", "device-spec"]
+                .iter()
+                .any(|name| sub_matches.is_present(name)) =>
+        {
+            return Err(Error::InvalidArgument(
+                "--serial-number, --bus-address and --device-spec only apply to USB devices"
+                    .to_string(),
+            ))
+        }
+        DeviceArg::Serial(path) => match path.strip_prefix("tcp:") {
+            Some(address) => {
+                if sub_matches.subcommand_name() == Some("set-baud") {
+                    return Err(Error::Unsupported(
+                        "Changing the baud rate is only supported on serial ports, not on TCP connections"
+                            .to_string(),
+                    ));
+                }
+
+                Box::new(TcpTransport::connect(address)?)
+            }
+            #[cfg(feature = "serial")]
+            None => Box::new(SerialPort::open(&path, baud, lock)?),
+            #[cfg(not(feature = "serial"))]
+            None => {
+                let _ = (baud, lock);
+                return Err(Error::not_in_build("Serial port"));
+            }
+        },
+        #[cfg(not(feature = "usb"))]
+        DeviceArg::Usb(..) => return Err(Error::not_in_build("USB")),
+        #[cfg(feature = "usb")]
+        DeviceArg::Usb(vendor_id, product_id) => {
+            if sub_matches.subcommand_name() == Some("set-baud") {
+                return Err(Error::Unsupported(
+                    "Changing the baud rate is only supported on serial ports, not on USB devices"
+                        .to_string(),
+                ));
+            }
+
+            let mut device = UsbCdcDevice::open_selected(
+                &usb_selector(sub_matches, config, Some((vendor_id, product_id))),
+                line_coding(sub_matches),
+                lock,
+            )?;
+            device.set_retry_policy(retry_policy(matches, device::STALL_RETRIES));
+
+            Box::new(UsbTransport::new(device))
+        }
+    };
+
+    Ok(device)
+}
+
+// Whether a transfer failed its checks, or didn't get as far as checking.
+// Tells which stage of run-at failed and what that left the device at, before
+// the error itself.
+fn run_at_stage<T>(stage: &str, state: &str, result: Result<T>) -> Result<T> {
+    if result.is_err() {
+        warning!("run-at failed at the {} stage, {}", stage, state);
+    }
+
+    result
+}
+
+// Passes on whatever the payload prints, for as long as it runs.
+fn console(
+    session: &mut bootstub::Session,
+    sub_matches: &ArgMatches,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let options = bootstub::ConsoleOptions {
+        first_output_timeout: sub_matches
+            .get_one::<Duration>("first-output-timeout")
+            .copied(),
+        exit_on_silence: sub_matches.is_present("exit-on-silence"),
+    };
+
+    // Keep the payload output away from the events.
+    if events::enabled() {
+        session.console(&options, &mut std::io::stderr(), cancel)
+    } else {
+        session.console(&options, &mut std::io::stdout(), cancel)
+    }
+}
+
+fn checksum_status<T>(result: &Result<T>) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(Error::Verification(_)) => Some(false),
+        Err(_) => None,
+    }
+}
+
+// Parses the whole script up front, so that a typo near the end doesn't
+// leave it half done.
+fn read_script(path: &str) -> Result<Vec<(usize, script::Command)>> {
+    let text = std::fs::read_to_string(path).map_err(|source| Error::File {
+        path: path.to_string(),
+        source,
+    })?;
+
+    let mut commands = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        match script::parse_line(line) {
+            Ok(Some(command)) => commands.push((index + 1, command)),
+            Ok(None) => {}
+            Err(err) => {
+                return Err(Error::InvalidArgument(format!(
+                    "{}, line {}: {}",
+                    path,
+                    index + 1,
+                    err
+                )))
+            }
+        }
+    }
+
+    Ok(commands)
+}
+
+// Errors only end the command that caused them, not the session.
+fn shell(session: &mut bootstub::Session) -> Result<()> {
+    let mut editor = LineEditor::new();
+
+    status!("Connected, type 'help' for the list of commands");
+
+    while let Some(line) = editor.read_line("bootstub> ")? {
+        let command = match script::parse_line(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("{}", err);
+                continue;
+            }
+        };
+
+        match command {
+            script::Command::Quit => break,
+            command => {
+                // Ctrl-C only ends the command, not the shell.
+                let interrupt = cancel::cancel_on_interrupt();
+                if let Err(err) = script::execute(session, &command, interrupt.token()) {
+                    error!("{}", err);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn dump_args(
+    sub_matches: &ArgMatches,
+    config: &Config,
+    mut retry: RetryPolicy,
+    replaying: bool,
+    profile: Option<&Profile>,
+) -> Result<dump::Dump> {
+    let start = parse_address(
+        sub_matches.value_of("start").unwrap(),
+        "start address",
+        profile,
+    )?;
+    let end = parse_address(sub_matches.value_of("end").unwrap(), "end address", profile)?;
+    let force = sub_matches.is_present("force");
+
+    let output = template::expand(
+        sub_matches.value_of("output").unwrap(),
+        &[
+            ("start", Value::Number(start)),
+            ("end", Value::Number(end)),
+            ("size", Value::Number(end.saturating_sub(start))),
+        ],
+    )
+    .map_err(Error::InvalidArgument)?;
+    let output = PathBuf::from(output);
+    let split_size = sub_matches.get_one::<u64>("split-size").copied();
+
+    // The dump has had its own --retries for longer than everything else.
+    if let Some(retries) = sub_matches.get_one::<u32>("retries") {
+        retry.attempts = *retries;
+    }
+
+    let options = bootstub::DumpOptions {
+        chunk_size: sub_matches
+            .get_one::<u64>("chunk-size")
+            .copied()
+            .unwrap_or(bootstub::DEFAULT_CHUNK_SIZE),
+        retry,
+        window: *sub_matches.get_one::<u32>("window").unwrap(),
+        access_width: access_width(sub_matches, profile, start, end),
+        keepalive: sub_matches.get_one::<Duration>("keepalive").copied(),
+        watchdog_period: config.watchdog_period.as_ref().map(|setting| setting.value),
+    };
+    bootstub::check_alignment(start, end, &options)?;
+
+    let resume = sub_matches.is_present("resume");
+    let resume_from = match resume {
+        true if replaying => return Err(Error::Unsupported(
+            "Dumps with --resume can't be replayed, what they request depends on the state file"
+                .to_string(),
+        )),
+        true => resume::State::for_dump(&output, start, end, options.chunk_size)?,
+        false => None,
+    };
+
+    let destination = dump::destination(&output, split_size, replaying, resume)?;
+    let plan = sink::Plan {
+        destination,
+        compression: sub_matches
+            .get_one::<sink::Compression>("compress")
+            .copied(),
+        hexdump: sub_matches.is_present("hexdump").then_some(start),
+        force,
+    };
+
+    if resume_from.is_none() {
+        plan.check()?;
+    }
+
+    Ok(dump::Dump {
+        start,
+        end,
+        output,
+        force,
+        expected_sha256: sub_matches.get_one::<[u8; 32]>("expected-sha256").copied(),
+        verify: !sub_matches.is_present("no-verify"),
+        options,
+        keep_partial: sub_matches.is_present("keep-partial"),
+        plan,
+        metadata: !sub_matches.is_present("no-metadata"),
+        resume,
+        resume_from,
+    })
+}
+
+// The access width from the command line, or otherwise the one of the region
+// of the profile that the range is in.
+fn access_width(sub_matches: &ArgMatches, profile: Option<&Profile>, start: u64, end: u64) -> u32 {
+    let access_width = *sub_matches.get_one::<u32>("access-width").unwrap();
+
+    if sub_matches.value_source("access-width") != Some(ValueSource::DefaultValue) {
+        return access_width;
+    }
+
+    match profile.and_then(|profile| profile.containing(start, end)) {
+        Some(region) if region.access_width != access_width => {
+            step!(
+                "reading with {}-byte accesses, as {} requires",
+                region.access_width,
+                region.name
+            );
+            region.access_width
+        }
+        _ => access_width,
+    }
+}
+
+// Memory is searched in pieces of this size, so that a search with a limit
+// stops soon after it was reached.
+const SEARCH_PIECE_SIZE: u64 = 16 * 1024 * 1024;
+
+struct SearchArgs {
+    start: u64,
+    end: u64,
+    pattern: Pattern,
+    limit: Option<usize>,
+    context: usize,
+}
+
+fn search_args(sub_matches: &ArgMatches, profile: Option<&Profile>) -> Result<SearchArgs> {
+    let start = parse_address(
+        sub_matches.value_of("start").unwrap(),
+        "start address",
+        profile,
+    )?;
+    let end = parse_address(sub_matches.value_of("end").unwrap(), "end address", profile)?;
+
+    if end < start {
+        return Err(Error::InvalidArgument(format!(
+            "End address {:#x} is before the start address {:#x}",
+            end, start
+        )));
+    }
+
+    let pattern = match sub_matches.value_of("ascii") {
+        Some(string) => Pattern::ascii(string),
+        None => {
+            let string = sub_matches.value_of("pattern").unwrap();
+            Pattern::parse_hex(string).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "'{}' is not a pattern, expected pairs of hexadecimal digits or ??",
+                    string
+                ))
+            })?
+        }
+    };
+
+    if pattern.is_empty() {
+        return Err(Error::InvalidArgument(
+            "There is nothing to search for".to_string(),
+        ));
+    }
+
+    Ok(SearchArgs {
+        start,
+        end,
+        pattern,
+        limit: sub_matches.get_one::<usize>("limit").copied(),
+        context: sub_matches
+            .get_one::<usize>("context")
+            .copied()
+            .unwrap_or(0),
+    })
+}
+
+fn search_memory(
+    session: &mut bootstub::Session,
+    search: SearchArgs,
+    options: &bootstub::DumpOptions,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let context = search.context;
+    let match_size = (search.pattern.len() + context) as u64;
+    let mut searcher = Search::new(
+        search.pattern,
+        search.start,
+        context,
+        search.limit,
+        |found: search::Match| {
+            events::emit(
+                "match",
+                Object::new().field("address", format!("{:#x}", found.address)),
+            );
+
+            if context == 0 {
+                say!("{:#x}", found.address);
+            } else {
+                say!("Match at {:#x}:", found.address);
+                for line in hexdump(&found.context, found.context_address) {
+                    say!("{}", line);
+                }
+            }
+        },
+    );
+
+    let mut address = search.start;
+    while address < search.end && !searcher.is_done() {
+        // Only the context of the last match is still missing.
+        let piece_size = if searcher.limit_reached() {
+            match_size
+        } else {
+            SEARCH_PIECE_SIZE
+        };
+        let piece_end = search.end.min(address.saturating_add(piece_size));
+        session.dump(address, piece_end, options, &mut searcher, cancel)?;
+        address = piece_end;
+    }
+    searcher.finish();
+
+    match searcher.found() {
+        0 => status!(
+            "No matches between {:#x} and {:#x}",
+            search.start,
+            search.end
+        ),
+        1 => status!("1 match"),
+        count => status!("{} matches", count),
+    }
+
+    Ok(())
+}
+
+// Where the patch goes and what it changes, checked before anything is
+// written.
+fn patch_args(sub_matches: &ArgMatches, profile: Option<&Profile>) -> Result<(u64, Vec<Run>)> {
+    let address = parse_address(sub_matches.value_of("address").unwrap(), "address", profile)?;
+    let path = sub_matches.value_of("patch").unwrap();
+
+    let data = std::fs::read(path).map_err(|source| Error::File {
+        path: path.to_string(),
+        source,
+    })?;
+    let runs = patch::parse(
+        &data,
+        *sub_matches.get_one::<patch::Format>("format").unwrap(),
+    )
+    .map_err(|err| Error::InvalidArgument(format!("Invalid patch {}: {}", path, err)))?;
+
+    // A patch for something in a region of the profile has to stay in it.
+    let region = profile.and_then(|profile| profile.containing(address, address + 1));
+
+    for run in &runs {
+        let start = address.checked_add(run.offset);
+        let end = address.checked_add(run.end());
+
+        let out_of_range = match (start, end, region) {
+            (Some(start), Some(end), Some(region)) => start < region.start || end > region.end,
+            (Some(_), Some(_), None) => false,
+            _ => true,
+        };
+
+        if out_of_range {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid patch {}: the change at offset {:#x} goes beyond {}",
+                path,
+                run.offset,
+                match region {
+                    Some(region) =>
+                        format!("{} ({:#x}-{:#x})", region.name, region.start, region.end),
+                    None => "the end of memory".to_string(),
+                }
+            )));
+        }
+    }
+
+    Ok((address, runs))
+}
+
+struct MapArgs {
+    start: u64,
+    end: u64,
+    page_size: u64,
+    remote_crc: bool,
+    format: String,
+}
+
+fn map_args(sub_matches: &ArgMatches, profile: Option<&Profile>) -> Result<MapArgs> {
+    let start = parse_address(
+        sub_matches.value_of("start").unwrap(),
+        "start address",
+        profile,
+    )?;
+    let end = parse_address(sub_matches.value_of("end").unwrap(), "end address", profile)?;
+
+    if end < start {
+        return Err(Error::InvalidArgument(format!(
+            "End address {:#x} is before the start address {:#x}",
+            end, start
+        )));
+    }
+
+    Ok(MapArgs {
+        start,
+        end,
+        page_size: *sub_matches.get_one::<u64>("page-size").unwrap(),
+        remote_crc: sub_matches.is_present("remote-crc"),
+        format: sub_matches.get_one::<String>("format").unwrap().clone(),
+    })
+}
+
+fn map_memory(
+    session: &mut bootstub::Session,
+    map: &MapArgs,
+    options: &bootstub::DumpOptions,
+    cancel: &CancelToken,
+) -> Result<PageMap> {
+    let mut page_map = PageMap::new(map.start, map.end, map.page_size);
+
+    if !map.remote_crc {
+        session.dump(map.start, map.end, options, &mut page_map, cancel)?;
+        page_map.finish();
+
+        return Ok(page_map);
+    }
+
+    session.capabilities().require(bootstub::Feature::Crc)?;
+
+    let classifier = CrcClassifier::new(map.page_size);
+    let mut address = map.start;
+    while address < map.end {
+        cancel.check()?;
+
+        let size = map.page_size.min(map.end - address);
+        let crc32 = session.memory_crc(address, size)?;
+
+        let kind = if size == map.page_size {
+            classifier.classify(crc32)
+        } else {
+            CrcClassifier::new(size).classify(crc32)
+        };
+        page_map.push(kind);
+
+        events::emit(
+            "map_progress",
+            Object::new()
+                .field("done", address + size - map.start)
+                .field("total", map.end - map.start),
+        );
+        address += size;
+    }
+
+    Ok(page_map)
+}
+
+fn print_page_map(page_map: &PageMap, format: &str) {
+    if format == "json" {
+        println!("{}", page_map.to_json());
+        return;
+    }
+
+    say!("{}", PageMap::legend());
+    for line in page_map.lines() {
+        say!("{}", line);
+    }
+
+    let regions = page_map.interesting_regions();
+    if regions.is_empty() {
+        say!("Nothing but zero and erased pages");
+        return;
+    }
+
+    say!("Regions with data:");
+    for region in regions {
+        say!(
+            "  {:#010x}-{:#010x}  {:<12} {}",
+            region.start,
+            region.end,
+            region.kind.name(),
+            human_bytes(region.end - region.start)
+        );
+    }
+}
+
+struct SoakArgs {
+    start: u64,
+    end: u64,
+    iterations: u32,
+    method: soak::Method,
+    format: String,
+}
+
+fn soak_args(sub_matches: &ArgMatches, profile: Option<&Profile>) -> Result<SoakArgs> {
+    let start = parse_address(
+        sub_matches.value_of("start").unwrap(),
+        "start address",
+        profile,
+    )?;
+    let end = parse_address(sub_matches.value_of("end").unwrap(), "end address", profile)?;
+
+    if end <= start {
+        return Err(Error::InvalidArgument(format!(
+            "End address {:#x} is not after the start address {:#x}",
+            end, start
+        )));
+    }
+
+    let method = if sub_matches.is_present("remote-crc") {
+        let block_size = *sub_matches.get_one::<u64>("block-size").unwrap();
+        if block_size == 0 {
+            return Err(Error::InvalidArgument(
+                "The block size can't be zero".to_string(),
+            ));
+        }
+
+        soak::Method::Crc { block_size }
+    } else {
+        soak::Method::Data
+    };
+
+    Ok(SoakArgs {
+        start,
+        end,
+        iterations: *sub_matches.get_one::<u32>("iterations").unwrap(),
+        method,
+        format: sub_matches.get_one::<String>("format").unwrap().clone(),
+    })
+}
+
+// Makes the passes until they are done or Ctrl-C is pressed. A pass that
+// fails ends the test, what the passes so far came to is still worth seeing.
+fn soak_memory(
+    session: &mut bootstub::Session,
+    args: &SoakArgs,
+    options: &bootstub::DumpOptions,
+    cancel: &CancelToken,
+) -> (Soak, Result<()>) {
+    let mut soak = Soak::new(args.start, args.end, args.method);
+
+    if let soak::Method::Crc { .. } = args.method {
+        if let Err(err) = session.capabilities().require(bootstub::Feature::Crc) {
+            return (soak, Err(err));
+        }
+    }
+
+    for number in 1..=args.iterations {
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        let started = Instant::now();
+        let before = session.statistics();
+
+        let result = match args.method {
+            soak::Method::Data => session
+                .dump(args.start, args.end, options, &mut soak, cancel)
+                .map(|_| ()),
+            soak::Method::Crc { block_size } => {
+                let mut address = args.start;
+                let mut result = Ok(());
+
+                while address < args.end {
+                    if let Err(err) = cancel.check() {
+                        result = Err(err);
+                        break;
+                    }
+
+                    let size = block_size.min(args.end - address);
+
+                    match session.memory_crc(address, size) {
+                        Ok(crc32) => soak.push_crc(crc32),
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    }
+                    address += size;
+                }
+
+                result
+            }
+        };
+
+        if let Err(Error::Cancelled) = result {
+            step!("gave up on pass {} after Ctrl-C", number);
+            soak.discard_pass();
+            break;
+        }
+
+        let pass = soak.finish_pass(
+            started.elapsed(),
+            session.statistics().since(&before),
+            result.as_ref().err().map(Error::to_string),
+        );
+        events::emit("soak_pass", pass.to_object());
+
+        let elapsed = human_duration(pass.elapsed);
+        match &pass.outcome {
+            soak::Outcome::Reference => status!(
+                "Pass {} of {}: read in {}, the others are compared with it",
+                number,
+                args.iterations,
+                elapsed
+            ),
+            soak::Outcome::Match => status!(
+                "Pass {} of {}: matches the first, {}",
+                number,
+                args.iterations,
+                elapsed
+            ),
+            soak::Outcome::Mismatch {
+                differing,
+                first_address,
+            } => warning!(
+                "Pass {} of {}: {} differ from the first pass, starting at {:#x}, {}",
+                number,
+                args.iterations,
+                human_bytes(*differing),
+                first_address,
+                elapsed
+            ),
+            soak::Outcome::Failed(_) => {}
+        }
+
+        if let Err(err) = result {
+            return (soak, Err(err));
+        }
+    }
+
+    soak.interrupted = cancel.is_cancelled();
+    (soak, Ok(()))
+}
+
+// The most regions that are listed in the human output.
+const SOAK_REGIONS_SHOWN: usize = 16;
+
+fn print_soak(soak: &Soak, iterations: u32, format: &str) {
+    if format == "json" {
+        println!("{}", soak.to_json());
+        return;
+    }
+
+    let made = soak.passes.len();
+    say!(
+        "{} of {} passes made{}: {} matched the first, {} differed, {} failed",
+        made,
+        iterations,
+        if soak.interrupted {
+            ", stopped by Ctrl-C"
+        } else {
+            ""
+        },
+        soak.count("match"),
+        soak.count("mismatch"),
+        soak.count("failed")
+    );
+
+    if let Some(timing) = soak.timing() {
+        say!(
+            "Passes took {} on average, {} at the fastest and {} at the slowest",
+            human_duration(timing.average),
+            human_duration(timing.fastest),
+            human_duration(timing.slowest)
+        );
+    }
+
+    let regions = soak.differing_regions();
+    if regions.is_empty() {
+        return;
+    }
+
+    say!(
+        "Addresses that differed in any pass ({}):",
+        human_bytes(regions.iter().map(|region| region.end - region.start).sum())
+    );
+    for region in regions.iter().take(SOAK_REGIONS_SHOWN) {
+        say!(
+            "  {:#010x}-{:#010x}  {}",
+            region.start,
+            region.end,
+            human_bytes(region.end - region.start)
+        );
+    }
+    if regions.len() > SOAK_REGIONS_SHOWN {
+        say!("  and {} more", regions.len() - SOAK_REGIONS_SHOWN);
+    }
+}
+
+fn bootstub_command(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+    replay: Option<MockTransport>,
+) -> Result<()> {
+    let replaying = replay.is_some();
+
+    // Looking at an earlier dump doesn't need the device.
+    if let Some(("dump", dump_matches)) = sub_matches.subcommand() {
+        if let Some(path) = dump_matches.get_one::<String>("describe") {
+            for line in metadata::describe(Path::new(path))? {
+                say!("{}", line);
+            }
+
+            return Ok(());
+        }
+    }
+
+    let timeouts = timeouts(
+        matches,
+        config,
+        sub_matches.get_one::<Duration>("read-timeout").copied(),
+        bootstub::DEFAULT_TIMEOUT,
+    );
+
+    if sub_matches.contains_id("read-timeout") {
+        warning!("--read-timeout is deprecated, use --timeout instead");
+    }
+
+    let profile = match sub_matches.get_one::<String>("profile") {
+        Some(name) => Some(profile::find(name)?),
+        None => None,
+    };
+    let profile = profile.as_ref();
+    let confirmed = sub_matches.is_present("yes");
+
+    // Don't find out about bad arguments only after connecting.
+    let dump = match sub_matches.subcommand() {
+        Some(("dump", sub_matches)) => Some(dump_args(
+            sub_matches,
+            config,
+            retry_policy(matches, bootstub::DEFAULT_RETRIES),
+            replaying,
+            profile,
+        )?),
+        _ => None,
+    };
+    let search = match sub_matches.subcommand() {
+        Some(("search", sub_matches)) => Some(search_args(sub_matches, profile)?),
+        _ => None,
+    };
+    let map = match sub_matches.subcommand() {
+        Some(("map", sub_matches)) => Some(map_args(sub_matches, profile)?),
+        _ => None,
+    };
+    let soaking = match sub_matches.subcommand() {
+        Some(("soak", sub_matches)) => Some(soak_args(sub_matches, profile)?),
+        _ => None,
+    };
+    let patching = match sub_matches.subcommand() {
+        Some(("patch", sub_matches)) => Some(patch_args(sub_matches, profile)?),
+        _ => None,
+    };
+
+    let range = dump
+        .as_ref()
+        .map(|dump| (dump.start, dump.end))
+        .or(search.as_ref().map(|search| (search.start, search.end)))
+        .or(map.as_ref().map(|map| (map.start, map.end)))
+        .or(soaking.as_ref().map(|soak| (soak.start, soak.end)))
+        .or(patching.as_ref().map(|(address, runs)| {
+            (
+                address + runs.iter().map(|run| run.offset).min().unwrap(),
+                address + runs.iter().map(Run::end).max().unwrap(),
+            )
+        }));
+    if let Some((start, end)) = range {
+        check_dangerous(profile, start, end, confirmed || replaying)?;
+    }
+
+    let commands = match sub_matches.subcommand() {
+        Some(("run", sub_matches)) => read_script(sub_matches.value_of("script").unwrap())?,
+        _ => Vec::new(),
+    };
+
+    let pacing = Pacing {
+        tx_delay: Duration::from_micros(
+            sub_matches.get_one::<u64>("tx-delay").copied().unwrap_or(0),
+        ),
+        max_rate: sub_matches.get_one::<u64>("max-rate").copied(),
+    };
+
+    let device = open_transport(matches, replay, || {
+        let device = open_bootstub_device(matches, sub_matches, config)?;
+
+        if pacing.is_enabled() {
+            Ok(Box::new(PacingTransport::new(device, pacing)))
+        } else {
+            Ok(device)
+        }
+    })?;
+
+    let mut session = if sub_matches.is_present("legacy-protocol") {
+        bootstub::Session::connect_legacy(device, timeouts)?
+    } else {
+        bootstub::Session::connect(device, timeouts)?
+    };
+    if !sub_matches.is_present("no-reconnect") && !replaying {
+        session.set_reconnect_timeout(Some(bootstub::RECONNECT_TIMEOUT));
+    }
+
+    let shut_down = shut_down_after(sub_matches, session.capabilities());
+
+    // Ctrl-C stops what is under way and still shuts the stub down, except in
+    // the shell, which arms it for each of its commands instead.
+    let interrupt = match sub_matches.subcommand() {
+        Some(("shell", _)) => None,
+        _ => Some(cancel::cancel_on_interrupt()),
+    };
+    let cancel = interrupt
+        .as_ref()
+        .map_or_else(CancelToken::new, |interrupt| interrupt.token().clone());
+
+    match sub_matches.subcommand() {
+        Some(("ping", _)) => {
+            let capabilities = session.capabilities();
+            let features = capabilities
+                .features()
+                .map(bootstub::Feature::name)
+                .collect::<Vec<_>>();
+
+            events::emit(
+                "capabilities",
+                Object::new()
+                    .field("version", capabilities.version)
+                    .field("features", &features)
+                    .field("checksum", session.checksum().name())
+                    .field("negotiated", session.negotiated()),
+            );
+
+            if session.negotiated() {
+                say!("The stub answered, version {}", capabilities.version);
+            } else {
+                say!("The stub answered, it wasn't asked what it supports (--legacy-protocol)");
+            }
+            if features.is_empty() {
+                say!("Optional features: none");
+            } else {
+                say!("Optional features: {}", features.join(", "));
+            }
+            say!("Dumps are checked with {}", session.checksum().name());
+        }
+        Some(("dump", _)) => {
+            let dump = dump.unwrap();
+
+            let started_at = SystemTime::now();
+            let started = Instant::now();
+            let before = session.statistics();
+
+            let (result, files) = dump::run(&mut session, &dump, &cancel)?;
+
+            summary::report(
+                "dump",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                checksum_status(&result),
+                Some(session.checksum().name()),
+            );
+            dump::clean_up(&dump, &result, &files);
+            let digest = result?;
+
+            let split = matches!(dump.plan.destination, sink::Destination::Split { .. });
+
+            if dump.metadata && dump.plan.destination == sink::Destination::Stdout {
+                step!("not writing any metadata for a dump to stdout");
+            } else if dump.metadata && !replaying {
+                let capabilities = session.capabilities();
+                let path = metadata::write(
+                    &dump.output,
+                    &DumpMetadata {
+                        device: device_identity(
+                            &bootstub_device_arg(matches, sub_matches, config)?,
+                            sub_matches,
+                            config,
+                        ),
+                        stub_version: capabilities.version,
+                        negotiated: session.negotiated(),
+                        stub_features: capabilities
+                            .features()
+                            .map(bootstub::Feature::name)
+                            .collect(),
+                        start: dump.start,
+                        end: dump.end,
+                        access_width: dump.options.access_width,
+                        checksum: session.checksum().name(),
+                        digest,
+                        parts: if split { files.clone() } else { Vec::new() },
+                        compression: dump.plan.compression.map(sink::Compression::name),
+                        started: started_at,
+                        finished: SystemTime::now(),
+                    },
+                )?;
+                step!("wrote the metadata to {}", path.display());
+            }
+
+            dump::report(&dump, &digest, &files);
+            dump::verify(&mut session, &dump, &digest, &files)?;
+        }
+        Some(("search", _)) => {
+            let search = search.unwrap();
+            let options = bootstub::DumpOptions {
+                retry: retry_policy(matches, bootstub::DEFAULT_RETRIES),
+                ..bootstub::DumpOptions::default()
+            };
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let result = search_memory(&mut session, search, &options, &cancel);
+
+            summary::report(
+                "search",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                Some(result.is_ok()),
+                Some(session.checksum().name()),
+            );
+            result?;
+        }
+        Some(("map", _)) => {
+            let map = map.unwrap();
+            let options = bootstub::DumpOptions {
+                retry: retry_policy(matches, bootstub::DEFAULT_RETRIES),
+                ..bootstub::DumpOptions::default()
+            };
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let result = map_memory(&mut session, &map, &options, &cancel);
+
+            // Pages that were read came with the checksums of the dump.
+            summary::report(
+                "map",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                (!map.remote_crc).then_some(result.is_ok()),
+                Some(session.checksum().name()),
+            );
+            print_page_map(&result?, &map.format);
+        }
+        Some(("soak", _)) => {
+            let soaking = soaking.unwrap();
+            let options = bootstub::DumpOptions {
+                retry: retry_policy(matches, bootstub::DEFAULT_RETRIES),
+                ..bootstub::DumpOptions::default()
+            };
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let (soak, result) = soak_memory(&mut session, &soaking, &options, &cancel);
+
+            summary::report(
+                "soak test",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                (soaking.method == soak::Method::Data).then_some(result.is_ok()),
+                Some(session.checksum().name()),
+            );
+            print_soak(&soak, soaking.iterations, &soaking.format);
+            result?;
+
+            let mismatched = soak.count("mismatch");
+            if mismatched > 0 {
+                return Err(Error::Verification(format!(
+                    "{} of {} passes didn't match the first",
+                    mismatched,
+                    soak.passes.len()
+                )));
+            }
+            if soak.interrupted {
+                return Err(Error::Cancelled);
+            }
+        }
+        Some(("boot", sub_matches)) => {
+            let binary_path = sub_matches.value_of("binary").unwrap();
+
+            let mut binary = File::options()
+                .read(true)
+                .write(false)
+                .create(false)
+                .truncate(false)
+                .open(binary_path)
+                .map_err(|source| Error::File {
+                    path: binary_path.to_string(),
+                    source,
+                })?;
+
+            let binary_size = binary.metadata()?.len();
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let result = session.boot(&mut binary, binary_size, &cancel);
+
+            // Every 256th byte is echoed back, which is as close to a
+            // checksum as this gets.
+            summary::report(
+                "upload",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                checksum_status(&result),
+                Some("echo"),
+            );
+            let digest = result?;
+
+            status!("SHA-256 of {}: {}", binary_path, sha256::to_hex(&digest));
+
+            console(&mut session, sub_matches, &cancel)?;
+        }
+        Some(("run-at", sub_matches)) => {
+            let address =
+                parse_address(sub_matches.value_of("address").unwrap(), "address", profile)?;
+            let binary_path = sub_matches.value_of("binary").unwrap();
+
+            let mut binary = File::open(binary_path).map_err(|source| Error::File {
+                path: binary_path.to_string(),
+                source,
+            })?;
+            let binary_size = binary.metadata()?.len();
+
+            // Don't write anything to a stub that can't jump to it afterwards.
+            let capabilities = session.capabilities();
+            capabilities.require(bootstub::Feature::BlockMode)?;
+            capabilities.require(bootstub::Feature::Exec)?;
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let result = session.write_memory(
+                address,
+                &mut binary,
+                binary_size,
+                &retry_policy(matches, bootstub::DEFAULT_RETRIES),
+                &cancel,
+            );
+
+            summary::report(
+                "write",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                checksum_status(&result),
+                Some(session.checksum().name()),
+            );
+            let digest = run_at_stage(
+                "write",
+                "nothing was jumped to and the memory may be partially written",
+                result,
+            )?;
+
+            status!(
+                "Wrote {} to {:#x}, SHA-256 {}",
+                binary_path,
+                address,
+                sha256::to_hex(&digest.sha256)
+            );
+
+            if sub_matches.is_present("verify") {
+                run_at_stage(
+                    "verify",
+                    "the binary was written, but not jumped to",
+                    session.verify_memory(address, binary_size, &digest, &cancel),
+                )?;
+
+                status!("The memory at {:#x} matches {}", address, binary_path);
+            }
+
+            run_at_stage(
+                "exec",
+                "the device may or may not have jumped to the binary",
+                session.exec(address),
+            )?;
+
+            status!("Jumped to {:#x}", address);
+
+            if sub_matches.is_present("console") {
+                console(&mut session, sub_matches, &cancel)?;
+            }
+        }
+        Some(("patch", sub_matches)) => {
+            let (address, runs) = patching.unwrap();
+
+            session
+                .capabilities()
+                .require(bootstub::Feature::BlockMode)?;
+
+            let started = Instant::now();
+            let before = session.statistics();
+            let retry = retry_policy(matches, bootstub::DEFAULT_RETRIES);
+            let verify = !sub_matches.is_present("no-verify");
+
+            for (index, run) in runs.iter().enumerate() {
+                let run_address = address + run.offset;
+                let size = run.data.len() as u64;
+
+                let digest = session
+                    .write_memory(run_address, &mut run.data.as_slice(), size, &retry, &cancel)
+                    .inspect_err(|_| {
+                        if index > 0 {
+                            warning!(
+                                "{} of the {} changes were written before this one failed",
+                                index,
+                                runs.len()
+                            );
+                        }
+                    })?;
+
+                if verify {
+                    session.verify_memory(run_address, size, &digest, &cancel)?;
+                }
+
+                step!("patched {} at {:#x}", human_bytes(size), run_address);
+            }
+
+            summary::report(
+                "patch",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                verify.then_some(true),
+                Some(session.checksum().name()),
+            );
+            status!(
+                "Patched {} in {} places from {:#x}",
+                human_bytes(runs.iter().map(|run| run.data.len() as u64).sum()),
+                runs.len(),
+                address
+            );
+        }
+        Some(("reset", sub_matches)) => {
+            let action = if sub_matches.is_present("poweroff") {
+                bootstub::ShutDown::PowerOff
+            } else {
+                bootstub::ShutDown::Reset
+            };
+
+            shut_down_device(&mut session, action)?;
+        }
+        Some(("set-baud", sub_matches)) => {
+            let rate = *sub_matches.get_one::<u32>("rate").unwrap();
+
+            session.set_baud(rate)?;
+
+            status!("Switched to {} baud", rate);
+        }
+        Some(("shell", _)) => shell(&mut session)?,
+        Some(("run", _)) => {
+            for (line_number, command) in commands {
+                step!("running line {}", line_number);
+
+                match command {
+                    script::Command::Quit => break,
+                    command => script::execute(&mut session, &command, &cancel)?,
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    // Only once the command went through, a device that is left alone after
+    // a failure can still be looked at.
+    if let Some(action) = shut_down {
+        if session.is_running() {
+            shut_down_device(&mut session, action)?;
+        } else {
+            warning!(
+                "Not asking for a {}, the stub doesn't run anymore after jumping to the binary",
+                action.name()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// What --reboot-after or --poweroff-after asks for, unless the stub can't do
+// it, which isn't worth failing the command over.
+fn shut_down_after(
+    sub_matches: &ArgMatches,
+    capabilities: bootstub::Capabilities,
+) -> Option<bootstub::ShutDown> {
+    let (action, flag) = if sub_matches.is_present("reboot-after") {
+        (bootstub::ShutDown::Reset, "--reboot-after")
+    } else if sub_matches.is_present("poweroff-after") {
+        (bootstub::ShutDown::PowerOff, "--poweroff-after")
+    } else {
+        return None;
+    };
+
+    if matches!(sub_matches.subcommand(), Some(("reset", _))) {
+        warning!("Ignoring {}, the device is reset anyway", flag);
+        return None;
+    }
+
+    if !capabilities.has(action.feature()) {
+        warning!(
+            "Ignoring {}, the stub (version {}) doesn't support {}, update it to version {} or later",
+            flag,
+            capabilities.version,
+            action.feature().name(),
+            action.feature().since()
+        );
+        return None;
+    }
+
+    Some(action)
+}
+
+fn shut_down_device(session: &mut bootstub::Session, action: bootstub::ShutDown) -> Result<()> {
+    session.shut_down(action)?;
+
+    match action {
+        bootstub::ShutDown::Reset => status!("The device is resetting"),
+        bootstub::ShutDown::PowerOff => status!("The device is powering off"),
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "usb")]
+fn download_command(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    config: &Config,
+    replay: Option<MockTransport>,
+) -> Result<()> {
+    let usb_id = match sub_matches.get_one::<(u16, u16)>("usb") {
+        Some(usb_id) => Some(*usb_id),
+        None => match matches.get_one::<DeviceArg>("device") {
+            Some(DeviceArg::Usb(vendor_id, product_id)) => Some((*vendor_id, *product_id)),
+            Some(DeviceArg::Serial(path)) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Download mode is only reachable over USB, but '{}' is not a vendor:product ID",
+                    path
+                )))
+            }
+            // Without any ID, look for a device in download mode.
+            None => config.usb.as_ref().map(|setting| setting.value),
+        },
+    };
+
+    let selector = usb_selector(sub_matches, config, usb_id);
+    let replaying = replay.is_some();
+
+    // Don't find out about bad arguments only after connecting.
+    let flash = match sub_matches.subcommand() {
+        Some(("flash", sub_matches)) => Some(flash_args(sub_matches)?),
+        Some(("flash-dir", sub_matches)) => Some(flash_dir_args(sub_matches)?),
+        _ => None,
+    };
+
+    let mut strings = device::Strings::default();
+    let device = open_transport(matches, replay, || {
+        maybe_wait_for_selected(matches, &selector)?;
+
+        let mut device = UsbCdcDevice::open_selected(
+            &selector,
+            line_coding(sub_matches),
+            lock_mode(sub_matches),
+        )?;
+        device.set_retry_policy(retry_policy(matches, device::STALL_RETRIES));
+        strings = device.strings().clone();
+
+        Ok(Box::new(UsbTransport::new(device)))
+    })?;
+    let mut session = odin::Session::begin_with_padding(
+        device,
+        timeouts(matches, config, None, odin::DEFAULT_TIMEOUT),
+        sub_matches.is_present("pad-to-max-packet"),
+    )?;
+    session.set_retry_policy(retry_policy(matches, odin::FILE_PART_RETRIES));
+
+    let identity = session.identity(strings.product, strings.serial_number);
+    events::emit("identity", identity.to_object());
+
+    match sub_matches.subcommand() {
+        Some(("identify", _)) => {
+            say!(
+                "Model: {}",
+                identity.product.as_deref().unwrap_or("unknown")
+            );
+            say!(
+                "Serial number: {}",
+                identity.serial_number.as_deref().unwrap_or("unknown")
+            );
+            match identity.lock_state {
+                Some(lock_state) => say!("Lock state: {}", lock_state),
+                None => say!("Lock state: not reported"),
+            }
+            if identity.default_packet_size != 0 {
+                say!(
+                    "The bootloader takes packets of {} bytes",
+                    identity.default_packet_size
+                );
+            } else {
+                say!("The bootloader is an older one without a default packet size");
+            }
+
+            return session.end();
+        }
+        Some(("reboot", sub_matches)) => {
+            let target = sub_matches.get_one::<String>("to").unwrap();
+
+            return session.reboot_to(odin::RebootTarget::parse(target).unwrap());
+        }
+        Some(("flash" | "flash-dir", _)) => {
+            let flash = flash.unwrap();
+            let reboot = flash.reboot;
+            let verify_after = flash.verify_after;
+
+            // Nothing has been written yet, and nothing will be.
+            if let Some(expected) = &flash.expect_serial {
+                if !replaying {
+                    identity.check_serial_number(expected)?;
+                }
+            }
+
+            status!(
+                "Flashing {} with serial number {}",
+                identity.product.as_deref().unwrap_or("a device"),
+                identity.serial_number.as_deref().unwrap_or("unknown")
+            );
+            if let Some(lock_state) = identity.lock_state.filter(|state| state.is_restrictive()) {
+                warning!(
+                    "The device is in lock state {}, it's likely to refuse images that aren't signed by the vendor",
+                    lock_state
+                );
+            }
+
+            let started = Instant::now();
+            let before = session.statistics();
+            // Ctrl-C stops before the next sequence that would be sent, which
+            // leaves the device in download mode.
+            let interrupt = cancel::cancel_on_interrupt();
+            let result =
+                flash::flash_files(&mut session, flash.pit, flash.source, interrupt.token());
+            drop(interrupt);
+
+            summary::report(
+                "flash",
+                &session.statistics().since(&before),
+                started.elapsed(),
+                None,
+                None,
+            );
+            let flashed = result?;
+
+            if let Some(reboot_first) = verify_after {
+                session = flash::verify_flashed(session, &identity, &flashed, reboot_first)?;
+            }
+
+            if !reboot {
+                return session.end();
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    session.reboot()
+}
+
+#[cfg(feature = "usb")]
+struct FlashArgs {
+    pit: Option<Vec<u8>>,
+    reboot: bool,
+    // For --verify-after, whether to reboot into download mode for the new
+    // session.
+    verify_after: Option<bool>,
+    expect_serial: Option<String>,
+    source: FlashSource,
+}
+
+#[cfg(feature = "usb")]
+fn read_pit_file(path: Option<String>) -> Result<Option<Vec<u8>>> {
+    match path {
+        Some(path) => Ok(Some(
+            std::fs::read(&path).map_err(|source| Error::File { path, source })?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// Picks the Heimdall-style --PARTITION <FILE> pairs apart. Options that come
+// after the first partition end up in there as well.
+#[cfg(feature = "usb")]
+fn flash_args(sub_matches: &ArgMatches) -> Result<FlashArgs> {
+    let mut pit_path = sub_matches.get_one::<String>("pit").cloned();
+    let mut reboot = !sub_matches.is_present("no-reboot");
+    let mut verify_after = sub_matches
+        .get_one::<String>("verify-after")
+        .map(|how| how == "reboot");
+    let mut expect_serial = sub_matches.get_one::<String>("expect-serial").cloned();
+    let mut pairs: Vec<(String, String)> = Vec::new();
+
+    let mut values = sub_matches.values_of("partitions").unwrap();
+    while let Some(value) = values.next() {
+        let (name, inline) = match value.strip_prefix("--") {
+            Some(option) => match option.split_once('=') {
+                Some((name, path)) => (name, Some(path.to_string())),
+                None => (option, None),
+            },
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "Expected --PARTITION <FILE>, got '{}'",
+                    value
+                )))
+            }
+        };
+
+        if name == "no-reboot" && inline.is_none() {
+            reboot = false;
+            continue;
+        }
+
+        if name == "verify-after" {
+            verify_after = match inline.as_deref() {
+                None | Some("session") => Some(false),
+                Some("reboot") => Some(true),
+                Some(how) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "'{}' is not a way to verify after flashing, expected session or reboot",
+                        how
+                    )))
+                }
+            };
+            continue;
+        }
+
+        let path = match inline.or_else(|| values.next().map(str::to_string)) {
+            Some(path) => path,
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "No file given for --{}",
+                    name
+                )))
+            }
+        };
+
+        if name.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "No partition given for {}",
+                path
+            )));
+        }
+
+        if name == "pit" {
+            if pit_path.replace(path).is_some() {
+                return Err(Error::InvalidArgument("--pit was given twice".to_string()));
+            }
+            continue;
+        }
+
+        if name == "expect-serial" {
+            if expect_serial.replace(path).is_some() {
+                return Err(Error::InvalidArgument(
+                    "--expect-serial was given twice".to_string(),
+                ));
+            }
+            continue;
+        }
+
+        if let Some((_, other)) = pairs
+            .iter()
+            .find(|(partition, _)| partition.eq_ignore_ascii_case(name))
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Partition {} was given twice, with {} and {}",
+                name, other, path
+            )));
+        }
+
+        pairs.push((name.to_string(), path));
+    }
+
+    if pairs.is_empty() {
+        return Err(Error::InvalidArgument(
+            "No files to flash given, use --PARTITION <FILE>".to_string(),
+        ));
+    }
+
+    let pit = read_pit_file(pit_path)?;
+
+    let files = pairs
+        .into_iter()
+        .map(|(partition, path)| {
+            let (file, size) = flash::open_image(&path)?;
+
+            Ok(FlashFile {
+                partition,
+                path,
+                file,
+                size,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(FlashArgs {
+        pit,
+        reboot,
+        verify_after,
+        expect_serial,
+        source: FlashSource::Files(files),
+    })
+}
+
+#[cfg(feature = "usb")]
+fn flash_dir_args(sub_matches: &ArgMatches) -> Result<FlashArgs> {
+    let directory = FlashDirectory::list(PathBuf::from(
+        sub_matches.get_one::<String>("directory").unwrap(),
+    ))?;
+
+    let partitions = |id: &str| {
+        sub_matches
+            .get_many::<String>(id)
+            .map(|names| names.map(|name| name.trim().to_string()).collect())
+            .unwrap_or_default()
+    };
+
+    Ok(FlashArgs {
+        pit: read_pit_file(sub_matches.get_one::<String>("pit").cloned())?,
+        reboot: !sub_matches.is_present("no-reboot"),
+        verify_after: sub_matches
+            .get_one::<String>("verify-after")
+            .map(|how| how == "reboot"),
+        expect_serial: sub_matches.get_one::<String>("expect-serial").cloned(),
+        source: FlashSource::Directory(FlashDirectory {
+            only: partitions("only"),
+            skip: partitions("skip"),
+            order: partitions("order"),
+            ..directory
+        }),
+    })
+}
+
+// Probing should be quick, a device that answers at all does so right away.
+const DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Download mode is only reachable over USB, and bootstub usually over
+// serial, so each kind of connection is probed with the protocol that is
+// to be expected there. Probing leaves the device as it was.
+fn detect_command(matches: &ArgMatches, sub_matches: &ArgMatches, config: &Config) -> Result<()> {
+    let timeouts = timeouts(matches, config, None, DETECT_TIMEOUT);
+    // Which device was found, for --print-device.
+    #[cfg_attr(not(feature = "usb"), allow(unused_mut))]
+    let mut spec: Option<DeviceSpec> = None;
+
+    let (protocol, result) = match given_device_arg(matches, sub_matches, config) {
+        Some(DeviceArg::Serial(path)) => {
+            let device = open_transport(matches, None, || {
+                open_connection(matches, sub_matches, config, DeviceArg::Serial(path))
+            })?;
+
+            (
+                "bootstub",
+                bootstub::Session::connect(device, timeouts).map(drop),
+            )
+        }
+        #[cfg(not(feature = "usb"))]
+        _ => return Err(Error::not_in_build("USB")),
+        #[cfg(feature = "usb")]
+        device_arg => {
+            let usb_id = match device_arg {
+                Some(DeviceArg::Usb(vendor_id, product_id)) => Some((vendor_id, product_id)),
+                _ => None,
+            };
+
+            let selector = usb_selector(sub_matches, config, usb_id);
+            let device = open_transport(matches, None, || {
+                maybe_wait_for_selected(matches, &selector)?;
+
+                let device = UsbCdcDevice::open_selected(
+                    &selector,
+                    line_coding(sub_matches),
+                    lock_mode(sub_matches),
+                )?;
+                spec = Some(device.spec()?);
+
+                Ok(Box::new(UsbTransport::new(device)))
+            })?;
+
+            (
+                "odin",
+                odin::Session::begin(device, timeouts).and_then(odin::Session::end),
+            )
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            events::emit("detected", Object::new().field("protocol", protocol));
+
+            if sub_matches.is_present("print-device") {
+                let spec = spec.ok_or_else(|| {
+                    Error::InvalidArgument("--print-device only applies to USB devices".to_string())
+                })?;
+                println!("{}", spec);
+
+                return Ok(());
+            }
+
+            match protocol {
+                "odin" => say!("The device is in download mode"),
+                _ => say!("The device is running bootstub"),
+            }
+
+            Ok(())
+        }
+        Err(err) => Err(Error::DeviceNotFound(format!(
+            "No known protocol responded, {} probe failed: {}",
+            protocol, err
+        ))),
+    }
+}
+
+fn replay_command(sub_matches: &ArgMatches) -> Result<()> {
+    let path = sub_matches.value_of("log").unwrap();
+    let recording = Recording::load(path)?;
+
+    let matches = cli()
+        .try_get_matches_from(std::iter::once("sbootil".to_string()).chain(recording.args.clone()))
+        .map_err(|err| {
+            Error::InvalidArgument(format!(
+                "{} contains invalid arguments: {}",
+                path,
+                err.to_string().trim()
+            ))
+        })?;
+
+    status!("Replaying: sbootil {}", recording.args.join(" "));
+
+    let result = match matches.subcommand() {
+        Some(("bootstub", sub_matches)) => bootstub_command(
+            &matches,
+            sub_matches,
+            &Config::default(),
+            Some(recording.transport.clone()),
+        ),
+        #[cfg(feature = "usb")]
+        Some(("download", sub_matches)) => download_command(
+            &matches,
+            sub_matches,
+            &Config::default(),
+            Some(recording.transport.clone()),
+        ),
+        #[cfg(not(feature = "usb"))]
+        Some(("download", _)) => Err(Error::not_in_build("USB")),
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "{} doesn't contain a session that can be replayed",
+                path
+            )))
+        }
+    };
+
+    let remaining = recording.transport.remaining_steps();
+
+    match (&recording.result, &result) {
+        (Some(Ok(())), Ok(())) if remaining == 0 => {}
+        (Some(Err(expected)), Err(err)) if *expected == err.to_string() => {}
+        // The recorded session was cut short, so the replay can't end any other way.
+        (None, Err(_)) if remaining == 0 => {}
+        (expected, _) => {
+            return Err(Error::ReplayMismatch(format!(
+                "the recording ended with {}, the replay with {} ({} recorded transfers left)",
+                match expected {
+                    Some(Ok(())) => "success".to_string(),
+                    Some(Err(message)) => format!("'{}'", message),
+                    None => "an interruption".to_string(),
+                },
+                match &result {
+                    Ok(()) => "success".to_string(),
+                    Err(err) => format!("'{}'", err),
+                },
+                remaining
+            )))
+        }
+    }
+
+    status!("Replay matches the recording");
+
+    Ok(())
+}
+
+fn capture_dump(sub_matches: &ArgMatches) -> Result<()> {
+    let recording = Recording::load(sub_matches.value_of("log").unwrap())?;
+    let full = sub_matches.is_present("hexdump");
+    let mut annotator = capture::Annotator::new();
+
+    say!("# sbootil {}", recording.args.join(" "));
+
+    for logged in &recording.transfers {
+        let (direction, data) = match &logged.transfer {
+            Transfer::Write(data) => (">", Some(data)),
+            Transfer::Read(data) => ("<", Some(data)),
+            Transfer::Timeout => ("!", None),
+        };
+
+        let size = data.map_or(String::new(), |data| format!("{} bytes", data.len()));
+
+        // Without anything better to show, the start of the data has to do.
+        let annotation = match (annotator.annotate(logged), data) {
+            (Some(annotation), _) => annotation,
+            (None, Some(data)) if !full => {
+                let preview = data
+                    .iter()
+                    .take(16)
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if data.len() > 16 {
+                    format!("{} ...", preview)
+                } else {
+                    preview
+                }
+            }
+            (None, _) => String::new(),
+        };
+
+        say!(
+            "{:>12.6} {} {:<14} {:>14}  {}",
+            logged.time.as_secs_f64(),
+            direction,
+            logged.phase,
+            size,
+            annotation
+        );
+
+        if let (true, Some(data)) = (full, data) {
+            for line in hexdump(data, 0) {
+                say!("    {}", line);
+            }
+        }
+    }
+
+    match &recording.result {
+        Some(Ok(())) => say!("# Finished successfully"),
+        Some(Err(message)) => say!("# Failed: {}", message),
+        None => say!("# Cut short"),
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn event_sink(fd: i32) -> Result<Box<dyn Write + Send>> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd == 1 {
+        return Ok(Box::new(std::io::stdout()));
+    }
+
+    // Make sure that the descriptor has actually been handed to us before
+    // taking ownership of it.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(Error::InvalidArgument(format!(
+            "File descriptor {} for --json-events is not open",
+            fd
+        )));
+    }
+
+    Ok(Box::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(windows)]
+fn event_sink(fd: i32) -> Result<Box<dyn Write + Send>> {
+    if fd == 1 {
+        return Ok(Box::new(std::io::stdout()));
+    }
+
+    Err(Error::Unsupported(
+        "Emitting events to file descriptors other than stdout is not supported on Windows"
+            .to_string(),
+    ))
+}
+
+#[cfg(all(unix, feature = "serial"))]
+fn simulate_command(sub_matches: &ArgMatches) -> Result<()> {
+    let options = simulator::Options {
+        corrupt_checksum: sub_matches.is_present("corrupt-checksum"),
+        delay: Duration::from_millis(*sub_matches.get_one::<u64>("delay").unwrap_or(&0)),
+        fragment_size: sub_matches
+            .get_one::<u64>("fragment")
+            .map(|&size| size as usize),
+        legacy: sub_matches.is_present("legacy"),
+        debug_output: sub_matches.is_present("debug-output"),
+        ..simulator::Options::default()
+    };
+    let options = match sub_matches.get_one::<String>("data") {
+        Some(path) => options.with_data_file(Path::new(path))?,
+        None => options,
+    };
+
+    let mut simulator = simulator::Simulator::new(options)?;
+
+    say!("Simulating bootstub on {}", simulator.path().display());
+
+    simulator.run()
+}
+
+#[cfg(not(all(unix, feature = "serial")))]
+fn simulate_command(_sub_matches: &ArgMatches) -> Result<()> {
+    Err(Error::not_in_build("Simulator"))
+}
+
+fn config_show() -> Result<()> {
+    let config = Config::load()?;
+
+    match (&config.path, sbootil::config::config_path()) {
+        (Some(path), _) => say!("# Read from {}", path.display()),
+        (None, Some((path, _))) => say!("# {} doesn't exist", path.display()),
+        (None, None) => say!("# No config directory found"),
+    }
+
+    for line in config.show() {
+        say!("{}", line);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn doctor(config: &Config) -> Result<()> {
+    let vendors = config
+        .vendors
+        .as_ref()
+        .map(|setting| setting.value.clone())
+        .unwrap_or_default();
+
+    let checks = doctor::run(&vendors);
+
+    for check in &checks {
+        let status = match check.status {
+            doctor::Status::Pass => "PASS",
+            doctor::Status::Warn => "WARN",
+            doctor::Status::Fail => "FAIL",
+        };
+        say!("{} {}: {}", status, check.name, check.message);
+        if let Some(remedy) = &check.remedy {
+            say!("     {}", remedy);
+        }
+
+        events::emit("doctor_check", check.to_object());
+    }
+
+    match checks
+        .iter()
+        .filter(|check| check.status == doctor::Status::Fail)
+        .count()
+    {
+        0 => Ok(()),
+        1 => Err(Error::PermissionDenied(
+            "1 problem stands in the way of using devices, see above".to_string(),
+        )),
+        count => Err(Error::PermissionDenied(format!(
+            "{} problems stand in the way of using devices, see above",
+            count
+        ))),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn doctor(_config: &Config) -> Result<()> {
+    Err(Error::Unsupported(
+        "doctor only knows how to check Linux hosts".to_string(),
+    ))
+}
+
+fn profiles_list() -> Result<()> {
+    if let Some(dir) = profile::profiles_dir() {
+        say!("# Profiles in {} replace the built-in ones", dir.display());
+    }
+
+    for profile in profile::load_all()? {
+        say!(
+            "{} ({}){}",
+            profile.name,
+            profile.origin,
+            profile
+                .description
+                .as_ref()
+                .map(|description| format!(": {}", description))
+                .unwrap_or_default()
+        );
+
+        if let Some(load_address) = profile.load_address {
+            say!("    load_address {:#010x}", load_address);
+        }
+
+        for region in &profile.regions {
+            let mut notes = vec![human_bytes(region.end - region.start)];
+            if region.access_width != 1 {
+                notes.push(format!("{}-byte accesses", region.access_width));
+            }
+            if region.dangerous {
+                notes.push("dangerous".to_string());
+            }
+            let description = region
+                .description
+                .as_ref()
+                .map(|description| format!(" ({})", description))
+                .unwrap_or_default();
+
+            say!(
+                "    {:<12} {:#010x}-{:#010x}  {}{}",
+                region.name,
+                region.start,
+                region.end,
+                notes.join(", "),
+                description
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let matches = cli().get_matches();
+
+    if let Some(fd) = matches.get_one::<i32>("json-events") {
+        events::enable(event_sink(*fd)?);
+    }
+
+    ui::set_quiet(matches.is_present("quiet"));
+    format::set_raw(matches.is_present("raw-sizes"));
+
+    log::set_level(log::level_from_count(
+        *matches.get_one::<u8>("verbose").unwrap(),
+    ));
+
+    #[cfg(feature = "usb")]
+    if let Some(level) = matches.get_one::<u8>("usb-debug") {
+        device::set_libusb_log_level(*level);
+    }
+
+    if matches.contains_id("device") {
+        warning!("--device is deprecated, use --serial or --usb on the subcommand instead");
+    }
+
+    let result = match matches.subcommand() {
+        #[cfg(feature = "usb")]
+        Some(("list-devices", sub_matches)) => list_devices_command(sub_matches, &Config::load()?),
+        Some(("bootstub", sub_matches)) => {
+            bootstub_command(&matches, sub_matches, &Config::load()?, None)
+        }
+        #[cfg(feature = "usb")]
+        Some(("download", sub_matches)) => {
+            download_command(&matches, sub_matches, &Config::load()?, None)
+        }
+        #[cfg(feature = "usb")]
+        Some(("udev-rule", _)) => {
+            say!("{}", device::udev_rule());
+            Ok(())
+        }
+        #[cfg(feature = "usb")]
+        Some(("usb", sub_matches)) => match sub_matches.subcommand() {
+            Some(("info", sub_matches)) => usb_info(sub_matches, &Config::load()?),
+            _ => unreachable!(),
+        },
+        #[cfg(not(feature = "usb"))]
+        Some(("list-devices" | "usb" | "download" | "udev-rule", _)) => {
+            Err(Error::not_in_build("USB"))
+        }
+        Some(("doctor", _)) => doctor(&Config::load()?),
+        Some(("detect", sub_matches)) => detect_command(&matches, sub_matches, &Config::load()?),
+        Some(("wait-for-device", sub_matches)) => {
+            let config = Config::load()?;
+            let device_arg = bootstub_device_arg(&matches, sub_matches, &config)?;
+
+            wait_for_device(
+                &device_arg,
+                sub_matches.get_one::<Duration>("SECONDS").copied(),
+            )
+        }
+        Some(("profiles", sub_matches)) => match sub_matches.subcommand() {
+            Some(("list", _)) => profiles_list(),
+            _ => unreachable!(),
+        },
+        Some(("config", sub_matches)) => match sub_matches.subcommand() {
+            Some(("show", _)) => config_show(),
+            _ => unreachable!(),
+        },
+        Some(("replay", sub_matches)) => replay_command(sub_matches),
+        Some(("capture", sub_matches)) => match sub_matches.subcommand() {
+            Some(("dump", sub_matches)) => capture_dump(sub_matches),
+            _ => unreachable!(),
+        },
+        Some(("simulate", sub_matches)) => simulate_command(sub_matches),
+        Some(("completions", sub_matches)) => {
+            let command = cli();
+            let script = match sub_matches.get_one::<String>("shell").unwrap().as_str() {
+                "bash" => completions::bash(&command),
+                "zsh" => completions::zsh(&command),
+                "fish" => completions::fish(&command),
+                _ => unreachable!(),
+            };
+
+            print!("{}", script);
+
+            Ok(())
+        }
+        _ => unreachable!(),
+    };
+
+    if let Some(path) = matches.get_one::<String>("record") {
+        record_result(path, &result)?;
+    }
+
+    result
+}
+
+fn main() {
+    cancel::catch_interrupt();
+
+    if let Err(err) = run() {
+        events::emit(
+            "error",
+            Object::new()
+                .field("kind", err.kind())
+                .field("message", err.to_string())
+                .field("exit_code", err.exit_code()),
+        );
+        error!("{}", err);
+        std::process::exit(err.exit_code());
+    }
 }
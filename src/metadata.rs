@@ -0,0 +1,300 @@
+use crate::bootstub::DumpDigest;
+use crate::error::{Error, Result};
+use crate::json::{self, Object, ToJson, Value};
+use crate::output::Part;
+use crate::sha256;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where a dump came from, written next to it so that it can still be told
+// apart from the others weeks later.
+pub struct DumpMetadata {
+    // How the device was reached, e.g. the serial port or the USB IDs.
+    pub device: Object,
+    pub stub_version: u32,
+    // Whether the version and features came from the stub, rather than
+    // being assumed with --legacy-protocol.
+    pub negotiated: bool,
+    pub stub_features: Vec<&'static str>,
+    pub start: u64,
+    pub end: u64,
+    pub access_width: u32,
+    // What the chunks were checked with on the way.
+    pub checksum: &'static str,
+    pub digest: DumpDigest,
+    // Empty unless the dump was split.
+    pub parts: Vec<Part>,
+    // What the output was compressed with, if anything.
+    pub compression: Option<&'static str>,
+    pub started: SystemTime,
+    pub finished: SystemTime,
+}
+
+// A point in time as UTC in RFC 3339, e.g. 2024-03-01T12:34:56Z.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Converts days since the epoch to a date in the proleptic Gregorian
+    // calendar, counting years from March so that leap days come last.
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+impl ToJson for DumpMetadata {
+    fn to_json(&self) -> String {
+        let parts = self
+            .parts
+            .iter()
+            .map(|part| {
+                Object::new()
+                    .field("file", part.path.display().to_string())
+                    .field("bytes", part.size)
+                    .field("sha256", sha256::to_hex(&part.sha256))
+            })
+            .collect::<Vec<_>>();
+
+        let mut object = Object::new()
+            .field("sbootil_version", env!("CARGO_PKG_VERSION"))
+            .field("device", &self.device)
+            .field(
+                "stub",
+                Object::new()
+                    .field("version", self.stub_version)
+                    .field("negotiated", self.negotiated)
+                    .field("features", &self.stub_features),
+            )
+            .field(
+                "range",
+                Object::new()
+                    .field("start", format!("{:#x}", self.start))
+                    .field("end", format!("{:#x}", self.end))
+                    .field("bytes", self.end - self.start),
+            )
+            .field("access_width", self.access_width)
+            .field("checksum", self.checksum)
+            .field("crc32", format!("{:08x}", self.digest.crc32))
+            .field("sha256", sha256::to_hex(&self.digest.sha256));
+
+        if !parts.is_empty() {
+            object = object.field("parts", parts);
+        }
+        if let Some(compression) = self.compression {
+            object = object.field("compression", compression);
+        }
+
+        object
+            .field("started", timestamp(self.started))
+            .field("finished", timestamp(self.finished))
+            .to_json()
+    }
+}
+
+// dump.bin.meta.json for dump.bin.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".meta.json");
+
+    PathBuf::from(path)
+}
+
+pub fn write(output: &Path, metadata: &DumpMetadata) -> Result<PathBuf> {
+    let path = sidecar_path(output);
+
+    std::fs::write(&path, format!("{}\n", metadata.to_json())).map_err(|source| Error::File {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    Ok(path)
+}
+
+fn describe_value(key: &str, value: &Value, indent: usize, lines: &mut Vec<String>) {
+    let prefix = " ".repeat(indent);
+
+    match value {
+        Value::Object(fields) => {
+            lines.push(format!("{}{}:", prefix, key));
+            for (key, value) in fields {
+                describe_value(key, value, indent + 2, lines);
+            }
+        }
+        Value::Array(values) if values.iter().any(|value| matches!(value, Value::Object(_))) => {
+            lines.push(format!("{}{}:", prefix, key));
+            for (index, value) in values.iter().enumerate() {
+                describe_value(&index.to_string(), value, indent + 2, lines);
+            }
+        }
+        Value::Array(values) if values.is_empty() => lines.push(format!("{}{}: none", prefix, key)),
+        Value::Array(values) => {
+            let values = values.iter().map(Value::to_string).collect::<Vec<_>>();
+            lines.push(format!("{}{}: {}", prefix, key, values.join(", ")));
+        }
+        value => lines.push(format!("{}{}: {}", prefix, key, value)),
+    }
+}
+
+// The contents of a metadata file as indented lines. The dump itself may be
+// given instead of its metadata file.
+pub fn describe(path: &Path) -> Result<Vec<String>> {
+    let path = if path.to_string_lossy().ends_with(".meta.json") {
+        path.to_path_buf()
+    } else {
+        sidecar_path(path)
+    };
+
+    let text = std::fs::read_to_string(&path).map_err(|source| Error::File {
+        path: path.display().to_string(),
+        source,
+    })?;
+    let value = json::parse(&text)
+        .map_err(|err| Error::InvalidArgument(format!("{}: {}", path.display(), err)))?;
+
+    let Value::Object(fields) = value else {
+        return Err(Error::InvalidArgument(format!(
+            "{}: expected an object",
+            path.display()
+        )));
+    };
+
+    let mut lines = Vec::new();
+    for (key, value) in &fields {
+        describe_value(key, value, 0, &mut lines);
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        assert_eq!(timestamp(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(at(951_782_400)), "2000-02-29T00:00:00Z");
+        assert_eq!(timestamp(at(1_709_253_296)), "2024-03-01T00:34:56Z");
+        assert_eq!(timestamp(at(4_102_444_799)), "2099-12-31T23:59:59Z");
+        // Clocks set before the epoch.
+        assert_eq!(
+            timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn sidecars_sit_next_to_the_dump() {
+        assert_eq!(
+            sidecar_path(Path::new("dumps/boot.bin")),
+            Path::new("dumps/boot.bin.meta.json")
+        );
+    }
+
+    #[test]
+    fn written_metadata_is_described() {
+        let output =
+            std::env::temp_dir().join(format!("sbootil-{}-metadata.bin", std::process::id()));
+        let metadata = DumpMetadata {
+            device: Object::new().field("serial", "/dev/ttyUSB0"),
+            stub_version: 2,
+            negotiated: true,
+            stub_features: vec!["crc", "window"],
+            start: 0x1000,
+            end: 0x1100,
+            access_width: 4,
+            checksum: "crc32",
+            digest: DumpDigest {
+                sha256: [0xab; 32],
+                crc32: 0x1234,
+            },
+            parts: vec![Part {
+                path: PathBuf::from("boot.bin.000"),
+                size: 0x100,
+                sha256: [0xcd; 32],
+            }],
+            compression: None,
+            started: at(0),
+            finished: at(61),
+        };
+
+        let path = write(&output, &metadata).unwrap();
+        assert_eq!(path, sidecar_path(&output));
+
+        let lines = describe(&output);
+        // Either the dump or its metadata file.
+        let same = describe(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = lines.unwrap();
+        let mut expected = vec![format!("sbootil_version: {}", env!("CARGO_PKG_VERSION"))];
+        expected.extend(
+            [
+                "device:",
+                "  serial: /dev/ttyUSB0",
+                "stub:",
+                "  version: 2",
+                "  negotiated: true",
+                "  features: crc, window",
+                "range:",
+                "  start: 0x1000",
+                "  end: 0x1100",
+                "  bytes: 256",
+                "access_width: 4",
+                "checksum: crc32",
+                "crc32: 00001234",
+                &format!("sha256: {}", "ab".repeat(32)),
+                "parts:",
+                "  0:",
+                "    file: boot.bin.000",
+                "    bytes: 256",
+                &format!("    sha256: {}", "cd".repeat(32)),
+                "started: 1970-01-01T00:00:00Z",
+                "finished: 1970-01-01T00:01:01Z",
+            ]
+            .map(String::from),
+        );
+        assert_eq!(lines, expected);
+        assert_eq!(same.unwrap(), lines);
+    }
+
+    #[test]
+    fn describing_needs_an_object() {
+        let path =
+            std::env::temp_dir().join(format!("sbootil-{}-list.meta.json", std::process::id()));
+        std::fs::write(&path, "[1, 2]\n").unwrap();
+
+        let result = describe(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(result, Err(Error::InvalidArgument(_))),
+            "{:?}",
+            result
+        );
+    }
+}
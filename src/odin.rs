@@ -0,0 +1,1562 @@
+use crate::cancel::CancelToken;
+use crate::error::{Error, Result};
+use crate::events;
+use crate::json::Object;
+use crate::pit::{Pit, PitEntry};
+use crate::retry::RetryPolicy;
+use crate::sha256::Sha256;
+use crate::timeouts::Timeouts;
+use crate::transport::{Statistics, Transport};
+use crate::{step, warning};
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+const PACKET_SIZE: usize = 1024;
+const RESPONSE_SIZE: usize = 8;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const SESSION_PACKET: u32 = 0x64;
+const PIT_FILE_PACKET: u32 = 0x65;
+const FILE_TRANSFER_PACKET: u32 = 0x66;
+const END_SESSION_PACKET: u32 = 0x67;
+
+const SESSION_BEGIN: u32 = 0x00;
+const SESSION_TOTAL_BYTES: u32 = 0x02;
+const SESSION_FILE_PART_SIZE: u32 = 0x05;
+// Asks for the lock state, which bootloaders that know it answer with, see
+// LockState.
+const SESSION_LOCK_STATE: u32 = 0x07;
+
+const PIT_FILE_DUMP: u32 = 0x01;
+const PIT_FILE_PART: u32 = 0x02;
+const PIT_FILE_END: u32 = 0x03;
+
+const FILE_TRANSFER_FLASH: u32 = 0x00;
+const FILE_TRANSFER_PART: u32 = 0x02;
+const FILE_TRANSFER_END: u32 = 0x03;
+
+const DESTINATION_PHONE: u32 = 0x00;
+const DESTINATION_MODEM: u32 = 0x01;
+
+// Partitions of this binary type belong to the modem rather than the
+// application processor.
+const BINARY_TYPE_MODEM: u32 = 0x01;
+
+const END_SESSION_END: u32 = 0x00;
+const END_SESSION_REBOOT: u32 = 0x01;
+// Selects where the following reboot goes, bootloaders that know it answer
+// with the target.
+const END_SESSION_REBOOT_TARGET: u32 = 0x02;
+
+// Rebooting back into download mode goes through the boot logo, which takes
+// a while on some devices.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where the device ends up after the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RebootTarget {
+    Normal,
+    Download,
+    Recovery,
+}
+
+impl RebootTarget {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(RebootTarget::Normal),
+            "download" => Some(RebootTarget::Download),
+            "recovery" => Some(RebootTarget::Recovery),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RebootTarget::Normal => "normal",
+            RebootTarget::Download => "download",
+            RebootTarget::Recovery => "recovery",
+        }
+    }
+
+    fn argument(self) -> u32 {
+        match self {
+            RebootTarget::Normal => 0x00,
+            RebootTarget::Download => 0x01,
+            RebootTarget::Recovery => 0x02,
+        }
+    }
+}
+
+// PIT files are sent back in parts of this size. Real ones are a few
+// kilobytes, anything much larger is a confused device.
+const PIT_PART_SIZE: usize = 500;
+const MAX_PIT_SIZE: usize = 1024 * 1024;
+
+// Files are sent in parts of this size, grouped into sequences of at most
+// this many parts. Bootloaders that announce a default packet size when the
+// session begins can deal with larger parts.
+const FILE_PART_SIZE: usize = 128 * 1024;
+const SEQUENCE_LENGTH: usize = 800;
+const LARGE_FILE_PART_SIZE: usize = 1024 * 1024;
+const LARGE_SEQUENCE_LENGTH: usize = 30;
+
+// Bootloaders that check every file part answer with this instead of the
+// packet type when a part arrived damaged, along with the index of the part,
+// which is then sent again up to this many times.
+const FILE_PART_RESEND: u32 = 0x80 | FILE_TRANSFER_PACKET;
+pub const FILE_PART_RETRIES: u32 = 3;
+
+// Bootloaders refuse to flash partitions that their lock state protects by
+// answering with this instead of the packet type, along with the lock state.
+const FILE_TRANSFER_REJECTED: u32 = 0x200 | FILE_TRANSFER_PACKET;
+
+// Whether the device takes images that aren't signed by the vendor, as the
+// OEM lock in bit 0 and the state of KG (Knox Guard) in bits 8 to 15.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockState(pub u32);
+
+impl LockState {
+    pub fn oem_locked(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn kg_state(self) -> Option<&'static str> {
+        match (self.0 >> 8) & 0xff {
+            0 => None,
+            1 => Some("prenormal"),
+            2 => Some("checking"),
+            3 => Some("completed"),
+            4 => Some("active"),
+            5 => Some("locked"),
+            6 => Some("broken"),
+            _ => Some("unknown"),
+        }
+    }
+
+    // Whether flashing images that aren't signed is likely to be refused.
+    pub fn is_restrictive(self) -> bool {
+        self.oem_locked() || matches!(self.kg_state(), Some("prenormal" | "locked" | "broken"))
+    }
+
+    pub fn to_object(self) -> Object {
+        Object::new()
+            .field("oem_locked", self.oem_locked())
+            .field("kg_state", self.kg_state())
+            .field("raw", format!("{:#x}", self.0))
+    }
+}
+
+impl std::fmt::Display for LockState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "OEM lock {}",
+            if self.oem_locked() { "on" } else { "off" }
+        )?;
+
+        match self.kg_state() {
+            Some(kg_state) => write!(f, ", KG {}", kg_state),
+            None => Ok(()),
+        }
+    }
+}
+
+fn rejected(partition: &str, lock_state: u32) -> Error {
+    Error::PermissionDenied(format!(
+        "The device refused to flash {}, the partition is protected by lock state {} ({:#x})",
+        partition,
+        LockState(lock_state),
+        lock_state
+    ))
+}
+
+// The bootloader may send a few more bytes after the end of the session,
+// which are read until nothing came for this long.
+const DRAIN_IDLE: Duration = Duration::from_millis(200);
+
+// What the device said about a file part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PartResponse {
+    Received(u32),
+    Resend(u32),
+    Rejected(u32),
+}
+
+// Writing a sequence to the flash can take a while, so the response to the
+// end of a sequence may be slow in coming.
+const SEQUENCE_END_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Which device is on the other end, to make sure it's the right one before
+// flashing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    // From the USB string descriptors, if the device has them.
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    // As announced when beginning the session, zero for older bootloaders.
+    pub default_packet_size: u32,
+    // If the bootloader tells.
+    pub lock_state: Option<LockState>,
+}
+
+impl Identity {
+    pub fn to_object(&self) -> Object {
+        Object::new()
+            .field("product", self.product.as_deref())
+            .field("serial_number", self.serial_number.as_deref())
+            .field("default_packet_size", self.default_packet_size)
+            .field("lock_state", self.lock_state.map(LockState::to_object))
+    }
+
+    // Fails unless the device reported the given serial number.
+    pub fn check_serial_number(&self, expected: &str) -> Result<()> {
+        match &self.serial_number {
+            Some(serial_number) if serial_number == expected => Ok(()),
+            Some(serial_number) => Err(Error::DeviceNotFound(format!(
+                "The device has serial number {}, not {}",
+                serial_number, expected
+            ))),
+            None => Err(Error::DeviceNotFound(format!(
+                "The device doesn't report a serial number, so it can't be checked against {}",
+                expected
+            ))),
+        }
+    }
+}
+
+pub struct Session {
+    transport: Box<dyn Transport>,
+    timeouts: Timeouts,
+    // As announced when beginning the session, zero for older bootloaders.
+    default_packet_size: u32,
+    lock_state: Option<LockState>,
+    file_part_size: Option<usize>,
+    // What was announced with set_total_bytes, and how much of it has been
+    // flashed so far.
+    total_bytes: Option<u64>,
+    flashed_bytes: u64,
+    // For file parts that the device asks for again.
+    retry: RetryPolicy,
+    // Pad packets and file parts only to a multiple of the max packet size
+    // of the endpoint, for bootloaders that choke on the padding.
+    pad_to_max_packet: bool,
+}
+
+impl Session {
+    pub fn begin(transport: Box<dyn Transport>, timeouts: Timeouts) -> Result<Self> {
+        Self::begin_with_padding(transport, timeouts, false)
+    }
+
+    // Like begin, with packets and file parts padded only to a multiple of
+    // the max packet size of the endpoint if `pad_to_max_packet` is set, from
+    // the very first packet on.
+    pub fn begin_with_padding(
+        mut transport: Box<dyn Transport>,
+        timeouts: Timeouts,
+        pad_to_max_packet: bool,
+    ) -> Result<Self> {
+        transport.set_timeout(Some(timeouts.response))?;
+
+        let mut session = Self {
+            transport,
+            timeouts,
+            default_packet_size: 0,
+            lock_state: None,
+            file_part_size: None,
+            total_bytes: None,
+            flashed_bytes: 0,
+            retry: RetryPolicy::new(FILE_PART_RETRIES),
+            pad_to_max_packet,
+        };
+
+        session.handshake()?;
+        session.transport.set_phase("begin-session");
+        session.default_packet_size = session.request(SESSION_PACKET, &[SESSION_BEGIN])?;
+
+        // Older bootloaders don't know about it.
+        if session.default_packet_size != 0 {
+            session.transport.set_phase("lock-state");
+            session.lock_state = match session.request(SESSION_PACKET, &[SESSION_LOCK_STATE]) {
+                Ok(value) => Some(LockState(value)),
+                Err(Error::Protocol { got, .. }) => {
+                    step!("the lock state query was refused with {:02x?}", got);
+                    None
+                }
+                // Some bootloaders that announce a packet size don't answer
+                // it at all, or not in full.
+                Err(err @ (Error::Timeout { .. } | Error::ShortRead { .. })) => {
+                    step!("the lock state query went unanswered: {}", err);
+                    session.drain();
+                    None
+                }
+                Err(err) => return Err(err),
+            };
+        }
+
+        Ok(session)
+    }
+
+    // Fills the whole buffer, with the timeout covering all of it.
+    fn read_exact(&mut self, buf: &mut [u8], timeout: Duration, phase: &str) -> Result<()> {
+        match self.transport.read_full(buf, timeout) {
+            Ok(count) if count < buf.len() => Err(Error::ShortRead {
+                phase: phase.to_string(),
+                expected: buf.len(),
+                got: count,
+            }),
+            Ok(_) => Ok(()),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut => Err(Error::Timeout {
+                phase: phase.to_string(),
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The traffic with the device so far.
+    pub fn statistics(&self) -> Statistics {
+        self.transport.statistics()
+    }
+
+    // What beginning the session told about the device, along with the
+    // string descriptors, which only the caller knows.
+    pub fn identity(&self, product: Option<String>, serial_number: Option<String>) -> Identity {
+        Identity {
+            product,
+            serial_number,
+            default_packet_size: self.default_packet_size,
+            lock_state: self.lock_state,
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.transport.set_phase("handshake");
+        step!("sending ODIN");
+        self.transport.write_all(b"ODIN")?;
+
+        let mut hello_response = [0u8; 4];
+
+        self.read_exact(
+            &mut hello_response,
+            self.timeouts.handshake,
+            "LOKE after sending ODIN",
+        )?;
+
+        if &hello_response != b"LOKE" {
+            return Err(Error::Protocol {
+                phase: "after sending ODIN".to_string(),
+                expected: b"LOKE".to_vec(),
+                got: hello_response.to_vec(),
+            });
+        }
+
+        step!("got LOKE");
+        events::emit("connected", Object::new().field("protocol", "odin"));
+
+        Ok(())
+    }
+
+    fn send_packet(&mut self, packet_type: u32, arguments: &[u32]) -> Result<()> {
+        step!("sending packet {:#x} {:x?}", packet_type, arguments);
+
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+
+        packet.extend_from_slice(&packet_type.to_le_bytes());
+        for argument in arguments {
+            packet.extend_from_slice(&argument.to_le_bytes());
+        }
+
+        self.write_padded(&packet, PACKET_SIZE)
+    }
+
+    // Sends `data` padded with zeros to `packet_size` bytes, or to the next
+    // multiple of the max packet size of the endpoint if set up that way.
+    fn write_padded(&mut self, data: &[u8], packet_size: usize) -> Result<()> {
+        let packet_size = match self.transport.max_packet_size() {
+            Some(max_packet_size) if self.pad_to_max_packet => {
+                data.len().max(1).next_multiple_of(max_packet_size)
+            }
+            _ => packet_size,
+        };
+
+        self.transport.write_padded(data, packet_size)
+    }
+
+    // Responses consist of the echoed packet type and a single value.
+    fn receive_response(&mut self, packet_type: u32) -> Result<u32> {
+        let mut response = [0u8; RESPONSE_SIZE];
+
+        self.read_exact(
+            &mut response,
+            self.timeouts.response,
+            &format!("the response to packet {:#x}", packet_type),
+        )?;
+
+        if response[0..4] != packet_type.to_le_bytes() {
+            return Err(Error::Protocol {
+                phase: format!("in response to packet {:#x}", packet_type),
+                expected: packet_type.to_le_bytes().to_vec(),
+                got: response.to_vec(),
+            });
+        }
+
+        let value = u32::from_le_bytes(response[4..8].try_into().unwrap());
+
+        step!("got response {:#x} {:#x}", packet_type, value);
+
+        Ok(value)
+    }
+
+    fn request(&mut self, packet_type: u32, arguments: &[u32]) -> Result<u32> {
+        self.send_packet(packet_type, arguments)?;
+
+        self.receive_response(packet_type)
+    }
+
+    pub fn receive_pit(&mut self) -> Result<Vec<u8>> {
+        self.transport.set_phase("pit");
+        let size = self.request(PIT_FILE_PACKET, &[PIT_FILE_DUMP])? as usize;
+        if size == 0 || size > MAX_PIT_SIZE {
+            return Err(Error::InvalidPit(format!(
+                "the device announced {:#x} bytes, which can't be a PIT",
+                size
+            )));
+        }
+
+        let mut pit = Vec::with_capacity(size);
+        let mut part = [0u8; PIT_PART_SIZE];
+
+        for index in 0..size.div_ceil(PIT_PART_SIZE) {
+            let wanted = (size - pit.len()).min(PIT_PART_SIZE);
+            let phase = format!("part {} of the PIT", index);
+            let mut retries = self.retry.start();
+
+            loop {
+                self.send_packet(PIT_FILE_PACKET, &[PIT_FILE_PART, index as u32])?;
+
+                match self.receive_pit_part(&mut part, wanted, &phase) {
+                    Ok(()) => break,
+                    Err(err @ (Error::Timeout { .. } | Error::ShortRead { .. }))
+                        if retries.remaining() =>
+                    {
+                        warning!("{}, asking for it again", err);
+                        self.transport.count_retry();
+                        // Whatever is still on its way belongs to the attempt
+                        // that was given up on.
+                        self.drain();
+                        retries.next(&phase);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            pit.extend_from_slice(&part[..wanted]);
+        }
+
+        self.request(PIT_FILE_PACKET, &[PIT_FILE_END])?;
+
+        // A PIT that got mixed up on the way doesn't hold the entries its
+        // header promises.
+        Pit::parse(&pit)?;
+
+        Ok(pit)
+    }
+
+    // Reads a part of the PIT, which is `wanted` bytes long. `part` has room
+    // for a whole part, as some bootloaders pad the last one, which would
+    // otherwise overflow the read or be taken as the next response.
+    fn receive_pit_part(&mut self, part: &mut [u8], wanted: usize, phase: &str) -> Result<()> {
+        let deadline = Instant::now() + self.timeouts.transfer;
+        let mut got = 0;
+
+        while got < wanted {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = match remaining.is_zero() {
+                true => Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+                false => self
+                    .transport
+                    .read_with_timeout(&mut part[got..], remaining),
+            };
+
+            match result {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(count) => got += count,
+                Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut && got > 0 => {
+                    return Err(Error::ShortRead {
+                        phase: phase.to_string(),
+                        expected: wanted,
+                        got,
+                    })
+                }
+                Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut => {
+                    return Err(Error::Timeout {
+                        phase: phase.to_string(),
+                    })
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        if got > wanted {
+            step!("dropping {} bytes after the end of the PIT", got - wanted);
+        }
+
+        Ok(())
+    }
+
+    // Announces how much is going to be flashed in this session. Sizes above
+    // 4 GiB only fit for bootloaders that take the upper half as well, older
+    // ones just ignore it.
+    pub fn set_total_bytes(&mut self, total: u64) -> Result<()> {
+        self.transport.set_phase("total-bytes");
+        self.request(
+            SESSION_PACKET,
+            &[SESSION_TOTAL_BYTES, total as u32, (total >> 32) as u32],
+        )?;
+
+        self.total_bytes = Some(total);
+
+        Ok(())
+    }
+
+    // Bootloaders don't take it well when a session ends short of what was
+    // announced, or goes past it.
+    fn check_total_bytes(&self, adding: u64) -> Result<()> {
+        let Some(total) = self.total_bytes else {
+            return Ok(());
+        };
+
+        let flashed = self.flashed_bytes + adding;
+        if flashed > total {
+            return Err(Error::InvalidArgument(format!(
+                "Flashing {} more bytes would go past the {} bytes announced for the session",
+                adding, total
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn check_session_complete(&self) -> Result<()> {
+        match self.total_bytes {
+            Some(total) if self.flashed_bytes != total => Err(Error::Verification(format!(
+                "Only {} of the {} bytes announced for the session were flashed",
+                self.flashed_bytes, total
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Agrees on the size of file parts, once per session.
+    fn file_part_size(&mut self) -> Result<(usize, usize)> {
+        if self.file_part_size.is_none() {
+            let size = if self.default_packet_size != 0 {
+                self.request(
+                    SESSION_PACKET,
+                    &[SESSION_FILE_PART_SIZE, LARGE_FILE_PART_SIZE as u32],
+                )?;
+                LARGE_FILE_PART_SIZE
+            } else {
+                FILE_PART_SIZE
+            };
+
+            step!("sending files in parts of {:#x} bytes", size);
+            self.file_part_size = Some(size);
+        }
+
+        Ok(match self.file_part_size {
+            Some(LARGE_FILE_PART_SIZE) => (LARGE_FILE_PART_SIZE, LARGE_SEQUENCE_LENGTH),
+            _ => (FILE_PART_SIZE, SEQUENCE_LENGTH),
+        })
+    }
+
+    // Writes `size` bytes from `data` to the partition, returning the SHA-256
+    // of what was sent.
+    pub fn flash(
+        &mut self,
+        entry: &PitEntry,
+        data: &mut dyn Read,
+        size: u64,
+        cancel: &CancelToken,
+    ) -> Result<[u8; 32]> {
+        if size == 0 {
+            return Err(Error::InvalidArgument(format!(
+                "The file for {} is empty",
+                entry.partition_name
+            )));
+        }
+
+        self.check_total_bytes(size)?;
+
+        self.transport.set_phase("flash");
+        let (part_size, sequence_length) = self.file_part_size()?;
+        let sequence_size = (part_size * sequence_length) as u64;
+
+        self.send_packet(FILE_TRANSFER_PACKET, &[FILE_TRANSFER_FLASH])?;
+        self.receive_flash_response(&entry.partition_name)?;
+
+        let mut sha256 = Sha256::new();
+        let mut done = 0u64;
+
+        self.flash_sequences(
+            entry,
+            data,
+            size,
+            (part_size, sequence_size),
+            (&mut sha256, &mut done),
+            cancel,
+        )
+        .map_err(|err| err.at_byte(&format!("flash of {}", entry.partition_name), done, size))?;
+
+        Ok(sha256.finish())
+    }
+
+    // Sends the data in sequences of parts, keeping track of how far it got.
+    fn flash_sequences(
+        &mut self,
+        entry: &PitEntry,
+        data: &mut dyn Read,
+        size: u64,
+        (part_size, sequence_size): (usize, u64),
+        (sha256, done): (&mut Sha256, &mut u64),
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        let mut part = vec![0u8; part_size];
+
+        while *done < size {
+            // Only between sequences, the bootloader has no way of being told
+            // to drop one that it's in the middle of.
+            cancel.check()?;
+
+            let sequence_bytes = (size - *done).min(sequence_size);
+            let last = *done + sequence_bytes == size;
+
+            step!(
+                "sending a sequence of {:#x} bytes at {:#x} for {}",
+                sequence_bytes,
+                *done,
+                entry.partition_name
+            );
+            self.send_packet(
+                FILE_TRANSFER_PACKET,
+                &[FILE_TRANSFER_PART, sequence_bytes as u32],
+            )?;
+            self.receive_flash_response(&entry.partition_name)?;
+
+            let part_count = sequence_bytes.div_ceil(part_size as u64);
+
+            for index in 0..part_count {
+                let count = (size - *done).min(part_size as u64) as usize;
+
+                data.read_exact(&mut part[..count])?;
+                sha256.update(&part[..count]);
+
+                self.send_part(
+                    &part[..count],
+                    part_size,
+                    index as u32,
+                    &entry.partition_name,
+                )?;
+
+                *done += count as u64;
+                self.flashed_bytes += count as u64;
+
+                events::progress(
+                    "flash_progress",
+                    *done,
+                    size,
+                    Object::new()
+                        .field("partition", entry.partition_name.as_str())
+                        .field("session_done", self.flashed_bytes)
+                        .field("session_total", self.total_bytes),
+                );
+            }
+
+            let end = if entry.binary_type == BINARY_TYPE_MODEM {
+                vec![
+                    FILE_TRANSFER_END,
+                    DESTINATION_MODEM,
+                    sequence_bytes as u32,
+                    0,
+                    entry.device_type,
+                    last as u32,
+                ]
+            } else {
+                vec![
+                    FILE_TRANSFER_END,
+                    DESTINATION_PHONE,
+                    sequence_bytes as u32,
+                    0,
+                    entry.device_type,
+                    entry.identifier,
+                    last as u32,
+                ]
+            };
+
+            self.send_packet(FILE_TRANSFER_PACKET, &end)?;
+
+            let previous = self.timeouts.response;
+            self.timeouts.response = previous.max(SEQUENCE_END_TIMEOUT);
+            let result = self.receive_flash_response(&entry.partition_name);
+            self.timeouts.response = previous;
+            result?;
+        }
+
+        Ok(())
+    }
+
+    // Like receive_response, for the file transfers of a flash, which the
+    // device may refuse because of its lock state.
+    fn receive_flash_response(&mut self, partition: &str) -> Result<u32> {
+        match self.receive_response(FILE_TRANSFER_PACKET) {
+            Err(Error::Protocol { got, .. }) if word(&got, 0) == FILE_TRANSFER_REJECTED => {
+                Err(rejected(partition, word(&got, 1)))
+            }
+            result => result,
+        }
+    }
+
+    // Sends a file part until the device confirms it, or gives up on it. The
+    // last part of a sequence is padded to the size agreed on for the session.
+    fn send_part(
+        &mut self,
+        part: &[u8],
+        part_size: usize,
+        index: u32,
+        partition: &str,
+    ) -> Result<()> {
+        let mut retries = self.retry.start();
+
+        loop {
+            self.write_padded(part, part_size)?;
+
+            match self.receive_part_response(index)? {
+                PartResponse::Received(received) if received == index => return Ok(()),
+                PartResponse::Resend(received) if received == index && retries.remaining() => {
+                    self.transport.count_retry();
+                    warning!(
+                        "The device asked for part {} of {} again ({} of {})",
+                        index,
+                        partition,
+                        retries.attempt() + 1,
+                        retries.attempts()
+                    );
+                    retries.next(&format!("part {} of {}", index, partition));
+                }
+                PartResponse::Rejected(lock_state) => return Err(rejected(partition, lock_state)),
+                PartResponse::Resend(received) if received == index => {
+                    return Err(Error::Verification(format!(
+                        "The device still rejected part {} of {} after {} retries",
+                        index,
+                        partition,
+                        retries.attempt()
+                    )))
+                }
+                // Confirming some other part means that the device and the
+                // host disagree about what has been written where.
+                PartResponse::Received(received) | PartResponse::Resend(received) => {
+                    return Err(Error::Protocol {
+                        phase: format!("after sending part {} of {}", index, partition),
+                        expected: index.to_le_bytes().to_vec(),
+                        got: received.to_le_bytes().to_vec(),
+                    })
+                }
+            }
+        }
+    }
+
+    // The device confirms every part with its index, or asks for it again.
+    fn receive_part_response(&mut self, index: u32) -> Result<PartResponse> {
+        let mut response = [0u8; RESPONSE_SIZE];
+
+        self.read_exact(
+            &mut response,
+            self.timeouts.transfer,
+            &format!("the confirmation of file part {}", index),
+        )?;
+
+        let value = word(&response, 1);
+
+        match word(&response, 0) {
+            FILE_TRANSFER_PACKET => Ok(PartResponse::Received(value)),
+            FILE_PART_RESEND => Ok(PartResponse::Resend(value)),
+            FILE_TRANSFER_REJECTED => Ok(PartResponse::Rejected(value)),
+            _ => Err(Error::Protocol {
+                phase: format!("in response to file part {}", index),
+                expected: FILE_TRANSFER_PACKET.to_le_bytes().to_vec(),
+                got: response.to_vec(),
+            }),
+        }
+    }
+
+    // Ends the session, leaving the device in download mode.
+    pub fn end(mut self) -> Result<()> {
+        self.finish(None)
+    }
+
+    // Leaves nothing behind for the next session with a device that stays in
+    // download mode. The session went through either way.
+    fn drain(&mut self) {
+        match self.transport.drain(DRAIN_IDLE) {
+            Ok(0) => {}
+            Ok(count) => step!("dropped {} bytes that the device still sent", count),
+            Err(err) => step!("failed to drain the connection: {}", err),
+        }
+    }
+
+    pub fn reboot(self) -> Result<()> {
+        self.reboot_to(RebootTarget::Normal)
+    }
+
+    // Reboots somewhere other than the system if the bootloader supports
+    // that. Otherwise, the device is left in download mode rather than
+    // rebooting into the system behind the user's back.
+    pub fn reboot_to(mut self, target: RebootTarget) -> Result<()> {
+        self.finish(Some(target))
+    }
+
+    // Ends the session and begins a new one, to check on the device after
+    // flashing. With `reboot`, the device reboots into download mode in
+    // between and the connection is made again once it's back.
+    pub fn restart(mut self, reboot: bool) -> Result<Self> {
+        if reboot {
+            self.finish(Some(RebootTarget::Download))?;
+
+            step!("waiting for the device to come back in download mode");
+            self.transport.reconnect(REBOOT_TIMEOUT)?;
+        } else {
+            self.finish(None)?;
+        }
+
+        let retry = self.retry;
+        let mut session = Self::begin(self.transport, self.timeouts)?;
+        session.retry = retry;
+
+        Ok(session)
+    }
+
+    // Ends the session, rebooting to `target` if given.
+    fn finish(&mut self, target: Option<RebootTarget>) -> Result<()> {
+        self.transport.set_phase("end-session");
+
+        // The device is left in download mode to flash again, rather than
+        // rebooting it into what was only partly written.
+        if let Err(err) = self.check_session_complete() {
+            match self.request(END_SESSION_PACKET, &[END_SESSION_END]) {
+                Ok(_) => self.drain(),
+                Err(end) => step!("ending the session failed as well: {}", end),
+            }
+
+            return Err(err);
+        }
+
+        let Some(target) = target else {
+            self.request(END_SESSION_PACKET, &[END_SESSION_END])?;
+            self.drain();
+
+            return Ok(());
+        };
+
+        if target != RebootTarget::Normal {
+            let answer = match self.request(
+                END_SESSION_PACKET,
+                &[END_SESSION_REBOOT_TARGET, target.argument()],
+            ) {
+                Ok(answer) => Some(answer),
+                Err(Error::Protocol { got, .. }) => {
+                    step!("the reboot target was refused with {:02x?}", got);
+                    None
+                }
+                Err(err) => return Err(err),
+            };
+
+            if answer != Some(target.argument()) {
+                self.request(END_SESSION_PACKET, &[END_SESSION_END])?;
+                self.drain();
+
+                return Err(Error::Unsupported(format!(
+                    "The bootloader doesn't support rebooting to {}, the device stays in download mode",
+                    target.name()
+                )));
+            }
+        }
+
+        self.request(END_SESSION_PACKET, &[END_SESSION_REBOOT])?;
+        self.drain();
+
+        Ok(())
+    }
+}
+
+fn packet_name(packet_type: u32) -> Option<&'static str> {
+    match packet_type {
+        SESSION_PACKET => Some("session"),
+        PIT_FILE_PACKET => Some("PIT file"),
+        FILE_TRANSFER_PACKET => Some("file transfer"),
+        END_SESSION_PACKET => Some("end session"),
+        _ => None,
+    }
+}
+
+fn word(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+// Explains a packet sent by the host, for looking through session logs.
+pub fn describe_packet(packet: &[u8]) -> Option<String> {
+    if packet.len() != PACKET_SIZE {
+        return None;
+    }
+
+    let action = match (word(packet, 0), word(packet, 1)) {
+        (SESSION_PACKET, SESSION_BEGIN) => "begin session",
+        (SESSION_PACKET, SESSION_TOTAL_BYTES) => "total bytes",
+        (SESSION_PACKET, SESSION_FILE_PART_SIZE) => "file part size",
+        (SESSION_PACKET, SESSION_LOCK_STATE) => "lock state",
+        (PIT_FILE_PACKET, PIT_FILE_DUMP) => "send PIT",
+        (PIT_FILE_PACKET, PIT_FILE_PART) => "PIT part",
+        (PIT_FILE_PACKET, PIT_FILE_END) => "end of PIT",
+        (FILE_TRANSFER_PACKET, FILE_TRANSFER_FLASH) => "flash",
+        (FILE_TRANSFER_PACKET, FILE_TRANSFER_PART) => "sequence",
+        (FILE_TRANSFER_PACKET, FILE_TRANSFER_END) => "end of sequence",
+        (END_SESSION_PACKET, END_SESSION_END) => "end session",
+        (END_SESSION_PACKET, END_SESSION_REBOOT) => "reboot",
+        (END_SESSION_PACKET, END_SESSION_REBOOT_TARGET) => "reboot target",
+        _ => return None,
+    };
+
+    // None of the packets have more arguments than this, the rest is padding.
+    let mut arguments = (2..8).map(|index| word(packet, index)).collect::<Vec<_>>();
+    while arguments.last() == Some(&0) {
+        arguments.pop();
+    }
+
+    Some(if arguments.is_empty() {
+        format!("odin: {}", action)
+    } else {
+        let arguments = arguments
+            .iter()
+            .map(|argument| format!("{:#x}", argument))
+            .collect::<Vec<_>>();
+        format!("odin: {} [{}]", action, arguments.join(", "))
+    })
+}
+
+// Explains a response from the device, the counterpart of describe_packet.
+pub fn describe_response(response: &[u8]) -> Option<String> {
+    if response.len() != RESPONSE_SIZE {
+        return None;
+    }
+
+    if word(response, 0) == FILE_PART_RESEND {
+        return Some(format!(
+            "odin: request to resend file part {:#x}",
+            word(response, 1)
+        ));
+    }
+
+    if word(response, 0) == FILE_TRANSFER_REJECTED {
+        return Some(format!(
+            "odin: flash refused, lock state {}",
+            LockState(word(response, 1))
+        ));
+    }
+
+    let name = packet_name(word(response, 0))?;
+
+    Some(format!(
+        "odin: response to {} packet, {:#x}",
+        name,
+        word(response, 1)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pit::tests::{pit_data, Random};
+    use crate::transport::MockTransport;
+    use std::io::Write;
+
+    fn packet(packet_type: u32, arguments: &[u32]) -> Vec<u8> {
+        let mut packet = packet_type.to_le_bytes().to_vec();
+        for argument in arguments {
+            packet.extend_from_slice(&argument.to_le_bytes());
+        }
+        packet.resize(PACKET_SIZE, 0);
+
+        packet
+    }
+
+    fn response(packet_type: u32, value: u32) -> Vec<u8> {
+        let mut response = packet_type.to_le_bytes().to_vec();
+        response.extend_from_slice(&value.to_le_bytes());
+
+        response
+    }
+
+    fn exchange(mock: &MockTransport, packet_type: u32, arguments: &[u32], value: u32) {
+        mock.expect_write(&packet(packet_type, arguments))
+            .respond(&response(packet_type, value));
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts::new(DEFAULT_TIMEOUT)
+    }
+
+    // An older bootloader, without a default packet size or lock state.
+    fn begin(mock: &MockTransport) -> Session {
+        mock.expect_write(b"ODIN").respond(b"LOKE");
+        exchange(mock, SESSION_PACKET, &[SESSION_BEGIN], 0);
+
+        Session::begin(Box::new(mock.clone()), timeouts()).unwrap()
+    }
+
+    fn boot_entry() -> PitEntry {
+        Pit::parse(&pit_data(&[("BOOT", "boot.img")], 1024))
+            .unwrap()
+            .entries
+            .remove(0)
+    }
+
+    // The part with the data of a file that fits into one, padded to the
+    // size of a part.
+    fn part(data: &[u8]) -> Vec<u8> {
+        let mut part = data.to_vec();
+        part.resize(FILE_PART_SIZE, 0);
+
+        part
+    }
+
+    // Everything up to sending the only part of a file of `size` bytes.
+    fn expect_flash_start(mock: &MockTransport, size: u32) {
+        exchange(mock, FILE_TRANSFER_PACKET, &[FILE_TRANSFER_FLASH], 0);
+        exchange(mock, FILE_TRANSFER_PACKET, &[FILE_TRANSFER_PART, size], 0);
+    }
+
+    fn expect_flash_end(mock: &MockTransport, entry: &PitEntry, size: u32) {
+        exchange(
+            mock,
+            FILE_TRANSFER_PACKET,
+            &[
+                FILE_TRANSFER_END,
+                DESTINATION_PHONE,
+                size,
+                0,
+                entry.device_type,
+                entry.identifier,
+                1,
+            ],
+            0,
+        );
+    }
+
+    #[test]
+    fn begin_with_an_older_bootloader() {
+        let mock = MockTransport::new();
+        let session = begin(&mock);
+
+        let identity = session.identity(None, None);
+        assert_eq!(identity.default_packet_size, 0);
+        assert_eq!(identity.lock_state, None);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn begin_asks_for_the_lock_state() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"ODIN").respond_fragmented(b"LOKE", 1);
+        exchange(&mock, SESSION_PACKET, &[SESSION_BEGIN], 0x100000);
+        exchange(&mock, SESSION_PACKET, &[SESSION_LOCK_STATE], 0x401);
+
+        let session = Session::begin(Box::new(mock.clone()), timeouts()).unwrap();
+
+        let lock_state = session.identity(None, None).lock_state.unwrap();
+        assert!(lock_state.oem_locked());
+        assert_eq!(lock_state.kg_state(), Some("active"));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn begin_without_an_answer_to_the_lock_state() {
+        for answer in [&[][..], &response(SESSION_PACKET, 0x401)[..3]] {
+            let mock = MockTransport::new();
+            mock.expect_write(b"ODIN").respond(b"LOKE");
+            exchange(&mock, SESSION_PACKET, &[SESSION_BEGIN], 0x100000);
+            mock.expect_write(&packet(SESSION_PACKET, &[SESSION_LOCK_STATE]));
+            if !answer.is_empty() {
+                mock.respond(answer);
+            }
+            mock.time_out();
+
+            let session = Session::begin(Box::new(mock.clone()), timeouts()).unwrap();
+            assert_eq!(session.identity(None, None).lock_state, None);
+
+            // The session carries on without it.
+            exchange(&mock, END_SESSION_PACKET, &[END_SESSION_END], 0);
+            session.end().unwrap();
+            assert!(mock.is_finished());
+        }
+    }
+
+    #[test]
+    fn handshake_fails_on_a_wrong_answer() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"ODIN").respond(b"LOK").time_out();
+
+        let result = Session::begin(Box::new(mock.clone()), timeouts());
+        assert!(matches!(result, Err(Error::ShortRead { got: 3, .. })));
+
+        let mock = MockTransport::new();
+        mock.expect_write(b"ODIN").respond(b"NOPE");
+
+        let result = Session::begin(Box::new(mock.clone()), timeouts());
+        assert!(matches!(result, Err(Error::Protocol { got, .. }) if got == b"NOPE"));
+    }
+
+    #[test]
+    fn receive_pit_in_parts() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        let pit = pit_data(&[("BOOT", "boot.img"), ("SYSTEM", "system.img")], 600);
+
+        exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_DUMP], 600);
+        mock.expect_write(&packet(PIT_FILE_PACKET, &[PIT_FILE_PART, 0]))
+            .respond_fragmented(&pit[..500], 64);
+        mock.expect_write(&packet(PIT_FILE_PACKET, &[PIT_FILE_PART, 1]))
+            .respond(&pit[500..]);
+        exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_END], 0);
+
+        assert_eq!(session.receive_pit().unwrap(), pit);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn receive_pits_of_different_sizes() {
+        let names = (0..121)
+            .map(|index| (format!("PART{}", index), format!("part{}.img", index)))
+            .collect::<Vec<_>>();
+        let names = names
+            .iter()
+            .map(|(name, file)| (name.as_str(), file.as_str()))
+            .collect::<Vec<_>>();
+
+        // From a single short part to ones that fill their last part exactly,
+        // the last one with 121 entries and no padding at all.
+        for (count, size) in [
+            (1, 160),
+            (2, 499),
+            (3, 500),
+            (7, 1000),
+            (7, 1001),
+            (30, 4096),
+            (121, 16000),
+        ] {
+            // Some bootloaders send whole parts even at the end.
+            for padded in [false, true] {
+                let mock = MockTransport::new();
+                let mut session = begin(&mock);
+                let pit = pit_data(&names[..count], size);
+
+                exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_DUMP], size as u32);
+                for (index, data) in pit.chunks(PIT_PART_SIZE).enumerate() {
+                    let mut data = data.to_vec();
+                    if padded {
+                        data.resize(PIT_PART_SIZE, 0);
+                    }
+
+                    mock.expect_write(&packet(PIT_FILE_PACKET, &[PIT_FILE_PART, index as u32]))
+                        .respond(&data);
+                }
+                exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_END], 0);
+
+                let received = session.receive_pit().unwrap();
+                assert_eq!(received, pit, "{} bytes", size);
+                assert_eq!(Pit::parse(&received).unwrap().entries.len(), count);
+                assert!(mock.is_finished(), "{} bytes", size);
+            }
+        }
+    }
+
+    #[test]
+    fn receive_pit_asks_for_a_part_that_timed_out_again() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        let pit = pit_data(&[("BOOT", "boot.img")], 300);
+
+        exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_DUMP], 300);
+        mock.expect_write(&packet(PIT_FILE_PACKET, &[PIT_FILE_PART, 0]))
+            .respond(&pit[..100])
+            .time_out();
+        mock.expect_write(&packet(PIT_FILE_PACKET, &[PIT_FILE_PART, 0]))
+            .respond(&pit);
+        exchange(&mock, PIT_FILE_PACKET, &[PIT_FILE_END], 0);
+
+        assert_eq!(session.receive_pit().unwrap(), pit);
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_sends_a_file() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        let entry = boot_entry();
+        let data = [0x5a; 300];
+
+        expect_flash_start(&mock, 300);
+        mock.expect_write(&part(&data))
+            .respond(&response(FILE_TRANSFER_PACKET, 0));
+        expect_flash_end(&mock, &entry, 300);
+
+        let digest = session
+            .flash(&entry, &mut data.as_slice(), 300, &CancelToken::new())
+            .unwrap();
+
+        assert_eq!(digest, crate::sha256::sha256(&data));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_pads_parts_to_the_size_agreed_on() {
+        let mock = MockTransport::new();
+        mock.expect_write(b"ODIN").respond(b"LOKE");
+        exchange(&mock, SESSION_PACKET, &[SESSION_BEGIN], 0x100000);
+        exchange(&mock, SESSION_PACKET, &[SESSION_LOCK_STATE], 0);
+        let mut session = Session::begin(Box::new(mock.clone()), timeouts()).unwrap();
+        let entry = boot_entry();
+        let data = [0x5a; 300];
+
+        exchange(
+            &mock,
+            SESSION_PACKET,
+            &[SESSION_FILE_PART_SIZE, LARGE_FILE_PART_SIZE as u32],
+            0,
+        );
+        expect_flash_start(&mock, 300);
+        let mut part = data.to_vec();
+        part.resize(LARGE_FILE_PART_SIZE, 0);
+        mock.expect_write(&part)
+            .respond(&response(FILE_TRANSFER_PACKET, 0));
+        expect_flash_end(&mock, &entry, 300);
+
+        session
+            .flash(&entry, &mut data.as_slice(), 300, &CancelToken::new())
+            .unwrap();
+
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_pads_only_to_the_max_packet_size_if_asked_to() {
+        let mock = MockTransport::new();
+        mock.set_max_packet_size(512);
+        // Everything goes out in a single packet of the endpoint.
+        let exchange = |packet_type, arguments: &[u32], value| {
+            mock.expect_write(&packet(packet_type, arguments)[..512])
+                .respond(&response(packet_type, value));
+        };
+
+        mock.expect_write(b"ODIN").respond(b"LOKE");
+        exchange(SESSION_PACKET, &[SESSION_BEGIN], 0);
+        let mut session =
+            Session::begin_with_padding(Box::new(mock.clone()), timeouts(), true).unwrap();
+        let entry = boot_entry();
+        let data = [0x5a; 300];
+
+        exchange(FILE_TRANSFER_PACKET, &[FILE_TRANSFER_FLASH], 0);
+        exchange(FILE_TRANSFER_PACKET, &[FILE_TRANSFER_PART, 300], 0);
+        mock.expect_write(&part(&data)[..512])
+            .respond(&response(FILE_TRANSFER_PACKET, 0));
+        exchange(
+            FILE_TRANSFER_PACKET,
+            &[
+                FILE_TRANSFER_END,
+                DESTINATION_PHONE,
+                300,
+                0,
+                entry.device_type,
+                entry.identifier,
+                1,
+            ],
+            0,
+        );
+
+        session
+            .flash(&entry, &mut data.as_slice(), 300, &CancelToken::new())
+            .unwrap();
+
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_sends_a_part_again_when_asked_to() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        let entry = boot_entry();
+        let data = [0x5a; 300];
+
+        expect_flash_start(&mock, 300);
+        mock.expect_write(&part(&data))
+            .respond(&response(FILE_PART_RESEND, 0))
+            .expect_write(&part(&data))
+            .respond(&response(FILE_TRANSFER_PACKET, 0));
+        expect_flash_end(&mock, &entry, 300);
+
+        session
+            .flash(&entry, &mut data.as_slice(), 300, &CancelToken::new())
+            .unwrap();
+
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_gives_up_after_the_resends_run_out() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        session.set_retry_policy(RetryPolicy::new(1));
+        let data = [0x5a; 300];
+
+        expect_flash_start(&mock, 300);
+        mock.expect_write(&part(&data))
+            .respond(&response(FILE_PART_RESEND, 0))
+            .expect_write(&part(&data))
+            .respond(&response(FILE_PART_RESEND, 0));
+
+        let result = session.flash(
+            &boot_entry(),
+            &mut data.as_slice(),
+            300,
+            &CancelToken::new(),
+        );
+
+        assert!(matches!(result, Err(Error::Verification(_))));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn flash_fails_when_the_device_confirms_the_wrong_part() {
+        let data = [0x5a; 300];
+
+        // After sending the part again, and when asked to send another one.
+        for answers in [
+            &[
+                response(FILE_PART_RESEND, 0),
+                response(FILE_TRANSFER_PACKET, 1),
+            ][..],
+            &[response(FILE_PART_RESEND, 7)][..],
+        ] {
+            let mock = MockTransport::new();
+            let mut session = begin(&mock);
+
+            expect_flash_start(&mock, 300);
+            for answer in answers {
+                mock.expect_write(&part(&data)).respond(answer);
+            }
+
+            let result = session.flash(
+                &boot_entry(),
+                &mut data.as_slice(),
+                300,
+                &CancelToken::new(),
+            );
+
+            match result {
+                Err(Error::Protocol { expected, got, .. }) => {
+                    assert_eq!(expected, 0u32.to_le_bytes());
+                    assert_eq!(got, word(answers.last().unwrap(), 1).to_le_bytes());
+                }
+                result => panic!("{:?}", result.map(|_| ())),
+            }
+            assert!(mock.is_finished());
+        }
+    }
+
+    #[test]
+    fn flash_refused_by_the_lock_state() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+
+        mock.expect_write(&packet(FILE_TRANSFER_PACKET, &[FILE_TRANSFER_FLASH]))
+            .respond(&response(FILE_TRANSFER_REJECTED, 1));
+
+        let result = session.flash(
+            &boot_entry(),
+            &mut [0x5a; 300].as_slice(),
+            300,
+            &CancelToken::new(),
+        );
+
+        assert!(matches!(result, Err(Error::PermissionDenied(_))));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn end_and_reboot() {
+        let mock = MockTransport::new();
+        let session = begin(&mock);
+        exchange(&mock, END_SESSION_PACKET, &[END_SESSION_END], 0);
+
+        session.end().unwrap();
+        assert!(mock.is_finished());
+
+        let mock = MockTransport::new();
+        let session = begin(&mock);
+        exchange(&mock, END_SESSION_PACKET, &[END_SESSION_REBOOT], 0);
+
+        session.reboot().unwrap();
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn end_a_session_short_of_what_was_announced() {
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        exchange(&mock, SESSION_PACKET, &[SESSION_TOTAL_BYTES, 4096, 0], 0);
+        session.set_total_bytes(4096).unwrap();
+
+        // The session is still ended, but the device isn't rebooted.
+        exchange(&mock, END_SESSION_PACKET, &[END_SESSION_END], 0);
+
+        let result = session.reboot();
+        assert!(matches!(result, Err(Error::Verification(_))));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn reboot_to_a_target_the_bootloader_doesnt_know() {
+        let mock = MockTransport::new();
+        let session = begin(&mock);
+        mock.expect_write(&packet(
+            END_SESSION_PACKET,
+            &[END_SESSION_REBOOT_TARGET, RebootTarget::Recovery.argument()],
+        ))
+        .respond(&response(0xff, 0));
+        exchange(&mock, END_SESSION_PACKET, &[END_SESSION_END], 0);
+
+        let result = session.reboot_to(RebootTarget::Recovery);
+
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn responses_round_trip_through_the_decoder() {
+        let mut random = Random::new(0x6a09e667);
+        let mock = MockTransport::new();
+        let mut session = begin(&mock);
+        let packet_types = [
+            SESSION_PACKET,
+            PIT_FILE_PACKET,
+            FILE_TRANSFER_PACKET,
+            END_SESSION_PACKET,
+        ];
+
+        for _ in 0..200 {
+            let packet_type = packet_types[random.below(packet_types.len())];
+            let arguments = (0..random.below(6))
+                .map(|_| random.word() as u32)
+                .collect::<Vec<_>>();
+            let value = random.word() as u32;
+            exchange(&mock, packet_type, &arguments, value);
+
+            assert_eq!(session.request(packet_type, &arguments).unwrap(), value);
+
+            let described = describe_response(&response(packet_type, value)).unwrap();
+            assert!(
+                described.ends_with(&format!("{:#x}", value)),
+                "{}",
+                described
+            );
+        }
+
+        assert!(mock.is_finished());
+    }
+
+    // Answers with whatever it was given, a few bytes at a time, and takes
+    // anything that is sent to it.
+    struct Arbitrary {
+        responses: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for Arbitrary {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.position == self.responses.len() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            let count = buf.len().min(self.responses.len() - self.position).min(7);
+            buf[..count].copy_from_slice(&self.responses[self.position..self.position + count]);
+            self.position += count;
+
+            Ok(count)
+        }
+    }
+
+    impl Write for Arbitrary {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Arbitrary {
+        fn timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn arbitrary_responses_never_panic() {
+        let mut random = Random::new(0xbb67ae85);
+        let entry = boot_entry();
+        let file = [0x5au8; 300];
+
+        for round in 0..2000 {
+            // Starting out right, to get past the handshake at least.
+            let mut responses = match round % 2 {
+                0 => b"LOKE".to_vec(),
+                _ => Vec::new(),
+            };
+            let len = random.below(200);
+            responses.extend(random.bytes(len));
+
+            for described in [describe_response(&responses), describe_packet(&responses)] {
+                assert!(described.is_none_or(|text| text.starts_with("odin: ")));
+            }
+
+            let device = Arbitrary {
+                responses,
+                position: 0,
+            };
+            let Ok(mut session) = Session::begin(Box::new(device), Timeouts::new(Duration::ZERO))
+            else {
+                continue;
+            };
+            session.set_retry_policy(RetryPolicy {
+                backoff: Duration::ZERO,
+                ..RetryPolicy::new(FILE_PART_RETRIES)
+            });
+
+            if let Ok(pit) = session.receive_pit() {
+                let _ = Pit::parse(&pit);
+            }
+            let _ = session.flash(
+                &entry,
+                &mut &file[..],
+                file.len() as u64,
+                &CancelToken::new(),
+            );
+        }
+    }
+}
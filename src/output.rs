@@ -0,0 +1,342 @@
+use crate::error::{Error, Result};
+use crate::sha256::{self, Sha256};
+use crate::{status, warning};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+fn already_exists(path: &Path) -> Error {
+    Error::InvalidArgument(format!(
+        "{} already exists, pass --force to overwrite it",
+        path.display()
+    ))
+}
+
+// Fails early if creating the file later on would fail because it exists, so
+// that nothing has been sent to the device by then.
+pub fn check(path: &Path, force: bool) -> Result<()> {
+    if !force && path.exists() {
+        return Err(already_exists(path));
+    }
+
+    Ok(())
+}
+
+// Creates a file for output, including any missing parent directories. An
+// existing file is only replaced if forced to.
+pub fn create(path: &Path, force: bool) -> Result<File> {
+    let file_error = |source| Error::File {
+        path: path.display().to_string(),
+        source,
+    };
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent).map_err(|source| Error::File {
+            path: parent.display().to_string(),
+            source,
+        })?;
+    }
+
+    let mut options = File::options();
+    options.write(true);
+
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        // Checking and creating in one go, so nothing can slip in between.
+        options.create_new(true);
+    }
+
+    options.open(path).map_err(|source| match source.kind() {
+        std::io::ErrorKind::AlreadyExists => already_exists(path),
+        _ => file_error(source),
+    })
+}
+
+// Reads a finished output file back in, to catch anything going wrong between
+// receiving the data and it ending up in the file.
+pub fn verify(path: &Path, expected: &[u8; 32]) -> Result<()> {
+    let file_error = |source| Error::File {
+        path: path.display().to_string(),
+        source,
+    };
+
+    let mut file = File::open(path).map_err(file_error)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        match file.read(&mut buf).map_err(file_error)? {
+            0 => break,
+            count => hasher.update(&buf[..count]),
+        }
+    }
+
+    let digest = hasher.finish();
+    if digest != *expected {
+        return Err(Error::Verification(format!(
+            "{} doesn't contain what was received, its SHA-256 is {}",
+            path.display(),
+            sha256::to_hex(&digest)
+        )));
+    }
+
+    Ok(())
+}
+
+// Gets a failed dump out of the way, so that it can't be mistaken for a good
+// one later on.
+pub fn discard(path: &Path, keep: bool) {
+    if !keep {
+        if let Err(err) = std::fs::remove_file(path) {
+            warning!("Failed to delete {}: {}", path.display(), err);
+        }
+        return;
+    }
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    match std::fs::rename(path, &partial) {
+        Ok(()) => status!("Kept what was received in {}", partial.display()),
+        Err(err) => warning!(
+            "Failed to rename {} to {}: {}",
+            path.display(),
+            partial.display(),
+            err
+        ),
+    }
+}
+
+// The name of a part of split output, e.g. `dump.bin.002`.
+pub fn part_path(path: &Path, index: usize) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(format!(".{:03}", index));
+
+    PathBuf::from(part)
+}
+
+// The parts that are already there, which are numbered without gaps.
+fn existing_parts(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    (0..)
+        .map(|index| part_path(path, index))
+        .take_while(|part| part.exists())
+}
+
+// Like `check`, but for all the parts of split output.
+pub fn check_parts(path: &Path, force: bool) -> Result<()> {
+    match existing_parts(path).next() {
+        Some(part) if !force => Err(already_exists(&part)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone)]
+pub struct Part {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+// Writes output into parts of a fixed size, so that concatenating them gives
+// back exactly what was written. Parts are only created once there is data
+// for them, except for the first one.
+pub struct SplitWriter {
+    path: PathBuf,
+    part_size: u64,
+    force: bool,
+    current: Option<(File, Sha256)>,
+    parts: Vec<Part>,
+}
+
+impl SplitWriter {
+    pub fn new(path: &Path, part_size: u64, force: bool) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            part_size: part_size.max(1),
+            force,
+            current: None,
+            parts: Vec::new(),
+        }
+    }
+
+    // The parts so far, including any that is still being written.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    fn finish_part(&mut self) {
+        if let Some((_, hasher)) = self.current.take() {
+            self.parts.last_mut().unwrap().sha256 = hasher.finish();
+        }
+    }
+
+    fn start_part(&mut self) -> Result<()> {
+        self.finish_part();
+
+        let path = part_path(&self.path, self.parts.len());
+        let file = create(&path, self.force)?;
+
+        self.parts.push(Part {
+            path,
+            size: 0,
+            sha256: [0; 32],
+        });
+        self.current = Some((file, Sha256::new()));
+
+        Ok(())
+    }
+
+    // Closes the last part and, when overwriting, removes parts from earlier
+    // output that went on for longer, so they don't get concatenated along.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.parts.is_empty() {
+            self.start_part()?;
+        }
+        self.finish_part();
+
+        for stale in existing_parts(&self.path).skip(self.parts.len()) {
+            std::fs::remove_file(&stale).map_err(|source| Error::File {
+                path: stale.display().to_string(),
+                source,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let full = self
+            .parts
+            .last()
+            .is_none_or(|part| part.size == self.part_size);
+        if full {
+            self.start_part().map_err(std::io::Error::other)?;
+        }
+
+        let part = self.parts.last_mut().unwrap();
+        let (file, hasher) = self.current.as_mut().unwrap();
+        let room = usize::try_from(self.part_size - part.size).unwrap_or(usize::MAX);
+        let count = buf.len().min(room);
+
+        let count = file.write(&buf[..count])?;
+        hasher.update(&buf[..count]);
+        part.size += count as u64;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRECTORIES: AtomicUsize = AtomicUsize::new(0);
+
+    // A directory of its own for every test, removed along with everything
+    // in it once dropped.
+    struct TemporaryDir(PathBuf);
+
+    impl TemporaryDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "sbootil-test-{}-{}",
+                std::process::id(),
+                DIRECTORIES.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir(&path).unwrap();
+
+            Self(path)
+        }
+    }
+
+    impl Drop for TemporaryDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn existing_files_are_only_replaced_when_forced() {
+        let dir = TemporaryDir::new();
+        let path = dir.0.join("dump.bin");
+        std::fs::write(&path, b"earlier").unwrap();
+
+        let err = check(&path, false).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{}", err);
+        assert!(err.to_string().contains("--force"), "{}", err);
+        let err = create(&path, false).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), b"earlier");
+
+        check(&path, true).unwrap();
+        create(&path, true).unwrap().write_all(b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn missing_parents_are_created() {
+        let dir = TemporaryDir::new();
+        let path = dir.0.join("board").join("rev2").join("dump.bin");
+
+        check(&path, false).unwrap();
+        create(&path, false).unwrap().write_all(b"data").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_directories_fail_with_the_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TemporaryDir::new();
+        std::fs::set_permissions(&dir.0, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions don't hold back root.
+        if File::create(dir.0.join("probe")).is_ok() {
+            std::fs::set_permissions(&dir.0, std::fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let path = dir.0.join("dump.bin");
+        check(&path, false).unwrap();
+        let err = create(&path, true).unwrap_err();
+        assert!(
+            matches!(&err, Error::File { path: failed, source }
+                if *failed == path.display().to_string()
+                    && source.kind() == std::io::ErrorKind::PermissionDenied),
+            "{}",
+            err
+        );
+
+        // A parent that can't be created is the one that's reported.
+        let parent = dir.0.join("board");
+        let err = create(&parent.join("dump.bin"), false).unwrap_err();
+        assert!(
+            matches!(&err, Error::File { path: failed, .. } if *failed == parent.display().to_string()),
+            "{}",
+            err
+        );
+
+        std::fs::set_permissions(&dir.0, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::crc32::Crc32;
+use crate::json::{Object, ToJson};
+use std::io::Write;
+
+// Pages whose contents have at least this many bits of entropy per byte are
+// most likely compressed or encrypted.
+const HIGH_ENTROPY: f64 = 7.0;
+
+const PAGES_PER_LINE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageKind {
+    Zero,
+    Erased,
+    Data,
+    HighEntropy,
+    // Neither zero nor erased, as far as a checksum tells.
+    NotEmpty,
+}
+
+impl PageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            PageKind::Zero => "zero",
+            PageKind::Erased => "erased",
+            PageKind::Data => "data",
+            PageKind::HighEntropy => "high-entropy",
+            PageKind::NotEmpty => "not-empty",
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            PageKind::Zero => '.',
+            PageKind::Erased => '-',
+            PageKind::Data => '+',
+            PageKind::HighEntropy => '#',
+            PageKind::NotEmpty => '?',
+        }
+    }
+
+    fn is_interesting(self) -> bool {
+        !matches!(self, PageKind::Zero | PageKind::Erased)
+    }
+}
+
+// Shannon entropy in bits per byte.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u32; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+
+    let total = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let probability = f64::from(count) / total;
+            -probability * probability.log2()
+        })
+        .sum()
+}
+
+pub fn classify(data: &[u8]) -> PageKind {
+    if data.iter().all(|&byte| byte == 0x00) {
+        PageKind::Zero
+    } else if data.iter().all(|&byte| byte == 0xff) {
+        PageKind::Erased
+    } else if entropy(data) >= HIGH_ENTROPY {
+        PageKind::HighEntropy
+    } else {
+        PageKind::Data
+    }
+}
+
+// Tells zero and erased pages from the CRC-32 of their contents, for when
+// the stub computes it and the data doesn't have to be transferred.
+pub struct CrcClassifier {
+    zero: u32,
+    erased: u32,
+}
+
+impl CrcClassifier {
+    pub fn new(page_size: u64) -> Self {
+        let crc_of = |byte: u8| {
+            let mut crc = Crc32::new();
+            let block = [byte; 4096];
+            let mut left = page_size;
+
+            while left > 0 {
+                let size = left.min(block.len() as u64) as usize;
+                crc.update(&block[..size]);
+                left -= size as u64;
+            }
+
+            crc.finish()
+        };
+
+        Self {
+            zero: crc_of(0x00),
+            erased: crc_of(0xff),
+        }
+    }
+
+    // Only for full pages, the last one may be shorter.
+    pub fn classify(&self, crc32: u32) -> PageKind {
+        if crc32 == self.zero {
+            PageKind::Zero
+        } else if crc32 == self.erased {
+            PageKind::Erased
+        } else {
+            PageKind::NotEmpty
+        }
+    }
+}
+
+// A run of pages of the same kind.
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: PageKind,
+}
+
+pub struct PageMap {
+    start: u64,
+    end: u64,
+    page_size: u64,
+    pages: Vec<PageKind>,
+    // The part of a page that has come in so far.
+    buffer: Vec<u8>,
+}
+
+impl PageMap {
+    pub fn new(start: u64, end: u64, page_size: u64) -> Self {
+        Self {
+            start,
+            end,
+            page_size,
+            pages: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, kind: PageKind) {
+        self.pages.push(kind);
+    }
+
+    // Classifies a last page that is shorter than the others.
+    pub fn finish(&mut self) {
+        if !self.buffer.is_empty() {
+            let kind = classify(&self.buffer);
+            self.pages.push(kind);
+            self.buffer.clear();
+        }
+    }
+
+    fn page_start(&self, index: usize) -> u64 {
+        self.start + index as u64 * self.page_size
+    }
+
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+
+        for (index, &kind) in self.pages.iter().enumerate() {
+            let end = self.end.min(self.page_start(index + 1));
+
+            match regions.last_mut() {
+                Some(region) if region.kind == kind => region.end = end,
+                _ => regions.push(Region {
+                    start: self.page_start(index),
+                    end,
+                    kind,
+                }),
+            }
+        }
+
+        regions
+    }
+
+    pub fn count(&self, kind: PageKind) -> usize {
+        self.pages.iter().filter(|&&page| page == kind).count()
+    }
+
+    // One character per page, with the address of the first page of every
+    // line in front.
+    pub fn lines(&self) -> Vec<String> {
+        self.pages
+            .chunks(PAGES_PER_LINE)
+            .enumerate()
+            .map(|(line, pages)| {
+                format!(
+                    "{:08x}  {}",
+                    self.page_start(line * PAGES_PER_LINE),
+                    pages.iter().map(|kind| kind.symbol()).collect::<String>()
+                )
+            })
+            .collect()
+    }
+
+    // The regions that are neither zero nor erased.
+    pub fn interesting_regions(&self) -> Vec<Region> {
+        self.regions()
+            .into_iter()
+            .filter(|region| region.kind.is_interesting())
+            .collect()
+    }
+
+    pub fn legend() -> String {
+        [
+            PageKind::Zero,
+            PageKind::Erased,
+            PageKind::Data,
+            PageKind::HighEntropy,
+            PageKind::NotEmpty,
+        ]
+        .iter()
+        .map(|kind| format!("{} {}", kind.symbol(), kind.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+impl Write for PageMap {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let page_size = self.page_size as usize;
+        let length = data.len();
+        let mut data = data;
+
+        while !data.is_empty() {
+            let take = data.len().min(page_size - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.buffer.len() == page_size {
+                let kind = classify(&self.buffer);
+                self.pages.push(kind);
+                self.buffer.clear();
+            }
+        }
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ToJson for PageMap {
+    fn to_json(&self) -> String {
+        let regions = self
+            .regions()
+            .iter()
+            .map(|region| {
+                Object::new()
+                    .field("start", format!("{:#x}", region.start))
+                    .field("end", format!("{:#x}", region.end))
+                    .field("kind", region.kind.name())
+            })
+            .collect::<Vec<_>>();
+
+        Object::new()
+            .field("start", format!("{:#x}", self.start))
+            .field("end", format!("{:#x}", self.end))
+            .field("page_size", self.page_size)
+            .field(
+                "pages",
+                Object::new()
+                    .field("zero", self.count(PageKind::Zero))
+                    .field("erased", self.count(PageKind::Erased))
+                    .field("data", self.count(PageKind::Data))
+                    .field("high_entropy", self.count(PageKind::HighEntropy))
+                    .field("not_empty", self.count(PageKind::NotEmpty)),
+            )
+            .field("regions", regions)
+            .to_json()
+    }
+}
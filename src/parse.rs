@@ -0,0 +1,232 @@
+// Parses a number as written on the command line: decimal, or hexadecimal,
+// octal and binary with a 0x, 0o or 0b prefix. Underscores can be used to
+// group digits, and a K, M, G or T suffix multiplies by the binary unit.
+pub fn parse_u64(string: &str) -> Result<u64, String> {
+    let digits = string.replace('_', "");
+
+    let (digits, shift) = match digits.chars().last() {
+        Some('k' | 'K') => (&digits[..digits.len() - 1], 10),
+        Some('m' | 'M') => (&digits[..digits.len() - 1], 20),
+        Some('g' | 'G') => (&digits[..digits.len() - 1], 30),
+        Some('t' | 'T') => (&digits[..digits.len() - 1], 40),
+        _ => (&digits[..], 0),
+    };
+
+    let (digits, radix) = match digits.get(..2) {
+        Some("0x" | "0X") => (&digits[2..], 16),
+        Some("0o" | "0O") => (&digits[2..], 8),
+        Some("0b" | "0B") => (&digits[2..], 2),
+        _ => (digits, 10),
+    };
+
+    if digits.is_empty() {
+        return Err(format!("'{}' is missing the digits", string));
+    }
+
+    // from_str_radix would happily take a sign as well.
+    if let Some(digit) = digits.chars().find(|digit| !digit.is_digit(radix)) {
+        return Err(format!(
+            "'{}' contains '{}', which isn't a base {} digit",
+            string, digit, radix
+        ));
+    }
+
+    let too_large = || format!("'{}' is too large", string);
+
+    let value = u64::from_str_radix(digits, radix).map_err(|_| too_large())?;
+
+    value.checked_mul(1 << shift).ok_or_else(too_large)
+}
+
+// Parses a hexadecimal vendor:product ID pair, like 04e8:685d.
+pub fn parse_usb_id(string: &str) -> Option<(u16, u16)> {
+    let (vendor_id, product_id) = string.split_once(':')?;
+
+    Some((
+        u16::from_str_radix(vendor_id, 16).ok()?,
+        u16::from_str_radix(product_id, 16).ok()?,
+    ))
+}
+
+// Parses a decimal bus:address pair, like 1:4, as shown by list-devices.
+pub fn parse_bus_address(string: &str) -> Option<(u8, u8)> {
+    let (bus, address) = string.split_once(':')?;
+
+    Some((bus.parse().ok()?, address.parse().ok()?))
+}
+
+// Picks out a USB device with a single string, for scripts to pass around.
+// It has the options of the command line, separated by commas, e.g.
+// `usb=04e8:685d,serial-number=R58M123456` or `bus-address=1:4`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub usb_id: Option<(u16, u16)>,
+    pub serial_number: Option<String>,
+    pub bus_address: Option<(u8, u8)>,
+}
+
+impl DeviceSpec {
+    pub fn parse(string: &str) -> Result<Self, String> {
+        let mut spec = Self::default();
+
+        for option in string
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+        {
+            let invalid = || format!("'{}' is not a valid device option", option);
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+
+            match key.trim() {
+                "usb" => spec.usb_id = Some(parse_usb_id(value).ok_or_else(invalid)?),
+                "serial-number" => spec.serial_number = Some(unescape(value).ok_or_else(invalid)?),
+                "bus-address" => {
+                    spec.bus_address = Some(parse_bus_address(value).ok_or_else(invalid)?)
+                }
+                _ => {
+                    return Err(format!(
+                        "'{}' is not a device option, expected usb, serial-number or bus-address",
+                        key.trim()
+                    ))
+                }
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl std::fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut options = Vec::new();
+
+        if let Some((vendor_id, product_id)) = self.usb_id {
+            options.push(format!("usb={:04x}:{:04x}", vendor_id, product_id));
+        }
+        if let Some(serial_number) = &self.serial_number {
+            options.push(format!("serial-number={}", escape(serial_number)));
+        }
+        if let Some((bus, address)) = self.bus_address {
+            options.push(format!("bus-address={}:{}", bus, address));
+        }
+
+        write!(f, "{}", options.join(","))
+    }
+}
+
+// Serial numbers are whatever the device says, so anything that would get in
+// the way of splitting the options, or of using them unquoted in a shell, is
+// written as %XX.
+fn escape(string: &str) -> String {
+    string
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b':' | b'/' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn unescape(string: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = string.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let digits = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(digits, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_u64_bases_and_separators() {
+        assert_eq!(parse_u64("0"), Ok(0));
+        assert_eq!(parse_u64("4096"), Ok(4096));
+        assert_eq!(parse_u64("0x4000_0000"), Ok(0x4000_0000));
+        assert_eq!(parse_u64("0XfF"), Ok(0xff));
+        assert_eq!(parse_u64("0o17"), Ok(0o17));
+        assert_eq!(parse_u64("0b1010"), Ok(0b1010));
+        assert_eq!(parse_u64("1_000_000"), Ok(1_000_000));
+    }
+
+    #[test]
+    fn parse_u64_suffixes() {
+        assert_eq!(parse_u64("4K"), Ok(4 << 10));
+        assert_eq!(parse_u64("16m"), Ok(16 << 20));
+        assert_eq!(parse_u64("1G"), Ok(1 << 30));
+        assert_eq!(parse_u64("2t"), Ok(2 << 40));
+        assert_eq!(parse_u64("0x10K"), Ok(0x10 << 10));
+        assert_eq!(parse_u64("0b1_0M"), Ok(2 << 20));
+    }
+
+    #[test]
+    fn parse_u64_overflow() {
+        assert_eq!(parse_u64("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_u64("0xffff_ffff_ffff_ffff"), Ok(u64::MAX));
+        assert!(parse_u64("18446744073709551616").is_err());
+        assert!(parse_u64("0x1_0000_0000_0000_0000").is_err());
+        assert_eq!(parse_u64("16777215T"), Ok(16777215 << 40));
+        assert_eq!(
+            parse_u64("16777216T"),
+            Err("'16777216T' is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_u64_malformed() {
+        for string in ["", "_", "0x", "0b_", "K", "0xK"] {
+            assert!(parse_u64(string).is_err(), "{:?}", string);
+        }
+
+        assert_eq!(
+            parse_u64("0x12g4"),
+            Err("'0x12g4' contains 'g', which isn't a base 16 digit".to_string())
+        );
+        assert_eq!(
+            parse_u64("0b102"),
+            Err("'0b102' contains '2', which isn't a base 2 digit".to_string())
+        );
+        assert!(parse_u64("-1").is_err());
+        assert!(parse_u64("+1").is_err());
+        assert!(parse_u64(" 1").is_err());
+        assert!(parse_u64("1KK").is_err());
+    }
+
+    #[test]
+    fn usb_ids_and_bus_addresses() {
+        assert_eq!(parse_usb_id("04e8:685d"), Some((0x04e8, 0x685d)));
+        assert_eq!(parse_usb_id("04e8"), None);
+        assert_eq!(parse_usb_id("04e8:1685d"), None);
+        assert_eq!(parse_bus_address("1:4"), Some((1, 4)));
+        assert_eq!(parse_bus_address("1:256"), None);
+    }
+
+    #[test]
+    fn device_spec_round_trip() {
+        let spec =
+            DeviceSpec::parse("usb=04e8:685d, serial-number=R58M%2C1,bus-address=1:4").unwrap();
+
+        assert_eq!(spec.usb_id, Some((0x04e8, 0x685d)));
+        assert_eq!(spec.serial_number.as_deref(), Some("R58M,1"));
+        assert_eq!(spec.bus_address, Some((1, 4)));
+        assert_eq!(DeviceSpec::parse(&spec.to_string()), Ok(spec));
+
+        assert!(DeviceSpec::parse("usb=04e8").is_err());
+        assert!(DeviceSpec::parse("port=1").is_err());
+        assert!(DeviceSpec::parse("serial-number=%2").is_err());
+    }
+}
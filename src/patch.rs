@@ -0,0 +1,357 @@
+use crate::parse::parse_u64;
+
+// Changes to a few places in memory, for trying out fixes to something that
+// has been loaded already without uploading all of it again. Offsets are
+// relative to the address that the patch is applied at.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl Run {
+    pub fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // `offset: bytes...` per line, e.g. `0x1f4: de ad be ef`, with # starting
+    // a comment.
+    Text,
+    // The IPS format: PATCH, then records of a 24-bit offset, a 16-bit size
+    // and the data, all big-endian, with a size of zero for a byte repeated
+    // as often as the 16-bit count after it says, and EOF at the end.
+    Ips,
+}
+
+impl Format {
+    pub fn parse(string: &str) -> Result<Self, String> {
+        match string {
+            "text" => Ok(Format::Text),
+            "ips" => Ok(Format::Ips),
+            _ => Err(format!(
+                "'{}' is not a patch format, expected text or ips",
+                string
+            )),
+        }
+    }
+}
+
+pub fn parse(data: &[u8], format: Format) -> Result<Vec<Run>, String> {
+    let runs = match format {
+        Format::Text => {
+            let text =
+                std::str::from_utf8(data).map_err(|_| "the patch is not text".to_string())?;
+            parse_text(text)?
+        }
+        Format::Ips => parse_ips(data)?,
+    };
+
+    if runs.is_empty() {
+        return Err("the patch doesn't change anything".to_string());
+    }
+
+    check_overlaps(&runs)?;
+
+    Ok(runs)
+}
+
+fn parse_text(text: &str) -> Result<Vec<Run>, String> {
+    let mut runs = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |message: String| format!("line {}: {}", index + 1, message);
+
+        let (offset, bytes) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("expected an offset, a colon and the bytes".to_string()))?;
+        let offset = parse_u64(offset.trim()).map_err(invalid)?;
+
+        let digits = bytes
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<Vec<_>>();
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(invalid(
+                "expected the bytes as pairs of hexadecimal digits".to_string(),
+            ));
+        }
+
+        let data = digits
+            .chunks(2)
+            .map(|pair| Some((pair[0].to_digit(16)? << 4 | pair[1].to_digit(16)?) as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid(format!("'{}' is not hexadecimal", bytes.trim())))?;
+
+        if offset.checked_add(data.len() as u64).is_none() {
+            return Err(invalid(format!("the offset {:#x} is too large", offset)));
+        }
+
+        runs.push(Run { offset, data });
+    }
+
+    Ok(runs)
+}
+
+fn parse_ips(data: &[u8]) -> Result<Vec<Run>, String> {
+    let mut rest = data.strip_prefix(b"PATCH").ok_or_else(|| {
+        "the patch doesn't start with PATCH, it's not in the IPS format".to_string()
+    })?;
+    let mut runs = Vec::new();
+
+    let mut take = |count: usize| -> Result<&[u8], String> {
+        if rest.len() < count {
+            return Err("the patch ends in the middle of a record, without EOF".to_string());
+        }
+
+        let (taken, remaining) = rest.split_at(count);
+        rest = remaining;
+        Ok(taken)
+    };
+
+    loop {
+        let offset = take(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+
+        let offset = u64::from(offset[0]) << 16 | u64::from(offset[1]) << 8 | u64::from(offset[2]);
+        let size = u16::from_be_bytes(take(2)?.try_into().unwrap());
+
+        let data = match size {
+            0 => {
+                let count = u16::from_be_bytes(take(2)?.try_into().unwrap());
+                let value = take(1)?[0];
+
+                vec![value; count.into()]
+            }
+            size => take(size.into())?.to_vec(),
+        };
+
+        // Nothing to do for a record without any bytes.
+        if !data.is_empty() {
+            runs.push(Run { offset, data });
+        }
+    }
+
+    Ok(runs)
+}
+
+// Each byte may only be set once, otherwise the order of the runs would
+// matter.
+fn check_overlaps(runs: &[Run]) -> Result<(), String> {
+    let mut sorted = runs.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|run| run.offset);
+
+    for pair in sorted.windows(2) {
+        if pair[1].offset < pair[0].end() {
+            return Err(format!(
+                "the run at {:#x} overlaps the one at {:#x}",
+                pair[1].offset, pair[0].offset
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pit::tests::Random;
+
+    fn run(offset: u64, data: &[u8]) -> Run {
+        Run {
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    // An IPS patch of the given records.
+    fn ips(records: &[&[u8]]) -> Vec<u8> {
+        let mut data = b"PATCH".to_vec();
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        data.extend_from_slice(b"EOF");
+
+        data
+    }
+
+    #[test]
+    fn text_patches_are_offsets_and_bytes() {
+        let text = "# boot counter\n\
+                    0x1f4: de ad be ef  # magic\n\
+                    \n\
+                    16:00 01 02\n\
+                    0x200 : C0FFEE\n";
+
+        assert_eq!(
+            parse(text.as_bytes(), Format::Text),
+            Ok(vec![
+                run(0x1f4, &[0xde, 0xad, 0xbe, 0xef]),
+                run(16, &[0x00, 0x01, 0x02]),
+                run(0x200, &[0xc0, 0xff, 0xee]),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_text_patches_name_the_line() {
+        for (text, message) in [
+            (
+                "0x10 de ad",
+                "line 1: expected an offset, a colon and the bytes",
+            ),
+            (
+                "# nothing\n0x10: dea",
+                "line 2: expected the bytes as pairs of hexadecimal digits",
+            ),
+            (
+                "0x10:",
+                "line 1: expected the bytes as pairs of hexadecimal digits",
+            ),
+            ("0x10: zz", "line 1: 'zz' is not hexadecimal"),
+            ("0x10: ä0", "line 1: 'ä0' is not hexadecimal"),
+            (
+                "0xffffffffffffffff: 00",
+                "line 1: the offset 0xffffffffffffffff is too large",
+            ),
+        ] {
+            assert_eq!(
+                parse(text.as_bytes(), Format::Text),
+                Err(message.to_string()),
+                "{:?}",
+                text
+            );
+        }
+
+        assert!(parse(b"0x10 : ", Format::Text).is_err());
+        assert!(parse(b"ten: 00", Format::Text).is_err());
+        assert_eq!(
+            parse(b"0x10: \xff", Format::Text),
+            Err("the patch is not text".to_string())
+        );
+    }
+
+    #[test]
+    fn ips_patches_have_plain_and_repeated_records() {
+        let data = ips(&[
+            &[0x01, 0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb],
+            // A byte repeated 3 times.
+            &[0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x03, 0x5a],
+            // Nothing to do.
+            &[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x5a],
+        ]);
+
+        assert_eq!(
+            parse(&data, Format::Ips),
+            Ok(vec![run(0x10000, &[0xaa, 0xbb]), run(0x10, &[0x5a; 3])])
+        );
+    }
+
+    #[test]
+    fn malformed_ips_patches_are_rejected() {
+        assert_eq!(
+            parse(b"PATCX\x00\x00\x00\x00\x01\x00EOF", Format::Ips),
+            Err("the patch doesn't start with PATCH, it's not in the IPS format".to_string())
+        );
+
+        let truncated = "the patch ends in the middle of a record, without EOF".to_string();
+        assert_eq!(parse(b"PATCH", Format::Ips), Err(truncated.clone()));
+        assert_eq!(
+            parse(b"PATCH\x00\x00\x10\x00\x04\xaa\xbb", Format::Ips),
+            Err(truncated.clone())
+        );
+        assert_eq!(
+            parse(b"PATCH\x00\x00\x10\x00\x00\x00", Format::Ips),
+            Err(truncated)
+        );
+    }
+
+    #[test]
+    fn patches_have_to_change_something_once() {
+        let nothing = Err("the patch doesn't change anything".to_string());
+        assert_eq!(parse(b"# just a comment\n\n", Format::Text), nothing);
+        assert_eq!(parse(&ips(&[]), Format::Ips), nothing);
+
+        assert_eq!(
+            parse(b"0x10: 00 01 02 03\n0x12: ff\n", Format::Text),
+            Err("the run at 0x12 overlaps the one at 0x10".to_string())
+        );
+        // Adjacent runs don't overlap, whatever order they come in.
+        assert!(parse(b"0x14: 04\n0x10: 00 01 02 03\n", Format::Text).is_ok());
+
+        assert!(parse(
+            &ips(&[
+                &[0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x04, 0x00],
+                &[0x00, 0x00, 0x13, 0x00, 0x01, 0xff],
+            ]),
+            Format::Ips
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn accepted_patches_can_be_applied_in_any_order() {
+        const LINES: &[&str] = &[
+            "0x10: 00 01\n",
+            "0x11: ff\n",
+            "0x12: 0203\n",
+            "4: aa\n",
+            "# comment\n",
+            "0x10 00\n",
+            "\n",
+        ];
+
+        let mut random = Random::new(0x7061_7463);
+        for round in 0..2000 {
+            let count = random.below(6);
+            let data = match round % 3 {
+                0 => (0..count)
+                    .map(|_| LINES[random.below(LINES.len())])
+                    .collect::<String>()
+                    .into_bytes(),
+                1 => {
+                    let mut data = b"PATCH".to_vec();
+                    for _ in 0..count {
+                        let length = random.below(8);
+                        data.extend(random.bytes(length));
+                    }
+                    if random.below(2) == 0 {
+                        data.extend_from_slice(b"EOF");
+                    }
+                    data
+                }
+                _ => {
+                    let length = random.below(64);
+                    random.bytes(length)
+                }
+            };
+
+            for format in [Format::Text, Format::Ips] {
+                if let Ok(runs) = parse(&data, format) {
+                    let mut runs = runs.iter().collect::<Vec<_>>();
+                    runs.sort_by_key(|run| run.offset);
+                    assert!(runs.windows(2).all(|pair| pair[0].end() <= pair[1].offset));
+                    assert!(runs.iter().all(|run| !run.data.is_empty()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formats_by_name() {
+        assert_eq!(Format::parse("text"), Ok(Format::Text));
+        assert_eq!(Format::parse("ips"), Ok(Format::Ips));
+        assert!(Format::parse("IPS").is_err());
+    }
+}
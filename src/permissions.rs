@@ -0,0 +1,206 @@
+use crate::error::Error;
+use std::path::Path;
+
+#[cfg(unix)]
+pub fn user_name(uid: libc::uid_t) -> String {
+    let entry = unsafe { libc::getpwuid(uid) };
+    if entry.is_null() {
+        return uid.to_string();
+    }
+
+    unsafe { std::ffi::CStr::from_ptr((*entry).pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(unix)]
+pub fn group_name(gid: libc::gid_t) -> String {
+    let entry = unsafe { libc::getgrgid(gid) };
+    if entry.is_null() {
+        return gid.to_string();
+    }
+
+    unsafe { std::ffi::CStr::from_ptr((*entry).gr_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// The groups that this process has, which only include groups that the user
+// was added to after logging in again.
+#[cfg(unix)]
+pub fn current_groups() -> Vec<libc::gid_t> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+
+    let count = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+
+    groups
+}
+
+// Explains why opening a device node was refused, one hint per line, as far
+// as the owner and the groups go.
+#[cfg(unix)]
+fn diagnose(path: &Path) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) => ownership_hints(
+            path,
+            Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mode: metadata.mode(),
+            },
+            &current_groups(),
+        ),
+        Err(_) => vec![format!("{} can't be looked at either", path.display())],
+    }
+}
+
+#[cfg(unix)]
+struct Owner {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    mode: u32,
+}
+
+// The hints for a device node of `owner`, for a process with `groups`.
+#[cfg(unix)]
+fn ownership_hints(path: &Path, owner: Owner, groups: &[libc::gid_t]) -> Vec<String> {
+    let group = group_name(owner.gid);
+    let mut hints = vec![format!(
+        "{} belongs to {}:{} with mode {:04o}",
+        path.display(),
+        user_name(owner.uid),
+        group,
+        owner.mode & 0o7777
+    )];
+
+    let in_group = groups.contains(&owner.gid);
+    let group_access = owner.mode & 0o060 == 0o060;
+
+    if group_access && !in_group && owner.gid != 0 {
+        hints.push(format!(
+            "You aren't in the {} group, add yourself with `sudo usermod -aG {} $USER` and log in again",
+            group, group
+        ));
+    } else if group_access && in_group {
+        hints.push(format!(
+            "You are in the {} group, so something else (e.g. an ACL or a sandbox) is in the way",
+            group
+        ));
+    }
+
+    hints
+}
+
+#[cfg(not(unix))]
+fn diagnose(path: &Path) -> Vec<String> {
+    vec![format!(
+        "Check that nothing else has {} open and that you may access it",
+        path.display()
+    )]
+}
+
+// The error for a device node that couldn't be opened for lack of
+// permissions, with what can be found out about why and a last hint that is
+// specific to the kind of device.
+pub fn denied(message: String, path: &Path, hint: &str) -> Error {
+    let mut lines = vec![message];
+    lines.extend(diagnose(path));
+    lines.push(hint.to_string());
+
+    Error::PermissionDenied(lines.join("\n  "))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // Groups that exist nowhere, so that their names are their IDs.
+    const DIALOUT: libc::gid_t = 3_000_020;
+    const USERS: libc::gid_t = 3_000_100;
+
+    fn owner(gid: libc::gid_t, mode: u32) -> Owner {
+        Owner {
+            uid: 0,
+            gid,
+            mode: 0o020000 | mode,
+        }
+    }
+
+    #[test]
+    fn missing_group_membership_says_how_to_join() {
+        let path = Path::new("/dev/ttyUSB0");
+        let hints = ownership_hints(path, owner(DIALOUT, 0o660), &[USERS]);
+
+        assert_eq!(
+            hints,
+            [
+                "/dev/ttyUSB0 belongs to root:3000020 with mode 0660".to_string(),
+                "You aren't in the 3000020 group, add yourself with `sudo usermod -aG 3000020 $USER` and log in again".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_hints_only_when_the_group_would_help() {
+        let path = Path::new("/dev/ttyUSB0");
+
+        let hints = ownership_hints(path, owner(DIALOUT, 0o660), &[USERS, DIALOUT]);
+        assert_eq!(hints.len(), 2);
+        assert!(
+            hints[1].starts_with("You are in the 3000020 group"),
+            "{}",
+            hints[1]
+        );
+
+        // Joining the group wouldn't give access, and nobody joins root.
+        for owner in [owner(DIALOUT, 0o600), owner(0, 0o660)] {
+            assert_eq!(ownership_hints(path, owner, &[USERS]).len(), 1);
+        }
+    }
+
+    #[test]
+    fn denied_lists_the_hints_after_the_message() {
+        let path = Path::new("/nonexistent/ttyUSB0");
+        let err = denied(
+            "Not allowed to open serial port /dev/ttyUSB0".to_string(),
+            path,
+            "Serial ports usually belong to the dialout (or uucp) group",
+        );
+
+        let Error::PermissionDenied(message) = err else {
+            panic!("{}", err);
+        };
+        assert_eq!(
+            message,
+            "Not allowed to open serial port /dev/ttyUSB0\n  \
+             /nonexistent/ttyUSB0 can't be looked at either\n  \
+             Serial ports usually belong to the dialout (or uucp) group"
+        );
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn denied_ends_with_the_udev_rule() {
+        let err = denied(
+            "Not allowed to open 1:7".to_string(),
+            Path::new("/nonexistent/001/007"),
+            &crate::device::udev_hint(),
+        );
+
+        let message = err.to_string();
+        let mut lines = message.lines().rev();
+        assert_eq!(
+            lines.next().unwrap(),
+            format!("    {}", crate::device::udev_rule())
+        );
+        assert!(lines
+            .next()
+            .unwrap()
+            .contains("/etc/udev/rules.d/51-sbootil.rules"));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::error::{Error, Result};
+use std::fmt::Display;
+use std::io::{BufRead, IsTerminal, Write};
+
+fn numbered<C: Display>(candidates: &[C]) -> String {
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| format!("  {}) {}", index + 1, candidate))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Lets the user pick one out of several matching devices. Without anyone at
+// the terminal to ask, this fails with the list and `hint` on how to narrow
+// down the selection instead.
+pub fn choose<C: Display>(what: &str, mut candidates: Vec<C>, hint: &str) -> Result<C> {
+    if candidates.len() == 1 {
+        return Ok(candidates.remove(0));
+    }
+
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Err(Error::DeviceNotFound(format!(
+            "Found several {}, {}:\n{}",
+            what,
+            hint,
+            numbered(&candidates)
+        )));
+    }
+
+    eprintln!("Found several {}:\n{}", what, numbered(&candidates));
+
+    let mut stdin = std::io::stdin().lock();
+
+    loop {
+        eprint!("Select one [1-{}]: ", candidates.len());
+        let _ = std::io::stderr().flush();
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Err(Error::DeviceNotFound(format!(
+                "No device selected, {}",
+                hint
+            )));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=candidates.len()).contains(&number) => {
+                return Ok(candidates.remove(number - 1))
+            }
+            _ => eprintln!("'{}' is not one of the listed numbers", line.trim()),
+        }
+    }
+}
+
+// Asks before doing something that may go wrong in bad ways. Without anyone
+// at the terminal to ask, this fails with `hint` on how to go ahead anyway.
+pub fn confirm(message: &str, hint: &str) -> Result<()> {
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Err(Error::InvalidArgument(format!("{}, {}", message, hint)));
+    }
+
+    eprint!("{}. Go ahead? [y/N] ", message);
+    let _ = std::io::stderr().flush();
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+
+    match line.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(Error::InvalidArgument("Not confirmed".to_string())),
+    }
+}
@@ -0,0 +1,451 @@
+use crate::error::{Error, Result};
+
+const PIT_MAGIC: u32 = 0x12349876;
+const HEADER_SIZE: usize = 28;
+const ENTRY_SIZE: usize = 132;
+const STRING_SIZE: usize = 32;
+
+// The partitions that the device needs to get as far as download mode, which
+// are flashed after everything else, so that a failure halfway leaves the old
+// ones in place.
+const BOOTLOADER_PARTITIONS: [&str; 10] = [
+    "BOOTLOADER",
+    "SBOOT",
+    "SBOOT2",
+    "TZSW",
+    "TZ",
+    "CM",
+    "UH",
+    "PARAM",
+    "UP_PARAM",
+    "KEYSTORAGE",
+];
+
+#[derive(Clone, Debug)]
+pub struct PitEntry {
+    pub binary_type: u32,
+    pub device_type: u32,
+    pub identifier: u32,
+    pub attributes: u32,
+    pub update_attributes: u32,
+    pub block_size_or_offset: u32,
+    pub block_count: u32,
+    pub file_offset: u32,
+    pub file_size: u32,
+    pub partition_name: String,
+    pub flash_filename: String,
+    pub fota_filename: String,
+}
+
+impl PitEntry {
+    pub fn is_bootloader(&self) -> bool {
+        BOOTLOADER_PARTITIONS
+            .iter()
+            .any(|name| self.partition_name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pit {
+    pub entries: Vec<PitEntry>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_string(data: &[u8], offset: usize) -> String {
+    let bytes = &data[offset..offset + STRING_SIZE];
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(STRING_SIZE);
+
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+impl Pit {
+    // Partitions are looked up by their name, or by their identifier like
+    // Heimdall does.
+    pub fn find_index(&self, partition: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.partition_name.eq_ignore_ascii_case(partition))
+            .or_else(|| {
+                let identifier = partition.parse::<u32>().ok()?;
+
+                self.entries
+                    .iter()
+                    .position(|entry| entry.identifier == identifier)
+            })
+    }
+
+    // The partition that takes a file of this name, as the flash file names
+    // in the PIT go. Firmware packages compress the larger images with LZ4.
+    pub fn find_by_file_name(&self, file_name: &str) -> Option<usize> {
+        let name = match file_name.to_ascii_lowercase().strip_suffix(".lz4") {
+            Some(stem) => &file_name[..stem.len()],
+            None => file_name,
+        };
+
+        self.entries.iter().position(|entry| {
+            !entry.flash_filename.is_empty() && entry.flash_filename.eq_ignore_ascii_case(name)
+        })
+    }
+
+    // The order to flash partitions in, as indices into the entries: the ones
+    // named in `order` first, in that order, then the others in the order of
+    // the PIT with the bootloader ones last. Named partitions that aren't
+    // being flashed are fine, as long as the PIT has them.
+    pub fn flash_order(&self, partitions: &[usize], order: &[String]) -> Result<Vec<usize>> {
+        let mut first = Vec::with_capacity(order.len());
+        for name in order {
+            let index = self.find_index(name).ok_or_else(|| {
+                Error::InvalidArgument(format!("There is no partition {} in the PIT", name))
+            })?;
+
+            if first.contains(&index) {
+                return Err(Error::InvalidArgument(format!(
+                    "Partition {} is in the order twice",
+                    self.entries[index].partition_name
+                )));
+            }
+            first.push(index);
+        }
+        first.retain(|index| partitions.contains(index));
+
+        let mut rest = partitions
+            .iter()
+            .copied()
+            .filter(|index| !first.contains(index))
+            .collect::<Vec<_>>();
+        rest.sort_by_key(|&index| (self.entries[index].is_bootloader(), index));
+        rest.dedup();
+
+        first.extend(rest);
+        Ok(first)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::InvalidPit(format!(
+                "{} bytes is too short for a header",
+                data.len()
+            )));
+        }
+
+        let magic = read_u32(data, 0);
+        if magic != PIT_MAGIC {
+            return Err(Error::InvalidPit(format!("unknown magic {:#x}", magic)));
+        }
+
+        let entry_count = read_u32(data, 4) as usize;
+        let needed = entry_count
+            .checked_mul(ENTRY_SIZE)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .filter(|&needed| needed <= data.len())
+            .ok_or_else(|| {
+                Error::InvalidPit(format!(
+                    "{} entries don't fit into {} bytes",
+                    entry_count,
+                    data.len()
+                ))
+            })?;
+
+        let entries = data[HEADER_SIZE..needed]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| PitEntry {
+                binary_type: read_u32(entry, 0),
+                device_type: read_u32(entry, 4),
+                identifier: read_u32(entry, 8),
+                attributes: read_u32(entry, 12),
+                update_attributes: read_u32(entry, 16),
+                block_size_or_offset: read_u32(entry, 20),
+                block_count: read_u32(entry, 24),
+                file_offset: read_u32(entry, 28),
+                file_size: read_u32(entry, 32),
+                partition_name: read_string(entry, 36),
+                flash_filename: read_string(entry, 36 + STRING_SIZE),
+                fota_filename: read_string(entry, 36 + 2 * STRING_SIZE),
+            })
+            .collect();
+
+        Ok(Self { entries })
+    }
+
+    pub fn find(&self, partition_name: &str) -> Option<&PitEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.partition_name.eq_ignore_ascii_case(partition_name))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn put_string(data: &mut Vec<u8>, string: &str) {
+        let mut field = [0u8; STRING_SIZE];
+        field[..string.len()].copy_from_slice(string.as_bytes());
+        data.extend_from_slice(&field);
+    }
+
+    // A PIT with partitions of the given names and flash file names, with
+    // identifiers counting up from 1, padded with zeros to `size` like the
+    // ones that devices send.
+    pub fn pit_data(partitions: &[(&str, &str)], size: usize) -> Vec<u8> {
+        let mut data = PIT_MAGIC.to_le_bytes().to_vec();
+        data.extend_from_slice(&(partitions.len() as u32).to_le_bytes());
+        data.resize(HEADER_SIZE, 0);
+
+        for (index, (name, file_name)) in partitions.iter().enumerate() {
+            for value in [0, 2, index as u32 + 1, 0, 0, 0x400, 0x800, 0, 0] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            put_string(&mut data, name);
+            put_string(&mut data, file_name);
+            put_string(&mut data, "");
+        }
+
+        assert!(data.len() <= size);
+        data.resize(size, 0);
+
+        data
+    }
+
+    // Xorshift, so that tests of arbitrary inputs see the same ones on every
+    // run.
+    pub struct Random(u64);
+
+    impl Random {
+        pub fn new(seed: u64) -> Self {
+            Self(seed | 1)
+        }
+
+        pub fn word(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            self.0
+        }
+
+        pub fn below(&mut self, bound: usize) -> usize {
+            (self.word() % bound as u64) as usize
+        }
+
+        pub fn bytes(&mut self, count: usize) -> Vec<u8> {
+            (0..count).map(|_| self.word() as u8).collect()
+        }
+
+        // Mostly letters and digits, with the odd other byte.
+        fn name(&mut self, max_len: usize) -> String {
+            const CHARACTERS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_.";
+
+            (0..self.below(max_len + 1))
+                .map(|_| CHARACTERS[self.below(CHARACTERS.len())] as char)
+                .collect()
+        }
+    }
+
+    fn order_of(pit: &Pit, partitions: &[&str], order: &[&str]) -> Result<Vec<String>> {
+        let partitions = partitions
+            .iter()
+            .map(|name| pit.find_index(name).unwrap())
+            .collect::<Vec<_>>();
+        let order = order
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+
+        Ok(pit
+            .flash_order(&partitions, &order)?
+            .into_iter()
+            .map(|index| pit.entries[index].partition_name.clone())
+            .collect())
+    }
+
+    fn firmware_pit() -> Pit {
+        Pit::parse(&pit_data(
+            &[
+                ("BOOTLOADER", "sboot.bin"),
+                ("PARAM", "param.bin"),
+                ("BOOT", "boot.img"),
+                ("RECOVERY", "recovery.img"),
+                ("SYSTEM", "system.img"),
+                ("CACHE", "cache.img"),
+            ],
+            4096,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn flash_order_puts_the_bootloader_last() {
+        let pit = firmware_pit();
+
+        assert_eq!(
+            order_of(&pit, &["PARAM", "SYSTEM", "BOOTLOADER", "BOOT"], &[]).unwrap(),
+            ["BOOT", "SYSTEM", "BOOTLOADER", "PARAM"]
+        );
+        // Duplicates are flashed once.
+        assert_eq!(
+            order_of(&pit, &["system", "BOOT", "SYSTEM"], &[]).unwrap(),
+            ["BOOT", "SYSTEM"]
+        );
+    }
+
+    #[test]
+    fn flash_order_follows_the_order_given() {
+        let pit = firmware_pit();
+
+        assert_eq!(
+            order_of(
+                &pit,
+                &["BOOTLOADER", "BOOT", "SYSTEM", "RECOVERY"],
+                &["bootloader", "SYSTEM"]
+            )
+            .unwrap(),
+            ["BOOTLOADER", "SYSTEM", "BOOT", "RECOVERY"]
+        );
+        // Partitions in the order that aren't being flashed don't matter,
+        // and they can be named by their identifier.
+        assert_eq!(
+            order_of(&pit, &["BOOT", "SYSTEM"], &["CACHE", "5", "4"]).unwrap(),
+            ["SYSTEM", "BOOT"]
+        );
+    }
+
+    #[test]
+    fn flash_order_rejects_unknown_and_repeated_names() {
+        let pit = firmware_pit();
+
+        for order in [
+            &["USERDATA"][..],
+            &["BOOT", "SYSTEM", "boot"],
+            &["SYSTEM", "5"],
+        ] {
+            assert!(matches!(
+                order_of(&pit, &["BOOT", "SYSTEM"], order),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_pits_of_different_sizes() {
+        let partitions = [
+            ("BOOT", "boot.img"),
+            ("SYSTEM", "system.img"),
+            ("SBOOT", ""),
+        ];
+
+        for size in [HEADER_SIZE + 3 * ENTRY_SIZE, 500, 1000, 4096] {
+            let pit = Pit::parse(&pit_data(&partitions, size)).unwrap();
+
+            assert_eq!(pit.entries.len(), 3);
+            assert_eq!(pit.entries[1].partition_name, "SYSTEM");
+            assert_eq!(pit.entries[1].flash_filename, "system.img");
+            assert_eq!(pit.entries[1].identifier, 2);
+            assert_eq!(pit.entries[2].flash_filename, "");
+        }
+
+        assert!(Pit::parse(&pit_data(&[], HEADER_SIZE))
+            .unwrap()
+            .entries
+            .is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_broken_pits() {
+        let pit = pit_data(&[("BOOT", "boot.img"), ("SYSTEM", "system.img")], 500);
+
+        // Cut off in the header, or in the last entry.
+        for size in [0, HEADER_SIZE - 1, HEADER_SIZE + 2 * ENTRY_SIZE - 1] {
+            assert!(matches!(
+                Pit::parse(&pit[..size]),
+                Err(Error::InvalidPit(_))
+            ));
+        }
+
+        let mut wrong_magic = pit.clone();
+        wrong_magic[0] ^= 1;
+        assert!(matches!(
+            Pit::parse(&wrong_magic),
+            Err(Error::InvalidPit(_))
+        ));
+
+        // More entries than the PIT has room for, up to ones that overflow.
+        for count in [4u32, u32::MAX] {
+            let mut too_many = pit.clone();
+            too_many[4..8].copy_from_slice(&count.to_le_bytes());
+            assert!(matches!(Pit::parse(&too_many), Err(Error::InvalidPit(_))));
+        }
+    }
+
+    #[test]
+    fn parse_round_trips_generated_pits() {
+        let mut random = Random::new(0x9e3779b9);
+
+        for _ in 0..500 {
+            let partitions = (0..random.below(10))
+                .map(|_| (random.name(STRING_SIZE), random.name(STRING_SIZE)))
+                .collect::<Vec<_>>();
+            let borrowed = partitions
+                .iter()
+                .map(|(name, file_name)| (name.as_str(), file_name.as_str()))
+                .collect::<Vec<_>>();
+            let size = HEADER_SIZE + partitions.len() * ENTRY_SIZE + random.below(600);
+
+            let pit = Pit::parse(&pit_data(&borrowed, size)).unwrap();
+
+            assert_eq!(pit.entries.len(), partitions.len());
+            for (index, (entry, (name, file_name))) in
+                pit.entries.iter().zip(&partitions).enumerate()
+            {
+                assert_eq!(entry.partition_name, *name);
+                assert_eq!(entry.flash_filename, *file_name);
+                assert_eq!(entry.identifier, index as u32 + 1);
+                assert!(pit.find(name).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn parse_never_panics_on_arbitrary_bytes() {
+        let mut random = Random::new(0x2545f491);
+        let valid = pit_data(&[("BOOT", "boot.img"), ("SYSTEM", "system.img")], 500);
+
+        for round in 0..5000 {
+            let len = random.below(1000);
+            let data = match round % 3 {
+                0 => random.bytes(len),
+                // Past the magic, to get to the entries.
+                1 => {
+                    let mut data = random.bytes(len);
+                    if data.len() >= 8 {
+                        data[..4].copy_from_slice(&PIT_MAGIC.to_le_bytes());
+                        data[4..8].copy_from_slice(&(random.below(10) as u32).to_le_bytes());
+                    }
+                    data
+                }
+                // A real one with some bytes changed, or cut off.
+                _ => {
+                    let mut data = valid.clone();
+                    for _ in 0..random.below(8) {
+                        let index = random.below(data.len());
+                        data[index] = random.word() as u8;
+                    }
+                    data.truncate(random.below(data.len() + 1));
+                    data
+                }
+            };
+
+            if let Ok(pit) = Pit::parse(&data) {
+                // Every entry has to have come out of the data.
+                assert!(HEADER_SIZE + pit.entries.len() * ENTRY_SIZE <= data.len());
+
+                for entry in &pit.entries {
+                    assert!(pit.find(&entry.partition_name).is_some());
+                }
+            }
+        }
+    }
+}
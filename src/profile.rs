@@ -0,0 +1,497 @@
+use crate::config::config_dir;
+use crate::error::{Error, Result};
+use crate::toml::{self, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// What is known about a SoC: where payloads usually go and which regions of
+// memory are worth dumping. A few profiles come built in, the ones in the
+// profiles directory next to the config file replace them by name, e.g.
+// ~/.config/sbootil/profiles/exynos4412.toml:
+//
+//     description = "Exynos 4412"
+//     load_address = 0x02023400
+//
+//     [regions.iram]
+//     start = 0x02020000
+//     end = 0x02060000
+//     access_width = 4
+//     dangerous = false
+
+const BUILTIN: [(&str, &str); 3] = [
+    (
+        "exynos4210",
+        r#"
+description = "Exynos 4210, e.g. the Galaxy S II"
+load_address = 0x02021400
+
+[regions.irom]
+start = 0x00000000
+end = 0x00010000
+access_width = 4
+dangerous = true
+description = "bootrom, reading it hangs the stub if it has been locked down"
+
+[regions.iram]
+start = 0x02020000
+end = 0x02040000
+access_width = 4
+
+[regions.chipid]
+start = 0x10000000
+end = 0x10000010
+access_width = 4
+"#,
+    ),
+    (
+        "exynos4412",
+        r#"
+description = "Exynos 4412, e.g. the Galaxy S III and Note II"
+load_address = 0x02023400
+
+[regions.irom]
+start = 0x00000000
+end = 0x00010000
+access_width = 4
+dangerous = true
+description = "bootrom, reading it hangs the stub if it has been locked down"
+
+[regions.iram]
+start = 0x02020000
+end = 0x02060000
+access_width = 4
+
+[regions.chipid]
+start = 0x10000000
+end = 0x10000010
+access_width = 4
+"#,
+    ),
+    (
+        "exynos5420",
+        r#"
+description = "Exynos 5420, e.g. the Galaxy Note 3 and Tab S"
+load_address = 0x02024400
+
+[regions.irom]
+start = 0x00000000
+end = 0x00010000
+access_width = 4
+dangerous = true
+description = "bootrom, reading it hangs the stub if it has been locked down"
+
+[regions.iram]
+start = 0x02020000
+end = 0x02074000
+access_width = 4
+
+[regions.chipid]
+start = 0x10000000
+end = 0x10000010
+access_width = 4
+"#,
+    ),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    BuiltIn,
+    File(PathBuf),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::BuiltIn => write!(f, "built in"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    // How the stub has to read it, some regions only take aligned accesses
+    // of a certain width.
+    pub access_width: u32,
+    // Reading it may hang or reset the device, so that's only done after
+    // asking.
+    pub dangerous: bool,
+    pub description: Option<String>,
+}
+
+impl Region {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            start: 0,
+            end: 0,
+            access_width: 1,
+            dangerous: false,
+            description: None,
+        }
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub description: Option<String>,
+    // Where payloads are usually loaded to.
+    pub load_address: Option<u64>,
+    pub regions: Vec<Region>,
+    pub origin: Origin,
+}
+
+impl Profile {
+    pub fn parse(name: &str, text: &str, origin: Origin) -> Result<Self> {
+        let invalid = |line: usize, message: String| {
+            Error::InvalidConfig(format!("{}:{}: {}", origin, line, message))
+        };
+
+        let entries = toml::parse(text).map_err(|err| invalid(err.line, err.message))?;
+
+        let mut profile = Self {
+            name: name.to_string(),
+            description: None,
+            load_address: None,
+            regions: Vec::new(),
+            origin: origin.clone(),
+        };
+        // Regions are checked once all of their keys have been seen.
+        let mut region_lines = Vec::new();
+
+        for entry in &entries {
+            let invalid =
+                |message: String| invalid(entry.line, format!("'{}': {}", entry.name(), message));
+            let address = || match entry.value {
+                Value::Integer(integer) if integer >= 0 => Ok(integer as u64),
+                _ => Err(invalid("expected an address".to_string())),
+            };
+            let string = || match &entry.value {
+                Value::String(string) => Ok(string.clone()),
+                value => Err(invalid(format!(
+                    "expected a string, got {}",
+                    value.type_name()
+                ))),
+            };
+
+            match (entry.table.as_str(), entry.key.as_str()) {
+                ("", "description") => profile.description = Some(string()?),
+                ("", "load_address") => profile.load_address = Some(address()?),
+                (table, key) if table.starts_with("regions.") => {
+                    let name = &table["regions.".len()..];
+                    if !is_name(name) {
+                        return Err(invalid(
+                            "region names have to be letters, digits and underscores, not starting with a digit"
+                                .to_string(),
+                        ));
+                    }
+
+                    let index = match profile
+                        .regions
+                        .iter()
+                        .position(|region| region.name == name)
+                    {
+                        Some(index) => index,
+                        None => {
+                            profile.regions.push(Region::new(name));
+                            region_lines.push(entry.line);
+                            profile.regions.len() - 1
+                        }
+                    };
+                    let region = &mut profile.regions[index];
+
+                    match key {
+                        "start" => region.start = address()?,
+                        "end" => region.end = address()?,
+                        "access_width" => {
+                            region.access_width = match entry.value {
+                                Value::Integer(width @ (1 | 2 | 4 | 8)) => width as u32,
+                                _ => {
+                                    return Err(invalid(
+                                        "the access width has to be 1, 2, 4 or 8 bytes".to_string(),
+                                    ))
+                                }
+                            }
+                        }
+                        "dangerous" => {
+                            region.dangerous = match entry.value {
+                                Value::Boolean(dangerous) => dangerous,
+                                ref value => {
+                                    return Err(invalid(format!(
+                                        "expected a boolean, got {}",
+                                        value.type_name()
+                                    )))
+                                }
+                            }
+                        }
+                        "description" => region.description = Some(string()?),
+                        _ => return Err(invalid("unknown key".to_string())),
+                    }
+                }
+                _ => return Err(invalid("unknown key".to_string())),
+            }
+        }
+
+        for (region, line) in profile.regions.iter().zip(region_lines) {
+            if region.end <= region.start {
+                return Err(invalid(
+                    line,
+                    format!("region {} has to end after it starts", region.name),
+                ));
+            }
+        }
+
+        Ok(profile)
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    // The names that addresses on the command line can use: every region
+    // for its start, with _end for its end, and load_address.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        if name == "load_address" {
+            return self.load_address;
+        }
+
+        if let Some(region) = name.strip_suffix("_end").and_then(|name| self.region(name)) {
+            return Some(region.end);
+        }
+
+        self.region(name).map(|region| region.start)
+    }
+
+    // The region that a range lies in, for its access width.
+    pub fn containing(&self, start: u64, end: u64) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.start <= start && end <= region.end)
+    }
+
+    pub fn dangerous_regions(&self, start: u64, end: u64) -> Vec<&Region> {
+        self.regions
+            .iter()
+            .filter(|region| region.dangerous && region.overlaps(start, end))
+            .collect()
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    // Anything starting with a digit would be taken for a number.
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn builtin() -> Vec<Profile> {
+    BUILTIN
+        .iter()
+        .map(|(name, text)| {
+            Profile::parse(name, text, Origin::BuiltIn).expect("built-in profiles are valid")
+        })
+        .collect()
+}
+
+pub fn profiles_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("profiles"))
+}
+
+// The profiles in the profiles directory, named after their files.
+pub fn load_user() -> Result<Vec<Profile>> {
+    match profiles_dir() {
+        Some(dir) => load_dir(&dir),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub fn load_dir(dir: &Path) -> Result<Vec<Profile>> {
+    let dir_error = |source| Error::File {
+        path: dir.display().to_string(),
+        source,
+    };
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(dir_error(err)),
+    };
+
+    let mut profiles = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(dir_error)?.path();
+
+        let name = match (path.file_stem(), path.extension()) {
+            (Some(name), Some(extension)) if extension == "toml" => {
+                name.to_string_lossy().into_owned()
+            }
+            _ => continue,
+        };
+
+        let text = std::fs::read_to_string(&path).map_err(|source| Error::File {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        profiles.push(Profile::parse(&name, &text, Origin::File(path))?);
+    }
+
+    Ok(profiles)
+}
+
+// User profiles replace built-in ones of the same name, as a whole.
+pub fn merge(builtin: Vec<Profile>, user: Vec<Profile>) -> Vec<Profile> {
+    let mut profiles = builtin;
+
+    for profile in user {
+        match profiles
+            .iter_mut()
+            .find(|existing| existing.name == profile.name)
+        {
+            Some(existing) => *existing = profile,
+            None => profiles.push(profile),
+        }
+    }
+
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    profiles
+}
+
+pub fn load_all() -> Result<Vec<Profile>> {
+    Ok(merge(builtin(), load_user()?))
+}
+
+pub fn find(name: &str) -> Result<Profile> {
+    let mut profiles = load_all()?;
+
+    match profiles.iter().position(|profile| profile.name == name) {
+        Some(index) => Ok(profiles.swap_remove(index)),
+        None => Err(Error::InvalidArgument(format!(
+            "There is no profile {}, there are {}",
+            name,
+            profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, text: &str) -> Profile {
+        Profile::parse(name, text, Origin::File(PathBuf::from(name))).unwrap()
+    }
+
+    // A profiles directory of its own, removed once dropped.
+    struct ProfilesDir(PathBuf);
+
+    impl ProfilesDir {
+        fn new(files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("sbootil-profiles-{}", std::process::id()));
+            std::fs::create_dir(&dir).unwrap();
+            for (name, text) in files {
+                std::fs::write(dir.join(name), text).unwrap();
+            }
+
+            Self(dir)
+        }
+    }
+
+    impl Drop for ProfilesDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn user_profiles_replace_built_in_ones_by_name() {
+        let profiles = merge(
+            builtin(),
+            vec![
+                user("exynos4412", "load_address = 0x02025000\n"),
+                user("custom", "description = \"Bench board\"\n"),
+            ],
+        );
+
+        let names = profiles
+            .iter()
+            .map(|profile| profile.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["custom", "exynos4210", "exynos4412", "exynos5420"]);
+
+        // As a whole, the regions of the built-in one are gone.
+        let replaced = &profiles[2];
+        assert_eq!(replaced.origin, Origin::File(PathBuf::from("exynos4412")));
+        assert_eq!(replaced.load_address, Some(0x02025000));
+        assert!(replaced.regions.is_empty());
+
+        assert_eq!(profiles[1].origin, Origin::BuiltIn);
+    }
+
+    #[test]
+    fn regions_take_a_width_and_can_be_dangerous() {
+        let profile = user(
+            "board",
+            "[regions.rom]\nstart = 0x0\nend = 0x1000\naccess_width = 8\ndangerous = true\n\n\
+             [regions.ram]\nstart = 0x1000\nend = 0x2000\n",
+        );
+
+        let rom = profile.region("rom").unwrap();
+        assert_eq!((rom.access_width, rom.dangerous), (8, true));
+
+        // Byte by byte and harmless unless told otherwise.
+        let ram = profile.region("ram").unwrap();
+        assert_eq!((ram.access_width, ram.dangerous), (1, false));
+
+        for text in [
+            "[regions.rom]\nstart = 0\nend = 1\naccess_width = 3\n",
+            "[regions.rom]\nstart = 0\nend = 1\naccess_width = \"4\"\n",
+            "[regions.rom]\nstart = 0\nend = 1\ndangerous = 1\n",
+            "[regions.rom]\nstart = 1\nend = 1\n",
+        ] {
+            let result = Profile::parse("board", text, Origin::BuiltIn);
+            assert!(matches!(result, Err(Error::InvalidConfig(_))), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn profiles_are_read_from_toml_files() {
+        let dir = ProfilesDir::new(&[
+            ("exynos4412.toml", "load_address = 0x02025000\n"),
+            ("notes.txt", "not a profile"),
+            ("broken.toml.bak", "load_address = ["),
+        ]);
+
+        let profiles = load_dir(&dir.0).unwrap();
+
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].name, "exynos4412");
+        assert_eq!(
+            profiles[0].origin,
+            Origin::File(dir.0.join("exynos4412.toml"))
+        );
+
+        // Errors point at the file.
+        std::fs::write(dir.0.join("broken.toml"), "load_address = -1\n").unwrap();
+        match load_dir(&dir.0) {
+            Err(Error::InvalidConfig(message)) => {
+                assert!(message.contains("broken.toml:1"), "{}", message)
+            }
+            result => panic!("{:?}", result),
+        }
+
+        // No profiles directory just means no user profiles.
+        assert!(load_dir(&dir.0.join("missing")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,513 @@
+use crate::bootstub::DumpDigest;
+use crate::crc32::Crc32;
+use crate::error::{Error, Result};
+use crate::format::human_bytes;
+use crate::sha256::Sha256;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// Which chunks of a dump have arrived with a matching checksum, kept next to
+// the output as <output>.resume so that a dump that died halfway can carry on
+// with exactly the chunks that are missing, however it died. It's a few lines
+// of text:
+//
+//     sbootil-resume 1
+//     start 0x0
+//     end 0x400000
+//     chunk_size 0x100000
+//     checksum crc32
+//     verified 0b
+//
+// with the verified chunks as a bitmap in hexadecimal, the first chunk in the
+// lowest bit of the first byte.
+
+const MAGIC: &str = "sbootil-resume";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    pub start: u64,
+    pub end: u64,
+    pub chunk_size: u64,
+    // What the chunks were checked with when they arrived.
+    pub checksum: String,
+    verified: Vec<u8>,
+}
+
+impl State {
+    pub fn new(start: u64, end: u64, chunk_size: u64, checksum: &str) -> Self {
+        let mut state = Self {
+            start,
+            end,
+            // Like the dump itself, which requests at least a byte at a time.
+            chunk_size: chunk_size.max(1),
+            checksum: checksum.to_string(),
+            verified: Vec::new(),
+        };
+        state.verified = vec![0; state.chunks().div_ceil(8)];
+
+        state
+    }
+
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".resume");
+
+        PathBuf::from(path)
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.end
+            .saturating_sub(self.start)
+            .div_ceil(self.chunk_size) as usize
+    }
+
+    // The addresses of a chunk, the last one may be shorter.
+    pub fn chunk(&self, index: usize) -> Range<u64> {
+        let start = self.start + index as u64 * self.chunk_size;
+
+        start..self.end.min(start + self.chunk_size)
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        self.verified[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set_verified(&mut self, index: usize) {
+        self.verified[index / 8] |= 1 << (index % 8);
+    }
+
+    pub fn verified_count(&self) -> usize {
+        (0..self.chunks())
+            .filter(|&index| self.is_verified(index))
+            .count()
+    }
+
+    // The chunks that still have to be requested, as runs of consecutive
+    // ones that can go into a single dump each.
+    pub fn missing(&self) -> Vec<Range<usize>> {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+
+        for index in (0..self.chunks()).filter(|&index| !self.is_verified(index)) {
+            match runs.last_mut() {
+                Some(run) if run.end == index => run.end += 1,
+                _ => runs.push(index..index + 1),
+            }
+        }
+
+        runs
+    }
+
+    pub fn to_text(&self) -> String {
+        let verified = self
+            .verified
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        format!(
+            "{} {}\nstart {:#x}\nend {:#x}\nchunk_size {:#x}\nchecksum {}\nverified {}\n",
+            MAGIC, VERSION, self.start, self.end, self.chunk_size, self.checksum, verified
+        )
+    }
+
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut lines = text.lines();
+
+        match lines.next().and_then(|line| line.split_once(' ')) {
+            Some((MAGIC, version)) if version == VERSION.to_string() => {}
+            Some((MAGIC, version)) => {
+                return Err(format!(
+                    "version {} is not supported, only version {}",
+                    version, VERSION
+                ))
+            }
+            _ => return Err(format!("it doesn't start with {}", MAGIC)),
+        }
+
+        let mut fields = [None; 5];
+        let names = ["start", "end", "chunk_size", "checksum", "verified"];
+
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("'{}' has no value", line))?;
+            let index = names
+                .iter()
+                .position(|known| *known == name)
+                .ok_or_else(|| format!("unknown field {}", name))?;
+
+            if fields[index].replace(value.trim()).is_some() {
+                return Err(format!("{} is there twice", name));
+            }
+        }
+
+        let field =
+            |index: usize| fields[index].ok_or_else(|| format!("{} is missing", names[index]));
+        let number = |index: usize| {
+            let value = field(index)?;
+            value
+                .strip_prefix("0x")
+                .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("{} '{}' is not a hexadecimal number", names[index], value))
+        };
+
+        let start = number(0)?;
+        let end = number(1)?;
+        let chunk_size = number(2)?;
+        if end < start || chunk_size == 0 {
+            return Err(format!(
+                "{:#x} to {:#x} in chunks of {:#x} is not a range",
+                start, end, chunk_size
+            ));
+        }
+
+        let mut state = Self::new(start, end, chunk_size, field(3)?);
+
+        let verified = field(4)?;
+        if !verified.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err("the bitmap is not hexadecimal".to_string());
+        }
+        if verified.len() != state.verified.len() * 2 {
+            return Err(format!(
+                "the bitmap has {} digits, {} chunks need {}",
+                verified.len(),
+                state.chunks(),
+                state.verified.len() * 2
+            ));
+        }
+        for (index, byte) in state.verified.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&verified[index * 2..index * 2 + 2], 16).unwrap();
+        }
+
+        // Nothing past the last chunk can have been verified.
+        let used = state.chunks() % 8;
+        if used != 0 && state.verified.last().unwrap() >> used != 0 {
+            return Err("the bitmap has chunks past the end of the range".to_string());
+        }
+
+        Ok(state)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(Error::File {
+                    path: path.display().to_string(),
+                    source,
+                })
+            }
+        };
+
+        Self::parse(&text).map(Some).map_err(|message| {
+            Error::InvalidArgument(format!(
+                "{} is not a resume state: {}",
+                path.display(),
+                message
+            ))
+        })
+    }
+
+    // The state that an earlier run of the same dump left behind, which has to
+    // be for the same range in the same chunks.
+    pub fn for_dump(output: &Path, start: u64, end: u64, chunk_size: u64) -> Result<Option<Self>> {
+        let path = Self::path(output);
+        let Some(state) = Self::load(&path)? else {
+            return Ok(None);
+        };
+
+        let expected = Self::new(start, end, chunk_size, &state.checksum);
+        if (state.start, state.end, state.chunk_size)
+            != (expected.start, expected.end, expected.chunk_size)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "{} is for {:#x} to {:#x} in chunks of {}, not {:#x} to {:#x} in chunks of {}, delete it to start over",
+                path.display(),
+                state.start,
+                state.end,
+                human_bytes(state.chunk_size),
+                start,
+                end,
+                human_bytes(expected.chunk_size)
+            )));
+        }
+
+        // Anything else can't hold the chunks where they belong.
+        let size = std::fs::metadata(output)
+            .map(|metadata| metadata.len())
+            .ok();
+        if size != Some(end - start) {
+            return Err(Error::InvalidArgument(format!(
+                "{} doesn't go with {}, {}, delete it to start over",
+                output.display(),
+                path.display(),
+                match size {
+                    Some(size) => format!(
+                        "it has {} rather than {}",
+                        human_bytes(size),
+                        human_bytes(end - start)
+                    ),
+                    None => "it's missing".to_string(),
+                }
+            )));
+        }
+
+        Ok(Some(state))
+    }
+
+    // Replaces the file in one go, so that a crash leaves either the old or
+    // the new state behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        let file_error = |path: &Path, source| Error::File {
+            path: path.display().to_string(),
+            source,
+        };
+
+        let mut file = File::create(&temporary).map_err(|source| file_error(&temporary, source))?;
+        file.write_all(self.to_text().as_bytes())
+            .and_then(|()| file.sync_data())
+            .map_err(|source| file_error(&temporary, source))?;
+        std::fs::rename(&temporary, path).map_err(|source| file_error(path, source))
+    }
+}
+
+// The state may only claim a chunk once its data is on the disk.
+pub trait SyncData {
+    fn sync_data(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+impl SyncData for Cursor<Vec<u8>> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes the chunks of a dump where they belong in the output and keeps the
+// state up to date. The data of a chunk whose checksum didn't match is still
+// written, but always last before the dump fails, so a chunk only counts as
+// verified once something after it was written or the dump went through.
+pub struct ResumeWriter<W> {
+    output: W,
+    state: State,
+    // Where to keep the state, if anywhere.
+    path: Option<PathBuf>,
+    // Where the next byte goes, relative to the start of the dump.
+    position: u64,
+    // The first chunk of the current run that hasn't been confirmed yet.
+    confirmed: usize,
+}
+
+impl<W: Write + Seek + SyncData> ResumeWriter<W> {
+    pub fn new(output: W, state: State, path: Option<PathBuf>) -> Self {
+        Self {
+            output,
+            state,
+            path,
+            position: 0,
+            confirmed: 0,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    // Goes on with the given chunk, after the previous run failed or went
+    // through. Anything that wasn't confirmed by then is dropped.
+    pub fn start_run(&mut self, chunk: usize) -> Result<()> {
+        self.confirmed = chunk;
+        self.position = chunk as u64 * self.state.chunk_size;
+        self.output.seek(SeekFrom::Start(self.position))?;
+
+        Ok(())
+    }
+
+    // The chunks of the current run that have been written in full.
+    fn written(&self) -> usize {
+        if self.position == self.state.end - self.state.start {
+            self.state.chunks()
+        } else {
+            (self.position / self.state.chunk_size) as usize
+        }
+    }
+
+    // Takes the chunks written so far as verified, once the dump they came
+    // from went through.
+    pub fn commit(&mut self) -> Result<()> {
+        let written = self.written();
+        if written <= self.confirmed {
+            return Ok(());
+        }
+
+        self.output.flush()?;
+        self.output.sync_data()?;
+
+        for index in self.confirmed..written {
+            self.state.set_verified(index);
+        }
+        self.confirmed = written;
+
+        match &self.path {
+            Some(path) => self.state.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write + Seek + SyncData> Write for ResumeWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        // The dump only goes on after a chunk was confirmed.
+        self.commit().map_err(std::io::Error::other)?;
+
+        let count = self.output.write(data)?;
+        self.position += count as u64;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+// What a finished dump came to, from the output file, for dumps that were
+// put together from several runs.
+pub fn digest(path: &Path) -> Result<DumpDigest> {
+    let file_error = |source| Error::File {
+        path: path.display().to_string(),
+        source,
+    };
+
+    let mut file = File::open(path).map_err(file_error)?;
+    let mut sha256 = Sha256::new();
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        match file.read(&mut buf).map_err(file_error)? {
+            0 => break,
+            count => {
+                sha256.update(&buf[..count]);
+                crc.update(&buf[..count]);
+            }
+        }
+    }
+
+    Ok(DumpDigest {
+        sha256: sha256.finish(),
+        crc32: crc.finish(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: u64 = 64;
+
+    fn memory() -> Vec<u8> {
+        (0..10 * CHUNK_SIZE + 20)
+            .map(|index| (index * 13 % 256) as u8)
+            .collect()
+    }
+
+    // Writes the chunks of a run in pieces of odd sizes, like the dump hands
+    // them over, and stops once `limit` bytes of the dump are written.
+    fn write_run(
+        writer: &mut ResumeWriter<Cursor<Vec<u8>>>,
+        data: &[u8],
+        run: Range<usize>,
+        limit: u64,
+    ) {
+        let state = writer.state().clone();
+        let range = state.chunk(run.start).start..state.chunk(run.end - 1).end;
+
+        writer.start_run(run.start).unwrap();
+        for piece in (range.start..range.end.min(limit)).step_by(7) {
+            let end = (piece + 7).min(range.end).min(limit);
+            writer
+                .write_all(&data[piece as usize..end as usize])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn resume_after_a_crash() {
+        let memory = memory();
+        let size = memory.len() as u64;
+
+        for crash in 0..size {
+            // The chunk that was coming in when it crashed went wrong.
+            let mut received = memory.clone();
+            received[crash as usize] ^= 0xff;
+
+            let state = State::new(0, size, CHUNK_SIZE, "crc32");
+            let chunks = state.chunks();
+            let mut writer = ResumeWriter::new(Cursor::new(Vec::new()), state, None);
+            write_run(&mut writer, &received, 0..chunks, crash + 1);
+
+            // What is on the disk afterwards: the state as it was last saved,
+            // and an output that's cut off wherever the data got to.
+            let state = State::parse(&writer.state().to_text()).unwrap();
+            let mut output = writer.into_inner().into_inner();
+            output.truncate(crash as usize);
+
+            assert!(!state.is_verified((crash / CHUNK_SIZE) as usize));
+            for index in (0..state.chunks()).filter(|&index| state.is_verified(index)) {
+                let chunk = state.chunk(index);
+                let chunk = chunk.start as usize..chunk.end as usize;
+                assert_eq!(output[chunk.clone()], memory[chunk]);
+            }
+
+            // Like dump_resumable, which gives the output its full size.
+            output.resize(size as usize, 0xee);
+            let mut writer = ResumeWriter::new(Cursor::new(output), state, None);
+            for run in writer.state().missing() {
+                write_run(&mut writer, &memory, run, size);
+                writer.commit().unwrap();
+            }
+
+            assert_eq!(writer.state().verified_count(), writer.state().chunks());
+            assert!(
+                writer.into_inner().into_inner() == memory,
+                "crash at {}",
+                crash
+            );
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut state = State::new(0x1000, 0x1000 + 10 * CHUNK_SIZE + 1, CHUNK_SIZE, "xor");
+        for index in [0, 1, 2, 7, 10] {
+            state.set_verified(index);
+        }
+
+        assert_eq!(state.chunks(), 11);
+        assert_eq!(state.chunk(10), 0x1280..0x1281);
+        assert_eq!(state.missing(), [3..7, 8..10]);
+        assert_eq!(State::parse(&state.to_text()).unwrap(), state);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::error::{Error, Result};
+use crate::step;
+use std::time::{Duration, Instant};
+
+// How to deal with errors that may go away when trying again, e.g. corrupted
+// chunks, file parts the device asks for again or stalled endpoints. Every
+// place that retries has its own number of attempts by default, which
+// --retries replaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // How often to try again after the first attempt failed.
+    pub attempts: u32,
+    // How long to wait before the first retry, doubled for every one after.
+    pub backoff: Duration,
+    // Retries that would only start after this long since the first attempt
+    // are given up on.
+    pub max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: u32) -> Self {
+        Self {
+            attempts,
+            backoff: Duration::ZERO,
+            max_elapsed: None,
+        }
+    }
+
+    pub fn start(&self) -> Retries {
+        Retries {
+            policy: *self,
+            attempt: 0,
+            started: Instant::now(),
+        }
+    }
+
+    // Runs `operation` until it succeeds, returning the last error once the
+    // policy runs out or `retry` turns an error down. `retry` is called with
+    // the error and the number of the retry that is about to happen, e.g. to
+    // tell the user about it.
+    pub fn run<T>(
+        &self,
+        what: &str,
+        mut operation: impl FnMut() -> Result<T>,
+        mut retry: impl FnMut(&Error, u32) -> bool,
+    ) -> Result<T> {
+        let mut retries = self.start();
+
+        loop {
+            let err = match operation() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            if !retries.remaining() || !retry(&err, retries.attempt + 1) {
+                return Err(err);
+            }
+
+            retries.next(what);
+        }
+    }
+}
+
+// The retries for one operation, for places that can't wrap it in a closure,
+// e.g. because the device asks for something to be sent again.
+pub struct Retries {
+    policy: RetryPolicy,
+    attempt: u32,
+    started: Instant,
+}
+
+impl Retries {
+    // How long to wait before the next retry.
+    fn delay(&self) -> Duration {
+        self.policy
+            .backoff
+            .saturating_mul(1 << self.attempt.min(16))
+    }
+
+    pub fn remaining(&self) -> bool {
+        let within_time = match self.policy.max_elapsed {
+            Some(max_elapsed) => self.started.elapsed() + self.delay() <= max_elapsed,
+            None => true,
+        };
+
+        self.attempt < self.policy.attempts && within_time
+    }
+
+    // Counts a retry of `what` and waits for the backoff, returning the
+    // number of the retry, or None if there are no more.
+    pub fn next(&mut self, what: &str) -> Option<u32> {
+        if !self.remaining() {
+            return None;
+        }
+
+        let delay = self.delay();
+        self.attempt += 1;
+
+        step!(
+            "retrying {} in {:.1} s ({} of {})",
+            what,
+            delay.as_secs_f64(),
+            self.attempt,
+            self.policy.attempts
+        );
+        std::thread::sleep(delay);
+
+        Some(self.attempt)
+    }
+
+    // Starts over after the operation went through, for operations that go
+    // on with the next piece.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.started = Instant::now();
+    }
+
+    // How many retries there have been so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.policy.attempts
+    }
+}
+
+pub fn parse_backoff(string: &str) -> std::result::Result<Duration, String> {
+    string
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("'{}' is not a number of seconds", string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure() -> Error {
+        Error::Verification("corrupted".to_string())
+    }
+
+    #[test]
+    fn attempts_count_the_retries_after_the_first_try() {
+        let mut tries = 0;
+        let mut retried = Vec::new();
+
+        let result = RetryPolicy::new(3).run(
+            "the chunk",
+            || -> Result<()> {
+                tries += 1;
+                Err(failure())
+            },
+            |_, retry| {
+                retried.push(retry);
+                true
+            },
+        );
+
+        assert!(matches!(result, Err(Error::Verification(_))));
+        assert_eq!(tries, 4);
+        assert_eq!(retried, [1, 2, 3]);
+    }
+
+    #[test]
+    fn success_and_refusals_end_the_retries() {
+        let mut tries = 0;
+        let result = RetryPolicy::new(3).run(
+            "the chunk",
+            || {
+                tries += 1;
+                if tries < 2 {
+                    Err(failure())
+                } else {
+                    Ok(tries)
+                }
+            },
+            |_, _| true,
+        );
+        assert_eq!(result.unwrap(), 2);
+
+        let mut tries = 0;
+        let result = RetryPolicy::new(3).run(
+            "the chunk",
+            || -> Result<()> {
+                tries += 1;
+                Err(failure())
+            },
+            |_, _| false,
+        );
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_limit() {
+        let mut retries = RetryPolicy {
+            backoff: Duration::from_millis(10),
+            ..RetryPolicy::new(u32::MAX)
+        }
+        .start();
+
+        let mut delays = Vec::new();
+        for attempt in [0, 1, 2, 3, 16, 17, 1000] {
+            retries.attempt = attempt;
+            delays.push(retries.delay());
+        }
+
+        assert_eq!(
+            delays,
+            [10, 20, 40, 80, 655_360, 655_360, 655_360].map(Duration::from_millis)
+        );
+
+        // Far beyond any sensible backoff, but not an overflow.
+        retries.policy.backoff = Duration::MAX;
+        assert_eq!(retries.delay(), Duration::MAX);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut retries = RetryPolicy::new(2).start();
+
+        assert_eq!(retries.next("the part"), Some(1));
+        assert_eq!(retries.next("the part"), Some(2));
+        assert_eq!(retries.next("the part"), None);
+        assert!(!retries.remaining());
+
+        retries.reset();
+        assert_eq!(retries.attempt(), 0);
+        assert!(retries.remaining());
+        assert_eq!(retries.next("the part"), Some(1));
+    }
+
+    #[test]
+    fn retries_past_the_time_limit_are_given_up_on() {
+        let retries = RetryPolicy {
+            backoff: Duration::from_secs(2),
+            max_elapsed: Some(Duration::from_secs(1)),
+            ..RetryPolicy::new(5)
+        }
+        .start();
+
+        assert!(!retries.remaining());
+    }
+
+    #[test]
+    fn backoffs_are_seconds() {
+        assert_eq!(parse_backoff("0"), Ok(Duration::ZERO));
+        assert_eq!(parse_backoff("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_backoff("2"), Ok(Duration::from_secs(2)));
+
+        for string in ["", "fast", "1s", "-1", "NaN", "inf", "1e400"] {
+            assert_eq!(
+                parse_backoff(string),
+                Err(format!("'{}' is not a number of seconds", string))
+            );
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::bootstub::{ConsoleOptions, DumpOptions, Session};
+use crate::cancel::CancelToken;
+use crate::crc32::Crc32;
+use crate::error::{Error, Result};
+use crate::expr;
+use crate::hexdump::hexdump;
+use crate::output;
+use crate::say;
+use crate::sha256;
+use crate::status;
+use std::fs::File;
+use std::path::PathBuf;
+
+// The commands that `bootstub run` scripts and `bootstub shell` take, one per
+// line. Words are separated by whitespace (so expressions can't contain any)
+// and can be quoted, everything after a `#` is a comment.
+pub enum Command {
+    Dump {
+        start: u64,
+        end: u64,
+        output: PathBuf,
+        force: bool,
+    },
+    Peek {
+        address: u64,
+        length: u64,
+    },
+    Crc {
+        start: u64,
+        end: u64,
+    },
+    Boot {
+        binary: PathBuf,
+    },
+    SetBaud(u32),
+    Help,
+    Quit,
+}
+
+pub const HELP: &str = "\
+dump <start> <end> <file> [--force]  Dump memory into a file
+peek <address> [<length>]            Print memory as a hex dump (16 bytes by default)
+crc <start> <end>                    Print the CRC32 of memory (computed on this end)
+boot <file>                          Boot a binary and show its console output
+set-baud <rate>                      Switch to a different baud rate
+help                                 Show this list
+quit                                 Close the connection";
+
+fn split_words(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => word.push(c),
+                    None => return Err("missing the closing '\"'".to_string()),
+                }
+            }
+
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace()) {
+                word.push(c);
+                chars.next();
+            }
+
+            words.push(word);
+        }
+    }
+
+    Ok(words)
+}
+
+fn address(string: &str, what: &str) -> std::result::Result<u64, String> {
+    expr::evaluate(string, |_| None).map_err(|err| format!("Invalid {}: {}", what, err))
+}
+
+// Parses a single line, which is None if it's empty or only a comment.
+pub fn parse_line(line: &str) -> std::result::Result<Option<Command>, String> {
+    let words = split_words(line)?;
+    let Some((verb, args)) = words.split_first() else {
+        return Ok(None);
+    };
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let usage = |usage: &str| Err(format!("Usage: {}", usage));
+
+    let command = match (verb.as_str(), args.as_slice()) {
+        ("dump", [start, end, output, flags @ ..]) => {
+            let force = match flags {
+                [] => false,
+                ["-f" | "--force"] => true,
+                _ => return usage("dump <start> <end> <file> [--force]"),
+            };
+
+            Command::Dump {
+                start: address(start, "start address")?,
+                end: address(end, "end address")?,
+                output: PathBuf::from(output),
+                force,
+            }
+        }
+        ("dump", _) => return usage("dump <start> <end> <file> [--force]"),
+        ("peek", [address_arg]) => Command::Peek {
+            address: address(address_arg, "address")?,
+            length: 16,
+        },
+        ("peek", [address_arg, length]) => Command::Peek {
+            address: address(address_arg, "address")?,
+            length: address(length, "length")?,
+        },
+        ("peek", _) => return usage("peek <address> [<length>]"),
+        ("crc", [start, end]) => Command::Crc {
+            start: address(start, "start address")?,
+            end: address(end, "end address")?,
+        },
+        ("crc", _) => return usage("crc <start> <end>"),
+        ("boot", [binary]) => Command::Boot {
+            binary: PathBuf::from(binary),
+        },
+        ("boot", _) => return usage("boot <file>"),
+        ("set-baud", [rate]) => Command::SetBaud(
+            crate::serial::parse_baud(rate).map_err(|err| format!("Invalid baud rate: {}", err))?,
+        ),
+        ("set-baud", _) => return usage("set-baud <rate>"),
+        ("write" | "poke", _) => {
+            return Err(format!(
+                "'{}' isn't possible, the stub has no command for writing memory",
+                verb
+            ))
+        }
+        ("help", []) => Command::Help,
+        ("quit" | "exit", []) => Command::Quit,
+        _ => return Err(format!("Unknown command '{}', try 'help'", verb)),
+    };
+
+    Ok(Some(command))
+}
+
+// Dumps a range into memory, for the commands that only print something.
+fn read_memory(
+    session: &mut Session,
+    start: u64,
+    end: u64,
+    cancel: &CancelToken,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    session.dump(start, end, &DumpOptions::default(), &mut data, cancel)?;
+
+    Ok(data)
+}
+
+// Runs a command on the session. Quitting is up to the caller.
+pub fn execute(session: &mut Session, command: &Command, cancel: &CancelToken) -> Result<()> {
+    match command {
+        Command::Dump {
+            start,
+            end,
+            output,
+            force,
+        } => {
+            let mut file = output::create(output, *force)?;
+            let result = session.dump(*start, *end, &DumpOptions::default(), &mut file, cancel);
+            drop(file);
+
+            if result.is_err() {
+                output::discard(output, matches!(result, Err(Error::Disconnected { .. })));
+            }
+
+            status!(
+                "SHA-256 of {}: {}",
+                output.display(),
+                sha256::to_hex(&result?.sha256)
+            );
+        }
+        Command::Peek { address, length } => {
+            let end = address.checked_add(*length).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "{:#x} bytes at {:#x} are too many",
+                    length, address
+                ))
+            })?;
+
+            for line in hexdump(&read_memory(session, *address, end, cancel)?, *address) {
+                say!("{}", line);
+            }
+        }
+        Command::Crc { start, end } => {
+            let mut crc = Crc32::new();
+            crc.update(&read_memory(session, *start, *end, cancel)?);
+
+            say!("{:08x}", crc.finish());
+        }
+        Command::Boot { binary } => {
+            let mut file = File::open(binary).map_err(|source| Error::File {
+                path: binary.display().to_string(),
+                source,
+            })?;
+            let size = file.metadata()?.len();
+
+            session.boot(&mut file, size, cancel)?;
+            session.console(&ConsoleOptions::default(), &mut std::io::stdout(), cancel)?;
+        }
+        Command::SetBaud(rate) => {
+            session.set_baud(*rate)?;
+            status!("Switched to {} baud", rate);
+        }
+        Command::Help => say!("{}", HELP),
+        Command::Quit => {}
+    }
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::io::Write;
+
+// What to look for, with None for bytes that may be anything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern(Vec<Option<u8>>);
+
+impl Pattern {
+    // Hexadecimal bytes with ?? for any byte, e.g. `de ad ?? ef` or
+    // `dead??ef`.
+    pub fn parse_hex(string: &str) -> Option<Self> {
+        let digits = string
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<Vec<_>>();
+
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return None;
+        }
+
+        let bytes = digits
+            .chunks(2)
+            .map(|pair| match pair {
+                ['?', '?'] => Some(None),
+                [high, low] => {
+                    let value = high.to_digit(16)? << 4 | low.to_digit(16)?;
+                    Some(Some(value as u8))
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self(bytes))
+    }
+
+    pub fn ascii(string: &str) -> Self {
+        Self(string.bytes().map(Some).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        self.0
+            .iter()
+            .zip(data)
+            .all(|(wanted, byte)| wanted.is_none_or(|wanted| wanted == *byte))
+    }
+}
+
+pub struct Match {
+    pub address: u64,
+    // The bytes around the match, starting at `context_address`, as far as
+    // the searched range goes.
+    pub context_address: u64,
+    pub context: Vec<u8>,
+}
+
+// Looks for a pattern in data that is written to it piece by piece, as it
+// comes out of a dump. Matches may span pieces, so the end of every piece is
+// kept around, along with what's needed for the context of a match.
+pub struct Search<F: FnMut(Match)> {
+    pattern: Pattern,
+    context: usize,
+    limit: Option<usize>,
+    found: usize,
+    on_match: F,
+    // The data that is still needed, starting at `address`.
+    buffer: Vec<u8>,
+    address: u64,
+    // Where in `buffer` the next match may start.
+    position: usize,
+    // Matches whose context after them hasn't fully arrived yet.
+    pending: Vec<u64>,
+}
+
+impl<F: FnMut(Match)> Search<F> {
+    pub fn new(
+        pattern: Pattern,
+        start_address: u64,
+        context: usize,
+        limit: Option<usize>,
+        on_match: F,
+    ) -> Self {
+        Self {
+            pattern,
+            context,
+            limit,
+            found: 0,
+            on_match,
+            buffer: Vec::new(),
+            address: start_address,
+            position: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn limit_reached(&self) -> bool {
+        self.limit.is_some_and(|limit| self.found >= limit)
+    }
+
+    // Whether there's no use in searching on, which is once the limit has
+    // been reached and the context of the last match has come in.
+    pub fn is_done(&self) -> bool {
+        self.limit_reached() && self.pending.is_empty()
+    }
+
+    pub fn found(&self) -> usize {
+        self.found
+    }
+
+    // Reports the matches whose context is complete, or all of them at the
+    // end of the range.
+    fn report(&mut self, finished: bool) {
+        let buffer_end = self.address + self.buffer.len() as u64;
+        let after = (self.pattern.len() + self.context) as u64;
+
+        while let Some(&address) = self.pending.first() {
+            if !finished && address + after > buffer_end {
+                break;
+            }
+            self.pending.remove(0);
+
+            let context_address = address
+                .saturating_sub(self.context as u64)
+                .max(self.address);
+            let context_end = (address + after).min(buffer_end);
+            let context = self.buffer
+                [(context_address - self.address) as usize..(context_end - self.address) as usize]
+                .to_vec();
+
+            (self.on_match)(Match {
+                address,
+                context_address,
+                context,
+            });
+        }
+    }
+
+    // Drops what's neither part of a possible match nor of the context of
+    // one.
+    fn trim(&mut self) {
+        let keep_from = self
+            .pending
+            .first()
+            .map_or(self.position, |&address| (address - self.address) as usize)
+            .min(self.position)
+            .saturating_sub(self.context);
+
+        self.buffer.drain(..keep_from);
+        self.address += keep_from as u64;
+        self.position -= keep_from;
+    }
+
+    pub fn finish(&mut self) {
+        self.report(true);
+    }
+}
+
+impl<F: FnMut(Match)> Write for Search<F> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+
+        while self.position + self.pattern.len() <= self.buffer.len() {
+            // Only the context of the last matches is still of interest.
+            if self.limit_reached() {
+                self.position = self.buffer.len();
+                break;
+            }
+
+            if self
+                .pattern
+                .matches(&self.buffer[self.position..self.position + self.pattern.len()])
+            {
+                self.pending.push(self.address + self.position as u64);
+                self.found += 1;
+            }
+
+            self.position += 1;
+        }
+
+        self.report(false);
+        self.trim();
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
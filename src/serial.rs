@@ -0,0 +1,35 @@
+#[cfg(all(feature = "serial", unix))]
+mod unix;
+#[cfg(all(feature = "serial", windows))]
+mod windows;
+
+#[cfg(all(feature = "serial", unix))]
+pub use unix::SerialPort;
+#[cfg(all(feature = "serial", windows))]
+pub use windows::SerialPort;
+
+pub const BAUD_RATES: [u32; 14] = [
+    9600, 19200, 38400, 57600, 115200, 230400, 460800, 500000, 576000, 921600, 1000000, 1152000,
+    1500000, 2000000,
+];
+
+pub fn parse_baud(string: &str) -> Result<u32, String> {
+    let rate = string
+        .parse::<u32>()
+        .map_err(|_| format!("'{}' is not a number", string))?;
+
+    if BAUD_RATES.contains(&rate) {
+        return Ok(rate);
+    }
+
+    let valid = BAUD_RATES
+        .iter()
+        .map(|rate| rate.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Err(format!(
+        "Unsupported baud rate {}, valid rates are: {}",
+        rate, valid
+    ))
+}
@@ -0,0 +1,532 @@
+use crate::error::{Error, Result};
+use crate::lock::{self, DeviceLock, LockMode};
+use crate::permissions;
+use crate::step;
+use crate::transport::Transport;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use termios::{
+    cfsetspeed, speed_t, tcdrain, tcflush, tcsetattr, Termios, BRKINT, CS8, CSIZE, ECHO, ECHONL,
+    ICANON, ICRNL, IEXTEN, IGNBRK, IGNCR, INLCR, ISIG, ISTRIP, IXON, OPOST, PARENB, PARMRK,
+    TCIOFLUSH, TCSANOW,
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn baud_speed(rate: u32) -> Result<speed_t> {
+    use termios::os::target::{
+        B1000000, B115200, B1152000, B1500000, B2000000, B230400, B460800, B500000, B57600,
+        B576000, B921600,
+    };
+    use termios::{B19200, B38400, B9600};
+
+    let speed = match rate {
+        9600 => B9600,
+        19200 => B19200,
+        38400 => B38400,
+        57600 => B57600,
+        115200 => B115200,
+        230400 => B230400,
+        460800 => B460800,
+        500000 => B500000,
+        576000 => B576000,
+        921600 => B921600,
+        1000000 => B1000000,
+        1152000 => B1152000,
+        1500000 => B1500000,
+        2000000 => B2000000,
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported baud rate {}",
+                rate
+            )))
+        }
+    };
+
+    Ok(speed)
+}
+
+// The BSDs (including macOS) use the plain numeric rate as the speed value.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn baud_speed(rate: u32) -> Result<speed_t> {
+    Ok(rate as speed_t)
+}
+
+// macOS refuses anything above 230400 through termios, the custom speed ioctl
+// has to be used instead.
+#[cfg(target_os = "macos")]
+fn set_custom_speed(fd: std::os::unix::io::RawFd, rate: u32) -> Result<()> {
+    const IOSSIOSPEED: libc::c_ulong = 0x80085402;
+
+    if rate <= 230400 {
+        return Ok(());
+    }
+
+    let speed = rate as speed_t;
+    if unsafe { libc::ioctl(fd, IOSSIOSPEED, &speed) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+// Lists the processes (other than ourselves) that have the given device node open.
+fn find_other_users(path: &Path) -> Vec<(u32, String)> {
+    let mut users = Vec::new();
+
+    let target = match path.canonicalize() {
+        Ok(target) => target,
+        Err(_) => return users,
+    };
+
+    let processes = match std::fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return users,
+    };
+
+    for process in processes.flatten() {
+        let pid = match process
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        if pid == std::process::id() {
+            continue;
+        }
+
+        let fds = match std::fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+
+        let uses_target = fds
+            .flatten()
+            .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link == target));
+
+        if uses_target {
+            let name = std::fs::read_to_string(process.path().join("comm"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            users.push((pid, name));
+        }
+    }
+
+    users
+}
+
+// How often to look for a port that went away.
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The serial number of the USB device behind a tty, to find it again after it
+// re-enumerated, possibly under a different name.
+#[cfg(target_os = "linux")]
+fn usb_serial_number(path: &Path) -> Option<String> {
+    let name = path.canonicalize().ok()?.file_name()?.to_owned();
+    let interface = Path::new("/sys/class/tty")
+        .join(name)
+        .join("device")
+        .canonicalize()
+        .ok()?;
+
+    // The interface is below the USB device, which is the first one up with
+    // IDs (the hubs further up have them as well).
+    let device = interface
+        .ancestors()
+        .find(|dir| dir.join("idVendor").exists())?;
+
+    std::fs::read_to_string(device.join("serial"))
+        .ok()
+        .map(|serial| serial.trim().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn usb_serial_number(_path: &Path) -> Option<String> {
+    None
+}
+
+// The tty of the USB device with the given serial number, if there is one.
+fn find_by_serial_number(serial_number: &str) -> Option<PathBuf> {
+    std::fs::read_dir("/sys/class/tty")
+        .ok()?
+        .flatten()
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+        .find(|path| usb_serial_number(path).as_deref() == Some(serial_number))
+}
+
+// The kernel driver behind a tty, e.g. cdc_acm or ftdi_sio. Pseudo
+// terminals don't have one.
+#[cfg(target_os = "linux")]
+fn tty_driver(path: &Path) -> Option<String> {
+    let driver = Path::new("/sys/class/tty")
+        .join(path.file_name()?)
+        .join("device")
+        .join("driver")
+        .canonicalize()
+        .ok()?;
+
+    Some(driver.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tty_driver(_path: &Path) -> Option<String> {
+    None
+}
+
+// Follows symlinks like the ones in /dev/serial/by-id to the actual port and
+// makes sure that it's a character device. Anything else would only fail
+// with a confusing termios error, or worse, be read as if the device had
+// sent it.
+fn resolve(path: &str) -> Result<PathBuf> {
+    let resolved = match std::fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(Error::DeviceNotFound(format!(
+                "Serial port {} doesn't exist",
+                path
+            )))
+        }
+        // Opening it will tell what's wrong.
+        Err(_) => return Ok(PathBuf::from(path)),
+    };
+
+    if resolved != Path::new(path) {
+        step!("{} is {}", path, resolved.display());
+    }
+
+    let Ok(metadata) = std::fs::metadata(&resolved) else {
+        return Ok(resolved);
+    };
+
+    let file_type = metadata.file_type();
+    if file_type.is_char_device() {
+        return Ok(resolved);
+    }
+
+    let what = if file_type.is_dir() {
+        "a directory"
+    } else if file_type.is_file() {
+        "a regular file"
+    } else {
+        "a special file"
+    };
+
+    Err(Error::InvalidArgument(format!(
+        "{} is {}, not a serial port",
+        resolved.display(),
+        what
+    )))
+}
+
+pub struct SerialPort {
+    file: File,
+    // As given, to open the port again under the same path.
+    path: String,
+    // Where that leads, which is what messages show.
+    name: String,
+    locked: bool,
+    timeout: Option<Duration>,
+    // To open the port again the same way after it went away.
+    baud: u32,
+    lock_mode: LockMode,
+    usb_serial_number: Option<String>,
+    _device_lock: Option<DeviceLock>,
+}
+
+impl SerialPort {
+    pub fn open(path: &str, baud: u32, lock: LockMode) -> Result<Self> {
+        let resolved = resolve(path)?;
+        let name = resolved.display().to_string();
+
+        // Before opening, as another invocation would have the port
+        // exclusively and make that fail less helpfully.
+        let device_lock = DeviceLock::acquire(&lock::serial_key(path), lock)?;
+
+        let file = match File::options().read(true).write(true).open(&resolved) {
+            Ok(file) => file,
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                return Err(Error::Serial(format!(
+                    "Serial port {} is opened exclusively by another program",
+                    name
+                )))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::DeviceNotFound(format!(
+                    "Serial port {} doesn't exist",
+                    name
+                )))
+            }
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                return Err(permissions::denied(
+                    format!("Not allowed to open serial port {}", name),
+                    &resolved,
+                    "Serial ports usually belong to the dialout (or uucp) group",
+                ))
+            }
+            Err(err) => {
+                return Err(Error::Serial(format!(
+                    "Failed to open serial port {}: {}",
+                    name, err
+                )))
+            }
+        };
+
+        // Character devices like /dev/null make it this far.
+        if unsafe { libc::isatty(file.as_raw_fd()) } == 0 {
+            return Err(Error::InvalidArgument(format!(
+                "{} is a character device, but not a terminal like serial ports are",
+                name
+            )));
+        }
+
+        if let Some(driver) = tty_driver(&resolved) {
+            step!("{} is driven by {}", name, driver);
+        }
+
+        let mut port = Self {
+            file,
+            path: path.to_string(),
+            name,
+            locked: false,
+            timeout: None,
+            baud,
+            lock_mode: lock,
+            usb_serial_number: usb_serial_number(Path::new(path)),
+            _device_lock: device_lock,
+        };
+
+        if lock != LockMode::Off {
+            port.lock()?;
+        }
+
+        port.configure(baud)?;
+
+        Ok(port)
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        let fd = self.file.as_raw_fd();
+
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(Error::Serial(format!(
+                "Serial port {} is locked by another program",
+                self.name
+            )));
+        }
+
+        let users = find_other_users(Path::new(&self.path));
+        if !users.is_empty() {
+            let users = users
+                .iter()
+                .map(|(pid, name)| format!("{} (pid {})", name, pid))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(Error::Serial(format!(
+                "Serial port {} is already in use by {} (use --no-lock to ignore)",
+                self.name, users
+            )));
+        }
+
+        if unsafe { libc::ioctl(fd, libc::TIOCEXCL as _) } != 0 {
+            return Err(Error::Serial(format!(
+                "Failed to get exclusive access to serial port {}: {}",
+                self.name,
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        self.locked = true;
+
+        Ok(())
+    }
+
+    fn configure(&mut self, baud: u32) -> Result<()> {
+        self.apply_settings(baud).map_err(|err| {
+            Error::Serial(format!(
+                "Failed to configure serial port {}: {}",
+                self.name, err
+            ))
+        })
+    }
+
+    fn apply_settings(&mut self, baud: u32) -> Result<()> {
+        let fd = self.file.as_raw_fd();
+        let mut termios = Termios::from_fd(fd)?;
+
+        #[cfg(target_os = "macos")]
+        cfsetspeed(&mut termios, baud_speed(baud.min(230400))?)?;
+        #[cfg(not(target_os = "macos"))]
+        cfsetspeed(&mut termios, baud_speed(baud)?)?;
+
+        // Set options for "raw" mode (similar to cfmakeraw).
+        termios.c_iflag &= !(IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON);
+        termios.c_oflag &= !(OPOST);
+        termios.c_lflag &= !(ECHO | ECHONL | ICANON | ISIG | IEXTEN);
+        termios.c_cflag &= !(CSIZE | PARENB);
+        termios.c_cflag |= CS8;
+
+        tcsetattr(fd, TCSANOW, &termios)?;
+        #[cfg(target_os = "macos")]
+        set_custom_speed(fd, baud)?;
+        tcflush(fd, TCIOFLUSH)?;
+
+        Ok(())
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> std::io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let millis = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+            match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+                0 => return Err(ErrorKind::TimedOut.into()),
+                -1 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+impl SerialPort {
+    // A port that went away (e.g. an unplugged USB adapter) reads as the end
+    // of the file or fails with EIO, which is made recognizable here.
+    fn map_error(&self, err: std::io::Error) -> std::io::Error {
+        match err.raw_os_error() {
+            Some(libc::EIO | libc::ENXIO | libc::ENODEV) => self.disconnected(),
+            _ => err,
+        }
+    }
+
+    fn disconnected(&self) -> std::io::Error {
+        std::io::Error::new(
+            ErrorKind::NotConnected,
+            format!("{} was disconnected", self.name),
+        )
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(timeout) = self.timeout {
+            self.wait_readable(timeout)
+                .map_err(|err| self.map_error(err))?;
+        }
+
+        match self.file.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(self.disconnected()),
+            Ok(count) => Ok(count),
+            Err(err) => Err(self.map_error(err)),
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf).map_err(|err| self.map_error(err))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Transport for SerialPort {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        tcdrain(self.file.as_raw_fd())?;
+
+        self.configure(baud)?;
+        self.baud = baud;
+
+        Ok(())
+    }
+
+    // USB serial adapters and CDC-ACM devices may come back under a different
+    // name, they are recognized by their USB serial number where possible.
+    // Anything else has to come back under the same path.
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        step!("waiting for {} to come back", self.name);
+
+        // The lock would keep us out if the port came back under its path.
+        self._device_lock = None;
+
+        let start = Instant::now();
+
+        loop {
+            let path = match &self.usb_serial_number {
+                Some(serial_number) => find_by_serial_number(serial_number),
+                None => Some(PathBuf::from(&self.path)).filter(|path| path.exists()),
+            };
+
+            if let Some(path) = path {
+                let path = path.display().to_string();
+
+                // Permissions may not have been applied to the new node yet.
+                match Self::open(&path, self.baud, self.lock_mode) {
+                    Ok(mut port) => {
+                        port.timeout = self.timeout;
+                        *self = port;
+
+                        return Ok(());
+                    }
+                    Err(err) => step!("failed to open {}: {}", path, err),
+                }
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(Error::Timeout {
+                    phase: format!("{} to come back", self.name),
+                });
+            }
+
+            std::thread::sleep(RECONNECT_POLL_INTERVAL);
+        }
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+}
+
+impl Drop for SerialPort {
+    fn drop(&mut self) {
+        if !self.locked {
+            return;
+        }
+
+        let fd = self.file.as_raw_fd();
+
+        unsafe {
+            libc::ioctl(fd, libc::TIOCNXCL as _);
+            libc::flock(fd, libc::LOCK_UN);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::{Error, Result};
+use crate::lock::{self, DeviceLock, LockMode};
+use crate::transport::Transport;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::time::Duration;
+
+type Handle = *mut std::ffi::c_void;
+
+#[repr(C)]
+#[derive(Default)]
+struct Dcb {
+    length: u32,
+    baud_rate: u32,
+    flags: u32,
+    reserved: u16,
+    xon_limit: u16,
+    xoff_limit: u16,
+    byte_size: u8,
+    parity: u8,
+    stop_bits: u8,
+    xon_char: i8,
+    xoff_char: i8,
+    error_char: i8,
+    eof_char: i8,
+    event_char: i8,
+    reserved1: u16,
+}
+
+#[repr(C)]
+struct CommTimeouts {
+    read_interval_timeout: u32,
+    read_total_timeout_multiplier: u32,
+    read_total_timeout_constant: u32,
+    write_total_timeout_multiplier: u32,
+    write_total_timeout_constant: u32,
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCommState(file: Handle, dcb: *mut Dcb) -> i32;
+    fn SetCommState(file: Handle, dcb: *const Dcb) -> i32;
+    fn SetCommTimeouts(file: Handle, timeouts: *const CommTimeouts) -> i32;
+    fn PurgeComm(file: Handle, flags: u32) -> i32;
+}
+
+const ERROR_ACCESS_DENIED: i32 = 5;
+
+// Bits of the DCB flags field.
+const DCB_BINARY: u32 = 1 << 0;
+const DCB_DTR_CONTROL_ENABLE: u32 = 1 << 4;
+const DCB_RTS_CONTROL_ENABLE: u32 = 1 << 12;
+
+const NOPARITY: u8 = 0;
+const ONESTOPBIT: u8 = 0;
+
+const PURGE_TXCLEAR: u32 = 0x4;
+const PURGE_RXCLEAR: u32 = 0x8;
+
+pub struct SerialPort {
+    file: File,
+    timeout: Option<Duration>,
+    _device_lock: Option<DeviceLock>,
+}
+
+impl SerialPort {
+    // Windows only ever grants exclusive access to a COM port, the lock is
+    // only there to tell who has it.
+    pub fn open(path: &str, baud: u32, lock: LockMode) -> Result<Self> {
+        let device_lock = DeviceLock::acquire(&lock::serial_key(path), lock)?;
+
+        // COM ports above COM9 are only reachable through the device namespace.
+        let device_path = if path.starts_with(r"\\") {
+            path.to_string()
+        } else {
+            format!(r"\\.\{}", path)
+        };
+
+        let file = match File::options()
+            .read(true)
+            .write(true)
+            .share_mode(0)
+            .open(&device_path)
+        {
+            Ok(file) => file,
+            Err(err) if err.raw_os_error() == Some(ERROR_ACCESS_DENIED) => {
+                return Err(Error::Serial(format!(
+                    "Serial port {} is in use by another program",
+                    path
+                )))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::DeviceNotFound(format!(
+                    "Serial port {} doesn't exist",
+                    path
+                )))
+            }
+            Err(err) => {
+                return Err(Error::Serial(format!(
+                    "Failed to open serial port {}: {}",
+                    path, err
+                )))
+            }
+        };
+
+        let mut port = Self {
+            file,
+            timeout: None,
+            _device_lock: device_lock,
+        };
+
+        port.configure(baud)?;
+        port.apply_timeout()?;
+
+        Ok(port)
+    }
+
+    fn handle(&self) -> Handle {
+        self.file.as_raw_handle() as Handle
+    }
+
+    fn configure(&mut self, baud: u32) -> Result<()> {
+        let mut dcb = Dcb {
+            length: std::mem::size_of::<Dcb>() as u32,
+            ..Default::default()
+        };
+
+        if unsafe { GetCommState(self.handle(), &mut dcb) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        // Raw 8N1 without any flow control, matching the unix setup.
+        dcb.baud_rate = baud;
+        dcb.flags = DCB_BINARY | DCB_DTR_CONTROL_ENABLE | DCB_RTS_CONTROL_ENABLE;
+        dcb.byte_size = 8;
+        dcb.parity = NOPARITY;
+        dcb.stop_bits = ONESTOPBIT;
+
+        if unsafe { SetCommState(self.handle(), &dcb) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        if unsafe { PurgeComm(self.handle(), PURGE_TXCLEAR | PURGE_RXCLEAR) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+
+    fn apply_timeout(&mut self) -> Result<()> {
+        // Return as soon as any data is available, or after the total timeout
+        // if nothing arrives at all. Without a timeout, wait as long as the API
+        // allows and retry from the read loop.
+        let constant = match self.timeout {
+            Some(timeout) => timeout.as_millis().clamp(1, u32::MAX as u128 - 1) as u32,
+            None => u32::MAX - 1,
+        };
+
+        let timeouts = CommTimeouts {
+            read_interval_timeout: u32::MAX,
+            read_total_timeout_multiplier: u32::MAX,
+            read_total_timeout_constant: constant,
+            write_total_timeout_multiplier: 0,
+            write_total_timeout_constant: 0,
+        };
+
+        if unsafe { SetCommTimeouts(self.handle(), &timeouts) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.file.read(buf)? {
+                0 if self.timeout.is_some() => return Err(ErrorKind::TimedOut.into()),
+                0 => continue,
+                count => return Ok(count),
+            }
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Transport for SerialPort {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.timeout = timeout;
+
+        self.apply_timeout()
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        // Make sure that everything has been sent out at the old rate.
+        self.file.sync_all()?;
+
+        self.configure(baud)
+    }
+}
@@ -0,0 +1,544 @@
+use crate::crc32::Crc32;
+use crate::error::{Error, Result};
+use crate::step;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use termios::{cfmakeraw, tcsetattr, Termios, TCSANOW};
+
+// Commands are told apart by the pause that the host leaves after each of
+// them, which is a lot longer than this.
+const COMMAND_GAP: Duration = Duration::from_millis(50);
+
+// Announced in answer to GETCAPS, the optional commands that the simulator has
+// are SETBAUD, SETCSUM and CRCMEM, WRITEMEM, UPLDWIN, SETWIDTH, PETWDOG,
+// EXECMEM, RESETDEV and PWROFF.
+const VERSION: u32 = 4;
+const FEATURES: u32 =
+    1 << 0 | 1 << 1 | 1 << 2 | 1 << 4 | 1 << 5 | 1 << 6 | 1 << 7 | 1 << 8 | 1 << 9;
+
+// How the simulated stub misbehaves, to exercise the host side.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    // Served for memory dumps, repeated over the address space. Without any,
+    // every byte is the lower byte of its address.
+    pub data: Option<Vec<u8>>,
+    // Sent along with the dumps instead of the right checksum, and expected
+    // for memory writes.
+    pub corrupt_checksum: bool,
+    // Waited before every write.
+    pub delay: Duration,
+    // Writes are split into pieces of this size, with the delay in between.
+    pub fragment_size: Option<usize>,
+    // Ignores the capabilities query, like stubs from before it.
+    pub legacy: bool,
+    // Prints a line of text before every marker, like stubs built for
+    // debugging.
+    pub debug_output: bool,
+}
+
+impl Options {
+    // Serves the contents of a file for dumps.
+    pub fn with_data_file(self, path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|source| Error::File {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        Ok(Self {
+            data: Some(data),
+            ..self
+        })
+    }
+}
+
+// The device side of a pseudo-terminal, which can be opened like any other
+// serial port.
+pub struct Simulator {
+    master: File,
+    path: PathBuf,
+    options: Options,
+    // Whether the host asked for CRC-32 instead of XOR.
+    crc: bool,
+    // The width of memory accesses for dumps, which only matters for
+    // checking the alignment of the requests.
+    access_width: u64,
+    // What the hosts wrote to memory, which dumps serve instead of the data.
+    written: HashMap<u64, u8>,
+}
+
+fn last_error() -> Error {
+    std::io::Error::last_os_error().into()
+}
+
+impl Simulator {
+    pub fn new(options: Options) -> Result<Self> {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            return Err(last_error());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+
+        if unsafe { libc::grantpt(master.as_raw_fd()) } != 0
+            || unsafe { libc::unlockpt(master.as_raw_fd()) } != 0
+        {
+            return Err(last_error());
+        }
+
+        let name = unsafe { libc::ptsname(master.as_raw_fd()) };
+        if name.is_null() {
+            return Err(last_error());
+        }
+        let path = PathBuf::from(
+            unsafe { std::ffi::CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned(),
+        );
+
+        let slave = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|source| Error::File {
+                path: path.display().to_string(),
+                source,
+            })?;
+
+        // Nothing may be echoed or translated until the host sets up the port.
+        let mut termios = Termios::from_fd(slave.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(slave.as_raw_fd(), TCSANOW, &termios)?;
+
+        // The host has to be the only one with the port open.
+        drop(slave);
+
+        Ok(Self {
+            master,
+            path,
+            options,
+            crc: false,
+            access_width: 1,
+            written: HashMap::new(),
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    // Serves hosts in the background for as long as the process runs, for
+    // anything that talks to the simulator from the same process.
+    pub fn spawn(options: Options) -> Result<PathBuf> {
+        let mut simulator = Self::new(options)?;
+        let path = simulator.path.clone();
+
+        std::thread::spawn(move || simulator.run());
+
+        Ok(path)
+    }
+
+    // Waits up to the timeout (or forever) for data to arrive.
+    fn poll(&self, timeout: Option<Duration>) -> Result<bool> {
+        loop {
+            let mut fd = libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let milliseconds = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+
+            match unsafe { libc::poll(&mut fd, 1, milliseconds) } {
+                count if count < 0 => return Err(last_error()),
+                0 => return Ok(false),
+                _ if fd.revents & libc::POLLIN != 0 => return Ok(true),
+                // Hung up because no host has the port open at the moment.
+                _ if timeout.is_some() => return Ok(false),
+                _ => std::thread::sleep(COMMAND_GAP),
+            }
+        }
+    }
+
+    // Reads everything up to the next pause.
+    fn read_command(&mut self) -> Result<Vec<u8>> {
+        let mut command = Vec::new();
+        let mut buf = [0u8; 256];
+
+        self.poll(None)?;
+
+        loop {
+            let count = self.master.read(&mut buf)?;
+            command.extend_from_slice(&buf[..count]);
+
+            if !self.poll(Some(COMMAND_GAP))? {
+                return Ok(command);
+            }
+        }
+    }
+
+    fn read_number(&mut self) -> Result<u64> {
+        let command = self.read_command()?;
+        let string = String::from_utf8_lossy(&command);
+
+        let number = match string.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => string.parse().ok(),
+        };
+
+        number.ok_or_else(|| Error::Protocol {
+            phase: "in the simulator, expected a number".to_string(),
+            expected: Vec::new(),
+            got: command,
+        })
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let fragment_size = self.options.fragment_size.unwrap_or(data.len()).max(1);
+
+        for fragment in data.chunks(fragment_size) {
+            if !self.options.delay.is_zero() {
+                std::thread::sleep(self.options.delay);
+            }
+
+            self.master.write_all(fragment)?;
+        }
+
+        Ok(())
+    }
+
+    // Markers are what the host waits for, anything else is sent as is.
+    fn send_marker(&mut self, marker: &[u8]) -> Result<()> {
+        if self.options.debug_output {
+            self.send(b"[stub] about to send a marker\r\n")?;
+        }
+
+        self.send(marker)
+    }
+
+    fn byte_at(&self, address: u64) -> u8 {
+        if let Some(byte) = self.written.get(&address) {
+            return *byte;
+        }
+
+        match &self.options.data {
+            Some(data) if !data.is_empty() => data[(address % data.len() as u64) as usize],
+            _ => address as u8,
+        }
+    }
+
+    // What follows the data of dumps.
+    fn checksum(&self, data: &[u8]) -> Vec<u8> {
+        let mut checksum = if self.crc {
+            let mut crc = Crc32::new();
+            crc.update(data);
+            crc.finish().to_le_bytes().to_vec()
+        } else {
+            vec![data.iter().fold(0, |checksum, byte| checksum ^ byte)]
+        };
+
+        if self.options.corrupt_checksum {
+            checksum[0] ^= 0xff;
+        }
+
+        checksum
+    }
+
+    fn upload_memory(&mut self) -> Result<()> {
+        let start = self.read_number()?;
+        let end = self.read_number()?;
+        step!("simulator: dumping {:#x} to {:#x}", start, end);
+
+        if !self.check_alignment(start, end) {
+            return Ok(());
+        }
+
+        let data = (start..end)
+            .map(|address| self.byte_at(address))
+            .collect::<Vec<_>>();
+        let checksum = self.checksum(&data);
+
+        self.send_marker(b"STRTUPLD")?;
+        self.send(&data)?;
+        self.send(&checksum)?;
+        self.send_marker(b"ENDUPLD")
+    }
+
+    // Sends the chunks of the range one after the other, as long as no more
+    // than the window are unconfirmed, and goes back to any chunk that the
+    // host rejects.
+    fn upload_window(&mut self) -> Result<()> {
+        let start = self.read_number()?;
+        let end = self.read_number()?.max(start);
+        let chunk_size = self.read_number()?.max(1);
+        let window = self.read_number()?.max(1);
+        let chunks = (end - start).div_ceil(chunk_size).max(1);
+        step!(
+            "simulator: dumping {:#x} to {:#x} in chunks of {:#x}, {} at a time",
+            start,
+            end,
+            chunk_size,
+            window
+        );
+
+        if !self.check_alignment(start, end) || !self.check_alignment(chunk_size, 0) {
+            return Ok(());
+        }
+
+        let mut next = 0;
+        let mut confirmed = 0;
+
+        while confirmed < chunks {
+            if next < chunks && next - confirmed < window {
+                self.upload_chunk(next, start, end, chunk_size)?;
+                next += 1;
+
+                // Answers are only waited for once the window is full.
+                if !self.poll(Some(Duration::ZERO))? {
+                    continue;
+                }
+            }
+
+            let mut answer = [0u8; 5];
+            self.master.read_exact(&mut answer)?;
+            let index = u64::from(u32::from_le_bytes(answer[1..].try_into().unwrap()));
+
+            match answer[0] {
+                b'A' => confirmed = confirmed.max(index + 1),
+                b'N' => {
+                    step!("simulator: sending chunk {} again", index);
+                    next = index;
+                }
+                b'K' => step!("simulator: servicing the watchdog"),
+                _ => {
+                    step!("simulator: dump aborted");
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn upload_chunk(&mut self, index: u64, start: u64, end: u64, chunk_size: u64) -> Result<()> {
+        let chunk_start = start + index * chunk_size;
+        let chunk_end = end.min(chunk_start + chunk_size);
+
+        let data = (chunk_start..chunk_end)
+            .map(|address| self.byte_at(address))
+            .collect::<Vec<_>>();
+        let checksum = self.checksum(&data);
+
+        self.send_marker(b"STRTUPLD")?;
+        self.send(&(index as u32).to_le_bytes())?;
+        self.send(&data)?;
+        self.send(&checksum)?;
+        self.send_marker(b"ENDUPLD")
+    }
+
+    fn boot_file(&mut self) -> Result<()> {
+        let size = self.read_number()?;
+        step!("simulator: receiving {:#x} bytes to boot", size);
+
+        self.send_marker(b"STRTUPLD")?;
+
+        // Every 256th byte (counted down from the end) is echoed back.
+        for remaining in (1..=size).rev() {
+            let mut value = [0u8; 1];
+            self.master.read_exact(&mut value)?;
+
+            if remaining.is_multiple_of(256) {
+                self.send(&value)?;
+            }
+        }
+
+        self.send_marker(b"ENDUPLD")?;
+        self.send(b"Hello from the simulated payload\r\n")
+    }
+
+    // Takes the blocks of a memory write in order, rejecting any whose
+    // checksum doesn't match.
+    fn write_memory(&mut self) -> Result<()> {
+        let address = self.read_number()?;
+        let size = self.read_number()?;
+        let block_size = self.read_number()?.max(1);
+        step!(
+            "simulator: writing {:#x} bytes to {:#x} in blocks of {:#x}",
+            size,
+            address,
+            block_size
+        );
+
+        self.send_marker(b"STRTDNLD")?;
+
+        let checksum_size = if self.crc { 4 } else { 1 };
+        let mut next = 0;
+
+        while next * block_size < size {
+            let length = (size - next * block_size).min(block_size) as usize;
+            let mut frame = vec![0u8; 4 + length + checksum_size];
+            self.master.read_exact(&mut frame)?;
+
+            let index = u64::from(u32::from_le_bytes(frame[..4].try_into().unwrap()));
+            let (data, checksum) = frame[4..].split_at(length);
+
+            let mut answer = vec![b'A'];
+            if index != next || checksum != self.checksum(data) {
+                step!("simulator: rejecting block {}", index);
+                answer[0] = b'N';
+            } else {
+                let block_start = address + next * block_size;
+                for (offset, byte) in data.iter().enumerate() {
+                    self.written.insert(block_start + offset as u64, *byte);
+                }
+                next += 1;
+            }
+
+            answer.extend_from_slice(&(index as u32).to_le_bytes());
+            self.send(&answer)?;
+        }
+
+        self.send_marker(b"ENDDNLD")
+    }
+
+    fn checksum_memory(&mut self) -> Result<()> {
+        let address = self.read_number()?;
+        let size = self.read_number()?;
+        step!(
+            "simulator: checksumming {:#x} bytes at {:#x}",
+            size,
+            address
+        );
+
+        let mut crc = Crc32::new();
+        for address in address..address + size {
+            crc.update(&[self.byte_at(address)]);
+        }
+
+        self.send_marker(b"MEMCRC")?;
+        self.send(&crc.finish().to_le_bytes())
+    }
+
+    fn exec(&mut self) -> Result<()> {
+        let address = self.read_number()?;
+        step!("simulator: jumping to {:#x}", address);
+
+        self.send_marker(b"JUMPING")?;
+        self.send(format!("Hello from the simulated payload at {:#x}\r\n", address).as_bytes())
+    }
+
+    fn set_baud(&mut self) -> Result<()> {
+        let rate = self.read_number()?;
+        step!("simulator: switching to {} baud", rate);
+
+        // The rate doesn't matter on a pseudo-terminal.
+        self.send_marker(b"BAUDSET")
+    }
+
+    fn select_checksum(&mut self) -> Result<()> {
+        let name = self.read_command()?;
+
+        match name.as_slice() {
+            b"crc32" => self.crc = true,
+            b"xor" => self.crc = false,
+            _ => {
+                step!(
+                    "simulator: unknown checksum {:?}",
+                    String::from_utf8_lossy(&name)
+                );
+                return Ok(());
+            }
+        }
+
+        step!("simulator: using {}", String::from_utf8_lossy(&name));
+        self.send_marker(b"CSUMSET")
+    }
+
+    fn select_access_width(&mut self) -> Result<()> {
+        let width = self.read_number()?;
+        if ![1, 2, 4, 8].contains(&width) {
+            step!("simulator: unknown access width {}", width);
+            return Ok(());
+        }
+
+        step!("simulator: reading {} bytes at a time", width);
+        self.access_width = width;
+
+        self.send_marker(b"WIDTHSET")
+    }
+
+    // A real device would fault on misaligned accesses, which leaves the
+    // stub silent.
+    fn check_alignment(&self, start: u64, end: u64) -> bool {
+        if start.is_multiple_of(self.access_width) && end.is_multiple_of(self.access_width) {
+            return true;
+        }
+
+        step!(
+            "simulator: {:#x} to {:#x} isn't aligned to {} bytes",
+            start,
+            end,
+            self.access_width
+        );
+
+        false
+    }
+
+    fn capabilities(&mut self) -> Result<()> {
+        if self.options.legacy {
+            step!("simulator: ignoring the capabilities query");
+            return Ok(());
+        }
+
+        let mut response = b"CAPS".to_vec();
+        response.extend_from_slice(&VERSION.to_le_bytes());
+        response.extend_from_slice(&FEATURES.to_le_bytes());
+
+        self.send(&response)
+    }
+
+    // Serves the commands of one host after another, until failing.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            let command = self.read_command()?;
+
+            match command.as_slice() {
+                b"WHOISDIS" => {
+                    // A new host starts out with the defaults.
+                    self.crc = false;
+                    self.access_width = 1;
+                    self.send(b"BOOTSTUB")?
+                }
+                b"UPLDMEM" => self.upload_memory()?,
+                b"UPLDWIN" => self.upload_window()?,
+                b"BOOTFILE" => self.boot_file()?,
+                b"WRITEMEM" => self.write_memory()?,
+                b"CRCMEM" => self.checksum_memory()?,
+                b"EXECMEM" => self.exec()?,
+                b"SETBAUD" => self.set_baud()?,
+                b"GETCAPS" => self.capabilities()?,
+                b"SETCSUM" => self.select_checksum()?,
+                b"SETWIDTH" => self.select_access_width()?,
+                // There's no device to reset or power off, the next host
+                // finds the stub again like after a real reset.
+                b"RESETDEV" => {
+                    step!("simulator: resetting");
+                    self.send_marker(b"DEVRESET")?
+                }
+                b"PWROFF" => {
+                    step!("simulator: powering off");
+                    self.send_marker(b"OFFPWR")?
+                }
+                b"PETWDOG" => {
+                    step!("simulator: servicing the watchdog");
+                    self.send_marker(b"WDOGPET")?
+                }
+                _ => step!(
+                    "simulator: ignoring {:?}",
+                    String::from_utf8_lossy(&command)
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,681 @@
+use crate::deflate::GzipEncoder;
+use crate::error::{Error, Result};
+use crate::hexdump::hexdump;
+use crate::output::{self, Part, SplitWriter};
+use crate::sha256::Sha256;
+use crate::zstd::ZstdEncoder;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Where the data of a dump goes on its way from the device, as a chain of
+// sinks that each pass it on to the next one: hashing it, showing it,
+// compressing it, and at the end writing it to a file, parts of one, stdout
+// or nowhere. The options of the dump command only pick the sinks, the
+// combinations take care of themselves.
+
+pub trait DumpSink {
+    // Takes the next piece of the dump.
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()>;
+
+    // Passes on whatever is still held back and closes the files, also after
+    // a failed dump, so that what arrived can be kept.
+    fn finish(&mut self) -> Result<Summary>;
+
+    // The files written so far, also if finishing failed.
+    fn files(&self) -> Vec<Part>;
+}
+
+// What a chain of sinks came to.
+#[derive(Clone, Default)]
+pub struct Summary {
+    // What went into it.
+    pub bytes: u64,
+    // Of the data as it came from the device.
+    pub sha256: Option<[u8; 32]>,
+    // As they are on the disk, which isn't the data if it's compressed.
+    pub files: Vec<Part>,
+}
+
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    sha256: Sha256,
+}
+
+impl FileSink {
+    pub fn create(path: &Path, force: bool) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: output::create(path, force)?,
+            size: 0,
+            sha256: Sha256::new(),
+        })
+    }
+}
+
+impl DumpSink for FileSink {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).map_err(|source| Error::File {
+            path: self.path.display().to_string(),
+            source,
+        })?;
+        self.sha256.update(data);
+        self.size += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        self.file.flush()?;
+
+        Ok(Summary {
+            bytes: self.size,
+            ..Summary::default()
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        vec![Part {
+            path: self.path.clone(),
+            size: self.size,
+            sha256: self.sha256.finish(),
+        }]
+    }
+}
+
+pub struct SplitSink {
+    writer: SplitWriter,
+    bytes: u64,
+}
+
+impl SplitSink {
+    pub fn new(path: &Path, part_size: u64, force: bool) -> Self {
+        Self {
+            writer: SplitWriter::new(path, part_size, force),
+            bytes: 0,
+        }
+    }
+}
+
+impl DumpSink for SplitSink {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.bytes += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        self.writer.finish()?;
+
+        Ok(Summary {
+            bytes: self.bytes,
+            ..Summary::default()
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        self.writer.parts().to_vec()
+    }
+}
+
+// For stdout, which has no file to read back.
+pub struct StreamSink<W> {
+    writer: W,
+    bytes: u64,
+}
+
+impl<W: Write> StreamSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, bytes: 0 }
+    }
+}
+
+impl<W: Write> DumpSink for StreamSink<W> {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.bytes += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        self.writer.flush()?;
+
+        Ok(Summary {
+            bytes: self.bytes,
+            ..Summary::default()
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        Vec::new()
+    }
+}
+
+// For replaying, which mustn't overwrite the dump from the recorded session.
+#[derive(Default)]
+pub struct Discard {
+    bytes: u64,
+}
+
+impl DumpSink for Discard {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.bytes += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        Ok(Summary {
+            bytes: self.bytes,
+            ..Summary::default()
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        Vec::new()
+    }
+}
+
+// Hashes what passes through.
+pub struct Sha256Tee {
+    sha256: Sha256,
+    next: Box<dyn DumpSink>,
+}
+
+impl Sha256Tee {
+    pub fn new(next: Box<dyn DumpSink>) -> Self {
+        Self {
+            sha256: Sha256::new(),
+            next,
+        }
+    }
+}
+
+impl DumpSink for Sha256Tee {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.sha256.update(data);
+        self.next.write_chunk(data)
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        Ok(Summary {
+            sha256: Some(self.sha256.finish()),
+            ..self.next.finish()?
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        self.next.files()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    // Only runs of a byte are compressed, see zstd.rs.
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(string: &str) -> std::result::Result<Self, String> {
+        match string {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "'{}' is not a compression, expected gzip or zstd",
+                string
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+enum Encoder {
+    Gzip(GzipEncoder),
+    Zstd(ZstdEncoder),
+}
+
+pub struct CompressSink {
+    encoder: Encoder,
+    // What the encoder came up with, before it's passed on.
+    buffer: Vec<u8>,
+    bytes: u64,
+    next: Box<dyn DumpSink>,
+}
+
+impl CompressSink {
+    pub fn new(compression: Compression, next: Box<dyn DumpSink>) -> Self {
+        Self {
+            encoder: match compression {
+                Compression::Gzip => Encoder::Gzip(GzipEncoder::new()),
+                Compression::Zstd => Encoder::Zstd(ZstdEncoder::new()),
+            },
+            buffer: Vec::new(),
+            bytes: 0,
+            next,
+        }
+    }
+
+    fn pass_on(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            self.next.write_chunk(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl DumpSink for CompressSink {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.encoder {
+            Encoder::Gzip(encoder) => encoder.compress(data, &mut self.buffer),
+            Encoder::Zstd(encoder) => encoder.compress(data, &mut self.buffer),
+        }
+        self.bytes += data.len() as u64;
+
+        self.pass_on()
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        match &mut self.encoder {
+            Encoder::Gzip(encoder) => encoder.finish(&mut self.buffer),
+            Encoder::Zstd(encoder) => encoder.finish(&mut self.buffer),
+        }
+        self.pass_on()?;
+
+        Ok(Summary {
+            bytes: self.bytes,
+            ..self.next.finish()?
+        })
+    }
+
+    fn files(&self) -> Vec<Part> {
+        self.next.files()
+    }
+}
+
+const BYTES_PER_LINE: usize = 16;
+
+// Shows what passes through like `hexdump -C`, with a line of * for lines
+// that repeat the one before.
+pub struct HexdumpSink {
+    terminal: Box<dyn Write>,
+    address: u64,
+    // The part of a line that has come in so far.
+    line: Vec<u8>,
+    previous: Option<Vec<u8>>,
+    repeating: bool,
+    next: Box<dyn DumpSink>,
+}
+
+impl HexdumpSink {
+    pub fn new(terminal: Box<dyn Write>, address: u64, next: Box<dyn DumpSink>) -> Self {
+        Self {
+            terminal,
+            address,
+            line: Vec::with_capacity(BYTES_PER_LINE),
+            previous: None,
+            repeating: false,
+            next,
+        }
+    }
+
+    fn show_line(&mut self) -> Result<()> {
+        if self.previous.as_ref() == Some(&self.line) && self.line.len() == BYTES_PER_LINE {
+            if !self.repeating {
+                writeln!(self.terminal, "*")?;
+                self.repeating = true;
+            }
+        } else {
+            for line in hexdump(&self.line, self.address) {
+                writeln!(self.terminal, "{}", line)?;
+            }
+            self.repeating = false;
+        }
+
+        self.address += self.line.len() as u64;
+        self.previous = Some(std::mem::replace(
+            &mut self.line,
+            Vec::with_capacity(BYTES_PER_LINE),
+        ));
+
+        Ok(())
+    }
+}
+
+impl DumpSink for HexdumpSink {
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            let take = rest.len().min(BYTES_PER_LINE - self.line.len());
+            self.line.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+
+            if self.line.len() == BYTES_PER_LINE {
+                self.show_line()?;
+            }
+        }
+
+        self.next.write_chunk(data)
+    }
+
+    fn finish(&mut self) -> Result<Summary> {
+        if !self.line.is_empty() {
+            self.show_line()?;
+        }
+        // Where the data ends, as after lines that were left out.
+        writeln!(self.terminal, "{:08x}", self.address)?;
+        self.terminal.flush()?;
+
+        self.next.finish()
+    }
+
+    fn files(&self) -> Vec<Part> {
+        self.next.files()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    File(PathBuf),
+    Split { path: PathBuf, part_size: u64 },
+    Stdout,
+    Discard,
+}
+
+// Which sinks a dump goes through.
+#[derive(Clone, Debug)]
+pub struct Plan {
+    pub destination: Destination,
+    pub compression: Option<Compression>,
+    // Shows a hexdump on the way, with addresses from this one.
+    pub hexdump: Option<u64>,
+    // Whether existing files may be replaced.
+    pub force: bool,
+}
+
+impl Plan {
+    // Fails early if the files can't be created later on, so that nothing
+    // has been sent to the device by then.
+    pub fn check(&self) -> Result<()> {
+        match &self.destination {
+            Destination::File(path) => output::check(path, self.force),
+            Destination::Split { path, .. } => output::check_parts(path, self.force),
+            Destination::Stdout | Destination::Discard => Ok(()),
+        }
+    }
+
+    // The names of the sinks, from the device on.
+    pub fn stages(&self) -> Vec<&'static str> {
+        let mut stages = vec!["sha256"];
+
+        if self.hexdump.is_some() {
+            stages.push("hexdump");
+        }
+        if let Some(compression) = self.compression {
+            stages.push(compression.name());
+        }
+        stages.push(match self.destination {
+            Destination::File(_) => "file",
+            Destination::Split { .. } => "split",
+            Destination::Stdout => "stdout",
+            Destination::Discard => "discard",
+        });
+
+        stages
+    }
+
+    // Puts the sinks in front of the destination. The hexdump goes to the
+    // terminal, which is stderr if stdout carries the dump.
+    pub fn compose(
+        &self,
+        destination: Box<dyn DumpSink>,
+        terminal: Box<dyn Write>,
+    ) -> Box<dyn DumpSink> {
+        let mut sink = destination;
+
+        if let Some(compression) = self.compression {
+            sink = Box::new(CompressSink::new(compression, sink));
+        }
+        if let Some(address) = self.hexdump {
+            sink = Box::new(HexdumpSink::new(terminal, address, sink));
+        }
+
+        Box::new(Sha256Tee::new(sink))
+    }
+
+    pub fn build(&self) -> Result<Pipeline> {
+        let destination: Box<dyn DumpSink> = match &self.destination {
+            Destination::File(path) => Box::new(FileSink::create(path, self.force)?),
+            Destination::Split { path, part_size } => {
+                Box::new(SplitSink::new(path, *part_size, self.force))
+            }
+            Destination::Stdout => Box::new(StreamSink::new(std::io::stdout())),
+            Destination::Discard => Box::new(Discard::default()),
+        };
+        let terminal: Box<dyn Write> = match self.destination {
+            Destination::Stdout => Box::new(std::io::stderr()),
+            _ => Box::new(std::io::stdout()),
+        };
+
+        Ok(Pipeline {
+            head: self.compose(destination, terminal),
+        })
+    }
+}
+
+// The first sink, for the dump to write into.
+pub struct Pipeline {
+    head: Box<dyn DumpSink>,
+}
+
+impl Pipeline {
+    pub fn new(head: Box<dyn DumpSink>) -> Self {
+        Self { head }
+    }
+
+    pub fn finish(&mut self) -> Result<Summary> {
+        let summary = self.head.finish()?;
+
+        Ok(Summary {
+            files: self.head.files(),
+            ..summary
+        })
+    }
+
+    pub fn files(&self) -> Vec<Part> {
+        self.head.files()
+    }
+}
+
+impl Write for Pipeline {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.head.write_chunk(data).map_err(std::io::Error::other)?;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::inflate::inflate;
+    use crate::sha256::sha256;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A Vec<u8> to check on after handing it to a sink, which takes at most
+    // `limit` bytes per write.
+    #[derive(Clone)]
+    struct Shared {
+        data: Rc<RefCell<Vec<u8>>>,
+        limit: usize,
+    }
+
+    impl Shared {
+        fn new(limit: usize) -> Self {
+            Self {
+                data: Rc::default(),
+                limit,
+            }
+        }
+
+        fn data(&self) -> Vec<u8> {
+            self.data.borrow().clone()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            let count = data.len().min(self.limit);
+            self.data.borrow_mut().extend_from_slice(&data[..count]);
+
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn dump() -> Vec<u8> {
+        let mut data = (0..1000)
+            .map(|index| (index % 251) as u8)
+            .collect::<Vec<_>>();
+        data.extend_from_slice(&[0; 100]);
+
+        data
+    }
+
+    // Hands the dump over in pieces of growing size, like the chunks and the
+    // reads within them.
+    fn feed(pipeline: &mut Pipeline, data: &[u8]) {
+        let mut rest = data;
+        let mut size = 1;
+
+        while !rest.is_empty() {
+            let take = size.min(rest.len());
+            pipeline.write_all(&rest[..take]).unwrap();
+            rest = &rest[take..];
+            size += 3;
+        }
+    }
+
+    fn plan(compression: Option<Compression>, hexdump: Option<u64>) -> Plan {
+        Plan {
+            destination: Destination::Stdout,
+            compression,
+            hexdump,
+            force: false,
+        }
+    }
+
+    #[test]
+    fn stream_takes_everything_in_order_despite_short_writes() {
+        let data = dump();
+        let output = Shared::new(5);
+        let mut pipeline = Pipeline::new(plan(None, None).compose(
+            Box::new(StreamSink::new(output.clone())),
+            Box::new(std::io::sink()),
+        ));
+
+        feed(&mut pipeline, &data);
+        let summary = pipeline.finish().unwrap();
+
+        assert_eq!(output.data(), data);
+        assert_eq!(summary.bytes, data.len() as u64);
+        assert_eq!(summary.sha256, Some(sha256(&data)));
+        assert!(summary.files.is_empty());
+    }
+
+    #[test]
+    fn compressed_data_is_hashed_before_it_is_compressed() {
+        let data = dump();
+        let output = Shared::new(7);
+        let plan = plan(Some(Compression::Gzip), None);
+        let mut pipeline = Pipeline::new(plan.compose(
+            Box::new(StreamSink::new(output.clone())),
+            Box::new(std::io::sink()),
+        ));
+
+        feed(&mut pipeline, &data);
+        let summary = pipeline.finish().unwrap();
+
+        assert_eq!(summary.bytes, data.len() as u64);
+        assert_eq!(summary.sha256, Some(sha256(&data)));
+
+        // The gzip header and trailer around the DEFLATE stream.
+        let gzip = output.data();
+        assert_eq!(gzip[..3], [0x1f, 0x8b, 8]);
+        let mut inflated = Vec::new();
+        inflate(&mut &gzip[10..gzip.len() - 8], &mut inflated).unwrap();
+        assert_eq!(inflated, data);
+        assert_eq!(
+            gzip[gzip.len() - 8..gzip.len() - 4],
+            crc32(&data).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn hexdump_doesnt_depend_on_the_pieces() {
+        let data = dump();
+        let terminal = Shared::new(usize::MAX);
+        let output = Shared::new(3);
+        let plan = plan(Some(Compression::Zstd), Some(0x1000));
+        let mut pipeline = Pipeline::new(plan.compose(
+            Box::new(StreamSink::new(output.clone())),
+            Box::new(terminal.clone()),
+        ));
+
+        assert_eq!(plan.stages(), ["sha256", "hexdump", "zstd", "stdout"]);
+
+        feed(&mut pipeline, &data);
+        pipeline.finish().unwrap();
+
+        let shown = String::from_utf8(terminal.data()).unwrap();
+        let lines = shown.lines().collect::<Vec<_>>();
+        let whole = hexdump(&data[..1000], 0x1000);
+
+        // The zeros at the end come to a line and a * for the ones that
+        // repeat it, after the line that has the last 8 bytes before them.
+        // The last line is short, and the address of the end follows.
+        assert_eq!(lines[..62], whole[..62]);
+        assert_eq!(lines[62], hexdump(&data[992..1008], 0x13e0)[0]);
+        assert_eq!(lines[63], hexdump(&[0; 16], 0x13f0)[0]);
+        assert_eq!(lines[64], "*");
+        assert_eq!(lines[65], hexdump(&[0; 12], 0x1440)[0]);
+        assert_eq!(lines[66..], ["0000144c"]);
+        assert!(!output.data().is_empty());
+    }
+
+    #[test]
+    fn discard_only_counts() {
+        let data = dump();
+        let mut pipeline = Pipeline::new(
+            plan(None, None).compose(Box::new(Discard::default()), Box::new(std::io::sink())),
+        );
+
+        feed(&mut pipeline, &data);
+        let summary = pipeline.finish().unwrap();
+
+        assert_eq!(summary.bytes, data.len() as u64);
+        assert_eq!(summary.sha256, Some(sha256(&data)));
+    }
+}
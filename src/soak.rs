@@ -0,0 +1,317 @@
+use crate::json::{Object, ToJson};
+use crate::transport::Statistics;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Duration;
+
+// Reads the same range over and over and compares every pass with the first
+// one, to find out whether a link or a region of memory gives the same answer
+// every time. Passes are compared byte for byte, or by the CRC-32 of every
+// block, which the stub computes without sending the data.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Data,
+    Crc { block_size: u64 },
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Data => "data",
+            Method::Crc { .. } => "crc",
+        }
+    }
+
+    // The bytes of a pass that make up one value, and the addresses that
+    // they stand for.
+    fn unit(self) -> (usize, u64) {
+        match self {
+            Method::Data => (1, 1),
+            Method::Crc { block_size } => (4, block_size),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // The first pass, which the others are compared with.
+    Reference,
+    Match,
+    Mismatch {
+        // The bytes that differ, or those of the blocks that do.
+        differing: u64,
+        first_address: u64,
+    },
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Reference => "reference",
+            Outcome::Match => "match",
+            Outcome::Mismatch { .. } => "mismatch",
+            Outcome::Failed(_) => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pass {
+    // Counting from 1.
+    pub number: u32,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+    pub statistics: Statistics,
+}
+
+impl Pass {
+    pub fn to_object(&self) -> Object {
+        let (differing, first_address) = match self.outcome {
+            Outcome::Mismatch {
+                differing,
+                first_address,
+            } => (Some(differing), Some(format!("{:#x}", first_address))),
+            _ => (None, None),
+        };
+        let error = match &self.outcome {
+            Outcome::Failed(message) => Some(message.as_str()),
+            _ => None,
+        };
+
+        Object::new()
+            .field("pass", self.number)
+            .field("outcome", self.outcome.name())
+            .field("elapsed_ms", self.elapsed.as_millis() as u64)
+            .field("differing_bytes", differing)
+            .field("first_difference", first_address)
+            .field("error", error)
+            .field("retries", self.statistics.retries)
+            .field("timeouts", self.statistics.timeouts)
+            .field("checksum_failures", self.statistics.checksum_failures)
+            .field("reconnects", self.statistics.reconnects)
+    }
+}
+
+// The fastest, slowest and average pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub fastest: Duration,
+    pub slowest: Duration,
+    pub average: Duration,
+}
+
+pub struct Soak {
+    pub start: u64,
+    pub end: u64,
+    pub method: Method,
+    pub passes: Vec<Pass>,
+    // Whether Ctrl-C ended the test before all passes were made.
+    pub interrupted: bool,
+    reference: Option<Vec<u8>>,
+    // What the current pass has read so far, which the dump writes into.
+    sample: Vec<u8>,
+    // The values that differed from the first pass in any pass, a bit each.
+    differing: Vec<u8>,
+}
+
+impl Soak {
+    pub fn new(start: u64, end: u64, method: Method) -> Self {
+        let mut soak = Self {
+            start,
+            end,
+            method,
+            passes: Vec::new(),
+            interrupted: false,
+            reference: None,
+            sample: Vec::new(),
+            differing: Vec::new(),
+        };
+        soak.differing = vec![0; soak.values().div_ceil(8)];
+
+        soak
+    }
+
+    // How many values a pass has.
+    fn values(&self) -> usize {
+        let (_, span) = self.method.unit();
+
+        (self.end - self.start).div_ceil(span) as usize
+    }
+
+    // The addresses of a value, the last one may be shorter.
+    fn value_range(&self, index: usize) -> Range<u64> {
+        let (_, span) = self.method.unit();
+        let start = self.start + index as u64 * span;
+
+        start..self.end.min(start + span)
+    }
+
+    fn is_differing(&self, index: usize) -> bool {
+        self.differing[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn push_crc(&mut self, crc32: u32) {
+        self.sample.extend_from_slice(&crc32.to_le_bytes());
+    }
+
+    // Compares what the pass read with the first pass, or notes down why it
+    // failed, and starts the next one.
+    pub fn finish_pass(
+        &mut self,
+        elapsed: Duration,
+        statistics: Statistics,
+        error: Option<String>,
+    ) -> &Pass {
+        let sample = std::mem::take(&mut self.sample);
+        let (unit, _) = self.method.unit();
+        let expected = self.values() * unit;
+
+        let outcome = match (error, &self.reference) {
+            (Some(message), _) => Outcome::Failed(message),
+            (None, _) if sample.len() != expected => Outcome::Failed(format!(
+                "the pass read {} bytes rather than {}",
+                sample.len(),
+                expected
+            )),
+            (None, None) => {
+                self.reference = Some(sample);
+                Outcome::Reference
+            }
+            (None, Some(reference)) => {
+                let indices = reference
+                    .chunks(unit)
+                    .zip(sample.chunks(unit))
+                    .enumerate()
+                    .filter(|(_, (expected, got))| expected != got)
+                    .map(|(index, _)| index)
+                    .collect::<Vec<_>>();
+
+                for &index in &indices {
+                    self.differing[index / 8] |= 1 << (index % 8);
+                }
+
+                match indices.first() {
+                    None => Outcome::Match,
+                    Some(&first) => Outcome::Mismatch {
+                        differing: indices
+                            .iter()
+                            .map(|&index| {
+                                let range = self.value_range(index);
+                                range.end - range.start
+                            })
+                            .sum(),
+                        first_address: self.value_range(first).start,
+                    },
+                }
+            }
+        };
+
+        self.passes.push(Pass {
+            number: self.passes.len() as u32 + 1,
+            outcome,
+            elapsed,
+            statistics,
+        });
+
+        self.passes.last().unwrap()
+    }
+
+    // Drops what a pass that was interrupted has read.
+    pub fn discard_pass(&mut self) {
+        self.sample.clear();
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.passes
+            .iter()
+            .filter(|pass| pass.outcome.name() == name)
+            .count()
+    }
+
+    // Over the passes that went through.
+    pub fn timing(&self) -> Option<Timing> {
+        let durations = self
+            .passes
+            .iter()
+            .filter(|pass| !matches!(pass.outcome, Outcome::Failed(_)))
+            .map(|pass| pass.elapsed)
+            .collect::<Vec<_>>();
+
+        Some(Timing {
+            fastest: *durations.iter().min()?,
+            slowest: *durations.iter().max()?,
+            average: durations.iter().sum::<Duration>() / durations.len() as u32,
+        })
+    }
+
+    // The addresses that differed from the first pass in any pass, as runs.
+    pub fn differing_regions(&self) -> Vec<Range<u64>> {
+        let mut regions: Vec<Range<u64>> = Vec::new();
+
+        for index in (0..self.values()).filter(|&index| self.is_differing(index)) {
+            let range = self.value_range(index);
+
+            match regions.last_mut() {
+                Some(region) if region.end == range.start => region.end = range.end,
+                _ => regions.push(range),
+            }
+        }
+
+        regions
+    }
+}
+
+impl Write for Soak {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.sample.extend_from_slice(data);
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ToJson for Soak {
+    fn to_json(&self) -> String {
+        let block_size = match self.method {
+            Method::Data => None,
+            Method::Crc { block_size } => Some(block_size),
+        };
+        let timing = self.timing().map(|timing| {
+            Object::new()
+                .field("fastest_ms", timing.fastest.as_millis() as u64)
+                .field("slowest_ms", timing.slowest.as_millis() as u64)
+                .field("average_ms", timing.average.as_millis() as u64)
+        });
+        let regions = self
+            .differing_regions()
+            .iter()
+            .map(|region| {
+                Object::new()
+                    .field("start", format!("{:#x}", region.start))
+                    .field("end", format!("{:#x}", region.end))
+            })
+            .collect::<Vec<_>>();
+
+        Object::new()
+            .field("start", format!("{:#x}", self.start))
+            .field("end", format!("{:#x}", self.end))
+            .field("method", self.method.name())
+            .field("block_size", block_size)
+            .field(
+                "passes",
+                self.passes.iter().map(Pass::to_object).collect::<Vec<_>>(),
+            )
+            .field("matched", self.count("match"))
+            .field("mismatched", self.count("mismatch"))
+            .field("failed", self.count("failed"))
+            .field("interrupted", self.interrupted)
+            .field("timing", timing)
+            .field("differing_regions", regions)
+            .to_json()
+    }
+}
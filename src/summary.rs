@@ -0,0 +1,124 @@
+use crate::events;
+use crate::format::{human_bytes, human_duration, human_rate};
+use crate::json::Object;
+use crate::transport::Statistics;
+use crate::{status, warning};
+use std::time::Duration;
+
+// More of any of these in one session and the link is likely to blame, not
+// the device. Reconnects only happen after the device dropped off.
+const POOR_RETRIES: u64 = 5;
+const POOR_TIMEOUTS: u64 = 3;
+const POOR_STALLS: u64 = 3;
+const POOR_CHECKSUM_FAILURES: u64 = 2;
+const POOR_RECONNECTS: u64 = 1;
+
+// What about the session points at a flaky cable or hub, if anything.
+fn link_problems(statistics: &Statistics) -> Vec<String> {
+    [
+        (statistics.retries, POOR_RETRIES, "retransmissions"),
+        (statistics.timeouts, POOR_TIMEOUTS, "timeouts"),
+        (statistics.stalls, POOR_STALLS, "stalled endpoints"),
+        (
+            statistics.checksum_failures,
+            POOR_CHECKSUM_FAILURES,
+            "checksum failures",
+        ),
+        (statistics.reconnects, POOR_RECONNECTS, "reconnects"),
+    ]
+    .iter()
+    .filter(|(count, threshold, _)| count >= threshold)
+    .map(|(count, _, what)| format!("{} {}", count, what))
+    .collect()
+}
+
+// Sums up a finished (or failed) transfer in one line, to compare cables,
+// hubs and stub versions with. `checksum_ok` is None if nothing was checked,
+// `algorithm` is what the data was checked with.
+pub fn report(
+    operation: &str,
+    statistics: &Statistics,
+    elapsed: Duration,
+    checksum_ok: Option<bool>,
+    algorithm: Option<&str>,
+) {
+    let bytes = statistics.bytes_read + statistics.bytes_written;
+    let seconds = elapsed.as_secs_f64();
+    let throughput = if seconds > 0.0 {
+        bytes as f64 / seconds
+    } else {
+        0.0
+    };
+    let checksum = match checksum_ok {
+        Some(true) => "ok",
+        Some(false) => "mismatch",
+        None => "not checked",
+    };
+    let checked_with = match (checksum_ok, algorithm) {
+        (Some(_), Some(algorithm)) => format!(" ({})", algorithm),
+        _ => String::new(),
+    };
+
+    let paced = if statistics.paced.is_zero() {
+        String::new()
+    } else {
+        format!(", {} of it paced", human_duration(statistics.paced))
+    };
+
+    // Only mentioned when they happened, they usually don't.
+    let mut trouble = String::new();
+    for (count, what) in [
+        (statistics.stalls, "stalls"),
+        (statistics.checksum_failures, "checksum failures"),
+        (statistics.reconnects, "reconnects"),
+    ] {
+        if count > 0 {
+            trouble += &format!(", {} {}", count, what);
+        }
+    }
+
+    status!(
+        "Summary of the {}: {} in {} ({}{}), {} retries, {} timeouts{}, checksum {}{}",
+        operation,
+        human_bytes(bytes),
+        human_duration(elapsed),
+        human_rate(bytes, elapsed),
+        paced,
+        statistics.retries,
+        statistics.timeouts,
+        trouble,
+        checksum,
+        checked_with
+    );
+
+    let problems = link_problems(statistics);
+
+    events::emit(
+        "summary",
+        Object::new()
+            .field("operation", operation)
+            .field("bytes_read", statistics.bytes_read)
+            .field("bytes_written", statistics.bytes_written)
+            .field("elapsed_ms", elapsed.as_millis() as u64)
+            .field("paced_ms", statistics.paced.as_millis() as u64)
+            .field("bytes_per_second", throughput as u64)
+            .field("retries", statistics.retries)
+            .field("timeouts", statistics.timeouts)
+            .field("stalls", statistics.stalls)
+            .field("checksum_failures", statistics.checksum_failures)
+            .field("reconnects", statistics.reconnects)
+            .field(
+                "link_quality",
+                if problems.is_empty() { "good" } else { "poor" },
+            )
+            .field("checksum", checksum)
+            .field("checksum_algorithm", algorithm),
+    );
+
+    if !problems.is_empty() {
+        warning!(
+            "Link quality poor: {}, check the cable and hub",
+            problems.join(", ")
+        );
+    }
+}
@@ -0,0 +1,117 @@
+// Expands `{name}` placeholders in file names, e.g. `dump_{start:#x}.bin`.
+// Numbers take an optional format after a colon, made up of `#` for a prefix,
+// a zero-padded width and `x` or `X` for hexadecimal. Braces are escaped by
+// doubling them.
+
+pub enum Value {
+    Number(u64),
+    Text(String),
+}
+
+fn format_number(value: u64, spec: &str) -> Option<String> {
+    let (prefix, spec) = match spec.strip_prefix('#') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+
+    let (spec, radix) = match spec.char_indices().last() {
+        Some((index, 'x')) => (&spec[..index], 'x'),
+        Some((index, 'X')) => (&spec[..index], 'X'),
+        Some((index, 'd')) => (&spec[..index], 'd'),
+        _ => (spec, 'd'),
+    };
+
+    // Wider than any number gets, but not wide enough to run out of memory.
+    let width = match spec {
+        "" => 0,
+        spec if spec.starts_with('0') => spec.parse().ok().filter(|&width| width <= 64)?,
+        _ => return None,
+    };
+
+    Some(match (radix, prefix) {
+        ('x', true) => format!("0x{:0width$x}", value, width = width),
+        ('x', false) => format!("{:0width$x}", value, width = width),
+        ('X', true) => format!("0x{:0width$X}", value, width = width),
+        ('X', false) => format!("{:0width$X}", value, width = width),
+        (_, false) => format!("{:0width$}", value, width = width),
+        (_, true) => return None,
+    })
+}
+
+fn valid_names(values: &[(&str, Value)]) -> String {
+    values
+        .iter()
+        .map(|(name, _)| format!("{{{}}}", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn expand(template: &str, values: &[(&str, Value)]) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(index) = rest.find(['{', '}']) {
+        result.push_str(&rest[..index]);
+
+        let escaped = &rest[index..index + 1];
+        if rest[index + 1..].starts_with(escaped) {
+            result.push_str(escaped);
+            rest = &rest[index + 2..];
+            continue;
+        }
+
+        if escaped == "}" {
+            return Err(format!(
+                "'{}' contains an unmatched '}}', use '}}}}' for a literal one",
+                template
+            ));
+        }
+
+        let end = match rest[index..].find('}') {
+            Some(end) => index + end,
+            None => {
+                return Err(format!(
+                    "'{}' contains an unmatched '{{', use '{{{{' for a literal one",
+                    template
+                ))
+            }
+        };
+
+        let placeholder = &rest[index + 1..end];
+        let (name, spec) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+
+        let value = match values.iter().find(|(candidate, _)| *candidate == name) {
+            Some((_, value)) => value,
+            None => {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in '{}', valid ones are {}",
+                    name,
+                    template,
+                    valid_names(values)
+                ))
+            }
+        };
+
+        let expanded = match value {
+            Value::Number(number) => format_number(*number, spec),
+            Value::Text(text) if spec.is_empty() => Some(text.clone()),
+            Value::Text(_) => None,
+        };
+
+        match expanded {
+            Some(expanded) => result.push_str(&expanded),
+            None => {
+                return Err(format!(
+                    "Invalid format '{}' for {{{}}} in '{}'",
+                    spec, name, template
+                ))
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+// How long to wait for the device in the different phases of a session.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    // Waiting for the device to answer the initial handshake.
+    pub handshake: Duration,
+    // Waiting for the response to a command.
+    pub response: Duration,
+    // Waiting for the next piece of data during large transfers.
+    pub transfer: Duration,
+}
+
+impl Timeouts {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            handshake: timeout,
+            response: timeout,
+            transfer: timeout,
+        }
+    }
+}
+
+// Anything below this is more likely to be a mistake (e.g. milliseconds given
+// as seconds) than something a device could actually keep up with.
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub fn parse_timeout(string: &str) -> Result<Duration, String> {
+    let seconds = string
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or_else(|| format!("'{}' is not a number of seconds", string))?;
+
+    let timeout = Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("'{}' is not a valid timeout", string))?;
+
+    if timeout < MIN_TIMEOUT {
+        return Err(format!(
+            "A timeout of {} seconds is too short, the minimum is {} seconds",
+            string,
+            MIN_TIMEOUT.as_secs_f64()
+        ));
+    }
+
+    Ok(timeout)
+}
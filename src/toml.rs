@@ -0,0 +1,331 @@
+use std::fmt;
+
+// A parser for the subset of TOML that configuration files need: tables,
+// key/value pairs with strings, integers, floats, booleans and arrays of
+// those. Entries keep their line numbers so that errors can point at them.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(string) => write!(f, "{:?}", string),
+            Value::Integer(integer) => write!(f, "{}", integer),
+            Value::Float(float) => write!(f, "{:?}", float),
+            Value::Boolean(boolean) => write!(f, "{}", boolean),
+            Value::Array(values) => {
+                let values = values.iter().map(Value::to_string).collect::<Vec<_>>();
+                write!(f, "[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    // The dotted name of the table the entry is in, empty for the top level.
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+impl Entry {
+    // The full dotted name of the key, for use in messages.
+    pub fn name(&self) -> String {
+        if self.table.is_empty() {
+            self.key.clone()
+        } else {
+            format!("{}.{}", self.table, self.key)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t')) {
+            self.chars.next();
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+
+        match self.chars.next() {
+            None | Some('#') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        self.skip_whitespace();
+
+        if self.chars.peek() == Some(&'"') {
+            self.chars.next();
+            return self.string();
+        }
+
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                break;
+            }
+
+            key.push(c);
+            self.chars.next();
+        }
+
+        if key.is_empty() {
+            return Err(self.error("expected a key"));
+        }
+
+        Ok(key)
+    }
+
+    fn dotted_key(&mut self) -> Result<String, ParseError> {
+        let mut parts = vec![self.key()?];
+
+        loop {
+            self.skip_whitespace();
+
+            if self.chars.peek() != Some(&'.') {
+                return Ok(parts.join("."));
+            }
+
+            self.chars.next();
+            parts.push(self.key()?);
+        }
+    }
+
+    // Called after the opening quote.
+    fn string(&mut self) -> Result<String, ParseError> {
+        let mut string = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some(c) => return Err(self.error(format!("unknown escape '\\{}'", c))),
+                    None => return Err(self.error("unterminated string")),
+                },
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    // Called after the opening quote.
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        let mut string = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some('\'') => return Ok(string),
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+
+        match self.chars.peek() {
+            Some('"') => {
+                self.chars.next();
+                Ok(Value::String(self.string()?))
+            }
+            Some('\'') => {
+                self.chars.next();
+                Ok(Value::String(self.literal_string()?))
+            }
+            Some('[') => {
+                self.chars.next();
+                self.array()
+            }
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        let mut values = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.chars.peek() == Some(&']') {
+                self.chars.next();
+                return Ok(Value::Array(values));
+            }
+
+            values.push(self.value()?);
+            self.skip_whitespace();
+
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, ParseError> {
+        let mut token = String::new();
+
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || c == ',' || c == ']' || c == '#' {
+                break;
+            }
+
+            token.push(c);
+            self.chars.next();
+        }
+
+        match token.as_str() {
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            _ => {}
+        }
+
+        let digits = token.replace('_', "");
+        let (negative, unsigned) = match digits.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+
+        let integer = if let Some(hex) = unsigned.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(octal) = unsigned.strip_prefix("0o") {
+            i64::from_str_radix(octal, 8).ok()
+        } else if let Some(binary) = unsigned.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()
+        } else {
+            unsigned.parse::<i64>().ok()
+        };
+
+        if let Some(integer) = integer {
+            return Ok(Value::Integer(if negative { -integer } else { integer }));
+        }
+
+        match digits.parse::<f64>() {
+            Ok(float) if digits.chars().any(|c| c.is_ascii_digit()) => Ok(Value::Float(float)),
+            _ => Err(self.error(format!("invalid value '{}'", token))),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut table = String::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let mut parser = Parser {
+            chars: line.chars().peekable(),
+            line: index + 1,
+        };
+
+        parser.skip_whitespace();
+
+        match parser.chars.peek() {
+            None | Some('#') => continue,
+            Some('[') => {
+                parser.chars.next();
+                table = parser.dotted_key()?;
+                parser.skip_whitespace();
+
+                if parser.chars.next() != Some(']') {
+                    return Err(parser.error("expected ']' after the table name"));
+                }
+
+                parser.expect_end()?;
+            }
+            Some(_) => {
+                let key = parser.dotted_key()?;
+                parser.skip_whitespace();
+
+                if parser.chars.next() != Some('=') {
+                    return Err(parser.error(format!("expected '=' after '{}'", key)));
+                }
+
+                let value = parser.value()?;
+                parser.expect_end()?;
+
+                // Dotted keys are a shorthand for keys in a nested table.
+                let (entry_table, key) = match key.rsplit_once('.') {
+                    Some((prefix, key)) if table.is_empty() => (prefix.to_string(), key),
+                    Some((prefix, key)) => (format!("{}.{}", table, prefix), key),
+                    None => (table.clone(), key.as_str()),
+                };
+
+                if let Some(previous) = entries
+                    .iter()
+                    .find(|entry| entry.table == entry_table && entry.key == key)
+                {
+                    return Err(parser.error(format!(
+                        "'{}' is already defined on line {}",
+                        previous.name(),
+                        previous.line
+                    )));
+                }
+
+                entries.push(Entry {
+                    table: entry_table,
+                    key: key.to_string(),
+                    value,
+                    line: index + 1,
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
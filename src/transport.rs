@@ -0,0 +1,341 @@
+use crate::error::{Error, Result};
+use crate::log::{self, Level};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+mod count;
+mod mock;
+mod pace;
+mod record;
+mod trace;
+#[cfg(feature = "usb")]
+mod usb;
+
+pub use count::{CountingTransport, Statistics};
+pub use mock::MockTransport;
+pub use pace::{Pacing, PacingTransport};
+pub use record::{record_result, LoggedTransfer, Recording, RecordingTransport, Transfer};
+pub use trace::TracingTransport;
+#[cfg(feature = "usb")]
+pub use usb::{BulkDevice, UsbTransport};
+
+// Draining gives up after this long, for devices that don't stop sending.
+const MAX_DRAIN_TIME: Duration = Duration::from_secs(2);
+
+// Protocol code only talks to the device through this trait, so it can be run
+// against serial ports, USB, TCP or a scripted mock alike. Writing and reading
+// whole buffers comes with `Read` and `Write`.
+pub trait Transport: Read + Write {
+    fn timeout(&self) -> Option<Duration>;
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()>;
+
+    // Reads with a timeout that differs from the configured one, restoring it
+    // afterwards.
+    fn read_with_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let previous = self.timeout();
+
+        self.set_timeout(Some(timeout))?;
+        let result = self.read(buf);
+        self.set_timeout(previous)?;
+
+        Ok(result?)
+    }
+
+    // Reads until the buffer is full, with the timeout applying to the whole
+    // read rather than to every chunk. Returns how much was read, which is
+    // only less than requested if the time ran out after some data arrived.
+    fn read_full(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let previous = self.timeout();
+        let deadline = Instant::now() + timeout;
+        let mut count = 0;
+
+        let result = loop {
+            if count == buf.len() {
+                break Ok(count);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break match count {
+                    0 => Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+                    count => Ok(count),
+                };
+            }
+
+            // Some backends treat a zero timeout as none at all.
+            if let Err(err) = self.set_timeout(Some(remaining.max(Duration::from_millis(1)))) {
+                break Err(err);
+            }
+
+            match self.read(&mut buf[count..]) {
+                Ok(0) => break Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(read) => count += read,
+                Err(err) if err.kind() == ErrorKind::TimedOut && count > 0 => break Ok(count),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => break Err(err.into()),
+            }
+        };
+
+        self.set_timeout(previous)?;
+
+        result
+    }
+
+    // Reads and drops whatever the device still sends until nothing arrived
+    // for `idle`, so that it doesn't confuse whoever talks to the device
+    // next. A device that went away has nothing left to send. Returns how
+    // much was dropped.
+    fn drain(&mut self, idle: Duration) -> Result<usize> {
+        let started = Instant::now();
+        let mut buf = [0u8; 512];
+        let mut drained = 0;
+
+        loop {
+            if started.elapsed() >= MAX_DRAIN_TIME {
+                crate::step!(
+                    "the device is still sending after {:.1} s, leaving it be",
+                    MAX_DRAIN_TIME.as_secs_f64()
+                );
+                break;
+            }
+
+            match self.read_with_timeout(&mut buf, idle) {
+                Ok(0) => break,
+                Ok(count) => {
+                    drained += count;
+
+                    if log::enabled(Level::Transfers) {
+                        log::write(format_args!("dropping {} bytes left over", count));
+                    }
+                }
+                Err(Error::Io(err)) if err.kind() == ErrorKind::TimedOut => break,
+                Err(err) if err.is_disconnect() => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(drained)
+    }
+
+    // Tells the transport which part of the protocol the following transfers
+    // belong to, for transports that keep track of the traffic.
+    fn set_phase(&mut self, _phase: &str) {}
+
+    fn set_baud(&mut self, _baud: u32) -> Result<()> {
+        Err(Error::Unsupported(
+            "Changing the baud rate is only supported on serial connections".to_string(),
+        ))
+    }
+
+    // What went through the transport so far, for transports that keep count.
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+
+    // Lets transports that keep count know that a transfer had to be repeated.
+    fn count_retry(&mut self) {}
+
+    // Lets transports that keep count know that data arrived corrupted.
+    fn count_checksum_failure(&mut self) {}
+
+    // Waits for a device that dropped off the bus to come back.
+    fn reconnect(&mut self, _timeout: Duration) -> Result<()> {
+        Err(Error::Unsupported(
+            "Reconnecting is not supported on this connection".to_string(),
+        ))
+    }
+
+    // Where the device is attached, e.g. the path of a serial port, for
+    // messages about it.
+    fn location(&self) -> Option<String> {
+        None
+    }
+
+    // The largest packet the device takes in one go, for transports that
+    // know it, e.g. the max packet size of a USB endpoint.
+    fn max_packet_size(&self) -> Option<usize> {
+        None
+    }
+
+    // Writes `buf` as a whole packet of `packet_size` bytes.
+    fn write_padded(&mut self, buf: &[u8], packet_size: usize) -> Result<()> {
+        self.write_all(&pad_packet(buf, packet_size)?)?;
+
+        Ok(())
+    }
+}
+
+// Pads `data` with zeros to a whole packet, for protocols whose packets have
+// a fixed size. Data that doesn't fit is refused rather than cut off.
+pub fn pad_packet(data: &[u8], packet_size: usize) -> Result<Vec<u8>> {
+    if data.len() > packet_size {
+        return Err(Error::InvalidArgument(format!(
+            "{} bytes don't fit into a packet of {} bytes",
+            data.len(),
+            packet_size
+        )));
+    }
+
+    let mut packet = data.to_vec();
+    packet.resize(packet_size, 0);
+
+    Ok(packet)
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+    address: String,
+}
+
+impl TcpTransport {
+    pub fn connect(address: &str) -> Result<Self> {
+        let stream = match TcpStream::connect(address) {
+            Ok(stream) => stream,
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                return Err(Error::DeviceNotFound(format!(
+                    "Connection to {} was refused, is the remote end listening?",
+                    address
+                )))
+            }
+            Err(err) => {
+                return Err(Error::DeviceNotFound(format!(
+                    "Failed to connect to {}: {}",
+                    address, err
+                )))
+            }
+        };
+
+        // The protocol consists of lots of tiny writes, don't let them pile up.
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            address: address.to_string(),
+        })
+    }
+
+    fn map_error(&self, err: std::io::Error) -> std::io::Error {
+        match err.kind() {
+            // Socket timeouts show up as WouldBlock on some platforms.
+            ErrorKind::WouldBlock => ErrorKind::TimedOut.into(),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("connection to {} was closed: {}", self.address, err),
+                )
+            }
+            _ => err,
+        }
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(std::io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!(
+                    "connection to {} was closed by the remote end",
+                    self.address
+                ),
+            )),
+            Ok(count) => Ok(count),
+            Err(err) => Err(self.map_error(err)),
+        }
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf).map_err(|err| self.map_error(err))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush().map_err(|err| self.map_error(err))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.stream.read_timeout().ok().flatten()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn packets_are_padded_with_zeros() {
+        let mut mock = MockTransport::new();
+        mock.expect_write(&[0; 8])
+            .expect_write(b"exactly8")
+            .expect_write(b"odd\0\0\0\0\0");
+
+        mock.write_padded(&[], 8).unwrap();
+        mock.write_padded(b"exactly8", 8).unwrap();
+        mock.write_padded(b"odd", 8).unwrap();
+
+        assert!(mock.is_finished());
+    }
+
+    #[test]
+    fn packets_that_are_too_large_are_refused() {
+        let mut mock = MockTransport::new();
+
+        // Nothing is written, the mock would refuse it otherwise.
+        match mock.write_padded(b"one over", 7) {
+            Err(Error::InvalidArgument(message)) => {
+                assert_eq!(message, "8 bytes don't fit into a packet of 7 bytes")
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(mock.write_padded(&[0], 0).is_err());
+    }
+
+    #[test]
+    fn drain_stops_once_the_device_is_idle() {
+        let mut mock = MockTransport::new();
+        mock.set_timeout(Some(Duration::from_secs(1))).unwrap();
+        mock.respond(b"left")
+            .respond_after(IDLE / 2, b"over")
+            .respond_after(IDLE * 3, b"next");
+
+        assert_eq!(mock.drain(IDLE).unwrap(), 8);
+        assert_eq!(mock.remaining_steps(), 1);
+        assert_eq!(mock.timeout(), Some(Duration::from_secs(1)));
+
+        // The response that came later is still there.
+        let mut buf = [0u8; 4];
+        mock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"next");
+
+        assert_eq!(mock.drain(IDLE).unwrap(), 0);
+    }
+
+    #[test]
+    fn drain_gives_up_on_a_device_that_keeps_sending() {
+        let mut mock = MockTransport::new();
+        for _ in 0..MAX_DRAIN_TIME.as_millis() / 50 + 20 {
+            mock.respond_after(IDLE / 2, b"x");
+        }
+
+        let started = Instant::now();
+        let drained = mock.drain(IDLE).unwrap();
+
+        assert!(started.elapsed() >= MAX_DRAIN_TIME);
+        assert!(started.elapsed() < MAX_DRAIN_TIME + IDLE * 2);
+        assert!(drained > 0);
+        assert!(!mock.is_finished());
+    }
+}
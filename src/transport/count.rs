@@ -0,0 +1,138 @@
+use super::Transport;
+use crate::error::Result;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+// Totals of the traffic through a transport, for summing up transfers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Statistics {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub timeouts: u64,
+    // Transfers that the protocol had to repeat.
+    pub retries: u64,
+    // Of those, the ones whose data arrived corrupted.
+    pub checksum_failures: u64,
+    // Stalled USB endpoints whose halt was cleared to go on.
+    pub stalls: u64,
+    // Times the device dropped off and came back.
+    pub reconnects: u64,
+    // Time that writes were held back by the pacing.
+    pub paced: Duration,
+}
+
+impl Statistics {
+    // What happened after the earlier snapshot.
+    pub fn since(&self, earlier: &Statistics) -> Statistics {
+        Statistics {
+            bytes_read: self.bytes_read - earlier.bytes_read,
+            bytes_written: self.bytes_written - earlier.bytes_written,
+            timeouts: self.timeouts - earlier.timeouts,
+            retries: self.retries - earlier.retries,
+            checksum_failures: self.checksum_failures - earlier.checksum_failures,
+            stalls: self.stalls.saturating_sub(earlier.stalls),
+            reconnects: self.reconnects - earlier.reconnects,
+            paced: self.paced.saturating_sub(earlier.paced),
+        }
+    }
+}
+
+// Wraps another transport and keeps count of everything going through it.
+pub struct CountingTransport {
+    inner: Box<dyn Transport>,
+    statistics: Statistics,
+}
+
+impl CountingTransport {
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self {
+            inner,
+            statistics: Statistics::default(),
+        }
+    }
+}
+
+impl Read for CountingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(count) => {
+                self.statistics.bytes_read += count as u64;
+
+                Ok(count)
+            }
+            Err(err) => {
+                if err.kind() == ErrorKind::TimedOut {
+                    self.statistics.timeouts += 1;
+                }
+
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Write for CountingTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.statistics.bytes_written += count as u64;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for CountingTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.inner.set_baud(baud)
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.reconnect(timeout)?;
+        self.statistics.reconnects += 1;
+
+        Ok(())
+    }
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn set_phase(&mut self, phase: &str) {
+        self.inner.set_phase(phase);
+    }
+
+    fn statistics(&self) -> Statistics {
+        // Pacing and clearing stalls happen further down, where the device
+        // is opened.
+        let inner = self.inner.statistics();
+
+        Statistics {
+            paced: inner.paced,
+            stalls: inner.stalls,
+            ..self.statistics
+        }
+    }
+
+    fn count_retry(&mut self) {
+        self.statistics.retries += 1;
+    }
+
+    fn count_checksum_failure(&mut self) {
+        self.statistics.checksum_failures += 1;
+    }
+}
@@ -0,0 +1,217 @@
+use super::Transport;
+use crate::error::Result;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Debug)]
+enum Step {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+    // A response that only arrives after a while.
+    Delayed(Duration, Vec<u8>),
+    Timeout,
+}
+
+#[derive(Default)]
+struct Script {
+    steps: VecDeque<Step>,
+    timeout: Option<Duration>,
+    baud: Option<u32>,
+    max_packet_size: Option<usize>,
+}
+
+// A transport that plays back a scripted conversation. Writes have to match
+// the expected data exactly, and every scripted response is handed out by a
+// single read at most, so fragmented responses can be scripted as several
+// consecutive responses.
+//
+// Clones share the same script, which allows keeping a handle around to check
+// the outcome after handing the transport over to a session.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    script: Rc<RefCell<Script>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn expect_write(&self, data: &[u8]) -> &Self {
+        self.push(Step::Write(data.to_vec()))
+    }
+
+    pub fn respond(&self, data: &[u8]) -> &Self {
+        self.push(Step::Read(data.to_vec()))
+    }
+
+    pub fn respond_fragmented(&self, data: &[u8], fragment_size: usize) -> &Self {
+        for fragment in data.chunks(fragment_size.max(1)) {
+            self.respond(fragment);
+        }
+
+        self
+    }
+
+    // Reads wait for the response, or time out if it takes longer than the
+    // timeout, in which case the response is that much closer.
+    pub fn respond_after(&self, delay: Duration, data: &[u8]) -> &Self {
+        self.push(Step::Delayed(delay, data.to_vec()))
+    }
+
+    pub fn time_out(&self) -> &Self {
+        self.push(Step::Timeout)
+    }
+
+    fn push(&self, step: Step) -> &Self {
+        self.script.borrow_mut().steps.push_back(step);
+
+        self
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.script.borrow().steps.is_empty()
+    }
+
+    pub fn remaining_steps(&self) -> usize {
+        self.script.borrow().steps.len()
+    }
+
+    pub fn baud(&self) -> Option<u32> {
+        self.script.borrow().baud
+    }
+
+    // Acts like a USB device whose endpoint takes packets of `size` bytes.
+    pub fn set_max_packet_size(&self, size: usize) -> &Self {
+        self.script.borrow_mut().max_packet_size = Some(size);
+
+        self
+    }
+}
+
+fn script_error(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut script = self.script.borrow_mut();
+        let timeout = script.timeout;
+
+        if let Some(Step::Delayed(delay, data)) = script.steps.front_mut() {
+            match timeout {
+                Some(timeout) if timeout < *delay => {
+                    std::thread::sleep(timeout);
+                    *delay -= timeout;
+
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                _ => {
+                    std::thread::sleep(*delay);
+                    let data = std::mem::take(data);
+                    script.steps[0] = Step::Read(data);
+                }
+            }
+        }
+
+        match script.steps.front_mut() {
+            Some(Step::Read(data)) => {
+                let count = buf.len().min(data.len());
+                buf[..count].copy_from_slice(&data[..count]);
+                data.drain(..count);
+
+                if data.is_empty() {
+                    script.steps.pop_front();
+                }
+
+                Ok(count)
+            }
+            Some(Step::Timeout) => {
+                script.steps.pop_front();
+
+                Err(ErrorKind::TimedOut.into())
+            }
+            Some(Step::Delayed(..)) => unreachable!(),
+            Some(Step::Write(expected)) => Err(script_error(format!(
+                "mock: read while a write of {:02x?} was expected",
+                expected
+            ))),
+            // Nothing left to answer with, which looks just like a silent device.
+            None => Err(ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut script = self.script.borrow_mut();
+
+        match script.steps.front_mut() {
+            Some(Step::Write(expected)) => {
+                let count = buf.len().min(expected.len());
+
+                if buf[..count] != expected[..count] {
+                    return Err(script_error(format!(
+                        "mock: wrote {:02x?}, expected {:02x?}",
+                        &buf[..count],
+                        &expected[..count]
+                    )));
+                }
+
+                expected.drain(..count);
+
+                if expected.is_empty() {
+                    script.steps.pop_front();
+                }
+
+                Ok(count)
+            }
+            step => Err(script_error(format!(
+                "mock: wrote {:02x?}, but the next step is {:?}",
+                buf, step
+            ))),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.script.borrow().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.script.borrow_mut().timeout = timeout;
+
+        Ok(())
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.script.borrow_mut().baud = Some(baud);
+
+        Ok(())
+    }
+
+    // The script simply carries on with the reconnected device.
+    fn reconnect(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.script.borrow().max_packet_size
+    }
+}
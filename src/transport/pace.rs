@@ -0,0 +1,124 @@
+use super::{Statistics, Transport};
+use crate::error::Result;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+// How writes are slowed down for links that can't keep up otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pacing {
+    // Waited before every write after the first.
+    pub tx_delay: Duration,
+    // An upper limit for the bytes written per second.
+    pub max_rate: Option<u64>,
+}
+
+impl Pacing {
+    pub fn is_enabled(&self) -> bool {
+        !self.tx_delay.is_zero() || self.max_rate.is_some()
+    }
+}
+
+// Wraps another transport and holds writes back according to the pacing.
+pub struct PacingTransport {
+    inner: Box<dyn Transport>,
+    pacing: Pacing,
+    // When the next write may start at the earliest.
+    next_write: Option<Instant>,
+    paced: Duration,
+}
+
+impl PacingTransport {
+    pub fn new(inner: Box<dyn Transport>, pacing: Pacing) -> Self {
+        Self {
+            inner,
+            pacing,
+            next_write: None,
+            paced: Duration::ZERO,
+        }
+    }
+}
+
+impl Read for PacingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for PacingTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(next_write) = self.next_write {
+            let wait = next_write.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                std::thread::sleep(wait);
+                self.paced += wait;
+            }
+        }
+
+        // Small pieces, so that the rate also holds within large writes.
+        let buf = match self.pacing.max_rate {
+            Some(rate) => &buf[..buf.len().min((rate / 100).max(1) as usize)],
+            None => buf,
+        };
+
+        let started = Instant::now();
+        let count = self.inner.write(buf)?;
+
+        // Time spent idle in between doesn't add up to a burst later on.
+        let mut next_write = started + self.pacing.tx_delay;
+        if let Some(rate) = self.pacing.max_rate {
+            next_write += Duration::from_secs_f64(count as f64 / rate as f64);
+        }
+        self.next_write = Some(next_write);
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for PacingTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.inner.set_baud(baud)
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.reconnect(timeout)
+    }
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn set_phase(&mut self, phase: &str) {
+        self.inner.set_phase(phase);
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics {
+            paced: self.paced,
+            ..self.inner.statistics()
+        }
+    }
+
+    fn count_retry(&mut self) {
+        self.inner.count_retry();
+    }
+
+    fn count_checksum_failure(&mut self) {
+        self.inner.count_checksum_failure();
+    }
+}
@@ -0,0 +1,355 @@
+use super::{MockTransport, Statistics, Transport};
+use crate::error::{Error, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+// Session logs are plain text, one event per line:
+//
+//   sbootil-log 1
+//   args bootstub<TAB>--serial<TAB>/dev/ttyUSB0<TAB>dump<TAB>0<TAB>0x100<TAB>out.bin
+//   0.000012 > handshake 57484f4953444953
+//   0.010342 < handshake 424f4f5453545542
+//   5.010811 ! dump
+//   result ok
+//
+// Lines with a timestamp are transfers from the host (>) or the device (<), or
+// reads that timed out (!), tagged with the protocol phase they belong to.
+const LOG_HEADER: &str = "sbootil-log 1";
+
+fn log_error(path: &str, source: std::io::Error) -> Error {
+    Error::File {
+        path: path.to_string(),
+        source,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(string: &str) -> Option<Vec<u8>> {
+    if !string.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..string.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(string.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Wraps another transport and logs everything going through it.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    log: File,
+    path: String,
+    start: Instant,
+    phase: String,
+}
+
+impl RecordingTransport {
+    pub fn create(inner: Box<dyn Transport>, path: &str, args: &[String]) -> Result<Self> {
+        let mut log = File::create(path).map_err(|source| log_error(path, source))?;
+
+        writeln!(log, "{}", LOG_HEADER).map_err(|source| log_error(path, source))?;
+        writeln!(log, "args {}", args.join("\t")).map_err(|source| log_error(path, source))?;
+
+        Ok(Self {
+            inner,
+            log,
+            path: path.to_string(),
+            start: Instant::now(),
+            phase: "start".to_string(),
+        })
+    }
+
+    // The log is written unbuffered, so that it stays useful even if the
+    // process is killed halfway through (e.g. when leaving the console).
+    fn record(&mut self, direction: char, data: Option<&[u8]>) -> std::io::Result<()> {
+        let elapsed = self.start.elapsed();
+        let mut line = format!(
+            "{}.{:06} {} {}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros(),
+            direction,
+            self.phase
+        );
+
+        if let Some(data) = data {
+            line.push(' ');
+            line.push_str(&hex(data));
+        }
+
+        writeln!(self.log, "{}", line).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("Failed to write to {}: {}", self.path, err),
+            )
+        })
+    }
+}
+
+impl Read for RecordingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(count) => {
+                self.record('<', Some(&buf[..count]))?;
+
+                Ok(count)
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                self.record('!', None)?;
+
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Write for RecordingTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+
+        if count > 0 {
+            self.record('>', Some(&buf[..count]))?;
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.inner.set_baud(baud)
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.reconnect(timeout)
+    }
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn set_phase(&mut self, phase: &str) {
+        self.phase = phase.to_string();
+        self.inner.set_phase(phase);
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.inner.statistics()
+    }
+
+    fn count_retry(&mut self) {
+        self.inner.count_retry();
+    }
+
+    fn count_checksum_failure(&mut self) {
+        self.inner.count_checksum_failure();
+    }
+}
+
+// Appends the outcome of the recorded command, so that a replay can check
+// that it ends the same way. Nothing is done if the command failed before any
+// recording was started.
+pub fn record_result<T>(path: &str, result: &Result<T>) -> Result<()> {
+    let mut log = match File::options().append(true).open(path) {
+        Ok(log) => log,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(source) => return Err(log_error(path, source)),
+    };
+
+    match result {
+        Ok(_) => writeln!(log, "result ok"),
+        Err(err) => writeln!(log, "result error {}", err.to_string().replace('\n', " ")),
+    }
+    .map_err(|source| log_error(path, source))
+}
+
+// A single line of a session log.
+pub enum Transfer {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+    Timeout,
+}
+
+pub struct LoggedTransfer {
+    // Since the start of the session.
+    pub time: Duration,
+    pub phase: String,
+    pub transfer: Transfer,
+}
+
+fn parse_time(string: &str) -> Option<Duration> {
+    let (seconds, micros) = string.split_once('.')?;
+
+    Some(Duration::new(seconds.parse().ok()?, 0) + Duration::from_micros(micros.parse().ok()?))
+}
+
+pub struct Recording {
+    pub args: Vec<String>,
+    pub transfers: Vec<LoggedTransfer>,
+    pub transport: MockTransport,
+    // None if the recorded process never got to finish (e.g. it was killed).
+    pub result: Option<std::result::Result<(), String>>,
+}
+
+impl Recording {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|source| log_error(path, source))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let invalid = |line_number: usize, message: &str| {
+            Error::InvalidArgument(format!(
+                "{} is not a valid session log (line {}): {}",
+                path, line_number, message
+            ))
+        };
+
+        let mut next_line = || -> Result<Option<String>> {
+            lines
+                .next()
+                .transpose()
+                .map_err(|source| log_error(path, source))
+        };
+
+        if next_line()?.as_deref() != Some(LOG_HEADER) {
+            return Err(invalid(1, "missing header"));
+        }
+
+        let args = match next_line()? {
+            Some(line) => match line.strip_prefix("args ") {
+                Some("") => Vec::new(),
+                Some(args) => args.split('\t').map(str::to_string).collect(),
+                None => return Err(invalid(2, "missing arguments")),
+            },
+            None => return Err(invalid(2, "missing arguments")),
+        };
+
+        let transport = MockTransport::new();
+        let mut transfers = Vec::new();
+        let mut result = None;
+        let mut line_number = 2;
+
+        while let Some(line) = next_line()? {
+            line_number += 1;
+
+            if let Some(outcome) = line.strip_prefix("result ") {
+                result = Some(match outcome.strip_prefix("error ") {
+                    Some(message) => Err(message.to_string()),
+                    None => Ok(()),
+                });
+                continue;
+            }
+
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let bad_data = || invalid(line_number, "bad data");
+
+            let (time, phase, transfer) = match fields.as_slice() {
+                [time, ">", phase, data] => {
+                    let data = unhex(data).ok_or_else(bad_data)?;
+                    transport.expect_write(&data);
+                    (time, phase, Transfer::Write(data))
+                }
+                [time, "<", phase, data] => {
+                    let data = unhex(data).ok_or_else(bad_data)?;
+                    transport.respond(&data);
+                    (time, phase, Transfer::Read(data))
+                }
+                [time, "!", phase] => {
+                    transport.time_out();
+                    (time, phase, Transfer::Timeout)
+                }
+                _ => return Err(invalid(line_number, "unknown event")),
+            };
+
+            transfers.push(LoggedTransfer {
+                time: parse_time(time).ok_or_else(|| invalid(line_number, "bad timestamp"))?,
+                phase: phase.to_string(),
+                transfer,
+            });
+        }
+
+        Ok(Self {
+            args,
+            transfers,
+            transport,
+            result,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstub::{self, DumpOptions};
+    use crate::cancel::CancelToken;
+    use crate::sha256;
+    use crate::timeouts::Timeouts;
+
+    // Recorded from the simulator, which serves the lower byte of each
+    // address.
+    const DUMP_LOG: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/bootstub-dump.log"
+    );
+
+    #[test]
+    fn recorded_dump_replays_to_the_same_result() {
+        let recording = Recording::load(DUMP_LOG).unwrap();
+        assert_eq!(
+            recording.args[3..],
+            [
+                "dump",
+                "--no-verify",
+                "--no-metadata",
+                "--chunk-size",
+                "0x40",
+                "0",
+                "0x100",
+                "/tmp/x"
+            ]
+        );
+        assert!(matches!(recording.result, Some(Ok(()))));
+
+        let mut session = bootstub::Session::connect(
+            Box::new(recording.transport.clone()),
+            Timeouts::new(bootstub::DEFAULT_TIMEOUT),
+        )
+        .unwrap();
+        let options = DumpOptions {
+            chunk_size: 0x40,
+            ..DumpOptions::default()
+        };
+        let mut data = Vec::new();
+        let digest = session
+            .dump(0, 0x100, &options, &mut data, &CancelToken::new())
+            .unwrap();
+        drop(session);
+
+        assert_eq!(data, (0..=0xff).collect::<Vec<u8>>());
+        assert_eq!(
+            sha256::to_hex(&digest.sha256),
+            "40aff2e9d2d8922e47afd4648e6967497158785fbd1da870e7110266bf944880"
+        );
+        assert_eq!(recording.transport.remaining_steps(), 0);
+    }
+}
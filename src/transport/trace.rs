@@ -0,0 +1,110 @@
+use super::{Statistics, Transport};
+use crate::error::Result;
+use crate::hexdump::hexdump;
+use crate::log;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+// Wraps another transport and logs every transfer with its contents.
+pub struct TracingTransport {
+    inner: Box<dyn Transport>,
+}
+
+impl TracingTransport {
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self { inner }
+    }
+}
+
+fn trace(direction: &str, data: &[u8], duration: Duration) {
+    log::write(format_args!(
+        "{} {} bytes in {:.3} ms",
+        direction,
+        data.len(),
+        duration.as_secs_f64() * 1000.0
+    ));
+
+    for line in hexdump(data, 0) {
+        log::write(format_args!("    {}", line));
+    }
+}
+
+impl Read for TracingTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
+
+        match self.inner.read(buf) {
+            Ok(count) => {
+                trace("<", &buf[..count], start.elapsed());
+
+                Ok(count)
+            }
+            Err(err) => {
+                log::write(format_args!(
+                    "< failed after {:.3} ms: {}",
+                    start.elapsed().as_secs_f64() * 1000.0,
+                    err
+                ));
+
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Write for TracingTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
+        let count = self.inner.write(buf)?;
+
+        trace(">", &buf[..count], start.elapsed());
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for TracingTransport {
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.inner.set_baud(baud)
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.reconnect(timeout)
+    }
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn set_phase(&mut self, phase: &str) {
+        self.inner.set_phase(phase);
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.inner.statistics()
+    }
+
+    fn count_retry(&mut self) {
+        self.inner.count_retry();
+    }
+
+    fn count_checksum_failure(&mut self) {
+        self.inner.count_checksum_failure();
+    }
+}
@@ -0,0 +1,378 @@
+use super::{Statistics, Transport};
+use crate::device::UsbCdcDevice;
+use crate::error::{Error, Result};
+use rusb::{GlobalContext, UsbContext};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+// What the transport needs of the device, which is a UsbCdcDevice other than
+// in tests.
+pub trait BulkDevice {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+
+    fn write_all(&mut self, buf: &[u8], timeout: Duration) -> Result<()>;
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()>;
+
+    fn location(&self) -> String;
+
+    fn max_packet_size(&self) -> usize;
+
+    fn stalls(&self) -> u64;
+}
+
+impl<T: UsbContext> BulkDevice for UsbCdcDevice<T> {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        UsbCdcDevice::read(self, buf, timeout)
+    }
+
+    fn write_all(&mut self, buf: &[u8], timeout: Duration) -> Result<()> {
+        UsbCdcDevice::write_all(self, buf, timeout)
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        UsbCdcDevice::reconnect(self, timeout)
+    }
+
+    fn location(&self) -> String {
+        UsbCdcDevice::location(self)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        UsbCdcDevice::max_packet_size(self)
+    }
+
+    fn stalls(&self) -> u64 {
+        UsbCdcDevice::stalls(self)
+    }
+}
+
+pub struct UsbTransport<D: BulkDevice = UsbCdcDevice<GlobalContext>> {
+    device: D,
+    timeout: Option<Duration>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<D: BulkDevice> UsbTransport<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            timeout: None,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    // Starts out with a timeout other than none, which is what the protocol
+    // sessions set up for themselves anyway.
+    pub fn with_timeout(device: D, timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..Self::new(device)
+        }
+    }
+
+    // Keeps the kind of error recognizable for code that only sees io::Error,
+    // timeouts in particular.
+    fn map_error(err: Error) -> std::io::Error {
+        let kind = match &err {
+            Error::Usb(err) => match err {
+                rusb::Error::Timeout => return ErrorKind::TimedOut.into(),
+                rusb::Error::Interrupted => ErrorKind::Interrupted,
+                rusb::Error::Access => ErrorKind::PermissionDenied,
+                rusb::Error::NoDevice => ErrorKind::NotConnected,
+                rusb::Error::NotFound => ErrorKind::NotFound,
+                rusb::Error::Busy => ErrorKind::ResourceBusy,
+                rusb::Error::InvalidParam => ErrorKind::InvalidInput,
+                rusb::Error::Overflow => ErrorKind::InvalidData,
+                rusb::Error::Pipe => ErrorKind::BrokenPipe,
+                rusb::Error::NoMem => ErrorKind::OutOfMemory,
+                rusb::Error::NotSupported => ErrorKind::Unsupported,
+                _ => ErrorKind::Other,
+            },
+            // Some data made it, but the rest didn't in time.
+            Error::ShortRead { .. } | Error::ShortWrite { .. } => ErrorKind::TimedOut,
+            Error::Stall { .. } => ErrorKind::BrokenPipe,
+            Error::Io(err) => err.kind(),
+            _ => ErrorKind::Other,
+        };
+
+        std::io::Error::new(kind, err.to_string())
+    }
+}
+
+impl<D: BulkDevice> Read for UsbTransport<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Bulk reads have to be able to hold a whole packet, so read into a
+        // larger buffer and hand out the data from there.
+        if self.position == self.buffer.len() {
+            self.buffer.resize(16 * 1024, 0);
+            self.position = 0;
+
+            let timeout = self.timeout.unwrap_or(Duration::ZERO);
+            match self.device.read(&mut self.buffer, timeout) {
+                Ok(count) => self.buffer.truncate(count),
+                Err(err) => {
+                    self.buffer.clear();
+                    return Err(Self::map_error(err));
+                }
+            }
+        }
+
+        let count = buf.len().min(self.buffer.len() - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+impl<D: BulkDevice> Write for UsbTransport<D> {
+    // Always writes everything, so that the timeout covers the whole buffer
+    // even when wrapped by transports that only forward `write`.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let timeout = self.timeout.unwrap_or(Duration::ZERO);
+        self.device
+            .write_all(buf, timeout)
+            .map_err(Self::map_error)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: BulkDevice> Transport for UsbTransport<D> {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        // Whatever was left over belongs to the previous connection.
+        self.buffer.clear();
+        self.position = 0;
+
+        self.device.reconnect(timeout)
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(self.device.location())
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        Some(self.device.max_packet_size())
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics {
+            stalls: self.device.stalls(),
+            ..Statistics::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstub::Session;
+    use crate::timeouts::Timeouts;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // Hands out the responses one transfer at a time and keeps what was
+    // written, where the test can still see it after the session is gone.
+    #[derive(Default)]
+    struct FakeDevice {
+        responses: VecDeque<Result<Vec<u8>>>,
+        written: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl BulkDevice for FakeDevice {
+        fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<usize> {
+            let data = self
+                .responses
+                .pop_front()
+                .unwrap_or(Err(Error::Usb(rusb::Error::Timeout)))?;
+            buf[..data.len()].copy_from_slice(&data);
+
+            Ok(data.len())
+        }
+
+        fn write_all(&mut self, buf: &[u8], _timeout: Duration) -> Result<()> {
+            self.written.borrow_mut().extend_from_slice(buf);
+
+            Ok(())
+        }
+
+        fn reconnect(&mut self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn location(&self) -> String {
+            "fake".to_string()
+        }
+
+        fn max_packet_size(&self) -> usize {
+            512
+        }
+
+        fn stalls(&self) -> u64 {
+            0
+        }
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts::new(Duration::from_millis(500))
+    }
+
+    #[test]
+    fn usb_errors_map_to_io_error_kinds() {
+        let cases = [
+            (Error::Usb(rusb::Error::Timeout), ErrorKind::TimedOut),
+            (Error::Usb(rusb::Error::Interrupted), ErrorKind::Interrupted),
+            (Error::Usb(rusb::Error::Access), ErrorKind::PermissionDenied),
+            (Error::Usb(rusb::Error::NoDevice), ErrorKind::NotConnected),
+            (Error::Usb(rusb::Error::NotFound), ErrorKind::NotFound),
+            (Error::Usb(rusb::Error::Busy), ErrorKind::ResourceBusy),
+            (
+                Error::Usb(rusb::Error::InvalidParam),
+                ErrorKind::InvalidInput,
+            ),
+            (Error::Usb(rusb::Error::Overflow), ErrorKind::InvalidData),
+            (Error::Usb(rusb::Error::Pipe), ErrorKind::BrokenPipe),
+            (Error::Usb(rusb::Error::NoMem), ErrorKind::OutOfMemory),
+            (
+                Error::Usb(rusb::Error::NotSupported),
+                ErrorKind::Unsupported,
+            ),
+            (Error::Usb(rusb::Error::Io), ErrorKind::Other),
+            (
+                Error::ShortRead {
+                    phase: "the bulk IN endpoint 0x81".to_string(),
+                    expected: 8,
+                    got: 3,
+                },
+                ErrorKind::TimedOut,
+            ),
+            (
+                Error::Stall {
+                    endpoint: 0x02,
+                    direction: "OUT",
+                },
+                ErrorKind::BrokenPipe,
+            ),
+            (
+                std::io::Error::from(ErrorKind::UnexpectedEof).into(),
+                ErrorKind::UnexpectedEof,
+            ),
+            (Error::InvalidArgument("bad".to_string()), ErrorKind::Other),
+        ];
+
+        for (err, kind) in cases {
+            let message = err.to_string();
+            let mapped = UsbTransport::<FakeDevice>::map_error(err);
+
+            assert_eq!(mapped.kind(), kind, "{}", message);
+        }
+    }
+
+    #[test]
+    fn failed_transfers_keep_their_kind_through_the_wrapper() {
+        let mut transport = UsbTransport::new(FakeDevice {
+            responses: VecDeque::from([Err(Error::Usb(rusb::Error::NoDevice))]),
+            ..FakeDevice::default()
+        });
+
+        let mut buf = [0u8; 8];
+        let err = transport.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(err.to_string().contains("No such device"), "{}", err);
+
+        // Nothing else to read is a timeout, like on a real device.
+        let err = transport.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn handshake_through_the_wrapper() {
+        let device = FakeDevice {
+            // In pieces, like packets that arrive one after another.
+            responses: VecDeque::from([Ok(b"BOOT".to_vec()), Ok(b"STUB".to_vec())]),
+            ..FakeDevice::default()
+        };
+        let written = device.written.clone();
+
+        let session = Session::connect_legacy(
+            Box::new(UsbTransport::with_timeout(device, None)),
+            timeouts(),
+        )
+        .unwrap();
+        drop(session);
+
+        assert_eq!(*written.borrow(), b"WHOISDIS");
+    }
+
+    // The other end of a socket pair, standing in for the serial port.
+    #[cfg(unix)]
+    struct PipeTransport(std::os::unix::net::UnixStream);
+
+    #[cfg(unix)]
+    impl Read for PipeTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf).map_err(|err| match err.kind() {
+                ErrorKind::WouldBlock => ErrorKind::TimedOut.into(),
+                _ => err,
+            })
+        }
+    }
+
+    #[cfg(unix)]
+    impl Write for PipeTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[cfg(unix)]
+    impl Transport for PipeTransport {
+        fn timeout(&self) -> Option<Duration> {
+            self.0.read_timeout().ok().flatten()
+        }
+
+        fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+            self.0.set_read_timeout(timeout)?;
+
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handshake_through_a_pipe_pair() {
+        let (host, mut stub) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        // Waits in the pipe until the host gets to reading it.
+        stub.write_all(b"BOOTSTUB").unwrap();
+
+        let session = Session::connect_legacy(Box::new(PipeTransport(host)), timeouts()).unwrap();
+        drop(session);
+
+        let mut request = [0u8; 8];
+        stub.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"WHOISDIS");
+    }
+}
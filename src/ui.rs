@@ -0,0 +1,89 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Leaves only errors and the output that was explicitly asked for.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy)]
+pub enum Style {
+    Status,
+    Warning,
+    Error,
+}
+
+impl Style {
+    fn prefix(self) -> &'static str {
+        match self {
+            Style::Status => "==>",
+            Style::Warning => "Warning:",
+            Style::Error => "Error:",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Style::Status => "\x1b[1;32m",
+            Style::Warning => "\x1b[1;33m",
+            Style::Error => "\x1b[1;31m",
+        }
+    }
+}
+
+// See https://no-color.org.
+fn use_color(stream: &impl IsTerminal) -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && stream.is_terminal()
+}
+
+fn write_line(stream: &mut (impl Write + IsTerminal), style: Style, message: std::fmt::Arguments) {
+    let _ = if use_color(stream) {
+        writeln!(
+            stream,
+            "{}{}\x1b[0m {}",
+            style.color(),
+            style.prefix(),
+            message
+        )
+    } else {
+        writeln!(stream, "{} {}", style.prefix(), message)
+    };
+}
+
+// Everything goes to stderr, stdout is reserved for the data that was asked
+// for (and for console output).
+pub fn print(style: Style, message: std::fmt::Arguments) {
+    if quiet() && !matches!(style, Style::Error) {
+        return;
+    }
+
+    write_line(&mut std::io::stderr().lock(), style, message);
+}
+
+// Progress and results of an operation, which `--quiet` does away with.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::ui::print($crate::ui::Style::Status, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::ui::print($crate::ui::Style::Warning, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::ui::print($crate::ui::Style::Error, format_args!($($arg)*))
+    };
+}
@@ -0,0 +1,94 @@
+use crate::cancel::CancelToken;
+use crate::config::VendorAllowList;
+use crate::error::{Error, Result};
+use crate::status;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug)]
+pub enum Target {
+    Usb(u16, u16),
+    // Any device of these vendors, for when no ID was given.
+    Vendors(VendorAllowList),
+    Serial(PathBuf),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Usb(vendor_id, product_id) => write!(f, "{:04x}:{:04x}", vendor_id, product_id),
+            Target::Vendors(vendors) if vendors.is_default() => write!(f, "a Samsung device"),
+            Target::Vendors(vendors) => write!(f, "a device from {}", vendors),
+            Target::Serial(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Target {
+    pub fn is_present(&self) -> Result<bool> {
+        match self {
+            #[cfg(feature = "usb")]
+            Target::Usb(..) | Target::Vendors(_) => {
+                for device in rusb::devices()?.iter() {
+                    // Devices can disappear while they are being looked at.
+                    let device_desc = match device.device_descriptor() {
+                        Ok(device_desc) => device_desc,
+                        Err(_) => continue,
+                    };
+
+                    let vendor_id = device_desc.vendor_id();
+                    let present = match self {
+                        Target::Usb(wanted_vendor_id, product_id) => {
+                            vendor_id == *wanted_vendor_id
+                                && device_desc.product_id() == *product_id
+                        }
+                        Target::Vendors(vendors) => {
+                            vendors.matches(vendor_id, crate::device::vendor_name(vendor_id))
+                        }
+                        Target::Serial(_) => false,
+                    };
+
+                    if present {
+                        return Ok(true);
+                    }
+                }
+
+                Ok(false)
+            }
+            #[cfg(not(feature = "usb"))]
+            Target::Usb(..) | Target::Vendors(_) => Err(Error::not_in_build("USB")),
+            Target::Serial(path) => Ok(path.exists()),
+        }
+    }
+}
+
+// Polls until the device shows up, or until the timeout runs out. Without a
+// timeout this waits until cancelled.
+pub fn wait_for(target: &Target, timeout: Option<Duration>, cancel: &CancelToken) -> Result<()> {
+    if target.is_present()? {
+        return Ok(());
+    }
+
+    status!("Waiting for {}...", target);
+
+    let start = Instant::now();
+
+    loop {
+        if let Some(timeout) = timeout {
+            if start.elapsed() >= timeout {
+                return Err(Error::Timeout {
+                    phase: format!("{} to appear", target),
+                });
+            }
+        }
+
+        cancel.sleep(POLL_INTERVAL)?;
+
+        if target.is_present()? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,661 @@
+use crate::crc32::Crc32;
+use crate::error::{Error, Result};
+use crate::inflate::inflate;
+use crate::status;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Reading the members of zip archives, which firmware usually comes in. Only
+// what firmware archives need is supported: members that are stored or
+// compressed with DEFLATE, in archives that may be larger than 4 GiB (zip64),
+// without encryption and on a single disk.
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+
+const END_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+// The end record is followed by a comment of up to this many bytes.
+const MAX_COMMENT_SIZE: usize = 0xffff;
+
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Splits paths like firmware.zip:boot.img into the archive and the member.
+pub fn split_path(path: &str) -> Option<(&str, &str)> {
+    let position = path.to_ascii_lowercase().find(".zip:")?;
+
+    Some((&path[..position + 4], &path[position + 5..]))
+}
+
+#[derive(Clone, Debug)]
+pub struct Member {
+    // The full name, with the directories in the archive.
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    method: u16,
+    flags: u16,
+    crc32: u32,
+    header_offset: u64,
+}
+
+impl Member {
+    // The name without the directories in the archive.
+    pub fn file_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+
+    // Whether the member can be read straight out of the archive, without
+    // decompressing it first.
+    pub fn is_stored(&self) -> bool {
+        self.method == METHOD_STORED
+    }
+}
+
+pub struct Archive {
+    path: PathBuf,
+    members: Vec<Member>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file_error = |source| Error::File {
+            path: path.display().to_string(),
+            source,
+        };
+        let invalid =
+            |message: &str| Error::InvalidArchive(format!("{}: {}", path.display(), message));
+
+        let mut file = File::open(path).map_err(file_error)?;
+        let size = file.metadata().map_err(file_error)?.len();
+
+        // The end record is somewhere in the last bytes, before the comment.
+        let tail_size = size.min((END_SIZE + MAX_COMMENT_SIZE + ZIP64_LOCATOR_SIZE) as u64);
+        let mut tail = vec![0u8; tail_size as usize];
+        file.seek(SeekFrom::Start(size - tail_size))
+            .and_then(|_| file.read_exact(&mut tail))
+            .map_err(file_error)?;
+
+        let end = (0..=tail.len().saturating_sub(END_SIZE))
+            .rev()
+            .filter(|_| tail.len() >= END_SIZE)
+            .find(|&offset| u32_at(&tail, offset) == END_SIGNATURE)
+            .ok_or_else(|| invalid("it's not a zip archive"))?;
+
+        if u16_at(&tail, end + 4) != 0 || u16_at(&tail, end + 6) != 0 {
+            return Err(invalid(
+                "archives split into several files aren't supported",
+            ));
+        }
+
+        let mut count = u64::from(u16_at(&tail, end + 10));
+        let mut directory_size = u64::from(u32_at(&tail, end + 12));
+        let mut directory_offset = u64::from(u32_at(&tail, end + 16));
+
+        // Larger archives have their numbers in the zip64 end record, which
+        // the locator right before the end record points at.
+        let locator = end.checked_sub(ZIP64_LOCATOR_SIZE);
+        if let Some(locator) =
+            locator.filter(|&locator| u32_at(&tail, locator) == ZIP64_LOCATOR_SIGNATURE)
+        {
+            let mut record = [0u8; 56];
+            file.seek(SeekFrom::Start(u64_at(&tail, locator + 8)))
+                .and_then(|_| file.read_exact(&mut record))
+                .map_err(file_error)?;
+
+            if u32_at(&record, 0) != ZIP64_END_SIGNATURE {
+                return Err(invalid("the zip64 end record is missing"));
+            }
+
+            count = u64_at(&record, 32);
+            directory_size = u64_at(&record, 40);
+            directory_offset = u64_at(&record, 48);
+        }
+
+        if directory_offset.saturating_add(directory_size) > size {
+            return Err(invalid("the central directory is past the end"));
+        }
+
+        let mut directory = vec![0u8; directory_size as usize];
+        file.seek(SeekFrom::Start(directory_offset))
+            .and_then(|_| file.read_exact(&mut directory))
+            .map_err(file_error)?;
+
+        let mut members = Vec::new();
+        let mut offset = 0;
+
+        for _ in 0..count {
+            let header = directory
+                .get(offset..offset + CENTRAL_HEADER_SIZE)
+                .filter(|header| u32_at(header, 0) == CENTRAL_HEADER_SIGNATURE)
+                .ok_or_else(|| invalid("the central directory is broken"))?;
+
+            let name_length = usize::from(u16_at(header, 28));
+            let extra_length = usize::from(u16_at(header, 30));
+            let comment_length = usize::from(u16_at(header, 32));
+
+            let variable = offset + CENTRAL_HEADER_SIZE;
+            let end = variable + name_length + extra_length + comment_length;
+            if end > directory.len() {
+                return Err(invalid("the central directory is broken"));
+            }
+
+            let name =
+                String::from_utf8_lossy(&directory[variable..variable + name_length]).into_owned();
+            let extra = &directory[variable + name_length..variable + name_length + extra_length];
+
+            let mut member = Member {
+                name,
+                size: u64::from(u32_at(header, 24)),
+                compressed_size: u64::from(u32_at(header, 20)),
+                method: u16_at(header, 10),
+                flags: u16_at(header, 8),
+                crc32: u32_at(header, 16),
+                header_offset: u64::from(u32_at(header, 42)),
+            };
+            read_zip64_extra(&mut member, extra);
+
+            // Directories are only there for their name.
+            if !member.name.ends_with('/') {
+                members.push(member);
+            }
+
+            offset = end;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            members,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    // Looks a member up by its full name, or by its file name if that's
+    // unique in the archive.
+    pub fn find(&self, name: &str) -> Result<&Member> {
+        if let Some(member) = self.members.iter().find(|member| member.name == name) {
+            return Ok(member);
+        }
+
+        let mut matches = self
+            .members
+            .iter()
+            .filter(|member| member.file_name().eq_ignore_ascii_case(name));
+
+        match (matches.next(), matches.next()) {
+            (Some(member), None) => Ok(member),
+            (Some(first), Some(second)) => Err(Error::InvalidArgument(format!(
+                "{} has several members named {}, like {} and {}, give the full name",
+                self.path.display(),
+                name,
+                first.name,
+                second.name
+            ))),
+            (None, _) => {
+                let names = self
+                    .members
+                    .iter()
+                    .map(|member| member.name.as_str())
+                    .collect::<Vec<_>>();
+
+                Err(Error::InvalidArgument(format!(
+                    "There is no {} in {}, it has {}",
+                    name,
+                    self.path.display(),
+                    names.join(", ")
+                )))
+            }
+        }
+    }
+
+    // Reads a member. Stored members are read straight out of the archive,
+    // compressed ones are extracted into a temporary file first, which goes
+    // away along with the reader. Either way, the data is checked against its
+    // CRC-32 before the reader is handed out, so that a broken member never
+    // gets as far as the device.
+    pub fn open_member(&self, member: &Member) -> Result<Box<dyn Read>> {
+        let description = format!("{}:{}", self.path.display(), member.name);
+        let file_error = |source| Error::File {
+            path: self.path.display().to_string(),
+            source,
+        };
+
+        if member.flags & FLAG_ENCRYPTED != 0 {
+            return Err(Error::Unsupported(format!(
+                "{} is encrypted, which isn't supported",
+                description
+            )));
+        }
+
+        let mut file = File::open(&self.path).map_err(file_error)?;
+
+        // The local header has its own, possibly different, extra field.
+        let mut header = [0u8; LOCAL_HEADER_SIZE];
+        file.seek(SeekFrom::Start(member.header_offset))
+            .and_then(|_| file.read_exact(&mut header))
+            .map_err(file_error)?;
+        if u32_at(&header, 0) != LOCAL_HEADER_SIGNATURE {
+            return Err(Error::InvalidArchive(format!(
+                "{}: the local header is missing",
+                description
+            )));
+        }
+        let data_offset = member.header_offset
+            + LOCAL_HEADER_SIZE as u64
+            + u64::from(u16_at(&header, 26))
+            + u64::from(u16_at(&header, 28));
+        file.seek(SeekFrom::Start(data_offset))
+            .map_err(file_error)?;
+
+        let data = file.take(member.compressed_size);
+
+        match member.method {
+            METHOD_STORED => {
+                status!("Checking {}", description);
+
+                let mut checked = CheckedWriter {
+                    inner: std::io::sink(),
+                    crc: Crc32::new(),
+                };
+                let mut data = data;
+                let size = std::io::copy(&mut data, &mut checked).map_err(file_error)?;
+
+                check(member, &description, size, checked.crc.finish())?;
+
+                // Checked again on the way, in case the archive changes in
+                // the meantime.
+                let mut file = data.into_inner();
+                file.seek(SeekFrom::Start(data_offset))
+                    .map_err(file_error)?;
+                let data = file.take(member.compressed_size);
+
+                Ok(Box::new(CheckedReader::new(data, member, description)))
+            }
+            METHOD_DEFLATED => {
+                status!("Extracting {}, it's compressed", description);
+
+                let mut temporary = TemporaryFile::create(member.file_name())?;
+                let mut checked = CheckedWriter {
+                    inner: &mut temporary.file,
+                    crc: Crc32::new(),
+                };
+                let mut data = data;
+                let size = inflate(&mut data, &mut checked)?;
+                let crc32 = checked.crc.finish();
+
+                check(member, &description, size, crc32)?;
+
+                temporary.file.rewind()?;
+                Ok(Box::new(temporary))
+            }
+            method => Err(Error::Unsupported(format!(
+                "{} is compressed with method {}, only stored and deflated members are supported",
+                description, method
+            ))),
+        }
+    }
+}
+
+// Members larger than 4 GiB have their sizes and offset in the zip64 extra
+// field, for every one that doesn't fit into the header.
+fn read_zip64_extra(member: &mut Member, mut extra: &[u8]) {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let size = usize::from(u16_at(extra, 2)).min(extra.len() - 4);
+        let mut field = &extra[4..4 + size];
+
+        if id == ZIP64_EXTRA_FIELD {
+            for value in [
+                &mut member.size,
+                &mut member.compressed_size,
+                &mut member.header_offset,
+            ] {
+                if *value == u64::from(u32::MAX) && field.len() >= 8 {
+                    *value = u64_at(field, 0);
+                    field = &field[8..];
+                }
+            }
+        }
+
+        extra = &extra[4 + size..];
+    }
+}
+
+fn check(member: &Member, description: &str, size: u64, crc32: u32) -> Result<()> {
+    if size != member.size {
+        return Err(Error::InvalidArchive(format!(
+            "{} came to {} bytes instead of {}",
+            description, size, member.size
+        )));
+    }
+
+    if crc32 != member.crc32 {
+        return Err(Error::Verification(format!(
+            "{} has the CRC-32 {:08x} instead of {:08x}",
+            description, crc32, member.crc32
+        )));
+    }
+
+    Ok(())
+}
+
+// Computes the CRC-32 of a stored member while it's read, and fails the read
+// at the end if it doesn't match.
+struct CheckedReader<R: Read> {
+    inner: R,
+    member: Member,
+    description: String,
+    crc: Crc32,
+    size: u64,
+}
+
+impl<R: Read> CheckedReader<R> {
+    fn new(inner: R, member: &Member, description: String) -> Self {
+        Self {
+            inner,
+            member: member.clone(),
+            description,
+            crc: Crc32::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.crc.update(&buf[..count]);
+        self.size += count as u64;
+
+        if count == 0 && !buf.is_empty() {
+            check(
+                &self.member,
+                &self.description,
+                self.size,
+                self.crc.finish(),
+            )
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+        }
+
+        Ok(count)
+    }
+}
+
+struct CheckedWriter<W: Write> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W: Write> Write for CheckedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(data)?;
+        self.crc.update(&data[..count]);
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Tells apart the temporary files of members with the same name.
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// A file in the temporary directory that is removed once it's dropped.
+struct TemporaryFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl TemporaryFile {
+    fn create(name: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "sbootil-{}-{}-{}",
+            std::process::id(),
+            TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|source| Error::File {
+                path: path.display().to_string(),
+                source,
+            })?;
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Read for TemporaryFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::Deflater;
+
+    // An archive with members that are stored, or compressed with `deflate`
+    // set, and names ending in a slash for directories.
+    fn zip(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+
+        for &(name, data, deflate) in members {
+            let (method, compressed) = match deflate {
+                true => {
+                    let mut deflater = Deflater::new();
+                    let mut compressed = Vec::new();
+                    deflater.compress(data, &mut compressed);
+                    deflater.finish(&mut compressed);
+                    (METHOD_DEFLATED, compressed)
+                }
+                false => (METHOD_STORED, data.to_vec()),
+            };
+
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&[0, 0]);
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crate::crc32::crc32(data).to_le_bytes());
+            fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0, 0]);
+
+            directory.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            archive.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+            archive.extend_from_slice(&[20, 0]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&compressed);
+        }
+
+        let offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&END_SIGNATURE.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&offset.to_le_bytes());
+        archive.extend_from_slice(&[0, 0]);
+
+        archive
+    }
+
+    fn write(data: &[u8]) -> TemporaryFile {
+        let mut file = TemporaryFile::create("test.zip").unwrap();
+        file.file.write_all(data).unwrap();
+
+        file
+    }
+
+    fn read_member(archive: &Archive, name: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        archive
+            .open_member(archive.find(name)?)?
+            .read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+
+    fn image(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_stored_and_deflated_members() {
+        let boot = image(3000);
+        let system = image(70000);
+        let file = write(&zip(&[
+            ("firmware/", b"", false),
+            ("firmware/boot.img", &boot, false),
+            ("firmware/system.img", &system, true),
+            ("empty.img", b"", false),
+        ]));
+
+        let archive = Archive::open(&file.path).unwrap();
+        let names = archive
+            .members()
+            .iter()
+            .map(|member| member.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["firmware/boot.img", "firmware/system.img", "empty.img"]
+        );
+        assert!(archive.members()[0].is_stored());
+        assert!(!archive.members()[1].is_stored());
+
+        assert_eq!(read_member(&archive, "firmware/boot.img").unwrap(), boot);
+        assert_eq!(read_member(&archive, "SYSTEM.img").unwrap(), system);
+        assert_eq!(read_member(&archive, "empty.img").unwrap(), b"");
+    }
+
+    #[test]
+    fn broken_members_fail_before_anything_is_read() {
+        let boot = image(3000);
+
+        for deflate in [false, true] {
+            let mut data = zip(&[("boot.img", &boot, deflate)]);
+            data[LOCAL_HEADER_SIZE + "boot.img".len() + 100] ^= 0x80;
+            let file = write(&data);
+
+            let archive = Archive::open(&file.path).unwrap();
+            // Stored members can only be wrong about their CRC-32, compressed
+            // ones might not even decompress.
+            match archive.open_member(&archive.members()[0]) {
+                Err(Error::Verification(_)) => {}
+                Err(_) if deflate => {}
+                Err(err) => panic!("{}", err),
+                Ok(_) => panic!("deflate: {}", deflate),
+            }
+        }
+    }
+
+    #[test]
+    fn find_members() {
+        let file = write(&zip(&[
+            ("AP/boot.img", b"ap", false),
+            ("CP/boot.img", b"cp", false),
+            ("BL/sboot.bin", b"bl", false),
+        ]));
+        let archive = Archive::open(&file.path).unwrap();
+
+        assert_eq!(read_member(&archive, "CP/boot.img").unwrap(), b"cp");
+        assert_eq!(read_member(&archive, "sboot.bin").unwrap(), b"bl");
+        assert!(matches!(
+            archive.find("boot.img"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            archive.find("param.bin"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn open_rejects_what_isnt_an_archive() {
+        for data in [&b""[..], b"PK", &[0x5a; 100]] {
+            let file = write(data);
+            assert!(matches!(
+                Archive::open(&file.path),
+                Err(Error::InvalidArchive(_))
+            ));
+        }
+
+        // More members than the central directory has room for.
+        let mut data = zip(&[("boot.img", b"boot", false)]);
+        let end = data.len() - END_SIZE;
+        data[end + 8..end + 12].copy_from_slice(&[2, 0, 2, 0]);
+        let file = write(&data);
+        assert!(matches!(
+            Archive::open(&file.path),
+            Err(Error::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(
+            split_path("firmware.zip:AP/boot.img"),
+            Some(("firmware.zip", "AP/boot.img"))
+        );
+        assert_eq!(
+            split_path("dir/Firmware.ZIP:boot.img"),
+            Some(("dir/Firmware.ZIP", "boot.img"))
+        );
+        assert_eq!(split_path("boot.img"), None);
+        assert_eq!(split_path("firmware.zip"), None);
+    }
+}
@@ -0,0 +1,100 @@
+// Writes the Zstandard frame format (RFC 8878) without any of its entropy
+// coding: runs of a single byte become RLE blocks and everything else is
+// stored as is. That is most of what there is to gain on dumps, which are
+// largely zero or erased memory, and anything that reads zstd can read it.
+
+const MAGIC: u32 = 0xfd2fb528;
+
+// The largest a block may be, which is also the window size.
+const BLOCK_SIZE: usize = 128 * 1024;
+// A window of 2^(10 + 7) bytes, in the exponent and mantissa encoding.
+const WINDOW_DESCRIPTOR: u8 = 7 << 3;
+
+// Shorter runs aren't worth the header of a block of their own.
+const MIN_RUN: usize = 32;
+
+const RAW_BLOCK: u32 = 0;
+const RLE_BLOCK: u32 = 1;
+
+#[derive(Default)]
+pub struct ZstdEncoder {
+    // What hasn't been put into blocks yet.
+    buffer: Vec<u8>,
+    started: bool,
+}
+
+impl ZstdEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn header(&mut self, output: &mut Vec<u8>) {
+        if !self.started {
+            output.extend_from_slice(&MAGIC.to_le_bytes());
+            // No content size, no checksum and no dictionary.
+            output.extend_from_slice(&[0, WINDOW_DESCRIPTOR]);
+            self.started = true;
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        self.header(output);
+        self.buffer.extend_from_slice(data);
+
+        if self.buffer.len() >= BLOCK_SIZE {
+            let whole = self.buffer.len() / BLOCK_SIZE * BLOCK_SIZE;
+            for block in self.buffer[..whole].chunks(BLOCK_SIZE) {
+                blocks(block, output);
+            }
+            self.buffer.drain(..whole);
+        }
+    }
+
+    pub fn finish(&mut self, output: &mut Vec<u8>) {
+        self.header(output);
+        blocks(&self.buffer, output);
+        self.buffer.clear();
+
+        // An empty last block, as the blocks so far didn't know they were.
+        block_header(true, RAW_BLOCK, 0, output);
+    }
+}
+
+fn block_header(last: bool, block_type: u32, size: usize, output: &mut Vec<u8>) {
+    let header = u32::from(last) | block_type << 1 | (size as u32) << 3;
+    output.extend_from_slice(&header.to_le_bytes()[..3]);
+}
+
+// Splits up to a block's worth of data into runs and whatever is in between.
+fn blocks(data: &[u8], output: &mut Vec<u8>) {
+    let mut index = 0;
+    // Where the data that is stored as is starts.
+    let mut stored = 0;
+
+    while index < data.len() {
+        let run = data[index..]
+            .iter()
+            .take_while(|&&byte| byte == data[index])
+            .count();
+
+        if run < MIN_RUN {
+            index += run;
+            continue;
+        }
+
+        if stored < index {
+            block_header(false, RAW_BLOCK, index - stored, output);
+            output.extend_from_slice(&data[stored..index]);
+        }
+        block_header(false, RLE_BLOCK, run, output);
+        output.push(data[index]);
+
+        index += run;
+        stored = index;
+    }
+
+    if stored < data.len() {
+        block_header(false, RAW_BLOCK, data.len() - stored, output);
+        output.extend_from_slice(&data[stored..]);
+    }
+}
@@ -0,0 +1,158 @@
+// Runs the binary the way scripts do and checks that the classes of failures
+// end with the exit statuses that `sbootil --help` lists.
+
+use sbootil::error::{EXIT_DEVICE_NOT_FOUND, EXIT_PROTOCOL, EXIT_USAGE};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn sbootil() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sbootil"));
+    // Whatever the user configured has no business in the tests.
+    command
+        .env("XDG_CONFIG_HOME", temporary_path("config"))
+        .env_remove("SBOOTIL_CONFIG");
+
+    command
+}
+
+fn temporary_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "sbootil-exit-{}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst),
+        name
+    ))
+}
+
+fn assert_exit(output: &Output, code: i32) {
+    assert_eq!(
+        output.status.code(),
+        Some(code),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn usage_errors_exit_with_2() {
+    // Rejected by the argument parser.
+    let output = sbootil()
+        .args(["bootstub", "--serial", "/dev/null", "dump", "0"])
+        .output()
+        .unwrap();
+    assert_exit(&output, EXIT_USAGE);
+
+    // Rejected after parsing.
+    let config = temporary_path("config.toml");
+    std::fs::write(&config, "baud = \"fast\"\n").unwrap();
+    let output = sbootil()
+        .env("SBOOTIL_CONFIG", &config)
+        .args(["bootstub", "--serial", "/dev/null", "ping"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(config).unwrap();
+    assert_exit(&output, EXIT_USAGE);
+}
+
+#[test]
+fn missing_devices_exit_with_3() {
+    // Nothing listens on the port once the listener is gone.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let output = sbootil()
+        .args(["bootstub", "--serial", &format!("tcp:{}", address), "ping"])
+        .output()
+        .unwrap();
+    assert_exit(&output, EXIT_DEVICE_NOT_FOUND);
+}
+
+#[test]
+fn wrong_answers_exit_with_5() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let device = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 8];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(b"NOTASTUB").unwrap();
+
+        // Staying connected until the host gives up.
+        let _ = stream.read(&mut request);
+    });
+
+    let output = sbootil()
+        .args(["--handshake-timeout", "0.5", "bootstub"])
+        .args(["--serial", &format!("tcp:{}", address), "ping"])
+        .output()
+        .unwrap();
+    assert_exit(&output, EXIT_PROTOCOL);
+
+    device.join().unwrap();
+}
+
+#[cfg(all(unix, feature = "serial"))]
+mod simulated {
+    use super::*;
+    use sbootil::error::{EXIT_INTERRUPTED, EXIT_VERIFICATION};
+    use sbootil::simulator::{Options, Simulator};
+    use std::time::Duration;
+
+    #[test]
+    fn verification_failures_exit_with_6() {
+        let port = Simulator::spawn(Options::default()).unwrap();
+        let output_path = temporary_path("dump");
+
+        let output = sbootil()
+            .args(["bootstub", "--serial"])
+            .arg(&port)
+            .args(["dump", "--no-metadata", "--expected-sha256"])
+            .arg("00".repeat(32))
+            .args(["0", "0x100"])
+            .arg(&output_path)
+            .output()
+            .unwrap();
+        assert_exit(&output, EXIT_VERIFICATION);
+
+        let _ = std::fs::remove_file(output_path);
+    }
+
+    #[test]
+    fn interrupts_exit_with_7() {
+        // Slow enough that the dump is still going when Ctrl-C comes.
+        let port = Simulator::spawn(Options {
+            delay: Duration::from_millis(20),
+            fragment_size: Some(16),
+            ..Options::default()
+        })
+        .unwrap();
+        let output_path = temporary_path("dump");
+
+        let child = sbootil()
+            .args(["bootstub", "--serial"])
+            .arg(&port)
+            .args(["dump", "--no-metadata", "0", "0x10000"])
+            .arg(&output_path)
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(1500));
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+        }
+
+        let output = child.wait_with_output().unwrap();
+        assert_exit(&output, EXIT_INTERRUPTED);
+
+        let _ = std::fs::remove_file(output_path);
+    }
+}
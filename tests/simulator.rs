@@ -0,0 +1,101 @@
+// Runs the bootstub protocol over a real serial port, the pseudo-terminal of
+// the simulator, rather than a scripted mock.
+
+#![cfg(all(unix, feature = "serial"))]
+
+use sbootil::bootstub::{Capabilities, Checksum, DumpOptions, Feature, Session};
+use sbootil::cancel::CancelToken;
+use sbootil::error::Error;
+use sbootil::lock::LockMode;
+use sbootil::retry::RetryPolicy;
+use sbootil::serial::SerialPort;
+use sbootil::sha256;
+use sbootil::simulator::{Options, Simulator};
+use sbootil::timeouts::Timeouts;
+use sbootil::transport::CountingTransport;
+use std::time::Duration;
+
+fn connect(options: Options) -> Session {
+    let path = Simulator::spawn(options).unwrap();
+    let port = SerialPort::open(&path.display().to_string(), 115200, LockMode::Off).unwrap();
+
+    Session::connect(
+        Box::new(CountingTransport::new(Box::new(port))),
+        Timeouts::new(Duration::from_millis(500)),
+    )
+    .unwrap()
+}
+
+#[test]
+fn handshake_negotiates_what_the_stub_supports() {
+    let session = connect(Options::default());
+
+    assert!(session.negotiated());
+    assert!(session.capabilities().has(Feature::Crc));
+    assert_eq!(session.checksum(), Checksum::Crc32);
+
+    // Stubs from before the capabilities query don't answer it.
+    let session = connect(Options {
+        legacy: true,
+        ..Options::default()
+    });
+
+    assert_eq!(session.capabilities(), Capabilities::LEGACY);
+    assert_eq!(session.checksum(), Checksum::Xor);
+}
+
+#[test]
+fn dump_arrives_in_pieces_and_checks_out() {
+    let mut session = connect(Options {
+        delay: Duration::from_millis(1),
+        fragment_size: Some(100),
+        ..Options::default()
+    });
+    let options = DumpOptions {
+        chunk_size: 0x100,
+        ..DumpOptions::default()
+    };
+
+    let mut data = Vec::new();
+    let digest = session
+        .dump(0x1000, 0x1400, &options, &mut data, &CancelToken::new())
+        .unwrap();
+
+    // The simulator serves the lower byte of every address.
+    let expected = (0x1000..0x1400u32)
+        .map(|address| address as u8)
+        .collect::<Vec<_>>();
+    assert_eq!(data, expected);
+    assert_eq!(digest.sha256, sha256::sha256(&expected));
+    assert_eq!(session.statistics().retries, 0);
+    assert_eq!(session.statistics().checksum_failures, 0);
+}
+
+#[test]
+fn corrupted_checksums_are_retried_then_given_up_on() {
+    let mut session = connect(Options {
+        corrupt_checksum: true,
+        ..Options::default()
+    });
+    let options = DumpOptions {
+        chunk_size: 0x100,
+        retry: RetryPolicy {
+            backoff: Duration::ZERO,
+            ..RetryPolicy::new(2)
+        },
+        ..DumpOptions::default()
+    };
+
+    let mut data = Vec::new();
+    let result = session.dump(0, 0x100, &options, &mut data, &CancelToken::new());
+
+    assert!(
+        matches!(result, Err(Error::Verification(_))),
+        "{:?}",
+        result.err()
+    );
+    // Only the failures that were retried are counted, the last one is the
+    // error.
+    assert_eq!(session.statistics().checksum_failures, 2);
+    assert_eq!(session.statistics().retries, 2);
+}
@@ -0,0 +1,114 @@
+// Dumps over TCP from a simulated stub whose answers take a while to arrive,
+// like on a link to a remote lab, with and without an acknowledgement window.
+
+#![cfg(all(unix, feature = "serial"))]
+
+use sbootil::bootstub::{DumpOptions, Session};
+use sbootil::cancel::CancelToken;
+use sbootil::simulator::{Options, Simulator};
+use sbootil::timeouts::Timeouts;
+use sbootil::transport::TcpTransport;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Added to everything the stub sends, so once to every round trip.
+const LATENCY: Duration = Duration::from_millis(30);
+
+fn open_port(port: &PathBuf) -> File {
+    File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(port)
+        .unwrap()
+}
+
+// Forwards one connection to a new simulator and back, holding back what the
+// stub sends for LATENCY without slowing it down otherwise.
+fn serve_with_latency() -> SocketAddr {
+    let port = Simulator::spawn(Options::default()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut from_host = stream.try_clone().unwrap();
+        let mut to_host = stream;
+        let mut to_device = open_port(&port);
+        let mut from_device = open_port(&port);
+
+        thread::spawn(move || std::io::copy(&mut from_host, &mut to_device));
+
+        let (sender, receiver) = mpsc::channel::<(Instant, Vec<u8>)>();
+        thread::spawn(move || {
+            for (due, data) in receiver {
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                if to_host.write_all(&data).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut buf = [0u8; 4096];
+        while let Ok(count @ 1..) = from_device.read(&mut buf) {
+            if sender
+                .send((Instant::now() + LATENCY, buf[..count].to_vec()))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    address
+}
+
+// How long a dump of 16 chunks takes with the given window.
+fn dump_with_window(window: u32) -> Duration {
+    let transport = TcpTransport::connect(&serve_with_latency().to_string()).unwrap();
+    let mut session =
+        Session::connect(Box::new(transport), Timeouts::new(Duration::from_secs(2))).unwrap();
+    let options = DumpOptions {
+        chunk_size: 0x100,
+        window,
+        ..DumpOptions::default()
+    };
+
+    let started = Instant::now();
+    let mut data = Vec::new();
+    session
+        .dump(0, 0x1000, &options, &mut data, &CancelToken::new())
+        .unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(
+        data,
+        (0..0x1000u32)
+            .map(|address| address as u8)
+            .collect::<Vec<_>>()
+    );
+
+    elapsed
+}
+
+#[test]
+fn a_window_keeps_latency_from_adding_up() {
+    let one_at_a_time = dump_with_window(1);
+    let windowed = dump_with_window(8);
+
+    // Every chunk waits for a round trip without a window, only every eighth
+    // or so with one.
+    assert!(one_at_a_time > LATENCY * 16, "{:?}", one_at_a_time);
+    assert!(
+        windowed * 2 < one_at_a_time,
+        "{:?} with a window, {:?} without",
+        windowed,
+        one_at_a_time
+    );
+}